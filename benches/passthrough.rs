@@ -0,0 +1,341 @@
+//! Throughput of play packets from the upstream server to the client,
+//! comparing spliced sockets with packets passing through the session actors,
+//! and how the throughput of concurrent sessions scales with the number of arbiters.
+
+use std::net::{IpAddr, SocketAddr, TcpListener as StdTcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use actix::prelude::*;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::channel::oneshot;
+use futures::future::{join_all, ready, BoxFuture, FutureExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use mineroute::net::manager::ProxyClientManager;
+use mineroute::arbiters::{available_cores, ArbiterPool};
+use mineroute::listener::{ApplyListeners, ListenerManager};
+use mineroute::server_state::{Configuration, SharedConfiguration, ServerConfig};
+use mineroute::settings::{CompressionLevel, FrameLimits, ListenerSettings};
+use mineroute::upstream::{ResolveError, Resolver, SrvTarget, UpstreamResolver};
+
+const PROTOCOL_VERSION: i32 = 340;
+const COMPRESSION_THRESHOLD: usize = 256;
+const HOST: &str = "bench.local";
+
+/// Chunk data packets interleaved with small entity movement packets
+const CHUNKS: usize = 400;
+const CHUNK_SIZE: usize = 8192;
+const MOVES_PER_CHUNK: usize = 8;
+
+/// Sessions transferring their play packets at the same time when measuring the scaling
+const CONCURRENT_SESSIONS: usize = 8;
+
+/// Upstreams are IP addresses, so no DNS queries are made
+struct NoDns;
+
+impl Resolver for NoDns {
+    fn lookup_srv(&self, _name: &str) -> BoxFuture<'static, Result<Vec<SrvTarget>, ResolveError>> {
+        ready(Ok(vec![])).boxed()
+    }
+
+    fn lookup_ip(&self, host: &str) -> BoxFuture<'static, Result<Vec<IpAddr>, ResolveError>> {
+        ready(Err(ResolveError(format!("{} not found", host)))).boxed()
+    }
+}
+
+fn var_int(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+fn string(buf: &mut Vec<u8>, value: &str) {
+    var_int(buf, value.len() as i32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn frame(buf: &mut Vec<u8>, payload: &[u8]) {
+    var_int(buf, payload.len() as i32);
+    buf.extend_from_slice(payload);
+}
+
+/// A frame in the format used once compression is enabled
+fn compressed_frame(buf: &mut Vec<u8>, packet: &[u8]) {
+    let mut payload = vec![];
+    if packet.len() < COMPRESSION_THRESHOLD {
+        var_int(&mut payload, 0);
+        payload.extend_from_slice(packet);
+    } else {
+        var_int(&mut payload, packet.len() as i32);
+        payload.extend_from_slice(&deflate::deflate_bytes_zlib(packet));
+    }
+    frame(buf, &payload);
+}
+
+/// The play packets sent by the upstream server, already framed and compressed
+fn play_frames() -> Vec<u8> {
+    // Somewhat compressible data, similar to block states of a chunk
+    let mut seed = 0x2545_F491_u32;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed % 16) as u8
+    };
+
+    let mut frames = vec![];
+    for _ in 0..CHUNKS {
+        let mut chunk = vec![0x20];
+        chunk.extend((0..CHUNK_SIZE).map(|_| next()));
+        compressed_frame(&mut frames, &chunk);
+
+        for _ in 0..MOVES_PER_CHUNK {
+            let mut movement = vec![0x26];
+            movement.extend((0..12).map(|_| next()));
+            compressed_frame(&mut frames, &movement);
+        }
+    }
+    frames
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+    let mut length = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = stream.read_u8().await.unwrap();
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).await.unwrap();
+    payload
+}
+
+fn bench_listener(bind: SocketAddr, splice: bool) -> ListenerSettings {
+    ListenerSettings {
+        name: "bench".to_owned(),
+        bind,
+        ipv6_only: false,
+        proxy_protocol: false,
+        hostnames: None,
+        nodelay: true,
+        keepalive: None,
+        reuse_port: false,
+        compression_threshold: None,
+        compression_level: CompressionLevel::Default,
+        splice,
+        limits: FrameLimits::default(),
+        query: None,
+        bedrock: None,
+    }
+}
+
+/// Accept the login of the proxy and send the play frames once told to
+async fn serve_upstream(mut upstream: TcpListener, frames: Arc<Vec<u8>>, start: oneshot::Receiver<()>) {
+    let (mut stream, _) = upstream.accept().await.unwrap();
+    read_frame(&mut stream).await; // Handshake
+    read_frame(&mut stream).await; // Login start
+
+    let mut login = vec![];
+    let mut set_compression = vec![0x03];
+    var_int(&mut set_compression, COMPRESSION_THRESHOLD as i32);
+    frame(&mut login, &set_compression);
+
+    let mut login_success = vec![0x02];
+    string(&mut login_success, "00000000-0000-0000-0000-000000000000");
+    string(&mut login_success, "bench");
+    compressed_frame(&mut login, &login_success);
+
+    stream.write_all(&login).await.unwrap();
+
+    // Play packets are only sent once the clock runs
+    let _ = start.await;
+    stream.write_all(&frames).await.unwrap();
+
+    // Keep the connection open until the session ends
+    let mut discard = [0; 1024];
+    while let Ok(read) = stream.read(&mut discard).await {
+        if read == 0 {
+            break;
+        }
+    }
+}
+
+/// Connect to the proxy and log in to the server of a hostname
+async fn log_in(proxy_address: SocketAddr, host: &str) -> TcpStream {
+    let mut client = TcpStream::connect(proxy_address).await.unwrap();
+    let mut handshake = vec![];
+    let mut packet = vec![0x00];
+    var_int(&mut packet, PROTOCOL_VERSION);
+    string(&mut packet, host);
+    packet.extend_from_slice(&proxy_address.port().to_be_bytes());
+    var_int(&mut packet, 2);
+    frame(&mut handshake, &packet);
+
+    let mut packet = vec![0x00];
+    string(&mut packet, "bench");
+    frame(&mut handshake, &packet);
+    client.write_all(&handshake).await.unwrap();
+
+    read_frame(&mut client).await; // Set compression
+    read_frame(&mut client).await; // Login success
+    client
+}
+
+fn bench_config(servers: &[(String, SocketAddr)]) -> Arc<SharedConfiguration> {
+    let resolver = Arc::new(UpstreamResolver::new(Arc::new(NoDns), Duration::from_secs(60)));
+    let mut config = Configuration::new(resolver);
+    for (host, upstream) in servers {
+        config.add_server(host, ServerConfig::new(upstream.to_string().parse().unwrap()));
+    }
+    Arc::new(SharedConfiguration::new(config))
+}
+
+/// Log in through a fresh proxy session and measure how long
+/// it takes until all play frames arrived at the client
+async fn transfer(splice: bool, frames: Arc<Vec<u8>>) -> Duration {
+    let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let upstream = TcpListener::bind(localhost).await.unwrap();
+    let mut proxy = TcpListener::bind(localhost).await.unwrap();
+    let proxy_address = proxy.local_addr().unwrap();
+    let length = frames.len();
+    let (start_tx, start_rx) = oneshot::channel();
+
+    let config = bench_config(&[(HOST.to_owned(), upstream.local_addr().unwrap())]);
+    let listener = Arc::new(bench_listener(proxy_address, splice));
+
+    actix::spawn(async move {
+        let (stream, address) = proxy.accept().await.unwrap();
+        ProxyClientManager::create(move |ctx| ProxyClientManager::new(config, listener, address, stream, ctx));
+    });
+    actix::spawn(serve_upstream(upstream, frames, start_rx));
+
+    let mut client = log_in(proxy_address, HOST).await;
+    let start = Instant::now();
+    let _ = start_tx.send(());
+    let mut received = vec![0; length];
+    client.read_exact(&mut received).await.unwrap();
+    start.elapsed()
+}
+
+/// Run concurrent sessions through a proxy whose sessions run on the provided number of arbiters
+/// and measure how long it takes until all of them received their play frames.
+/// The fake clients and upstreams run on arbiters of their own.
+async fn concurrent_transfer(workers: usize, load: &ArbiterPool, frames: Arc<Vec<u8>>) -> Duration {
+    let upstreams: Vec<StdTcpListener> = (0..CONCURRENT_SESSIONS)
+        .map(|_| StdTcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let servers: Vec<(String, SocketAddr)> = upstreams.iter().enumerate()
+        .map(|(index, upstream)| (format!("{}.{}", index, HOST), upstream.local_addr().unwrap()))
+        .collect();
+    let proxy_address = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let listeners = ListenerManager::new(bench_config(&servers), Arc::new(ArbiterPool::new(workers)), PathBuf::new()).start();
+    listeners.send(ApplyListeners(vec![bench_listener(proxy_address, false)])).await.unwrap().unwrap();
+
+    let mut logged_in = vec![];
+    let mut starts = vec![];
+    let mut received = vec![];
+    for (upstream, (host, _)) in upstreams.into_iter().zip(servers) {
+        let (logged_in_tx, logged_in_rx) = oneshot::channel();
+        let (start_tx, start_rx) = oneshot::channel();
+        let (received_tx, received_rx) = oneshot::channel();
+        let frames = frames.clone();
+
+        load.spawn(async move {
+            upstream.set_nonblocking(true).unwrap();
+            let (upstream_start_tx, upstream_start_rx) = oneshot::channel();
+            actix::spawn(serve_upstream(TcpListener::from_std(upstream).unwrap(), frames.clone(), upstream_start_rx));
+
+            let mut client = log_in(proxy_address, &host).await;
+            let _ = logged_in_tx.send(());
+            let _ = start_rx.await;
+            let _ = upstream_start_tx.send(());
+
+            let mut buffer = vec![0; frames.len()];
+            client.read_exact(&mut buffer).await.unwrap();
+            let _ = received_tx.send(());
+        });
+        logged_in.push(logged_in_rx);
+        starts.push(start_tx);
+        received.push(received_rx);
+    }
+
+    join_all(logged_in).await;
+    let start = Instant::now();
+    for start in starts {
+        let _ = start.send(());
+    }
+    join_all(received).await;
+    let elapsed = start.elapsed();
+
+    drop(listeners);
+    elapsed
+}
+
+fn passthrough(c: &mut Criterion) {
+    let frames = Arc::new(play_frames());
+    let mut system = System::new("passthrough");
+
+    let mut group = c.benchmark_group("play_passthrough");
+    group.throughput(Throughput::Bytes(frames.len() as u64));
+    group.sample_size(20);
+
+    for &(name, splice) in &[("actor", false), ("splice", true)] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iterations| {
+                let frames = frames.clone();
+                system.block_on(async move {
+                    let mut elapsed = Duration::from_secs(0);
+                    for _ in 0..iterations {
+                        elapsed += transfer(splice, frames.clone()).await;
+                    }
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn scaling(c: &mut Criterion) {
+    let frames = Arc::new(play_frames());
+    let mut system = System::new("scaling");
+    let cores = available_cores();
+    let load = system.block_on(async move { Arc::new(ArbiterPool::new(cores)) });
+
+    let mut group = c.benchmark_group("play_scaling");
+    group.throughput(Throughput::Bytes((frames.len() * CONCURRENT_SESSIONS) as u64));
+    group.sample_size(10);
+
+    let mut workers = vec![1, 2, 4, cores];
+    workers.retain(|&count| count <= cores);
+    workers.dedup();
+    for count in workers {
+        group.bench_with_input(BenchmarkId::new("workers", count), &count, |b, &count| {
+            b.iter_custom(|iterations| {
+                let (frames, load) = (frames.clone(), load.clone());
+                system.block_on(async move {
+                    let mut elapsed = Duration::from_secs(0);
+                    for _ in 0..iterations {
+                        elapsed += concurrent_transfer(count, &load, frames.clone()).await;
+                    }
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, passthrough, scaling);
+criterion_main!(benches);
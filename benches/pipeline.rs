@@ -0,0 +1,107 @@
+//! Throughput of play packets sent through a `PipelineSink` and received from a `PipelineStream`
+//! over a loopback connection, with and without compression.
+//!
+//! Every benchmark is reported twice, in `pipeline/packets` as packets/s and in `pipeline/bytes` as bytes/s.
+
+use std::net::SocketAddr;
+use std::time::Instant;
+use actix::prelude::*;
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{join, stream, SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use mineroute::net::{Client, PacketClientEnum, Protocol, Server};
+use mineroute::net::pipeline::{HandlerPipeline, PipelineSink, PipelineStream};
+use mineroute::net::play::RawPacket;
+use mineroute::settings::CompressionLevel;
+
+/// How many bytes of payload an iteration sends
+const BATCH_BYTES: usize = 1024 * 1024;
+
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Somewhat compressible data, similar to block states of a chunk
+fn payload(size: usize) -> Bytes {
+    let mut seed = 0x2545_F491_u32;
+    (0..size).map(|_| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed % 16) as u8
+    }).collect::<Vec<_>>().into()
+}
+
+/// How many packets of a size are sent per iteration
+fn batch(size: usize) -> usize {
+    (BATCH_BYTES / size).clamp(1, 10_000)
+}
+
+/// Both ends of a loopback connection in the play state
+async fn loopback(compression: Option<usize>) -> (PipelineSink<Server>, PipelineStream<Client>) {
+    let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut listener = TcpListener::bind(localhost).await.unwrap();
+    let connecting = TcpStream::connect(listener.local_addr().unwrap());
+    let (sending, accepted) = join!(connecting, listener.accept());
+    let (receiving, _) = accepted.unwrap();
+
+    let (sender, sink, _) = HandlerPipeline::<Server>::new(sending.unwrap());
+    let (receiver, _, stream) = HandlerPipeline::<Client>::new(receiving);
+    let mut sender = sender.borrow_mut();
+    sender.set_protocol(Protocol::Play);
+    sender.enable_compression(compression, CompressionLevel::Default);
+    let mut receiver = receiver.borrow_mut();
+    receiver.set_protocol(Protocol::Play);
+    receiver.enable_compression(compression, CompressionLevel::Default);
+
+    (sink, stream)
+}
+
+/// Send packets of the provided size and receive them on the other end
+async fn round_trip(sink: &mut PipelineSink<Server>, stream: &mut PipelineStream<Client>, data: &Bytes, count: usize) {
+    let mut packets = stream::iter((0..count).map(|_| {
+        Ok(PacketClientEnum::Raw(RawPacket { id: 0x20, data: data.clone(), compressed: None }))
+    }));
+    let send = sink.send_all(&mut packets);
+    let receive = async {
+        for _ in 0..count {
+            stream.next().await.unwrap().unwrap();
+        }
+    };
+    let (sent, _) = join!(send, receive);
+    sent.unwrap();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut system = System::new("pipeline");
+
+    for &(name, compression) in &[("uncompressed", None), ("threshold_256", Some(COMPRESSION_THRESHOLD))] {
+        for &size in &[32, 1024, 8192] {
+            let data = payload(size);
+            let count = batch(size);
+            let throughputs = [("packets", Throughput::Elements(count as u64)), ("bytes", Throughput::Bytes((count * size) as u64))];
+
+            for (unit, throughput) in &throughputs {
+                let mut group = c.benchmark_group(format!("pipeline/{}", unit));
+                group.throughput(throughput.clone());
+                group.sample_size(20);
+                group.bench_function(format!("{}/{}", name, size), |b| {
+                    b.iter_custom(|iterations| {
+                        let data = data.clone();
+                        system.block_on(async move {
+                            let (mut sink, mut stream) = loopback(compression).await;
+                            let start = Instant::now();
+                            for _ in 0..iterations {
+                                round_trip(&mut sink, &mut stream, &data, count).await;
+                            }
+                            start.elapsed()
+                        })
+                    })
+                });
+                group.finish();
+            }
+        }
+    }
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
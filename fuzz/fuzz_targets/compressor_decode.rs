@@ -0,0 +1,12 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use mineroute_protocol::compressor::{Compressor, CompressionLevel};
+use mineroute_protocol::framing::MAX_DECOMPRESSED_SIZE;
+
+fuzz_target!(|data: &[u8]| {
+    let mut compressor = Compressor::new(256, CompressionLevel::Default);
+    if let Ok(packet) = compressor.decode(BytesMut::from(data), MAX_DECOMPRESSED_SIZE) {
+        assert!(packet.len() <= MAX_DECOMPRESSED_SIZE);
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use mineroute_protocol::framing::{FrameCodec, MAX_FRAME_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    while let Ok(Some(frame)) = FrameCodec::try_decode(&mut src, MAX_FRAME_SIZE) {
+        assert!(frame.len() <= MAX_FRAME_SIZE);
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use mineroute_protocol::query::{QueryRequest, QueryResponse};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = QueryRequest::read(data) {
+        let mut buffer = BytesMut::new();
+        request.write(&mut buffer);
+        assert_eq!(QueryRequest::read(&buffer), Ok(request));
+    }
+
+    let request = QueryRequest::FullStat { session_id: 1, token: 1 };
+    if let Ok(response) = QueryResponse::read(&request, data) {
+        let mut buffer = BytesMut::new();
+        response.write(&mut buffer);
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mineroute_protocol::bedrock::login_display_name;
+use mineroute_protocol::raknet::{read_frames, Reassembler};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = login_display_name(data);
+
+    let mut reassembler = Reassembler::default();
+    for frame in read_frames(data).unwrap_or_default() {
+        if let Some(packet) = reassembler.push(frame) {
+            let _ = login_display_name(&packet);
+        }
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use mineroute_protocol::{Client, Protocol, Server};
+use mineroute_protocol::buffer::Buffer;
+use mineroute_protocol::wire_codec::{ClientWireCodec, ServerWireCodec, WireCodec};
+
+const PROTOCOLS: [Protocol; 4] = [Protocol::Handshake, Protocol::Status, Protocol::Login, Protocol::Play];
+
+// The first byte selects the protocol state, the remainder is a decompressed frame
+fuzz_target!(|data: &[u8]| {
+    if let Some((state, frame)) = data.split_first() {
+        let protocol = &PROTOCOLS[*state as usize % PROTOCOLS.len()];
+
+        let mut buf = BytesMut::from(frame);
+        if let Ok(packet_id) = buf.read_u8() {
+            let _ = <ClientWireCodec as WireCodec<Client>>::read_packet(protocol, packet_id, &mut buf.clone(), None);
+            let _ = <ServerWireCodec as WireCodec<Server>>::read_packet(protocol, packet_id, &mut buf, None);
+        }
+    }
+});
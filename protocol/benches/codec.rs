@@ -0,0 +1,204 @@
+//! Throughput of the protocol primitives.
+//!
+//! Every benchmark handles a batch of packets and is reported twice,
+//! in the `packets` groups as packets/s and in the `bytes` groups as bytes/s.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use mineroute_protocol::{Protocol, PacketClientEnum};
+use mineroute_protocol::buffer::{Buffer, BufferMut};
+use mineroute_protocol::compressor::{Compressor, CompressionLevel};
+use mineroute_protocol::framing::{FrameBuffer, FrameCodec, MAX_DECOMPRESSED_SIZE, MAX_FRAME_SIZE};
+use mineroute_protocol::play::RawPacket;
+use mineroute_protocol::wire_codec::{ClientWireCodec, ServerWireCodec, WireCodec};
+
+/// How many bytes of payload a batch handles, so that large packets do not take forever
+const BATCH_BYTES: usize = 256 * 1024;
+
+/// Var-ints of all sizes from one to five bytes
+const VAR_INTS: [i32; 5] = [1, 300, 70_000, 10_000_000, -1];
+
+/// Somewhat compressible data, similar to block states of a chunk
+fn payload(size: usize) -> Vec<u8> {
+    let mut seed = 0x2545_F491_u32;
+    (0..size).map(|_| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed % 16) as u8
+    }).collect()
+}
+
+/// How many packets of a size fit into a batch
+fn batch(size: usize) -> usize {
+    (BATCH_BYTES / size.max(1)).clamp(1, 1000)
+}
+
+/// Register a benchmark of a batch in both the `packets` and the `bytes` group
+fn bench_batch<F: FnMut()>(c: &mut Criterion, group: &str, id: &str, packets: usize, bytes: usize, mut routine: F) {
+    for (unit, throughput) in &[("packets", Throughput::Elements(packets as u64)), ("bytes", Throughput::Bytes(bytes as u64))] {
+        let mut benchmarks = c.benchmark_group(format!("{}/{}", group, unit));
+        benchmarks.throughput(throughput.clone());
+        benchmarks.bench_function(id, |b| b.iter(&mut routine));
+        benchmarks.finish();
+    }
+}
+
+fn var_int(c: &mut Criterion) {
+    let values: Vec<i32> = VAR_INTS.iter().cycle().take(1000).copied().collect();
+    let mut encoded = BytesMut::new();
+    for value in &values {
+        encoded.write_var_int(*value);
+    }
+    let size = encoded.len();
+
+    let mut buffer = BytesMut::with_capacity(size);
+    bench_batch(c, "var_int", "write", values.len(), size, || {
+        buffer.clear();
+        for value in &values {
+            buffer.write_var_int(*value);
+        }
+    });
+
+    bench_batch(c, "var_int", "read", values.len(), size, || {
+        let mut buffer = encoded.clone();
+        for _ in 0..values.len() {
+            buffer.read_var_int().unwrap();
+        }
+    });
+}
+
+fn frame_codec(c: &mut Criterion) {
+    for &size in &[16, 256, 4096] {
+        let payload = BytesMut::from(&payload(size)[..]);
+        let count = batch(size);
+
+        bench_batch(c, "frame_codec", &format!("encode/{}", size), count, count * size, || {
+            for _ in 0..count {
+                FrameCodec::encode(payload.clone()).unwrap();
+            }
+        });
+
+        let mut buffer = BytesMut::new();
+        bench_batch(c, "frame_codec", &format!("frame_buffer/{}", size), count, count * size, || {
+            for _ in 0..count {
+                let mut frame = FrameBuffer::new(&mut buffer);
+                frame.payload_mut().extend_from_slice(&payload);
+                frame.finish().unwrap();
+            }
+        });
+
+        let mut frames = BytesMut::new();
+        for _ in 0..count {
+            frames.extend_from_slice(&FrameCodec::encode(payload.clone()).unwrap());
+        }
+        bench_batch(c, "frame_codec", &format!("decode/{}", size), count, count * size, || {
+            let mut frames = frames.clone();
+            while FrameCodec::try_decode(&mut frames, MAX_FRAME_SIZE).unwrap().is_some() {}
+        });
+    }
+}
+
+fn compressor(c: &mut Criterion) {
+    for &threshold in &[64, 256, 1024] {
+        for &size in &[128, 1024, 16384] {
+            let mut compressor = Compressor::new(threshold, CompressionLevel::Default);
+            let payload = payload(size);
+            let count = batch(size);
+
+            let mut buffer = BytesMut::new();
+            let id = format!("encode/threshold_{}/{}", threshold, size);
+            bench_batch(c, "compressor", &id, count, count * size, || {
+                for _ in 0..count {
+                    let mut packet = FrameBuffer::new(&mut buffer);
+                    packet.payload_mut().extend_from_slice(&payload);
+                    compressor.encode(packet).unwrap().finish().unwrap();
+                }
+            });
+
+            let mut packet = FrameBuffer::new(&mut buffer);
+            packet.payload_mut().extend_from_slice(&payload);
+            let frame = BytesMut::from(compressor.encode(packet).unwrap().payload());
+            let id = format!("decode/threshold_{}/{}", threshold, size);
+            bench_batch(c, "compressor", &id, count, count * size, || {
+                for _ in 0..count {
+                    compressor.decode(frame.clone(), MAX_DECOMPRESSED_SIZE).unwrap();
+                }
+            });
+        }
+    }
+}
+
+fn wire_codec(c: &mut Criterion) {
+    for &size in &[32, 1024] {
+        let data = Bytes::from(payload(size));
+        let count = batch(size);
+
+        let packet = PacketClientEnum::Raw(RawPacket { id: 0x20, data: data.clone(), compressed: None });
+        let mut buffer = BytesMut::with_capacity(size + 1);
+        bench_batch(c, "wire_codec", &format!("write_packet/{}", size), count, count * size, || {
+            for _ in 0..count {
+                buffer.clear();
+                ServerWireCodec::write_packet(&Protocol::Play, &packet, &mut buffer).unwrap();
+            }
+        });
+
+        let mut body = BytesMut::new();
+        body.write_raw_bytes(&data);
+        bench_batch(c, "wire_codec", &format!("read_packet/{}", size), count, count * size, || {
+            for _ in 0..count {
+                let mut body = body.clone();
+                ClientWireCodec::read_packet(&Protocol::Play, 0x20, &mut body, None).unwrap();
+            }
+        });
+    }
+}
+
+/// Compress a packet the way the compressor did before it reused its zlib state and buffers
+fn one_shot_encode(payload: &BytesMut) -> BytesMut {
+    let compressed = deflate::deflate_bytes_zlib_conf(payload, deflate::Compression::Default);
+    let mut frame = BytesMut::with_capacity(5 + compressed.len());
+    frame.write_var_int(payload.len() as i32);
+    frame.extend_from_slice(&compressed);
+    frame
+}
+
+fn one_shot_decode(mut frame: BytesMut) -> BytesMut {
+    let size = frame.read_var_int().unwrap() as usize;
+    let mut decompressed = BytesMut::with_capacity(size);
+    let mut stream = inflate::InflateStream::from_zlib();
+    let mut data = &frame[..];
+    loop {
+        let (read, output) = stream.update(data).unwrap();
+        if output.is_empty() {
+            break;
+        }
+        decompressed.extend_from_slice(output);
+        data = &data[read..];
+    }
+    decompressed
+}
+
+/// The previous one-shot compression as a baseline for the `compressor` benchmarks
+fn one_shot_compressor(c: &mut Criterion) {
+    for &size in &[1024, 16384] {
+        let payload = BytesMut::from(&payload(size)[..]);
+        let count = batch(size);
+
+        bench_batch(c, "compressor", &format!("one_shot_encode/{}", size), count, count * size, || {
+            for _ in 0..count {
+                one_shot_encode(&payload);
+            }
+        });
+
+        let frame = one_shot_encode(&payload);
+        bench_batch(c, "compressor", &format!("one_shot_decode/{}", size), count, count * size, || {
+            for _ in 0..count {
+                one_shot_decode(frame.clone());
+            }
+        });
+    }
+}
+
+criterion_group!(benches, var_int, frame_codec, compressor, one_shot_compressor, wire_codec);
+criterion_main!(benches);
@@ -0,0 +1,278 @@
+//! Reads the name of a player from the Login packet of Minecraft Bedrock Edition.
+//!
+//! Game packets are batched into one RakNet packet, which is compressed
+//! once the server sent its network settings. The Login packet is sent before
+//! encryption is enabled and carries the identity of the player as a JWT chain.
+//! The signatures of the chain are not verified, the name is as claimed by the client.
+
+use bytes::Buf;
+use flate2::{Decompress, FlushDecompress, Status};
+use serde_json::Value;
+
+/// The RakNet packet id of batched game packets
+pub const GAME_PACKET: u8 = 0xfe;
+
+const LOGIN: u32 = 0x01;
+
+/// The compression algorithms announced in front of a batch since 1.20.60
+const ZLIB: u8 = 0x00;
+const NO_COMPRESSION: u8 = 0xff;
+
+/// The identity of the player is at the start of the Login packet,
+/// the skin data following it does not need to be inflated
+const MAX_INFLATED_SIZE: usize = 256 * 1024;
+
+/// Longer names or ones containing control characters are ignored
+const MAX_NAME_LENGTH: usize = 32;
+
+/// The display name of the player, if the RakNet packet is a batch containing the Login packet
+pub fn login_display_name(packet: &[u8]) -> Option<String> {
+    let batch = match packet.split_first() {
+        Some((&GAME_PACKET, batch)) if !batch.is_empty() => batch,
+        _ => return None,
+    };
+
+    // Whether the batch is compressed, and how, depends on the version of the client
+    let name = match batch[0] {
+        NO_COMPRESSION => read_batch(&batch[1..]),
+        ZLIB => read_batch(&inflate(&batch[1..])),
+        _ => None,
+    };
+    name.or_else(|| read_batch(&inflate(batch)))
+        .or_else(|| read_batch(batch))
+}
+
+/// Find the Login packet among the packets of a batch, each of which is prefixed with its length.
+/// A packet truncated by the inflation limit is read as far as it goes.
+fn read_batch(mut batch: &[u8]) -> Option<String> {
+    while batch.has_remaining() {
+        let length = read_var_int(&mut batch)? as usize;
+        let mut packet = &batch[..length.min(batch.len())];
+        batch.advance(packet.len());
+
+        if read_var_int(&mut packet)? & 0x3ff == LOGIN {
+            return read_login(packet);
+        }
+    }
+    None
+}
+
+fn read_login(mut packet: &[u8]) -> Option<String> {
+    if packet.remaining() < 4 {
+        return None;
+    }
+    let _protocol_version = packet.get_i32();
+    let _length = read_var_int(&mut packet)?;
+
+    if packet.remaining() < 4 {
+        return None;
+    }
+    let chain_length = packet.get_i32_le();
+    if chain_length < 0 || chain_length as usize > packet.remaining() {
+        return None;
+    }
+    let chain: Value = serde_json::from_slice(&packet[..chain_length as usize]).ok()?;
+
+    // Since 1.21.90 the chain is wrapped in a certificate
+    let chain = match chain.get("Certificate").and_then(Value::as_str) {
+        Some(certificate) => serde_json::from_str::<Value>(certificate).ok()?.get("chain")?.clone(),
+        None => chain.get("chain")?.clone(),
+    };
+
+    chain.as_array()?.iter()
+        .filter_map(|token| token_display_name(token.as_str()?))
+        .next()
+}
+
+/// Read the name of the player from the claims of a JWT
+fn token_display_name(token: &str) -> Option<String> {
+    let claims = decode_base64_url(token.split('.').nth(1)?)?;
+    let claims: Value = serde_json::from_slice(&claims).ok()?;
+    let name = claims.pointer("/extraData/displayName")?.as_str()?;
+
+    let valid = !name.is_empty() && name.chars().count() <= MAX_NAME_LENGTH && !name.chars().any(char::is_control);
+    if valid {
+        Some(name.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Inflate a raw deflate stream as far as it is valid, up to [MAX_INFLATED_SIZE]
+fn inflate(data: &[u8]) -> Vec<u8> {
+    let mut inflater = Decompress::new(false);
+    let mut inflated = Vec::with_capacity((data.len() * 4).min(MAX_INFLATED_SIZE));
+    while inflated.len() < MAX_INFLATED_SIZE {
+        if inflated.len() == inflated.capacity() {
+            inflated.reserve(inflated.len().max(1024).min(MAX_INFLATED_SIZE - inflated.len()));
+        }
+        let consumed = inflater.total_in() as usize;
+        let produced = inflated.len();
+        match inflater.decompress_vec(&data[consumed..], &mut inflated, FlushDecompress::None) {
+            Ok(Status::StreamEnd) | Err(_) => break,
+            Ok(_) if inflater.total_in() as usize == consumed && inflated.len() == produced => break,
+            Ok(_) => continue,
+        }
+    }
+    inflated.truncate(MAX_INFLATED_SIZE);
+    inflated
+}
+
+fn read_var_int(buffer: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        if !buffer.has_remaining() {
+            return None;
+        }
+        let byte = buffer.get_u8();
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Decode base64 of the url-safe or the standard alphabet, with or without padding
+fn decode_base64_url(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in encoded.bytes().take_while(|byte| *byte != b'=') {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'-' | b'+' => 62,
+            b'_' | b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+
+    fn encode_base64_url(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut encoded = String::new();
+        for chunk in data.chunks(3) {
+            let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+        }
+        encoded
+    }
+
+    fn token(claims: &str) -> String {
+        format!("eyJhbGciOiJFUzM4NCJ9.{}.c2lnbmF0dXJl", encode_base64_url(claims.as_bytes()))
+    }
+
+    fn var_int(mut value: u32, buffer: &mut Vec<u8>) {
+        while value >= 0x80 {
+            buffer.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buffer.push(value as u8);
+    }
+
+    /// A batch with a Login packet whose chain is the provided json
+    fn login_batch(chain: &str) -> Vec<u8> {
+        let skin = token(r#"{"SkinData":"AAAA"}"#);
+        let mut request = vec![];
+        request.extend_from_slice(&(chain.len() as i32).to_le_bytes());
+        request.extend_from_slice(chain.as_bytes());
+        request.extend_from_slice(&(skin.len() as i32).to_le_bytes());
+        request.extend_from_slice(skin.as_bytes());
+
+        let mut packet = vec![];
+        var_int(LOGIN, &mut packet);
+        packet.extend_from_slice(&685i32.to_be_bytes());
+        var_int(request.len() as u32, &mut packet);
+        packet.extend_from_slice(&request);
+
+        let mut batch = vec![];
+        var_int(packet.len() as u32, &mut batch);
+        batch.extend_from_slice(&packet);
+        batch
+    }
+
+    fn chain() -> String {
+        let tokens = [token(r#"{"certificateAuthority":true}"#), token(r#"{"extraData":{"displayName":"Steve Bedrock","XUID":"1"}}"#)];
+        format!(r#"{{"chain":["{}","{}"]}}"#, tokens[0], tokens[1])
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn game_packet(prefix: &[u8], batch: &[u8]) -> Vec<u8> {
+        let mut packet = vec![GAME_PACKET];
+        packet.extend_from_slice(prefix);
+        packet.extend_from_slice(batch);
+        packet
+    }
+
+    #[test]
+    fn names_are_read_from_batches_of_all_versions() {
+        let batch = login_batch(&chain());
+        let expected = Some("Steve Bedrock".to_owned());
+        assert_eq!(login_display_name(&game_packet(&[NO_COMPRESSION], &batch)), expected);
+        assert_eq!(login_display_name(&game_packet(&[ZLIB], &deflate(&batch))), expected);
+        assert_eq!(login_display_name(&game_packet(&[], &deflate(&batch))), expected);
+    }
+
+    #[test]
+    fn names_are_read_from_certificates() {
+        let certificate = serde_json::to_string(&chain()).unwrap();
+        let chain = format!(r#"{{"AuthenticationType":0,"Certificate":{},"Token":""}}"#, certificate);
+        assert_eq!(login_display_name(&game_packet(&[NO_COMPRESSION], &login_batch(&chain))), Some("Steve Bedrock".to_owned()));
+    }
+
+    #[test]
+    fn other_packets_have_no_name() {
+        let mut batch = vec![];
+        var_int(3, &mut batch);
+        batch.extend_from_slice(&[0xc1, 0x01, 0]);
+        assert_eq!(login_display_name(&game_packet(&[NO_COMPRESSION], &batch)), None);
+        assert_eq!(login_display_name(&[0x09, 1, 2, 3]), None);
+        assert_eq!(login_display_name(&[GAME_PACKET]), None);
+        assert_eq!(login_display_name(&game_packet(&[ZLIB], &[1, 2, 3])), None);
+    }
+
+    #[test]
+    fn invalid_names_are_ignored() {
+        let chain = format!(r#"{{"chain":["{}"]}}"#, token(r#"{"extraData":{"displayName":"a\nb"}}"#));
+        assert_eq!(login_display_name(&game_packet(&[NO_COMPRESSION], &login_batch(&chain))), None);
+    }
+
+    #[test]
+    fn inflating_stops_at_the_limit() {
+        let bomb = deflate(&vec![0; 4 * MAX_INFLATED_SIZE]);
+        assert_eq!(inflate(&bomb).len(), MAX_INFLATED_SIZE);
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for length in 0..10 {
+            let data: Vec<u8> = (0..length).map(|i| (i * 37) as u8).collect();
+            assert_eq!(decode_base64_url(&encode_base64_url(&data)), Some(data));
+        }
+        assert_eq!(decode_base64_url("aGk="), Some(b"hi".to_vec()));
+        assert_eq!(decode_base64_url("a b"), None);
+    }
+}
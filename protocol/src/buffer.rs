@@ -3,7 +3,9 @@ use bytes::{Buf, BufMut, BytesMut, Bytes};
 use uuid::Uuid;
 
 /// Calculate the wire size of a number when encoded as var-int in bytes
-pub fn var_int_size(mut int: i32) -> usize {
+pub fn var_int_size(int: i32) -> usize {
+    // Negative numbers are encoded as their unsigned two's complement
+    let mut int = int as u32;
     let mut size = 1;
     while (int & !127) != 0 {
         int >>= 7;
@@ -43,7 +45,7 @@ impl Buffer for BytesMut {
     }
 
     fn read_u64(&mut self) -> Result<u64, ()> {
-        if self.remaining() >= 4 {
+        if self.remaining() >= 8 {
             Ok(self.get_u64())
         } else {
             Err(())
@@ -64,8 +66,11 @@ impl Buffer for BytesMut {
     }
 
     fn read_byte_array(&mut self) -> Result<Vec<u8>, ()> {
-        let size = self.read_var_int()? as usize;
-        (0..size).map(|_| self.read_u8()).collect::<Result<Vec<u8>, ()>>()
+        let size = self.read_var_int()?;
+        if size < 0 || size as usize > self.remaining() {
+            return Err(());
+        }
+        Ok(self.split_to(size as usize).to_vec())
     }
 
     fn read_string(&mut self) -> Result<String, ()> {
@@ -108,13 +113,14 @@ impl BufferMut for BytesMut {
     }
 
     fn write_u64(&mut self, long: u64) {
-        self.reserve(4);
+        self.reserve(8);
         self.put_u64(long);
     }
 
-    fn write_var_int(&mut self, mut int: i32) {
+    fn write_var_int(&mut self, int: i32) {
         self.reserve(var_int_size(int));
 
+        let mut int = int as u32;
         while (int & !127) != 0 {
             self.put_u8((int as u8) & 127 | 128);
             int >>= 7;
@@ -142,3 +148,61 @@ impl BufferMut for BytesMut {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn var_int_round_trip(int in any::<i32>()) {
+            let mut buf = BytesMut::new();
+            buf.write_var_int(int);
+            prop_assert_eq!(buf.len(), var_int_size(int));
+            prop_assert_eq!(buf.read_var_int(), Ok(int));
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn primitives_round_trip(byte in any::<u8>(), short in any::<u16>(), long in any::<u64>(),
+                                 string in ".*", uuid in any::<u128>()) {
+            let uuid = Uuid::from_u128(uuid);
+            let mut buf = BytesMut::new();
+            buf.write_u8(byte);
+            buf.write_u16(short);
+            buf.write_u64(long);
+            buf.write_string(&string);
+            buf.write_uuid(&uuid);
+
+            prop_assert_eq!(buf.read_u8(), Ok(byte));
+            prop_assert_eq!(buf.read_u16(), Ok(short));
+            prop_assert_eq!(buf.read_u64(), Ok(long));
+            prop_assert_eq!(buf.read_string(), Ok(string));
+            prop_assert_eq!(buf.read_uuid(), Ok(uuid));
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn reads_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..32)) {
+            let _ = BytesMut::from(&bytes[..]).read_u8();
+            let _ = BytesMut::from(&bytes[..]).read_u16();
+            let _ = BytesMut::from(&bytes[..]).read_u64();
+            let _ = BytesMut::from(&bytes[..]).read_var_int();
+            let _ = BytesMut::from(&bytes[..]).read_string();
+            let _ = BytesMut::from(&bytes[..]).read_uuid();
+        }
+    }
+
+    #[test]
+    fn var_int_longer_than_five_bytes_is_rejected() {
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01][..]);
+        assert_eq!(buf.read_var_int(), Err(()));
+    }
+
+    #[test]
+    fn u64_requires_eight_bytes() {
+        let mut buf = BytesMut::from(&[0u8; 7][..]);
+        assert_eq!(buf.read_u64(), Err(()));
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::{ConnectionType, Protocol};
+use crate::buffer::Buffer;
+use crate::compressor::{Compressor, CompressionLevel};
+use crate::framing::{FrameBuffer, FrameCodec, FrameError, MAX_FRAME_SIZE, MAX_DECOMPRESSED_SIZE};
+use crate::play::CompressedFrame;
+use crate::wire_codec::WireCodec;
+
+/// A tokio codec for one side of a minecraft connection.
+///
+/// `C` determines the direction, e.g. [crate::Server] decodes the packets
+/// that a client receives from a server. The protocol state and compression
+/// have to be updated as the connection progresses, e.g. through `Framed::codec_mut`.
+pub struct MinecraftCodec<C: ConnectionType> {
+    protocol: Protocol,
+    compressor: Option<Compressor>,
+    max_frame_size: usize,
+    max_decompressed_size: usize,
+
+    /// Packets are encoded into this buffer before they are copied to the destination
+    buffer: BytesMut,
+    phantom: PhantomData<C>,
+}
+
+impl<C: ConnectionType> MinecraftCodec<C> {
+    /// A codec in the handshake state without compression
+    pub fn new() -> MinecraftCodec<C> {
+        MinecraftCodec {
+            protocol: Protocol::Handshake,
+            compressor: None,
+            max_frame_size: MAX_FRAME_SIZE,
+            max_decompressed_size: MAX_DECOMPRESSED_SIZE,
+            buffer: BytesMut::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Compress packets exceeding the size limit, or disable compression if there is none
+    pub fn enable_compression(&mut self, size_limit: Option<usize>, level: CompressionLevel) {
+        self.compressor = size_limit.map(|size_limit| Compressor::new(size_limit, level));
+    }
+
+    /// The compression threshold, if compression is enabled
+    pub fn compression(&self) -> Option<usize> {
+        self.compressor.as_ref().map(|compressor| compressor.size_limit)
+    }
+
+    /// Reject received frames and decompressed packets exceeding these sizes
+    pub fn set_limits(&mut self, max_frame_size: usize, max_decompressed_size: usize) {
+        self.max_frame_size = max_frame_size;
+        self.max_decompressed_size = max_decompressed_size;
+    }
+}
+
+impl<C: ConnectionType> Default for MinecraftCodec<C> {
+    fn default() -> Self {
+        MinecraftCodec::new()
+    }
+}
+
+impl<C: ConnectionType> Decoder for MinecraftCodec<C> {
+    type Item = C::In;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<C::In>, CodecError> {
+        let mut frame = match FrameCodec::try_decode(src, self.max_frame_size)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let mut compressed = None;
+        if let Some(compressor) = &mut self.compressor {
+            // Keep the compressed frame, so that it may be forwarded without compressing it again
+            if Compressor::is_compressed(&frame) {
+                let data = frame.freeze();
+                frame = compressor.decompress(&data, self.max_decompressed_size)?;
+                compressed = Some(CompressedFrame {
+                    size_limit: compressor.size_limit,
+                    data,
+                });
+            } else {
+                frame = compressor.decode(frame, self.max_decompressed_size)?;
+            }
+        }
+
+        let packet_id = frame.read_u8().map_err(FrameError::from)?;
+        let packet = C::WC::read_packet(&self.protocol, packet_id, &mut frame, compressed).map_err(FrameError::from)?;
+        Ok(Some(packet))
+    }
+}
+
+impl<C: ConnectionType> Encoder for MinecraftCodec<C> {
+    type Item = C::Out;
+    type Error = CodecError;
+
+    fn encode(&mut self, packet: C::Out, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut frame = FrameBuffer::new(&mut self.buffer);
+        C::WC::write_packet(&self.protocol, &packet, frame.payload_mut()).map_err(|_| CodecError::Unencodable)?;
+
+        if let Some(compressor) = &mut self.compressor {
+            frame = compressor.encode(frame).map_err(|_| CodecError::Unencodable)?;
+        }
+
+        let frame = frame.finish().map_err(|_| CodecError::Unencodable)?;
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+/// The reason why a [MinecraftCodec] failed
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+
+    /// A received frame was rejected
+    Frame(FrameError),
+
+    /// The packet cannot be sent in the current protocol state
+    Unencodable,
+}
+
+impl From<io::Error> for CodecError {
+    fn from(error: io::Error) -> Self {
+        CodecError::Io(error)
+    }
+}
+
+impl From<FrameError> for CodecError {
+    fn from(error: FrameError) -> Self {
+        CodecError::Frame(error)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(error) => error.fmt(f),
+            CodecError::Frame(error) => error.fmt(f),
+            CodecError::Unencodable => f.write_str("the packet cannot be sent in the current protocol state"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::{Client, Server, PacketClientEnum, PacketServerEnum};
+    use crate::play::RawPacket;
+
+    #[test]
+    fn play_packets_round_trip_with_compression() {
+        let mut client = MinecraftCodec::<Server>::new();
+        client.set_protocol(Protocol::Play);
+        client.enable_compression(Some(64), CompressionLevel::Default);
+
+        let mut server = MinecraftCodec::<Client>::new();
+        server.set_protocol(Protocol::Play);
+        server.enable_compression(Some(64), CompressionLevel::Default);
+
+        let mut wire = BytesMut::new();
+        for size in &[3, 1000] {
+            let packet = RawPacket { id: 0x0b, data: Bytes::from(vec![7; *size]), compressed: None };
+            client.encode(PacketClientEnum::Raw(packet), &mut wire).unwrap();
+        }
+
+        for size in &[3, 1000] {
+            match server.decode(&mut wire).unwrap() {
+                Some(PacketClientEnum::Raw(packet)) => {
+                    assert_eq!(packet.id, 0x0b);
+                    assert_eq!(packet.data, Bytes::from(vec![7; *size]));
+                    assert_eq!(packet.compressed.is_some(), *size >= 64);
+                }
+                packet => panic!("unexpected packet {:?}", packet),
+            }
+        }
+        assert!(server.decode(&mut wire).unwrap().is_none());
+    }
+
+    #[test]
+    fn packets_of_other_states_cannot_be_encoded() {
+        let mut codec = MinecraftCodec::<Client>::new();
+        let packet = PacketServerEnum::Raw(RawPacket { id: 0, data: Bytes::new(), compressed: None });
+        assert!(matches!(codec.encode(packet, &mut BytesMut::new()), Err(CodecError::Unencodable)));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut codec = MinecraftCodec::<Client>::new();
+        codec.set_limits(16, 16);
+        let mut wire = BytesMut::from(&[0x20, 0x00][..]);
+        assert!(matches!(codec.decode(&mut wire), Err(CodecError::Frame(FrameError::TooLarge { size: 32, limit: 16 }))));
+    }
+}
@@ -0,0 +1,312 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use bytes::{Buf, BytesMut};
+use crate::framing::{FrameBuffer, FrameError};
+use serde::Deserialize;
+
+/// Buffer space allocated upfront for decompressing a packet
+const MAX_PREALLOCATION: usize = 64 * 1024;
+
+/// Space for the zlib header and block headers of a packet that cannot be compressed
+const DEFLATE_OVERHEAD: usize = 64;
+
+/// Trade-off between compression speed and size
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionLevel {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl From<CompressionLevel> for Compression {
+    fn from(level: CompressionLevel) -> Compression {
+        match level {
+            CompressionLevel::Fast => Compression::fast(),
+            CompressionLevel::Default => Compression::default(),
+            CompressionLevel::Best => Compression::best(),
+        }
+    }
+}
+
+/// An additional step in the pipeline compressing (deflate)
+/// packets exceeding a defined byte size.
+///
+/// Each connection has its own compressor, whose zlib state and buffers are reused for every packet.
+pub struct Compressor {
+    pub size_limit: usize,
+    deflater: Compress,
+    inflater: Decompress,
+
+    /// Deflated packets are framed in this buffer
+    encoded: BytesMut,
+
+    /// Decompressed packets are split off this buffer
+    decoded: BytesMut,
+}
+
+impl Compressor {
+    pub fn new(size_limit: usize, level: CompressionLevel) -> Compressor {
+        Compressor {
+            size_limit,
+            deflater: Compress::new(level.into(), true),
+            inflater: Decompress::new(true),
+            encoded: BytesMut::new(),
+            decoded: BytesMut::new(),
+        }
+    }
+
+    /// Prefix a packet with its uncompressed size, deflating it if it exceeds the size limit.
+    /// Deflated packets are written to a buffer of the compressor.
+    pub fn encode<'a>(&'a mut self, mut packet: FrameBuffer<'a>) -> Result<FrameBuffer<'a>, ()> {
+        let size = packet.payload().len();
+        if size < self.size_limit {
+            packet.prepend_var_int(0)?;
+            return Ok(packet);
+        }
+
+        let mut compressed = FrameBuffer::new(&mut self.encoded);
+        deflate(&mut self.deflater, packet.payload(), compressed.payload_mut())?;
+        compressed.prepend_var_int(size as i32)?;
+        Ok(compressed)
+    }
+
+    /// Whether the sender compressed a frame, rather than just prefixing it with a zero size
+    pub fn is_compressed(frame: &BytesMut) -> bool {
+        frame.first().is_some_and(|byte| *byte != 0)
+    }
+
+    /// Decompress a received frame.
+    /// Inflating stops as soon as the packet exceeds `limit` or the size announced by the sender.
+    pub fn decode(&mut self, mut buffer: BytesMut, limit: usize) -> Result<BytesMut, FrameError> {
+        if buffer.first() == Some(&0) {
+            buffer.advance(1);
+            Ok(buffer)
+        } else {
+            self.decompress(&buffer, limit)
+        }
+    }
+
+    /// Decompress a received frame without consuming it,
+    /// so that it can be kept to be forwarded as it is
+    pub fn decompress(&mut self, frame: &[u8], limit: usize) -> Result<BytesMut, FrameError> {
+        let (uncompressed_size, data) = read_size(frame)?;
+        match uncompressed_size {
+            0 => Ok(BytesMut::from(data)),
+            uncompressed_size if uncompressed_size < 0 => Err(FrameError::Malformed),
+            uncompressed_size => {
+                let uncompressed_size = uncompressed_size as usize;
+                if uncompressed_size > limit {
+                    return Err(FrameError::DecompressedTooLarge { size: uncompressed_size, limit });
+                }
+
+                let result = self.inflate(data, uncompressed_size);
+                if result.is_err() {
+                    self.decoded.clear();
+                }
+                result
+            }
+        }
+    }
+
+    /// Read the id of the packet in a received frame without consuming it.
+    /// Only the first byte of a compressed packet is inflated.
+    pub fn peek_packet_id(&mut self, frame: &[u8]) -> Option<u8> {
+        match read_size(frame).ok()? {
+            (0, data) => data.first().copied(),
+            (uncompressed_size, data) if uncompressed_size > 0 => {
+                let mut id = [0];
+                self.inflater.reset(true);
+                self.inflater.decompress(data, &mut id, FlushDecompress::None).ok()?;
+                match self.inflater.total_out() {
+                    1 => Some(id[0]),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Inflate zlib data that is expected to decompress to exactly `size` bytes
+    fn inflate(&mut self, data: &[u8], size: usize) -> Result<BytesMut, FrameError> {
+        self.inflater.reset(true);
+        loop {
+            let consumed = self.inflater.total_in() as usize;
+            let produced = self.inflater.total_out() as usize;
+
+            // The announced size is not trusted until that many bytes were actually inflated.
+            // Room for one more byte reveals packets exceeding it.
+            let room = (size + 1 - produced).min(MAX_PREALLOCATION);
+            self.decoded.resize(produced + room, 0);
+
+            let status = self.inflater.decompress(&data[consumed..], &mut self.decoded[produced..], FlushDecompress::None);
+            let total = self.inflater.total_out() as usize;
+            self.decoded.truncate(total);
+
+            if total > size {
+                return Err(FrameError::DecompressedTooLarge { size: total, limit: size });
+            }
+            match status {
+                Ok(Status::StreamEnd) => break,
+                Ok(_) if self.inflater.total_in() as usize == consumed && total == produced => return Err(FrameError::Malformed),
+                Ok(_) => continue,
+                Err(_) => return Err(FrameError::Malformed),
+            }
+        }
+
+        if self.decoded.len() == size {
+            Ok(self.decoded.split())
+        } else {
+            Err(FrameError::Malformed)
+        }
+    }
+}
+
+/// Split the var-int encoded uncompressed size off a frame
+fn read_size(mut frame: &[u8]) -> Result<(i32, &[u8]), FrameError> {
+    let mut size = 0;
+    for i in 0..5 {
+        if !frame.has_remaining() {
+            break;
+        }
+        let byte = frame.get_u8();
+        size |= ((byte & 127) as i32) << (i * 7);
+        if byte & 128 == 0 {
+            return Ok((size, frame));
+        }
+    }
+    Err(FrameError::Malformed)
+}
+
+/// Append the zlib stream of a payload to a buffer
+fn deflate(deflater: &mut Compress, payload: &[u8], buffer: &mut BytesMut) -> Result<(), ()> {
+    deflater.reset();
+    loop {
+        let consumed = deflater.total_in() as usize;
+        let written = buffer.len();
+        let before = deflater.total_out();
+        buffer.resize(written + payload.len() - consumed + DEFLATE_OVERHEAD, 0);
+
+        let status = deflater.compress(&payload[consumed..], &mut buffer[written..], FlushCompress::Finish);
+        buffer.truncate(written + (deflater.total_out() - before) as usize);
+        match status {
+            Ok(Status::StreamEnd) => return Ok(()),
+            Ok(_) => continue,
+            Err(_) => return Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use bytes::BufMut;
+    use flate2::write::ZlibEncoder;
+    use crate::buffer::BufferMut;
+
+    fn compressor() -> Compressor {
+        Compressor::new(16, CompressionLevel::Default)
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Encode a packet, without the length prefix of its frame
+    fn encode(compressor: &mut Compressor, payload: &[u8]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        let mut packet = FrameBuffer::new(&mut buffer);
+        packet.payload_mut().put_slice(payload);
+        BytesMut::from(compressor.encode(packet).unwrap().payload())
+    }
+
+    fn frame(uncompressed_size: i32, data: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        frame.write_var_int(uncompressed_size);
+        frame.put_slice(data);
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        let payload = BytesMut::from(&[7u8; 1000][..]);
+        let frame = encode(&mut compressor(), &payload);
+        assert_eq!(compressor().decode(frame, 1000), Ok(payload));
+    }
+
+    #[test]
+    fn state_is_reused_across_packets() {
+        let mut sender = compressor();
+        let mut receiver = compressor();
+        for size in [1000, 8, 70_000, 16, 3] {
+            let payload: BytesMut = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>()[..].into();
+            let frame = encode(&mut sender, &payload);
+            assert_eq!(receiver.decode(frame, 100_000), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn incompressible_payload_round_trips() {
+        let mut seed = 1u32;
+        let data: Vec<u8> = (0..5000).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+
+        let payload = BytesMut::from(&data[..]);
+        let frame = encode(&mut compressor(), &payload);
+        assert_eq!(compressor().decode(frame, 5000), Ok(payload));
+    }
+
+    #[test]
+    fn packet_ids_are_peeked_without_consuming_the_frame() {
+        let mut receiver = compressor();
+        let payload: Vec<u8> = (0..1000).map(|i| (i % 7) as u8 + 0x20).collect();
+        let compressed = frame(1000, &zlib(&payload));
+        assert_eq!(receiver.peek_packet_id(&compressed), Some(0x20));
+        assert_eq!(receiver.peek_packet_id(&frame(0, &[0x21, 1])), Some(0x21));
+        assert_eq!(receiver.peek_packet_id(&frame(1000, &[0xff, 0xff])), None);
+        assert_eq!(receiver.peek_packet_id(&frame(-1, &[0x21])), None);
+        assert_eq!(receiver.decode(compressed, 1000), Ok(BytesMut::from(&payload[..])));
+    }
+
+    #[test]
+    fn failed_packet_does_not_affect_the_next() {
+        let mut receiver = compressor();
+        assert_eq!(receiver.decode(frame(1000, &[0x78, 0x9c, 0xff]), 1000), Err(FrameError::Malformed));
+
+        let payload = BytesMut::from(&[1u8; 100][..]);
+        assert_eq!(receiver.decode(frame(100, &zlib(&payload)), 1000), Ok(payload));
+    }
+
+    #[test]
+    fn announced_size_above_limit_is_rejected() {
+        let frame = frame(1001, &zlib(&[0; 1001]));
+        assert_eq!(compressor().decode(frame, 1000), Err(FrameError::DecompressedTooLarge { size: 1001, limit: 1000 }));
+    }
+
+    #[test]
+    fn inflating_stops_at_announced_size() {
+        let bomb = zlib(&vec![0; 4 * 1024 * 1024]);
+        match compressor().decode(frame(1000, &bomb), 1000) {
+            Err(FrameError::DecompressedTooLarge { size, limit: 1000 }) => assert!(size < 1000 + MAX_PREALLOCATION),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn mismatching_size_is_rejected() {
+        let frame = frame(1000, &zlib(&[0; 999]));
+        assert_eq!(compressor().decode(frame, 1000), Err(FrameError::Malformed));
+    }
+
+    #[test]
+    fn truncated_stream_is_rejected() {
+        let data = zlib(&[5; 1000]);
+        let frame = frame(1000, &data[..data.len() / 2]);
+        assert_eq!(compressor().decode(frame, 1000), Err(FrameError::Malformed));
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt;
+use bytes::{BytesMut, Buf, BufMut};
+use crate::buffer::var_int_size;
+
+/// The largest frame size that fits into the 21-bit length prefix
+pub const MAX_FRAME_SIZE: usize = (1 << 21) - 1;
+
+/// The largest packet size that clients accept after decompression
+pub const MAX_DECOMPRESSED_SIZE: usize = 1 << 23;
+
+/// Space reserved in front of an encoded packet for the length prefix of its frame
+/// and the uncompressed size prepended by the compression
+pub const HEADROOM: usize = 3 + 5;
+
+/// The reason why a received frame was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame or the packet within it could not be decoded
+    Malformed,
+
+    /// The length prefix announced more bytes than allowed in the current protocol state
+    TooLarge { size: usize, limit: usize },
+
+    /// The packet would decompress to more bytes than allowed
+    DecompressedTooLarge { size: usize, limit: usize },
+}
+
+// The protocol primitives report malformed data as `Err(())`
+impl From<()> for FrameError {
+    fn from(_: ()) -> Self {
+        FrameError::Malformed
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Malformed => f.write_str("malformed frame"),
+            FrameError::TooLarge { size, limit } =>
+                write!(f, "frame of {} bytes exceeds the limit of {} bytes", size, limit),
+            FrameError::DecompressedTooLarge { size, limit } =>
+                write!(f, "packet decompressing to {} bytes exceeds the limit of {} bytes", size, limit),
+        }
+    }
+}
+
+/// Utility for encoding and decoding frames.
+///
+/// A frame is the data of one packet preceded by its size.
+/// The size is encoded as a var-int with a maximum of 21-bit.
+pub struct FrameCodec;
+
+impl FrameCodec {
+    /// Try to decode a frame from a provided buffer.
+    /// This may fail if the frame was not yet completely received.
+    ///
+    /// Frames announcing more than `limit` bytes are rejected before buffering them.
+    pub fn try_decode(src: &mut BytesMut, limit: usize) -> Result<Option<BytesMut>, FrameError> {
+        if let Some(frame_size) = peek_var_int_21(src)? {
+            if frame_size.value > limit {
+                return Err(FrameError::TooLarge { size: frame_size.value, limit });
+            }
+
+            let total_frame_size = frame_size.size + frame_size.value;
+            if src.len() < total_frame_size {
+                // Reserve enough space to read the missing remainder of this frame
+                src.reserve(total_frame_size - src.len());
+            } else {
+                src.advance(frame_size.size);
+                let frame = src.split_to(frame_size.value);
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Determine the size of the first frame on a buffer, without consuming it.
+    ///
+    /// Returns the size of the length prefix and of the frame data,
+    /// if the prefix was already received.
+    pub fn peek_frame(src: &[u8]) -> Result<Option<(usize, usize)>, ()> {
+        Ok(peek_var_int_21(src)?.map(|frame_size| (frame_size.size, frame_size.value)))
+    }
+
+    /// Prefix a payload with its size.
+    /// Packets are framed without copying them by encoding them into a [FrameBuffer].
+    pub fn encode(payload: BytesMut) -> Result<BytesMut, ()> {
+        let mut buffer = BytesMut::new();
+        let mut frame = FrameBuffer::new(&mut buffer);
+        frame.payload_mut().put_slice(&payload);
+        frame.finish()
+    }
+}
+
+/// A packet that is encoded in place into a reused buffer.
+///
+/// The buffer starts with [HEADROOM] unused bytes,
+/// so that the prefixes of the frame are filled in once the size of the packet is known.
+pub struct FrameBuffer<'a> {
+    buffer: &'a mut BytesMut,
+
+    /// Where the prefixed packet starts within the buffer
+    start: usize,
+}
+
+impl<'a> FrameBuffer<'a> {
+    /// Start a packet on a buffer, discarding its contents.
+    /// Space of frames that were split off the buffer before is reused once they were dropped.
+    pub fn new(buffer: &'a mut BytesMut) -> FrameBuffer<'a> {
+        buffer.clear();
+        buffer.reserve(HEADROOM);
+        buffer.put_slice(&[0; HEADROOM]);
+        FrameBuffer { buffer, start: HEADROOM }
+    }
+
+    /// The packet including the prefixes added so far
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+
+    /// The buffer that the packet is appended to.
+    /// Only the bytes after the headroom may be modified.
+    pub fn payload_mut(&mut self) -> &mut BytesMut {
+        self.buffer
+    }
+
+    /// Remove the packet, keeping the headroom
+    pub fn clear(&mut self) {
+        self.start = HEADROOM;
+        self.buffer.truncate(HEADROOM);
+    }
+
+    /// Write a var-int in front of the packet
+    pub fn prepend_var_int(&mut self, int: i32) -> Result<(), ()> {
+        let size = var_int_size(int);
+        if size > self.start {
+            return Err(());
+        }
+
+        self.start -= size;
+        let mut int = int as u32;
+        for byte in &mut self.buffer[self.start..self.start + size] {
+            *byte = (int as u8) & 127 | 128;
+            int >>= 7;
+        }
+        self.buffer[self.start + size - 1] &= 127;
+        Ok(())
+    }
+
+    /// Prefix the packet with its size and split it off the buffer
+    pub fn finish(mut self) -> Result<BytesMut, ()> {
+        let size = self.payload().len();
+        if size > MAX_FRAME_SIZE {
+            return Err(());
+        }
+        self.prepend_var_int(size as i32)?;
+        self.buffer.advance(self.start);
+        Ok(self.buffer.split())
+    }
+}
+
+struct PeekedVarInt {
+    value: usize,
+    size: usize,
+}
+
+/// Read a var-int encoded 21-bit number from a buffer without consuming it.
+///
+/// Returns the peeked number and its size on the buffer, if it could already be read.
+fn peek_var_int_21(src: &[u8]) -> Result<Option<PeekedVarInt>, ()> {
+    let mut result = 0;
+    for i in 0..3 {
+        if let Some(byte) = src.get(i) {
+            result |= ((byte & 127) as usize) << (i * 7);
+
+            if byte & 128 == 0 {
+                return Ok(Some(PeekedVarInt { value: result, size: i + 1}));
+            }
+        } else {
+            return Ok(None);
+        }
+    }
+    Err(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferMut;
+
+    #[test]
+    fn frames_within_the_limit_are_decoded() {
+        let mut src = FrameCodec::encode(BytesMut::from(&[1u8; 300][..])).unwrap();
+        assert_eq!(FrameCodec::try_decode(&mut src, 300), Ok(Some(BytesMut::from(&[1u8; 300][..]))));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected_before_buffering() {
+        let mut src = BytesMut::new();
+        src.write_var_int(MAX_FRAME_SIZE as i32);
+        assert_eq!(FrameCodec::try_decode(&mut src, 1024), Err(FrameError::TooLarge { size: MAX_FRAME_SIZE, limit: 1024 }));
+        assert!(src.capacity() < 1024);
+    }
+
+    #[test]
+    fn frame_buffer_prefixes_in_place() {
+        let mut buffer = BytesMut::new();
+        for size in [0, 127, 128, 300, 20_000] {
+            let mut frame = FrameBuffer::new(&mut buffer);
+            frame.payload_mut().put_slice(&vec![9; size]);
+            frame.prepend_var_int(0).unwrap();
+
+            let mut expected = BytesMut::new();
+            expected.put_u8(0);
+            expected.put_slice(&vec![9; size]);
+            assert_eq!(frame.finish(), FrameCodec::encode(expected));
+        }
+    }
+
+    #[test]
+    fn frame_buffer_reuses_space_of_dropped_frames() {
+        let mut buffer = BytesMut::with_capacity(1024);
+        let mut frame = FrameBuffer::new(&mut buffer);
+        frame.payload_mut().put_slice(&[1; 600]);
+        let first = frame.finish().unwrap();
+        let address = first.as_ptr() as usize;
+        drop(first);
+
+        let mut frame = FrameBuffer::new(&mut buffer);
+        frame.payload_mut().put_slice(&[2; 600]);
+        let second = frame.finish().unwrap();
+        assert_eq!(second.as_ptr() as usize, address);
+    }
+
+    #[test]
+    fn oversized_frames_are_not_encoded() {
+        let mut buffer = BytesMut::new();
+        let mut frame = FrameBuffer::new(&mut buffer);
+        frame.payload_mut().put_slice(&vec![0; MAX_FRAME_SIZE + 1]);
+        assert_eq!(frame.finish(), Err(()));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::net::{Protocol, Packet, PacketCodec};
-use crate::net::buffer::{Buffer, BufferMut};
+use serde::Serialize;
+use crate::{Protocol, Packet, PacketCodec};
+use crate::buffer::{Buffer, BufferMut};
 
 /// The first packet that is ever send to the server.
 /// It indicates whether the client wants to join or view the server status.
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct HandshakePacket {
     pub protocol_version: i32,
     pub server_address: String,
@@ -1,5 +1,6 @@
 //! All packets of the [Protocol::Handshake] Protocol
 
+#[allow(clippy::module_inception)]
 mod handshake;
 
 pub use handshake::HandshakePacket;
@@ -0,0 +1,207 @@
+//! The Minecraft protocol as spoken by mineroute: packet definitions,
+//! framing, compression and a tokio codec tying them together.
+
+// Decoding errors are reported as `Err(())` throughout the protocol code
+#![allow(clippy::result_unit_err)]
+
+pub mod buffer;
+pub mod framing;
+pub mod compressor;
+pub mod codec;
+pub mod handshake;
+pub mod login;
+pub mod play;
+pub mod query;
+pub mod raknet;
+pub mod bedrock;
+pub mod rcon;
+pub mod status;
+pub mod wire_codec;
+
+use serde::Serialize;
+use crate::buffer::{Buffer, BufferMut};
+use crate::wire_codec::{WireCodec, ServerWireCodec, ClientWireCodec};
+
+/// One side of a connection, determining which packets are received and sent
+pub trait ConnectionType: Sized + 'static {
+    type In;
+    type Out;
+    type WC: WireCodec<Self>;
+}
+
+/// ConnectionType for connections from a client to a server
+pub struct Server;
+impl ConnectionType for Server {
+    type In = PacketServerEnum;
+    type Out = PacketClientEnum;
+    type WC = ServerWireCodec;
+}
+
+/// Connectiontype for connections from a server to a client
+pub struct Client;
+impl ConnectionType for Client {
+    type In = PacketClientEnum;
+    type Out = PacketServerEnum;
+    type WC = ClientWireCodec;
+}
+
+/// Packets send by the client to the server
+#[allow(unused)]
+#[derive(Serialize, Debug, Clone)]
+pub enum PacketClientEnum {
+    Handshake(handshake::HandshakePacket),
+
+    StatusRequest(status::StatusRequestPacket),
+    Ping(status::PingPacket),
+
+    LoginStart(login::LoginStartPacket),
+
+    Raw(play::RawPacket),
+}
+
+/// Packets send by the server to the client
+#[allow(unused)]
+#[derive(Serialize, Debug, Clone)]
+pub enum PacketServerEnum {
+    StatusResponse(status::StatusResponsePacket),
+    Pong(status::PongPacket),
+
+    Disconnect(login::DisconnectPacket),
+    Compression(login::CompressionPacket),
+    LoginSuccess(login::LoginSuccessPacket),
+
+    Raw(play::RawPacket),
+}
+
+pub trait Packet: Sized {}
+pub trait PacketCodec: Packet {
+    fn decode<B: Buffer>(buf: &mut B) -> Result<Self, ()>;
+    fn encode<B: BufferMut>(&self, buf: &mut B) -> Result<(), ()>;
+}
+
+/// The different sub-protocols of the minecraft protocol.
+///
+/// Each Protocol defines a set a Packets, that each have a packet id.
+/// The meaning of a PacketId varies between the different Protocols and
+/// whether the packet is directed to the server or client.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    Handshake,
+    Play,
+    Status,
+    Login,
+}
+
+impl Protocol {
+    pub fn from_int(int: i32) -> Option<Protocol> {
+        match int {
+            0 => Some(Protocol::Play),
+            1 => Some(Protocol::Status),
+            2 => Some(Protocol::Login),
+            _ => None
+        }
+    }
+
+    pub fn to_int(&self) -> i32 {
+        match self {
+            Protocol::Play => 0,
+            Protocol::Status => 1,
+            Protocol::Login => 2,
+            Protocol::Handshake => panic!("Protocol 'Handshake' cannot be converted into an int"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+    use bytes::BytesMut;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+    use crate::status::server_status::{ServerInfo, Version, Players};
+
+    /// Encode and decode a packet, checking that the whole encoding gets consumed
+    /// and that no prefix of it makes the decoder panic.
+    fn round_trip<P: PacketCodec + Serialize + Debug>(packet: &P) -> Result<(), TestCaseError> {
+        let mut buf = BytesMut::new();
+        packet.encode(&mut buf).map_err(|_| TestCaseError::fail("encoding failed"))?;
+
+        for len in 0..buf.len() {
+            let _ = P::decode(&mut BytesMut::from(&buf[..len]));
+        }
+
+        let decoded = P::decode(&mut buf).map_err(|_| TestCaseError::fail("decoding failed"))?;
+        prop_assert!(buf.is_empty(), "{} trailing bytes", buf.len());
+        prop_assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(packet).unwrap());
+        Ok(())
+    }
+
+    fn next_protocol() -> impl Strategy<Value=Protocol> {
+        prop_oneof![Just(Protocol::Status), Just(Protocol::Login), Just(Protocol::Play)]
+    }
+
+    fn server_info() -> impl Strategy<Value=ServerInfo> {
+        (".*", any::<i32>(), any::<u32>(), any::<u32>(), ".*", proptest::option::of("[A-Za-z0-9+/=]*"))
+            .prop_map(|(name, protocol, max, online, description, favicon)| ServerInfo {
+                version: Version { name, protocol },
+                players: Players { max, online, sample: None },
+                description: serde_json::Value::String(description),
+                favicon,
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn handshake(protocol_version in any::<i32>(), server_address in ".*",
+                     server_port in any::<u16>(), next_protocol in next_protocol()) {
+            round_trip(&handshake::HandshakePacket { protocol_version, server_address, server_port, next_protocol })?;
+        }
+
+        #[test]
+        fn status_request(_ in Just(())) {
+            round_trip(&status::StatusRequestPacket)?;
+        }
+
+        #[test]
+        fn status_response(status in server_info()) {
+            round_trip(&status::StatusResponsePacket { status })?;
+        }
+
+        #[test]
+        fn ping(payload in any::<u64>()) {
+            round_trip(&status::PingPacket { payload })?;
+        }
+
+        #[test]
+        fn pong(payload in any::<u64>()) {
+            round_trip(&status::PongPacket { payload })?;
+        }
+
+        #[test]
+        fn login_start(name in ".*") {
+            round_trip(&login::LoginStartPacket { name })?;
+        }
+
+        #[test]
+        fn login_success(uuid in any::<u128>(), name in ".*") {
+            round_trip(&login::LoginSuccessPacket { uuid: Uuid::from_u128(uuid), name })?;
+        }
+
+        #[test]
+        fn disconnect(reason in ".*") {
+            round_trip(&login::DisconnectPacket { reason })?;
+        }
+
+        #[test]
+        fn compression(size_limit in proptest::option::of(0..=i32::MAX as usize)) {
+            round_trip(&login::CompressionPacket { size_limit })?;
+        }
+    }
+
+    #[test]
+    fn compression_limit_overflowing_var_int_is_rejected() {
+        let packet = login::CompressionPacket { size_limit: Some(i32::MAX as usize + 1) };
+        assert_eq!(packet.encode(&mut BytesMut::new()), Err(()));
+    }
+}
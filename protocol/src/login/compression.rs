@@ -1,8 +1,9 @@
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{Buffer, BufferMut};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{Buffer, BufferMut};
 
 /// Tell the client to enable or disable compression
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct CompressionPacket {
     /// Compress packets exceeding this size in bytes.
     /// Disables compression if no limit is provided.
@@ -0,0 +1,36 @@
+use minecraft_chat::{MessageBuilder, Payload};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{Buffer, BufferMut};
+
+/// This packet tells the client a disconnect reason, before closing the connection
+#[derive(Serialize, Debug, Clone)]
+pub struct DisconnectPacket {
+    /// The reason as json chat message
+    pub reason: String,
+}
+
+impl DisconnectPacket {
+    /// Create a packet with a plain text reason
+    pub fn from_text(text: &str) -> DisconnectPacket {
+        let message = MessageBuilder::builder(Payload::text(text)).build();
+        DisconnectPacket {
+            reason: message.to_json().unwrap_or_default(),
+        }
+    }
+}
+
+impl Packet for DisconnectPacket {}
+
+impl PacketCodec for DisconnectPacket {
+    fn decode<B: Buffer>(buf: &mut B) -> Result<Self, ()> {
+        Ok(DisconnectPacket {
+            reason: buf.read_string()?,
+        })
+    }
+
+    fn encode<B: BufferMut>(&self, buf: &mut B) -> Result<(), ()> {
+        buf.write_string(&self.reason);
+        Ok(())
+    }
+}
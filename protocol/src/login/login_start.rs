@@ -1,8 +1,9 @@
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{Buffer, BufferMut};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{Buffer, BufferMut};
 
 /// Request to login with a certain username
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct LoginStartPacket {
     pub name: String,
 }
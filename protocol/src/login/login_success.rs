@@ -1,10 +1,11 @@
 use uuid::Uuid;
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{Buffer, BufferMut};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{Buffer, BufferMut};
 
 /// Tell the client that the login procedure was successfully completed.
 /// The client should switch to the `Protocol::Play` protocol.
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct LoginSuccessPacket {
     pub uuid: Uuid,
     pub name: String,
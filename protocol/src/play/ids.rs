@@ -0,0 +1,114 @@
+use bytes::BytesMut;
+use minecraft_chat::{MessageBuilder, Payload};
+use crate::buffer::{Buffer, BufferMut};
+use crate::login::DisconnectPacket;
+use crate::play::RawPacket;
+
+/// Ids of the play packets that mineroute inspects.
+/// Play packets are otherwise forwarded without being decoded,
+/// so their ids are only known for some protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayPacketIds {
+    /// Keep Alive sent by the server
+    pub keep_alive_clientbound: u8,
+    /// Keep Alive answered by the client
+    pub keep_alive_serverbound: u8,
+    /// Disconnect sent by the server
+    pub disconnect: u8,
+    /// Chat message sent by the client, including commands
+    pub chat_serverbound: u8,
+    /// Chat message sent by the server
+    pub chat_clientbound: u8,
+    /// Whether chat messages of the server name their sender (1.16 and later)
+    pub chat_sender: bool,
+}
+
+impl PlayPacketIds {
+    /// Look up the packet ids of a protocol version as sent in the handshake
+    pub fn for_version(protocol_version: i32) -> Option<PlayPacketIds> {
+        let (keep_alive_clientbound, keep_alive_serverbound, disconnect, chat_serverbound, chat_clientbound) = match protocol_version {
+            47 => (0x00, 0x00, 0x40, 0x01, 0x02),              // 1.8
+            107..=316 => (0x1F, 0x0B, 0x1A, 0x02, 0x0F),       // 1.9 - 1.11.2
+            335 => (0x1F, 0x0C, 0x1A, 0x03, 0x0F),             // 1.12
+            338..=340 => (0x1F, 0x0B, 0x1A, 0x02, 0x0F),       // 1.12.1 - 1.12.2
+            393..=404 => (0x21, 0x0E, 0x1B, 0x02, 0x0E),       // 1.13 - 1.13.2
+            477..=498 => (0x20, 0x0F, 0x1A, 0x03, 0x0E),       // 1.14 - 1.14.4
+            573..=578 => (0x21, 0x0F, 0x1B, 0x03, 0x0F),       // 1.15 - 1.15.2
+            735..=736 => (0x20, 0x10, 0x1A, 0x03, 0x0E),       // 1.16 - 1.16.1
+            751..=754 => (0x1F, 0x10, 0x19, 0x03, 0x0E),       // 1.16.2 - 1.16.5
+            _ => return None,
+        };
+
+        Some(PlayPacketIds {
+            keep_alive_clientbound,
+            keep_alive_serverbound,
+            disconnect,
+            chat_serverbound,
+            chat_clientbound,
+            chat_sender: protocol_version >= 735,
+        })
+    }
+
+    /// Encode a disconnect packet of the play protocol
+    pub fn disconnect_packet(&self, packet: &DisconnectPacket) -> RawPacket {
+        let mut data = BytesMut::new();
+        data.write_string(&packet.reason);
+        RawPacket {
+            id: self.disconnect,
+            data: data.freeze(),
+            compressed: None,
+        }
+    }
+
+    /// Decode the message of a chat packet sent by the client
+    pub fn read_chat(&self, packet: &RawPacket) -> Option<String> {
+        if packet.id != self.chat_serverbound {
+            return None;
+        }
+        BytesMut::from(&packet.data[..]).read_string().ok()
+    }
+
+    /// Encode a plain text message shown in the chat of the client
+    pub fn chat_packet(&self, text: &str) -> RawPacket {
+        let message = MessageBuilder::builder(Payload::text(text)).build();
+        let mut data = BytesMut::new();
+        data.write_string(&message.to_json().unwrap_or_default());
+        data.write_u8(1); // system message
+        if self.chat_sender {
+            // Messages of the system are sent by the nil uuid
+            data.write_u64(0);
+            data.write_u64(0);
+        }
+        RawPacket {
+            id: self.chat_clientbound,
+            data: data.freeze(),
+            compressed: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_of_the_client_is_decoded() {
+        let ids = PlayPacketIds::for_version(340).unwrap();
+        let mut data = BytesMut::new();
+        data.write_string("/server lobby");
+        let packet = RawPacket { id: ids.chat_serverbound, data: data.freeze(), compressed: None };
+
+        assert_eq!(ids.read_chat(&packet).as_deref(), Some("/server lobby"));
+        assert_eq!(ids.read_chat(&RawPacket { id: ids.keep_alive_serverbound, ..packet }), None);
+    }
+
+    #[test]
+    fn chat_names_its_sender_since_1_16() {
+        let old = PlayPacketIds::for_version(578).unwrap().chat_packet("hi");
+        let new = PlayPacketIds::for_version(754).unwrap().chat_packet("hi");
+
+        assert_eq!(new.data.len(), old.data.len() + 16);
+        assert_eq!(old.data[old.data.len() - 1], 1);
+        assert_eq!(BytesMut::from(&old.data[..]).read_string().unwrap(), r#"{"text":"hi"}"#);
+    }
+}
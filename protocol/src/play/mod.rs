@@ -0,0 +1,9 @@
+//! All packets of the [Protocol::Play] Protocol
+
+mod raw;
+mod ids;
+mod world;
+
+pub use raw::{RawPacket, CompressedFrame};
+pub use ids::PlayPacketIds;
+pub use world::{WorldPacketIds, JoinGame, ServerOverlay};
@@ -0,0 +1,32 @@
+use bytes::Bytes;
+use serde::{Serialize, Serializer};
+use crate::Packet;
+
+/// The id and data of a not decoded packet
+#[derive(Serialize, Debug, Clone)]
+pub struct RawPacket {
+    pub id: u8,
+    #[serde(serialize_with = "serialize_hex")]
+    pub data: Bytes,
+
+    /// The frame as it was compressed by the sender.
+    /// Connections with the same compression threshold forward it
+    /// as is instead of compressing the packet again.
+    #[serde(skip)]
+    pub compressed: Option<CompressedFrame>,
+}
+
+/// The payload of a compressed frame, before it was decompressed
+#[derive(Debug, Clone)]
+pub struct CompressedFrame {
+    /// The compression threshold of the connection that received the frame
+    pub size_limit: usize,
+    pub data: Bytes,
+}
+
+impl Packet for RawPacket {}
+
+fn serialize_hex<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    serializer.serialize_str(&hex)
+}
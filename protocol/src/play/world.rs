@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use bytes::{Buf, BytesMut};
+use uuid::Uuid;
+use crate::buffer::{Buffer, BufferMut};
+use crate::play::RawPacket;
+
+/// Ids of the play packets that mineroute needs to move a client to the world of another server.
+/// Their layout is only known for 1.12 to 1.12.2, clients of other versions cannot switch servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorldPacketIds {
+    pub join_game: u8,
+    pub respawn: u8,
+    pub player_list_item: u8,
+    pub boss_bar: u8,
+    pub scoreboard_objective: u8,
+    pub teams: u8,
+}
+
+/// The Join Game packet of a server, as sent by 1.12 servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinGame {
+    pub entity_id: i32,
+    /// The game mode, with the hardcore flag in bit 3
+    pub game_mode: u8,
+    pub dimension: i32,
+    pub difficulty: u8,
+    pub max_players: u8,
+    pub level_type: String,
+    pub reduced_debug_info: bool,
+}
+
+impl WorldPacketIds {
+    /// Look up the packet ids of a protocol version as sent in the handshake
+    pub fn for_version(protocol_version: i32) -> Option<WorldPacketIds> {
+        let (join_game, respawn, player_list_item, boss_bar, scoreboard_objective, teams) = match protocol_version {
+            335 => (0x23, 0x34, 0x2D, 0x0C, 0x41, 0x43),       // 1.12
+            338..=340 => (0x23, 0x35, 0x2E, 0x0C, 0x42, 0x44), // 1.12.1 - 1.12.2
+            _ => return None,
+        };
+
+        Some(WorldPacketIds {
+            join_game,
+            respawn,
+            player_list_item,
+            boss_bar,
+            scoreboard_objective,
+            teams,
+        })
+    }
+
+    /// Decode a Join Game packet of the server
+    pub fn read_join_game(&self, packet: &RawPacket) -> Option<JoinGame> {
+        if packet.id != self.join_game {
+            return None;
+        }
+        let mut data = BytesMut::from(&packet.data[..]);
+        let entity_id = read_i32(&mut data).ok()?;
+        let game_mode = data.read_u8().ok()?;
+        let dimension = read_i32(&mut data).ok()?;
+        Some(JoinGame {
+            entity_id,
+            game_mode,
+            dimension,
+            difficulty: data.read_u8().ok()?,
+            max_players: data.read_u8().ok()?,
+            level_type: data.read_string().ok()?,
+            reduced_debug_info: data.read_u8().ok()? != 0,
+        })
+    }
+
+    /// Encode a Respawn packet moving the client to a dimension of the world it joined
+    pub fn respawn_packet(&self, dimension: i32, join: &JoinGame) -> RawPacket {
+        let mut data = BytesMut::new();
+        data.write_raw_bytes(&dimension.to_be_bytes());
+        data.write_u8(join.difficulty);
+        // Respawn packets do not carry the hardcore flag
+        data.write_u8(join.game_mode & 0x7);
+        data.write_string(&join.level_type);
+        RawPacket {
+            id: self.respawn,
+            data: data.freeze(),
+            compressed: None,
+        }
+    }
+
+    /// The packets that move a client that already played to the world of a Join Game packet.
+    ///
+    /// The client only unloads its world when the dimension changes,
+    /// so it is first sent to another dimension and then back to the one of the new world.
+    /// The Join Game packet itself replaces the entity id of the player,
+    /// the entities of the previous server are dropped along with its world.
+    pub fn rejoin_packets(&self, join_game: RawPacket, join: &JoinGame) -> Vec<RawPacket> {
+        let detour = if join.dimension == 0 { -1 } else { 0 };
+        vec![
+            join_game,
+            self.respawn_packet(detour, join),
+            self.respawn_packet(join.dimension, join),
+        ]
+    }
+}
+
+fn read_i32(data: &mut BytesMut) -> Result<i32, ()> {
+    if data.remaining() < 4 {
+        return Err(());
+    }
+    Ok(data.get_i32())
+}
+
+/// What a server showed to a client besides its world, which the client keeps when the world changes.
+/// It has to be removed when the client moves on to another server.
+#[derive(Debug, Default)]
+pub struct ServerOverlay {
+    players: HashSet<Uuid>,
+    boss_bars: HashSet<Uuid>,
+    objectives: HashSet<String>,
+    teams: HashSet<String>,
+}
+
+impl ServerOverlay {
+    /// Follow a packet sent by the server to the client
+    pub fn track(&mut self, ids: &WorldPacketIds, packet: &RawPacket) {
+        let mut data = BytesMut::from(&packet.data[..]);
+        // Malformed packets are left to the client to reject
+        let _ = match packet.id {
+            id if id == ids.player_list_item => self.track_player_list(&mut data),
+            id if id == ids.boss_bar => Self::track_added(&mut self.boss_bars, data.read_uuid(), data.read_var_int()),
+            id if id == ids.scoreboard_objective => Self::track_added(&mut self.objectives, data.read_string(), data.read_u8().map(i32::from)),
+            id if id == ids.teams => Self::track_added(&mut self.teams, data.read_string(), data.read_u8().map(i32::from)),
+            _ => Ok(()),
+        };
+    }
+
+    /// Action 0 adds an entry and action 1 removes it for boss bars, objectives and teams alike
+    fn track_added<K: Eq + std::hash::Hash>(entries: &mut HashSet<K>, key: Result<K, ()>, action: Result<i32, ()>) -> Result<(), ()> {
+        match (key?, action?) {
+            (key, 0) => entries.insert(key),
+            (key, 1) => entries.remove(&key),
+            _ => false,
+        };
+        Ok(())
+    }
+
+    fn track_player_list(&mut self, data: &mut BytesMut) -> Result<(), ()> {
+        let action = data.read_var_int()?;
+        for _ in 0..data.read_var_int()? {
+            let uuid = data.read_uuid()?;
+            match action {
+                0 => {
+                    self.players.insert(uuid);
+                    skip_player_entry(data)?;
+                }
+                4 => {
+                    self.players.remove(&uuid);
+                }
+                // Updates of entries do not change which players are listed
+                _ => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The packets that remove everything that was tracked from the client, forgetting about it
+    pub fn clear(&mut self, ids: &WorldPacketIds) -> Vec<RawPacket> {
+        let mut packets = Vec::new();
+        if !self.players.is_empty() {
+            let mut data = BytesMut::new();
+            data.write_var_int(4);
+            data.write_var_int(self.players.len() as i32);
+            for uuid in self.players.drain() {
+                data.write_uuid(&uuid);
+            }
+            packets.push(RawPacket { id: ids.player_list_item, data: data.freeze(), compressed: None });
+        }
+        for uuid in self.boss_bars.drain() {
+            let mut data = BytesMut::new();
+            data.write_uuid(&uuid);
+            data.write_var_int(1);
+            packets.push(RawPacket { id: ids.boss_bar, data: data.freeze(), compressed: None });
+        }
+        for name in self.objectives.drain() {
+            let mut data = BytesMut::new();
+            data.write_string(&name);
+            data.write_u8(1);
+            packets.push(RawPacket { id: ids.scoreboard_objective, data: data.freeze(), compressed: None });
+        }
+        for name in self.teams.drain() {
+            let mut data = BytesMut::new();
+            data.write_string(&name);
+            data.write_u8(1);
+            packets.push(RawPacket { id: ids.teams, data: data.freeze(), compressed: None });
+        }
+        packets
+    }
+}
+
+/// Skip the name, properties, game mode, ping and display name of a player added to the player list
+fn skip_player_entry(data: &mut BytesMut) -> Result<(), ()> {
+    data.read_string()?;
+    for _ in 0..data.read_var_int()? {
+        data.read_string()?;
+        data.read_string()?;
+        if data.read_u8()? != 0 {
+            data.read_string()?;
+        }
+    }
+    data.read_var_int()?;
+    data.read_var_int()?;
+    if data.read_u8()? != 0 {
+        data.read_string()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids() -> WorldPacketIds {
+        WorldPacketIds::for_version(340).unwrap()
+    }
+
+    fn packet(id: u8, data: BytesMut) -> RawPacket {
+        RawPacket { id, data: data.freeze(), compressed: None }
+    }
+
+    fn join_game(dimension: i32) -> RawPacket {
+        let mut data = BytesMut::new();
+        data.write_raw_bytes(&7i32.to_be_bytes());
+        data.write_u8(0x8 | 1); // hardcore creative
+        data.write_raw_bytes(&dimension.to_be_bytes());
+        data.write_u8(2);
+        data.write_u8(20);
+        data.write_string("default");
+        data.write_u8(0);
+        packet(ids().join_game, data)
+    }
+
+    #[test]
+    fn joins_are_followed_by_a_detour_through_another_dimension() {
+        let ids = ids();
+        for (dimension, detour) in [(0, -1), (-1, 0), (1, 0)] {
+            let join = ids.read_join_game(&join_game(dimension)).unwrap();
+            assert_eq!(join.entity_id, 7);
+            let packets = ids.rejoin_packets(join_game(dimension), &join);
+            assert_eq!(packets.iter().map(|packet| packet.id).collect::<Vec<_>>(), vec![ids.join_game, ids.respawn, ids.respawn]);
+
+            let mut respawn = BytesMut::from(&packets[1].data[..]);
+            assert_eq!(read_i32(&mut respawn), Ok(detour));
+            assert_eq!(respawn.read_u8(), Ok(2));
+            assert_eq!(respawn.read_u8(), Ok(1));
+            assert_eq!(respawn.read_string().as_deref(), Ok("default"));
+            assert_eq!(read_i32(&mut BytesMut::from(&packets[2].data[..])), Ok(dimension));
+        }
+        assert_eq!(ids.read_join_game(&packet(ids.respawn, BytesMut::new())), None);
+        assert_eq!(ids.read_join_game(&packet(ids.join_game, BytesMut::from(&[0u8, 1][..]))), None);
+    }
+
+    #[test]
+    fn everything_shown_by_a_server_is_removed() {
+        let ids = ids();
+        let mut overlay = ServerOverlay::default();
+        let (steve, alex, bar) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+
+        let mut players = BytesMut::new();
+        players.write_var_int(0);
+        players.write_var_int(2);
+        for (uuid, name) in &[(steve, "steve"), (alex, "alex")] {
+            players.write_uuid(uuid);
+            players.write_string(name);
+            players.write_var_int(1);
+            players.write_string("textures");
+            players.write_string("value");
+            players.write_u8(1);
+            players.write_string("signature");
+            players.write_var_int(0);
+            players.write_var_int(50);
+            players.write_u8(0);
+        }
+        overlay.track(&ids, &packet(ids.player_list_item, players));
+        let mut removed = BytesMut::new();
+        removed.write_var_int(4);
+        removed.write_var_int(1);
+        removed.write_uuid(&alex);
+        overlay.track(&ids, &packet(ids.player_list_item, removed));
+
+        let mut boss_bar = BytesMut::new();
+        boss_bar.write_uuid(&bar);
+        boss_bar.write_var_int(0);
+        overlay.track(&ids, &packet(ids.boss_bar, boss_bar));
+        for (id, name) in &[(ids.scoreboard_objective, "kills"), (ids.teams, "red")] {
+            let mut data = BytesMut::new();
+            data.write_string(name);
+            data.write_u8(0);
+            overlay.track(&ids, &packet(*id, data));
+        }
+
+        let cleared = overlay.clear(&ids);
+        assert_eq!(cleared.iter().map(|packet| packet.id).collect::<Vec<_>>(),
+                   vec![ids.player_list_item, ids.boss_bar, ids.scoreboard_objective, ids.teams]);
+        let mut players = BytesMut::from(&cleared[0].data[..]);
+        assert_eq!((players.read_var_int(), players.read_var_int(), players.read_uuid()), (Ok(4), Ok(1), Ok(steve)));
+        let mut boss_bar = BytesMut::from(&cleared[1].data[..]);
+        assert_eq!((boss_bar.read_uuid(), boss_bar.read_var_int()), (Ok(bar), Ok(1)));
+        let mut team = BytesMut::from(&cleared[3].data[..]);
+        assert_eq!((team.read_string().as_deref(), team.read_u8()), (Ok("red"), Ok(1)));
+
+        assert!(overlay.clear(&ids).is_empty());
+    }
+}
@@ -0,0 +1,395 @@
+//! The UDP query protocol (GameSpy4) that servers answer with `enable-query`.
+//!
+//! A client first asks for a challenge token with a handshake and then requests
+//! either the basic or the full stat, which includes the list of players.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+/// Every request starts with these bytes
+pub const MAGIC: [u8; 2] = [0xFE, 0xFD];
+
+const HANDSHAKE: u8 = 9;
+const STAT: u8 = 0;
+
+/// Precedes the key-value section of a full stat response
+const FULL_STAT_HEADER: &[u8] = b"splitnum\0\x80\0";
+
+/// Precedes the player list of a full stat response
+const PLAYERS_HEADER: &[u8] = b"\x01player_\0\0";
+
+/// A request of a query client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryRequest {
+    Handshake { session_id: i32 },
+    BasicStat { session_id: i32, token: i32 },
+    FullStat { session_id: i32, token: i32 },
+}
+
+impl QueryRequest {
+    /// Identifies the requests of a client, it is echoed in the response
+    pub fn session_id(&self) -> i32 {
+        match *self {
+            QueryRequest::Handshake { session_id } |
+            QueryRequest::BasicStat { session_id, .. } |
+            QueryRequest::FullStat { session_id, .. } => session_id,
+        }
+    }
+
+    pub fn read(mut datagram: &[u8]) -> Result<QueryRequest, ()> {
+        if datagram.remaining() < 7 || datagram[..2] != MAGIC {
+            return Err(());
+        }
+        datagram.advance(2);
+        let kind = datagram.get_u8();
+        let session_id = datagram.get_i32();
+
+        match (kind, datagram.remaining()) {
+            (HANDSHAKE, 0) => Ok(QueryRequest::Handshake { session_id }),
+            (STAT, 4) => Ok(QueryRequest::BasicStat { session_id, token: datagram.get_i32() }),
+            // The full stat is requested by padding the token
+            (STAT, 8) => Ok(QueryRequest::FullStat { session_id, token: datagram.get_i32() }),
+            _ => Err(()),
+        }
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) {
+        buffer.put_slice(&MAGIC);
+        match *self {
+            QueryRequest::Handshake { session_id } => {
+                buffer.put_u8(HANDSHAKE);
+                buffer.put_i32(session_id);
+            }
+            QueryRequest::BasicStat { session_id, token } => {
+                buffer.put_u8(STAT);
+                buffer.put_i32(session_id);
+                buffer.put_i32(token);
+            }
+            QueryRequest::FullStat { session_id, token } => {
+                buffer.put_u8(STAT);
+                buffer.put_i32(session_id);
+                buffer.put_i32(token);
+                buffer.put_u32(0);
+            }
+        }
+    }
+}
+
+/// The response of a server to a [QueryRequest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryResponse {
+    /// The challenge token that the following stat requests have to include
+    Handshake { session_id: i32, token: i32 },
+    BasicStat { session_id: i32, stat: ServerStat },
+    FullStat { session_id: i32, stat: ServerStat },
+}
+
+/// What a server reports about itself.
+/// A basic stat only includes the description, game type, map, player counts and address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStat {
+    pub motd: String,
+    pub game_type: String,
+    pub game_id: String,
+    pub version: String,
+    pub plugins: String,
+    pub map: String,
+    pub num_players: usize,
+    pub max_players: usize,
+    pub host_port: u16,
+    pub host_ip: String,
+    pub players: Vec<String>,
+}
+
+impl QueryResponse {
+    pub fn session_id(&self) -> i32 {
+        match *self {
+            QueryResponse::Handshake { session_id, .. } |
+            QueryResponse::BasicStat { session_id, .. } |
+            QueryResponse::FullStat { session_id, .. } => session_id,
+        }
+    }
+
+    /// Read the response to a request, whose kind cannot be told from the response alone
+    pub fn read(request: &QueryRequest, mut datagram: &[u8]) -> Result<QueryResponse, ()> {
+        if datagram.remaining() < 5 {
+            return Err(());
+        }
+        let kind = datagram.get_u8();
+        let session_id = datagram.get_i32();
+
+        match (request, kind) {
+            (QueryRequest::Handshake { .. }, HANDSHAKE) => {
+                let token = read_string(&mut datagram)?.parse().map_err(|_| ())?;
+                Ok(QueryResponse::Handshake { session_id, token })
+            }
+            (QueryRequest::BasicStat { .. }, STAT) => {
+                let stat = ServerStat::read_basic(&mut datagram)?;
+                Ok(QueryResponse::BasicStat { session_id, stat })
+            }
+            (QueryRequest::FullStat { .. }, STAT) => {
+                let stat = ServerStat::read_full(&mut datagram)?;
+                Ok(QueryResponse::FullStat { session_id, stat })
+            }
+            _ => Err(()),
+        }
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) {
+        match self {
+            QueryResponse::Handshake { session_id, token } => {
+                buffer.put_u8(HANDSHAKE);
+                buffer.put_i32(*session_id);
+                write_string(buffer, &token.to_string());
+            }
+            QueryResponse::BasicStat { session_id, stat } => {
+                buffer.put_u8(STAT);
+                buffer.put_i32(*session_id);
+                stat.write_basic(buffer);
+            }
+            QueryResponse::FullStat { session_id, stat } => {
+                buffer.put_u8(STAT);
+                buffer.put_i32(*session_id);
+                stat.write_full(buffer);
+            }
+        }
+    }
+}
+
+impl ServerStat {
+    fn read_basic(datagram: &mut &[u8]) -> Result<ServerStat, ()> {
+        let motd = read_string(datagram)?;
+        let game_type = read_string(datagram)?;
+        let map = read_string(datagram)?;
+        let num_players = read_string(datagram)?.parse().map_err(|_| ())?;
+        let max_players = read_string(datagram)?.parse().map_err(|_| ())?;
+        if datagram.remaining() < 2 {
+            return Err(());
+        }
+        let host_port = datagram.get_u16_le();
+        let host_ip = read_string(datagram)?;
+
+        Ok(ServerStat {
+            motd,
+            game_type,
+            game_id: String::new(),
+            version: String::new(),
+            plugins: String::new(),
+            map,
+            num_players,
+            max_players,
+            host_port,
+            host_ip,
+            players: Vec::new(),
+        })
+    }
+
+    fn write_basic(&self, buffer: &mut BytesMut) {
+        write_string(buffer, &self.motd);
+        write_string(buffer, &self.game_type);
+        write_string(buffer, &self.map);
+        write_string(buffer, &self.num_players.to_string());
+        write_string(buffer, &self.max_players.to_string());
+        buffer.put_u16_le(self.host_port);
+        write_string(buffer, &self.host_ip);
+    }
+
+    fn read_full(datagram: &mut &[u8]) -> Result<ServerStat, ()> {
+        if !datagram.starts_with(FULL_STAT_HEADER) {
+            return Err(());
+        }
+        datagram.advance(FULL_STAT_HEADER.len());
+
+        let mut stat = ServerStat {
+            motd: String::new(),
+            game_type: String::new(),
+            game_id: String::new(),
+            version: String::new(),
+            plugins: String::new(),
+            map: String::new(),
+            num_players: 0,
+            max_players: 0,
+            host_port: 0,
+            host_ip: String::new(),
+            players: Vec::new(),
+        };
+
+        // Unknown keys are skipped, the section ends with an empty key
+        loop {
+            let key = read_string(datagram)?;
+            if key.is_empty() {
+                break;
+            }
+            let value = read_string(datagram)?;
+            match key.as_str() {
+                "hostname" => stat.motd = value,
+                "gametype" => stat.game_type = value,
+                "game_id" => stat.game_id = value,
+                "version" => stat.version = value,
+                "plugins" => stat.plugins = value,
+                "map" => stat.map = value,
+                "numplayers" => stat.num_players = value.parse().map_err(|_| ())?,
+                "maxplayers" => stat.max_players = value.parse().map_err(|_| ())?,
+                "hostport" => stat.host_port = value.parse().map_err(|_| ())?,
+                "hostip" => stat.host_ip = value,
+                _ => {}
+            }
+        }
+
+        if !datagram.starts_with(PLAYERS_HEADER) {
+            return Err(());
+        }
+        datagram.advance(PLAYERS_HEADER.len());
+        loop {
+            let player = read_string(datagram)?;
+            if player.is_empty() {
+                break;
+            }
+            stat.players.push(player);
+        }
+        Ok(stat)
+    }
+
+    fn write_full(&self, buffer: &mut BytesMut) {
+        buffer.put_slice(FULL_STAT_HEADER);
+        let values = [
+            ("hostname", self.motd.as_str()),
+            ("gametype", &self.game_type),
+            ("game_id", &self.game_id),
+            ("version", &self.version),
+            ("plugins", &self.plugins),
+            ("map", &self.map),
+            ("numplayers", &self.num_players.to_string()),
+            ("maxplayers", &self.max_players.to_string()),
+            ("hostport", &self.host_port.to_string()),
+            ("hostip", &self.host_ip),
+        ];
+        for (key, value) in values.iter() {
+            write_string(buffer, key);
+            write_string(buffer, value);
+        }
+        buffer.put_u8(0);
+
+        buffer.put_slice(PLAYERS_HEADER);
+        for player in &self.players {
+            write_string(buffer, player);
+        }
+        buffer.put_u8(0);
+    }
+}
+
+/// Read a null terminated string
+fn read_string(datagram: &mut &[u8]) -> Result<String, ()> {
+    let end = datagram.iter().position(|byte| *byte == 0).ok_or(())?;
+    let string = String::from_utf8_lossy(&datagram[..end]).into_owned();
+    datagram.advance(end + 1);
+    Ok(string)
+}
+
+/// Write a null terminated string, dropping null characters that would end it early
+fn write_string(buffer: &mut BytesMut, string: &str) {
+    buffer.extend(string.bytes().filter(|byte| *byte != 0));
+    buffer.put_u8(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat() -> ServerStat {
+        ServerStat {
+            motd: "A Minecraft Server".to_owned(),
+            game_type: "SMP".to_owned(),
+            game_id: "MINECRAFT".to_owned(),
+            version: "1.12.2".to_owned(),
+            plugins: String::new(),
+            map: "world".to_owned(),
+            num_players: 2,
+            max_players: 20,
+            host_port: 25565,
+            host_ip: "127.0.0.1".to_owned(),
+            players: vec!["steve".to_owned(), "alex".to_owned()],
+        }
+    }
+
+    fn round_trip(request: &QueryRequest, response: &QueryResponse) -> QueryResponse {
+        let mut buffer = BytesMut::new();
+        response.write(&mut buffer);
+        QueryResponse::read(request, &buffer).unwrap()
+    }
+
+    #[test]
+    fn requests_are_read() {
+        assert_eq!(QueryRequest::read(&[0xFE, 0xFD, 9, 0, 0, 0, 1]), Ok(QueryRequest::Handshake { session_id: 1 }));
+        assert_eq!(QueryRequest::read(&[0xFE, 0xFD, 0, 0, 0, 0, 1, 0x00, 0x91, 0x29, 0x5B]),
+                   Ok(QueryRequest::BasicStat { session_id: 1, token: 9_513_307 }));
+        assert_eq!(QueryRequest::read(&[0xFE, 0xFD, 0, 0, 0, 0, 1, 0x00, 0x91, 0x29, 0x5B, 0, 0, 0, 0]),
+                   Ok(QueryRequest::FullStat { session_id: 1, token: 9_513_307 }));
+
+        assert_eq!(QueryRequest::read(&[0xFE, 0xFC, 9, 0, 0, 0, 1]), Err(()));
+        assert_eq!(QueryRequest::read(&[0xFE, 0xFD, 9, 0, 0, 0]), Err(()));
+        assert_eq!(QueryRequest::read(&[0xFE, 0xFD, 0, 0, 0, 0, 1, 0]), Err(()));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in &[
+            QueryRequest::Handshake { session_id: 0x0102_0304 },
+            QueryRequest::BasicStat { session_id: 7, token: -5 },
+            QueryRequest::FullStat { session_id: 7, token: 123 },
+        ] {
+            let mut buffer = BytesMut::new();
+            request.write(&mut buffer);
+            assert_eq!(QueryRequest::read(&buffer), Ok(*request));
+        }
+    }
+
+    #[test]
+    fn handshake_response_carries_the_token_as_text() {
+        let response = QueryResponse::Handshake { session_id: 1, token: 9_513_307 };
+        let mut buffer = BytesMut::new();
+        response.write(&mut buffer);
+        assert_eq!(&buffer[..], b"\x09\x00\x00\x00\x019513307\0");
+        assert_eq!(round_trip(&QueryRequest::Handshake { session_id: 1 }, &response), response);
+    }
+
+    #[test]
+    fn basic_stat_round_trips() {
+        let response = QueryResponse::BasicStat { session_id: 1, stat: stat() };
+        let mut buffer = BytesMut::new();
+        response.write(&mut buffer);
+        assert_eq!(&buffer[..], &b"\x00\x00\x00\x00\x01A Minecraft Server\0SMP\0world\x002\x0020\0\xDD\x63127.0.0.1\0"[..]);
+
+        let expected = ServerStat { game_id: String::new(), version: String::new(), players: Vec::new(), ..stat() };
+        let request = QueryRequest::BasicStat { session_id: 1, token: 0 };
+        assert_eq!(round_trip(&request, &response), QueryResponse::BasicStat { session_id: 1, stat: expected });
+    }
+
+    #[test]
+    fn full_stat_round_trips() {
+        let response = QueryResponse::FullStat { session_id: 1, stat: stat() };
+        let mut buffer = BytesMut::new();
+        response.write(&mut buffer);
+        assert!(buffer.starts_with(b"\x00\x00\x00\x00\x01splitnum\0\x80\0hostname\0A Minecraft Server\0"));
+        assert!(buffer.ends_with(b"\0\x01player_\0\0steve\0alex\0\0"));
+
+        let request = QueryRequest::FullStat { session_id: 1, token: 0 };
+        assert_eq!(round_trip(&request, &response), response);
+    }
+
+    #[test]
+    fn truncated_responses_are_rejected() {
+        let request = QueryRequest::FullStat { session_id: 1, token: 0 };
+        let mut buffer = BytesMut::new();
+        QueryResponse::FullStat { session_id: 1, stat: stat() }.write(&mut buffer);
+        for length in 0..buffer.len() {
+            assert_eq!(QueryResponse::read(&request, &buffer[..length]), Err(()));
+        }
+    }
+
+    #[test]
+    fn null_characters_are_dropped() {
+        let mut buffer = BytesMut::new();
+        write_string(&mut buffer, "a\0b");
+        assert_eq!(&buffer[..], b"ab\0");
+    }
+}
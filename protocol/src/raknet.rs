@@ -0,0 +1,319 @@
+//! The parts of RakNet, the UDP transport of Minecraft Bedrock Edition,
+//! that a relay needs to answer pings and to follow the packets of a connection.
+//!
+//! Connected datagrams carry frames, which may be split across several datagrams.
+//! Acknowledgements and resends are left to the endpoints.
+
+use std::collections::HashMap;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Identifies offline messages, which are sent before a connection is established
+pub const MAGIC: [u8; 16] = [0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78];
+
+pub const UNCONNECTED_PING: u8 = 0x01;
+
+/// A ping that only servers accepting more connections answer
+pub const UNCONNECTED_PING_OPEN_CONNECTIONS: u8 = 0x02;
+pub const UNCONNECTED_PONG: u8 = 0x1c;
+
+/// The first message of a client opening a connection
+pub const OPEN_CONNECTION_REQUEST_1: u8 = 0x05;
+
+/// Sent in a frame by the side closing a connection
+pub const DISCONNECTION_NOTIFICATION: u8 = 0x15;
+
+/// Marks a datagram carrying frames, as opposed to offline messages
+const VALID: u8 = 0x80;
+const ACK: u8 = 0x40;
+const NACK: u8 = 0x20;
+
+/// Set in the flags of a frame that is a part of a split packet
+const SPLIT: u8 = 0x10;
+
+/// Split packets with more parts are dropped, so that a peer cannot make the relay allocate arbitrarily much
+pub const MAX_SPLIT_COUNT: u32 = 512;
+
+/// How many split packets may be reassembled at once, incomplete ones are dropped beyond that
+const MAX_PENDING_SPLITS: usize = 4;
+
+/// A ping of a client looking for servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnconnectedPing {
+    /// Echoed in the pong, so that the client can measure the latency
+    pub time: i64,
+    pub client_guid: i64,
+}
+
+impl UnconnectedPing {
+    pub fn read(mut datagram: &[u8]) -> Result<UnconnectedPing, ()> {
+        if datagram.len() < 33 || !matches!(datagram[0], UNCONNECTED_PING | UNCONNECTED_PING_OPEN_CONNECTIONS) {
+            return Err(());
+        }
+        datagram.advance(1);
+        let time = datagram.get_i64();
+        if datagram[..16] != MAGIC {
+            return Err(());
+        }
+        datagram.advance(16);
+        Ok(UnconnectedPing { time, client_guid: datagram.get_i64() })
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(UNCONNECTED_PING);
+        buffer.put_i64(self.time);
+        buffer.put_slice(&MAGIC);
+        buffer.put_i64(self.client_guid);
+    }
+}
+
+/// The answer to an [UnconnectedPing], describing the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnconnectedPong {
+    pub time: i64,
+    pub server_guid: i64,
+
+    /// Fields separated by semicolons, e.g. `MCPE;<motd>;<protocol>;<version>;<players>;<max players>;...`
+    pub motd: String,
+}
+
+impl UnconnectedPong {
+    pub fn read(mut datagram: &[u8]) -> Result<UnconnectedPong, ()> {
+        if datagram.len() < 35 || datagram[0] != UNCONNECTED_PONG {
+            return Err(());
+        }
+        datagram.advance(1);
+        let time = datagram.get_i64();
+        let server_guid = datagram.get_i64();
+        if datagram[..16] != MAGIC {
+            return Err(());
+        }
+        datagram.advance(16);
+        let length = datagram.get_u16() as usize;
+        if datagram.len() != length {
+            return Err(());
+        }
+        let motd = String::from_utf8(datagram.to_vec()).map_err(|_| ())?;
+        Ok(UnconnectedPong { time, server_guid, motd })
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) {
+        let motd = &self.motd.as_bytes()[..self.motd.len().min(u16::MAX as usize)];
+        buffer.put_u8(UNCONNECTED_PONG);
+        buffer.put_i64(self.time);
+        buffer.put_i64(self.server_guid);
+        buffer.put_slice(&MAGIC);
+        buffer.put_u16(motd.len() as u16);
+        buffer.put_slice(motd);
+    }
+}
+
+/// Whether a client opens a connection with this datagram
+pub fn is_connection_request(datagram: &[u8]) -> bool {
+    datagram.len() >= 17 && datagram[0] == OPEN_CONNECTION_REQUEST_1 && datagram[1..17] == MAGIC
+}
+
+/// A frame of a connected datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub split: Option<Split>,
+    pub body: Bytes,
+}
+
+/// Identifies the part of a split packet that a frame carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Split {
+    pub count: u32,
+    pub id: u16,
+    pub index: u32,
+}
+
+/// Read the frames of a connected datagram.
+/// Acknowledgements and offline messages carry no frames.
+pub fn read_frames(datagram: &[u8]) -> Result<Vec<Frame>, ()> {
+    let flags = *datagram.first().ok_or(())?;
+    if flags & VALID == 0 || flags & (ACK | NACK) != 0 {
+        return Ok(Vec::new());
+    }
+    if datagram.len() < 4 {
+        return Err(());
+    }
+    // Skip the flags and the sequence number
+    let mut datagram = &datagram[4..];
+
+    let mut frames = Vec::new();
+    while datagram.has_remaining() {
+        if datagram.remaining() < 3 {
+            return Err(());
+        }
+        let flags = datagram.get_u8();
+        let length = (datagram.get_u16() as usize).div_ceil(8);
+        let reliability = flags >> 5;
+
+        let mut header = 0;
+        if matches!(reliability, 2 | 3 | 4 | 6 | 7) {
+            header += 3; // Message index
+        }
+        if matches!(reliability, 1 | 4) {
+            header += 3; // Sequence index
+        }
+        if matches!(reliability, 1 | 3 | 4 | 7) {
+            header += 4; // Order index and channel
+        }
+        if datagram.remaining() < header {
+            return Err(());
+        }
+        datagram.advance(header);
+
+        let split = if flags & SPLIT != 0 {
+            if datagram.remaining() < 10 {
+                return Err(());
+            }
+            Some(Split {
+                count: datagram.get_u32(),
+                id: datagram.get_u16(),
+                index: datagram.get_u32(),
+            })
+        } else {
+            None
+        };
+
+        if datagram.remaining() < length {
+            return Err(());
+        }
+        frames.push(Frame { split, body: Bytes::copy_from_slice(&datagram[..length]) });
+        datagram.advance(length);
+    }
+    Ok(frames)
+}
+
+/// Joins the parts of split packets, in whichever order they arrive
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: HashMap<u16, Vec<Option<Bytes>>>,
+}
+
+impl Reassembler {
+    /// Returns the packet of a frame once all of its parts were received.
+    /// Parts of split packets exceeding the limits are dropped.
+    pub fn push(&mut self, frame: Frame) -> Option<Bytes> {
+        let split = match frame.split {
+            Some(split) => split,
+            None => return Some(frame.body),
+        };
+        if split.count == 0 || split.count > MAX_SPLIT_COUNT || split.index >= split.count {
+            return None;
+        }
+        // Parts of packets that were never completed, e.g. because of packet loss, must not block later packets
+        if !self.pending.contains_key(&split.id) && self.pending.len() >= MAX_PENDING_SPLITS {
+            self.pending.clear();
+        }
+
+        let parts = self.pending.entry(split.id).or_insert_with(|| vec![None; split.count as usize]);
+        if parts.len() != split.count as usize {
+            return None;
+        }
+        parts[split.index as usize] = Some(frame.body);
+        if parts.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let parts = self.pending.remove(&split.id)?;
+        let mut packet = BytesMut::with_capacity(parts.iter().flatten().map(Bytes::len).sum());
+        for part in parts.iter().flatten() {
+            packet.extend_from_slice(part);
+        }
+        Some(packet.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A datagram with one reliable ordered frame
+    fn datagram(body: &[u8], split: Option<Split>) -> Vec<u8> {
+        let mut datagram = vec![0x84, 1, 0, 0];
+        datagram.push((3 << 5) | if split.is_some() { SPLIT } else { 0 });
+        datagram.put_u16((body.len() * 8) as u16);
+        datagram.extend_from_slice(&[0, 0, 0]); // Message index
+        datagram.extend_from_slice(&[0, 0, 0, 0]); // Order index and channel
+        if let Some(split) = split {
+            datagram.put_u32(split.count);
+            datagram.put_u16(split.id);
+            datagram.put_u32(split.index);
+        }
+        datagram.extend_from_slice(body);
+        datagram
+    }
+
+    #[test]
+    fn pings_are_read() {
+        let mut buffer = BytesMut::new();
+        UnconnectedPing { time: 42, client_guid: -7 }.write(&mut buffer);
+        assert_eq!(UnconnectedPing::read(&buffer), Ok(UnconnectedPing { time: 42, client_guid: -7 }));
+
+        buffer[10] ^= 1;
+        assert_eq!(UnconnectedPing::read(&buffer), Err(()));
+        assert_eq!(UnconnectedPing::read(&[UNCONNECTED_PING, 0]), Err(()));
+    }
+
+    #[test]
+    fn pongs_round_trip() {
+        let pong = UnconnectedPong { time: 42, server_guid: 1234, motd: "MCPE;mineroute;685;1.21.0;0;20;1234;mineroute;Survival;1;19132;19133;".to_owned() };
+        let mut buffer = BytesMut::new();
+        pong.write(&mut buffer);
+        assert_eq!(UnconnectedPong::read(&buffer), Ok(pong));
+        assert_eq!(UnconnectedPong::read(&buffer[..buffer.len() - 1]), Err(()));
+    }
+
+    #[test]
+    fn connection_requests_are_recognized() {
+        let mut request = vec![OPEN_CONNECTION_REQUEST_1];
+        request.extend_from_slice(&MAGIC);
+        request.extend_from_slice(&[11, 0, 0]);
+        assert!(is_connection_request(&request));
+        assert!(!is_connection_request(&request[..10]));
+        assert!(!is_connection_request(&datagram(&[0xfe], None)));
+    }
+
+    #[test]
+    fn frames_are_read() {
+        let frames = read_frames(&datagram(&[0xfe, 1, 2], None)).unwrap();
+        assert_eq!(frames, vec![Frame { split: None, body: Bytes::from_static(&[0xfe, 1, 2]) }]);
+
+        // Acknowledgements carry no frames
+        assert_eq!(read_frames(&[0xc0, 0, 1, 1, 0, 0, 0]), Ok(vec![]));
+        assert!(read_frames(&datagram(&[0xfe, 1, 2], None)[..9]).is_err());
+    }
+
+    #[test]
+    fn split_packets_are_joined_in_any_order() {
+        let mut reassembler = Reassembler::default();
+        let parts: [&[u8]; 3] = [b"ab", b"cd", b"e"];
+        for index in [2, 0] {
+            let split = Split { count: 3, id: 9, index };
+            let frame = read_frames(&datagram(parts[index as usize], Some(split))).unwrap().remove(0);
+            assert_eq!(reassembler.push(frame), None);
+        }
+        let frame = read_frames(&datagram(parts[1], Some(Split { count: 3, id: 9, index: 1 }))).unwrap().remove(0);
+        assert_eq!(reassembler.push(frame), Some(Bytes::from_static(b"abcde")));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn oversized_splits_are_dropped() {
+        let mut reassembler = Reassembler::default();
+        let frame = |count, id, index| Frame { split: Some(Split { count, id, index }), body: Bytes::from_static(b"a") };
+        assert_eq!(reassembler.push(frame(MAX_SPLIT_COUNT + 1, 1, 0)), None);
+        assert_eq!(reassembler.push(frame(2, 1, 2)), None);
+        assert!(reassembler.pending.is_empty());
+
+        for id in 0..MAX_PENDING_SPLITS as u16 {
+            reassembler.push(frame(2, id, 0));
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_SPLITS);
+        reassembler.push(frame(2, 100, 0));
+        assert_eq!(reassembler.pending.len(), 1);
+        assert_eq!(reassembler.push(frame(2, 100, 1)), Some(Bytes::from_static(b"aa")));
+    }
+}
@@ -0,0 +1,175 @@
+//! The RCON protocol that servers accept with `enable-rcon`.
+//!
+//! A client authenticates with the password of the server and then executes commands.
+//! Responses exceeding [MAX_RESPONSE_PAYLOAD] bytes are split into several packets.
+
+use std::fmt;
+use std::io;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Packet type of the login request of a client
+pub const LOGIN: i32 = 3;
+
+/// Packet type of a command, and of the response to a login
+pub const COMMAND: i32 = 2;
+
+/// Packet type of a command response
+pub const RESPONSE_VALUE: i32 = 0;
+
+/// Longer commands are rejected by the vanilla server
+pub const MAX_COMMAND_LENGTH: usize = 1446;
+
+/// Servers split responses into packets of at most this many bytes of payload
+pub const MAX_RESPONSE_PAYLOAD: usize = 4096;
+
+/// Some servers exceed the payload size of the vanilla server, so larger packets are accepted
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// The request id and type in front of the payload, and the two null bytes following it
+const PACKET_OVERHEAD: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RconPacket {
+    /// Chosen by the client and echoed by the server, or -1 if the login failed
+    pub request_id: i32,
+    pub kind: i32,
+
+    /// Usually UTF-8, but responses split into several packets may split a character as well
+    pub payload: Bytes,
+}
+
+/// A tokio codec for both sides of a RCON connection
+#[derive(Debug, Default)]
+pub struct RconCodec;
+
+impl Decoder for RconCodec {
+    type Item = RconPacket;
+    type Error = RconError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RconPacket>, RconError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = i32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+        if length < PACKET_OVERHEAD as i32 || length as usize > MAX_PACKET_SIZE {
+            return Err(RconError::Malformed);
+        }
+        let length = length as usize;
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let mut packet = src.split_to(length);
+        let request_id = packet.get_i32_le();
+        let kind = packet.get_i32_le();
+
+        // The payload is null terminated and followed by an empty string
+        if packet[length - PACKET_OVERHEAD..] != [0, 0] {
+            return Err(RconError::Malformed);
+        }
+        Ok(Some(RconPacket {
+            request_id,
+            kind,
+            payload: packet.split_to(length - PACKET_OVERHEAD).freeze(),
+        }))
+    }
+}
+
+impl Encoder for RconCodec {
+    type Item = RconPacket;
+    type Error = RconError;
+
+    fn encode(&mut self, packet: RconPacket, dst: &mut BytesMut) -> Result<(), RconError> {
+        if packet.payload.contains(&0) || packet.payload.len() + PACKET_OVERHEAD > MAX_PACKET_SIZE {
+            return Err(RconError::Unencodable);
+        }
+        dst.reserve(4 + PACKET_OVERHEAD + packet.payload.len());
+        dst.put_i32_le((PACKET_OVERHEAD + packet.payload.len()) as i32);
+        dst.put_i32_le(packet.request_id);
+        dst.put_i32_le(packet.kind);
+        dst.put_slice(&packet.payload);
+        dst.put_u16(0);
+        Ok(())
+    }
+}
+
+/// The reason why a [RconCodec] failed
+#[derive(Debug)]
+pub enum RconError {
+    Io(io::Error),
+
+    /// A received packet has an invalid length or is not terminated properly
+    Malformed,
+
+    /// The payload contains a null character or is too long
+    Unencodable,
+}
+
+impl From<io::Error> for RconError {
+    fn from(error: io::Error) -> Self {
+        RconError::Io(error)
+    }
+}
+
+impl std::error::Error for RconError {}
+
+impl fmt::Display for RconError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RconError::Io(error) => error.fmt(f),
+            RconError::Malformed => f.write_str("received a malformed packet"),
+            RconError::Unencodable => f.write_str("the payload cannot be sent"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(payload: &str) -> RconPacket {
+        RconPacket { request_id: 7, kind: COMMAND, payload: Bytes::copy_from_slice(payload.as_bytes()) }
+    }
+
+    #[test]
+    fn packets_are_framed() {
+        let mut buffer = BytesMut::new();
+        RconCodec.encode(packet("list"), &mut buffer).unwrap();
+        assert_eq!(&buffer[..], b"\x0e\0\0\0\x07\0\0\0\x02\0\0\0list\0\0");
+    }
+
+    #[test]
+    fn packets_round_trip_in_pieces() {
+        let mut wire = BytesMut::new();
+        RconCodec.encode(packet("say hello"), &mut wire).unwrap();
+        RconCodec.encode(packet(""), &mut wire).unwrap();
+
+        let mut received = BytesMut::new();
+        let mut packets = vec![];
+        for byte in wire.iter() {
+            received.put_u8(*byte);
+            if let Some(packet) = RconCodec.decode(&mut received).unwrap() {
+                packets.push(packet);
+            }
+        }
+        assert_eq!(packets, vec![packet("say hello"), packet("")]);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn invalid_packets_are_rejected() {
+        let mut short = BytesMut::from(&b"\x09\0\0\0\x07\0\0\0\x02\0\0\0\0"[..]);
+        assert!(matches!(RconCodec.decode(&mut short), Err(RconError::Malformed)));
+
+        let mut unterminated = BytesMut::from(&b"\x0a\0\0\0\x07\0\0\0\x02\0\0\0a\0"[..]);
+        assert!(matches!(RconCodec.decode(&mut unterminated), Err(RconError::Malformed)));
+
+        let mut oversized = BytesMut::from(&b"\xff\xff\xff\x7f"[..]);
+        assert!(matches!(RconCodec.decode(&mut oversized), Err(RconError::Malformed)));
+
+        assert!(matches!(RconCodec.encode(packet("a\0b"), &mut BytesMut::new()), Err(RconError::Unencodable)));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{BufferMut, Buffer};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{BufferMut, Buffer};
 
 /// Send a number to the server that gets immediately echoed.
 /// This is usually the system time of the client
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct PingPacket {
     pub payload: u64,
 }
@@ -1,8 +1,9 @@
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{Buffer, BufferMut};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{Buffer, BufferMut};
 
 /// The echo response to the [PingPacket].
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct PongPacket {
     pub payload: u64,
 }
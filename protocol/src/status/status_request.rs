@@ -1,8 +1,9 @@
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{BufferMut, Buffer};
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{BufferMut, Buffer};
 
 /// Request the status (playercount, Motd, etc.) of the connected server
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct StatusRequestPacket;
 
 impl Packet for StatusRequestPacket {}
@@ -1,10 +1,11 @@
 use serde_json::{de, ser};
-use crate::net::{Packet, PacketCodec};
-use crate::net::buffer::{BufferMut, Buffer};
-use crate::net::status::server_status;
+use serde::Serialize;
+use crate::{Packet, PacketCodec};
+use crate::buffer::{BufferMut, Buffer};
+use crate::status::server_status;
 
 /// Response to the [StatusRequestPacket] containing the status information of the server
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct StatusResponsePacket {
     pub status: server_status::ServerInfo,
 }
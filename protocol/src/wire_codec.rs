@@ -1,19 +1,23 @@
-use crate::net::*;
-use crate::net::buffer::{Buffer, BufferMut};
-use crate::net::play::RawPacket;
+use crate::*;
+use crate::buffer::{Buffer, BufferMut};
+use crate::play::{RawPacket, CompressedFrame};
 
 /// A utility encapsulating the whole packet serialization process
 pub trait WireCodec<C: ConnectionType> {
-    /// Deserialize the packet data of a packet based on its id and the current protocol state
-    fn read_packet<B: Buffer>(protocol: &Protocol, packet_id: u8, buf: &mut B) -> Result<C::In, ()>;
+    /// Deserialize the packet data of a packet based on its id and the current protocol state.
+    /// The frame is provided as received if it was compressed.
+    fn read_packet<B: Buffer>(protocol: &Protocol, packet_id: u8, buf: &mut B, compressed: Option<CompressedFrame>) -> Result<C::In, ()>;
 
     /// Serialize the provided packet by writing its packet id and data into the provided byte buffer.
     fn write_packet<B: BufferMut>(protocol: &Protocol, packet: &C::Out, buf: &mut B) -> Result<(), ()>;
+
+    /// The compressed frame that an outgoing packet was received as, if it is still available
+    fn compressed_frame(packet: &C::Out) -> Option<&CompressedFrame>;
 }
 
 pub struct ClientWireCodec;
 impl WireCodec<Client> for ClientWireCodec {
-    fn read_packet<B: Buffer>(protocol: &Protocol, packet_id: u8, buf: &mut B) -> Result<PacketClientEnum, ()> {
+    fn read_packet<B: Buffer>(protocol: &Protocol, packet_id: u8, buf: &mut B, compressed: Option<CompressedFrame>) -> Result<PacketClientEnum, ()> {
         match protocol {
             Protocol::Handshake => match packet_id {
                 0 => handshake::HandshakePacket::decode(buf).map(PacketClientEnum::Handshake),
@@ -24,13 +28,19 @@ impl WireCodec<Client> for ClientWireCodec {
                 1 => status::PingPacket::decode(buf).map(PacketClientEnum::Ping),
                 _ => Err(()),
             },
+            // Play packets that the client sent before it saw the login succeed are passed on as they are
             Protocol::Login => match packet_id {
                 0 => login::LoginStartPacket::decode(buf).map(PacketClientEnum::LoginStart),
-                _ => Err(())
+                _ => Ok(PacketClientEnum::Raw(RawPacket {
+                    id: packet_id,
+                    data: buf.remaining_bytes(),
+                    compressed,
+                })),
             }
             Protocol::Play => Ok(PacketClientEnum::Raw(RawPacket {
                 id: packet_id,
                 data: buf.remaining_bytes(),
+                compressed,
             })),
         }
     }
@@ -59,11 +69,18 @@ impl WireCodec<Client> for ClientWireCodec {
             },
         }
     }
+
+    fn compressed_frame(packet: &PacketServerEnum) -> Option<&CompressedFrame> {
+        match packet {
+            PacketServerEnum::Raw(packet) => packet.compressed.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 pub struct ServerWireCodec;
 impl WireCodec<Server> for ServerWireCodec {
-    fn read_packet<B: Buffer>(protocol: &Protocol, packet_id: u8, buf: &mut B) -> Result<PacketServerEnum, ()> {
+    fn read_packet<B: Buffer>(protocol: &Protocol, packet_id: u8, buf: &mut B, compressed: Option<CompressedFrame>) -> Result<PacketServerEnum, ()> {
         match protocol {
             Protocol::Handshake => Err(()),
             Protocol::Status => match packet_id {
@@ -80,6 +97,7 @@ impl WireCodec<Server> for ServerWireCodec {
             Protocol::Play => Ok(PacketServerEnum::Raw(RawPacket {
                 id: packet_id,
                 data: buf.remaining_bytes(),
+                compressed,
             })),
         }
     }
@@ -97,6 +115,11 @@ impl WireCodec<Server> for ServerWireCodec {
             },
             Protocol::Login => match packet {
                 PacketClientEnum::LoginStart(packet) => write(0, packet, buf),
+                PacketClientEnum::Raw(packet) => {
+                    buf.write_u8(packet.id);
+                    buf.write_raw_bytes(&packet.data);
+                    Ok(())
+                },
                 _ => Err(()),
             },
             Protocol::Play => match packet {
@@ -109,6 +132,13 @@ impl WireCodec<Server> for ServerWireCodec {
             },
         }
     }
+
+    fn compressed_frame(packet: &PacketClientEnum) -> Option<&CompressedFrame> {
+        match packet {
+            PacketClientEnum::Raw(packet) => packet.compressed.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 fn write<B: BufferMut, P: PacketCodec>(packet_id: u8, packet: &P, buf: &mut B) -> Result<(), ()> {
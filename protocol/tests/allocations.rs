@@ -0,0 +1,73 @@
+//! Encoding and decoding play packets reuses the buffers of the codec,
+//! so that a busy connection does not allocate for every packet.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use mineroute_protocol::{Client, PacketClientEnum, Protocol, Server};
+use mineroute_protocol::codec::MinecraftCodec;
+use mineroute_protocol::compressor::CompressionLevel;
+use mineroute_protocol::play::RawPacket;
+
+/// Counts the allocations of the current thread, since tests run in parallel
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// Send 100 play packets from a client to a server and count the allocations once the buffers are warmed up
+fn allocations_for_packets(compression: Option<usize>, size: usize) -> usize {
+    let mut server = MinecraftCodec::<Client>::new();
+    let mut client = MinecraftCodec::<Server>::new();
+    server.set_protocol(Protocol::Play);
+    client.set_protocol(Protocol::Play);
+    server.enable_compression(compression, CompressionLevel::Default);
+    client.enable_compression(compression, CompressionLevel::Default);
+
+    let data = Bytes::from(vec![3; size]);
+    let mut wire = BytesMut::with_capacity(64 * 1024);
+    let mut send = |round: usize| -> usize {
+        let start = allocations();
+        for _ in 0..round {
+            let packet = PacketClientEnum::Raw(RawPacket { id: 0x20, data: data.clone(), compressed: None });
+            client.encode(packet, &mut wire).unwrap();
+            drop(server.decode(&mut wire).unwrap().unwrap());
+        }
+        allocations() - start
+    };
+
+    send(10);
+    send(100)
+}
+
+#[test]
+fn uncompressed_packets_do_not_allocate() {
+    assert_eq!(allocations_for_packets(None, 32), 0);
+    assert_eq!(allocations_for_packets(None, 4096), 0);
+}
+
+#[test]
+fn compressed_packets_do_not_allocate() {
+    assert_eq!(allocations_for_packets(Some(256), 32), 0);
+    assert_eq!(allocations_for_packets(Some(256), 4096), 0);
+}
@@ -0,0 +1,101 @@
+//! The arbiters that sessions are distributed to, so that mineroute uses more than one core
+
+use std::future::Future;
+use std::io;
+#[cfg(unix)]
+use std::net::TcpStream as StdTcpStream;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use actix::Arbiter;
+#[cfg(unix)]
+use log::warn;
+use tokio::net::TcpStream;
+
+/// Arbiters that accepted connections are handed to in turn.
+///
+/// A session stays on the arbiter of its client connection. The connection to its upstream
+/// is started from there as well, so both legs of a session share one thread.
+pub struct ArbiterPool {
+    arbiters: Vec<Arbiter>,
+    next: AtomicUsize,
+}
+
+impl ArbiterPool {
+    /// Start the provided number of arbiters, at least one
+    pub fn new(count: usize) -> ArbiterPool {
+        ArbiterPool {
+            arbiters: (0..count.max(1)).map(|_| Arbiter::new()).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// One arbiter per core
+    pub fn per_core() -> ArbiterPool {
+        ArbiterPool::new(available_cores())
+    }
+
+    pub fn len(&self) -> usize {
+        self.arbiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arbiters.is_empty()
+    }
+
+    /// Hand a connection accepted on the current thread to the next arbiter.
+    /// The function receives the connection on the thread of that arbiter.
+    #[cfg(unix)]
+    pub fn dispatch<F>(&self, stream: TcpStream, start: F) -> io::Result<()>
+        where F: FnOnce(TcpStream) + Send + 'static {
+        // A tokio stream stays bound to the reactor of the thread it was accepted on
+        let stream = detach(stream)?;
+        self.next_arbiter().exec_fn(move || match TcpStream::from_std(stream) {
+            Ok(stream) => start(stream),
+            Err(error) => warn!("Cannot hand over a connection: {}", error),
+        });
+        Ok(())
+    }
+
+    /// Start a connection on the current thread.
+    /// Tokio does not expose the socket of a stream on other platforms, so it cannot be moved to another arbiter.
+    #[cfg(not(unix))]
+    pub fn dispatch<F>(&self, stream: TcpStream, start: F) -> io::Result<()>
+        where F: FnOnce(TcpStream) + Send + 'static {
+        start(stream);
+        Ok(())
+    }
+
+    /// Run a future on the next arbiter
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        self.next_arbiter().send(Box::pin(future));
+    }
+
+    fn next_arbiter(&self) -> &Arbiter {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.arbiters.len();
+        &self.arbiters[index]
+    }
+}
+
+impl Drop for ArbiterPool {
+    fn drop(&mut self) {
+        for arbiter in &self.arbiters {
+            arbiter.stop();
+        }
+    }
+}
+
+/// The number of arbiters started if none is configured
+pub fn available_cores() -> usize {
+    thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+/// Duplicate the socket of a stream, so that it can be registered with another reactor.
+/// The stream itself is closed.
+#[cfg(unix)]
+fn detach(stream: TcpStream) -> io::Result<StdTcpStream> {
+    // Safety: the descriptor stays open while the stream is borrowed
+    let socket = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    Ok(StdTcpStream::from(socket.try_clone_to_owned()?))
+}
@@ -0,0 +1,57 @@
+//! An append-only record of the commands executed on upstream servers through the admin api
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use crate::capture::unix_millis;
+
+/// Appends one json object per line to a file
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+/// An executed command and its outcome
+#[derive(Serialize, Debug, Clone)]
+pub struct AuditRecord<'a> {
+    pub host: &'a str,
+    pub command: &'a str,
+
+    /// The address of the api client that requested the command
+    pub requested_by: Option<String>,
+
+    /// The error that prevented the command from being executed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> AuditLog {
+        AuditLog { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a record with the current time.
+    /// Each record is written at once, so that concurrent writers do not interleave.
+    pub fn record(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&TimedRecord { time: unix_millis(), record })?;
+        line.push(b'\n');
+
+        if let Some(directory) = self.path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+            fs::create_dir_all(directory)?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(&line)
+    }
+}
+
+#[derive(Serialize)]
+struct TimedRecord<'a> {
+    time: u64,
+
+    #[serde(flatten)]
+    record: &'a AuditRecord<'a>,
+}
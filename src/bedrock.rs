@@ -0,0 +1,381 @@
+//! Relays Bedrock Edition clients over UDP to a Bedrock server.
+//!
+//! Datagrams are forwarded as they are, through one socket per client towards the upstream,
+//! so that the upstream tells the clients apart by the port they come from.
+//! The proxy only answers unconnected pings itself, and reads the name of each player
+//! from its Login packet to list them next to the players of Java Edition.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use actix::prelude::*;
+use bytes::{Bytes, BytesMut};
+use futures::future::poll_fn;
+use futures::stream::{self, Stream};
+use log::{debug, error, info, warn};
+use socket2::{Domain, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+use crate::events::Event;
+use crate::net::bedrock::{login_display_name, GAME_PACKET};
+use crate::net::raknet::{self, Reassembler, UnconnectedPing, UnconnectedPong, DISCONNECTION_NOTIFICATION};
+use crate::server_state::SharedConfiguration;
+use crate::sessions::{Kick, PlayerEntry, RegisterSession, SessionRegistry, UnregisterSession};
+use crate::settings::{BedrockSettings, ListenerSettings};
+use crate::upstream::{ResolveError, Upstream};
+
+/// The port of the vanilla server, used if the configured upstream has none
+pub const DEFAULT_PORT: u16 = 19132;
+
+/// Larger than the maximum transfer unit RakNet negotiates
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Connection requests of further clients are dropped
+const MAX_SESSIONS: usize = 4096;
+
+/// Datagrams to clients that the listener socket cannot keep up with are dropped
+const REPLY_QUEUE_SIZE: usize = 1024;
+
+/// How often sessions check whether they have been idle for too long
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The listener socket receives the datagrams of every client, bursts of them must not be dropped
+const RECEIVE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Clients send their Login packet right after connecting,
+/// later game packets are encrypted and not worth inspecting
+const MAX_INSPECTED_PACKETS: usize = 32;
+
+/// Parse the address of a Bedrock upstream.
+/// Unlike Java upstreams, no SRV record is looked up for addresses without a port.
+pub fn parse_upstream(address: &str) -> Result<Upstream, ResolveError> {
+    let mut upstream: Upstream = address.parse()?;
+    upstream.port.get_or_insert(DEFAULT_PORT);
+    Ok(upstream)
+}
+
+/// Bind the socket that a listener accepts Bedrock clients on
+pub fn bind(address: SocketAddr, ipv6_only: bool) -> io::Result<UdpSocket> {
+    let domain = match address {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+
+    let socket = Socket::new(domain, Type::dgram(), None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_recv_buffer_size(RECEIVE_BUFFER_SIZE)?;
+    socket.bind(&address.into())?;
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+/// Answer pings and relay the sessions of clients until the listener gets closed
+pub async fn serve(socket: UdpSocket, listener: Arc<ListenerSettings>, config: Arc<SharedConfiguration>,
+                   mut shutdown: oneshot::Receiver<()>, stopped: oneshot::Sender<()>) {
+    let settings = match &listener.bedrock {
+        Some(settings) => settings.clone(),
+        None => return,
+    };
+    let upstream = match parse_upstream(&settings.upstream) {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            error!("Listener '{}' cannot relay Bedrock clients: {}", listener.name, error.0);
+            return;
+        }
+    };
+    let local = socket.local_addr().unwrap_or(listener.bind);
+    let server_guid = Uuid::new_v4().as_u128() as i64;
+
+    // Sessions send their datagrams to the clients through the listener socket
+    let (replies, mut outgoing) = mpsc::channel::<(SocketAddr, Bytes)>(REPLY_QUEUE_SIZE);
+    let mut sessions: HashMap<SocketAddr, Addr<BedrockSession>> = HashMap::new();
+    let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+    let mut buffer = BytesMut::new();
+
+    loop {
+        let (address, received) = tokio::select! {
+            _ = &mut shutdown => break,
+            Some((address, reply)) = outgoing.recv() => {
+                if let Err(error) = poll_fn(|cx| socket.poll_send_to(cx, &reply, &address)).await {
+                    debug!("Cannot relay a datagram to the Bedrock client {}: {}", address, error);
+                }
+                continue;
+            }
+            received = poll_fn(|cx| socket.poll_recv_from(cx, &mut datagram)) => match received {
+                Ok((size, address)) => (address, &datagram[..size]),
+                Err(error) => {
+                    warn!("Bedrock socket of listener '{}' on {} failed to receive: {}", listener.name, local, error);
+                    continue;
+                }
+            },
+        };
+
+        if let Ok(ping) = UnconnectedPing::read(received) {
+            let pong = UnconnectedPong {
+                time: ping.time,
+                server_guid,
+                motd: server_description(&settings, local.port(), server_guid, &config),
+            };
+            buffer.clear();
+            pong.write(&mut buffer);
+            if let Err(error) = poll_fn(|cx| socket.poll_send_to(cx, &buffer, &address)).await {
+                debug!("Cannot answer the ping of {}: {}", address, error);
+            }
+            continue;
+        }
+
+        let datagram = ClientDatagram(Bytes::copy_from_slice(received));
+        match sessions.get(&address).filter(|session| session.connected()) {
+            Some(session) => session.do_send(datagram),
+            // Datagrams of sessions that expired are dropped until the client reconnects
+            None if raknet::is_connection_request(received) => {
+                if sessions.len() >= MAX_SESSIONS {
+                    sessions.retain(|_, session| session.connected());
+                    if sessions.len() >= MAX_SESSIONS {
+                        continue;
+                    }
+                }
+                let session = BedrockSession::new(config.clone(), &settings, upstream.clone(), address, replies.clone()).start();
+                session.do_send(datagram);
+                sessions.insert(address, session);
+            }
+            None => {}
+        }
+    }
+
+    // The relayed sessions cannot reach their clients without the listener socket
+    for session in sessions.values() {
+        session.do_send(Kick("The listener was closed".to_owned()));
+    }
+    drop(socket);
+    let _ = stopped.send(());
+}
+
+/// The description of the server in the format of the unconnected pong
+fn server_description(settings: &BedrockSettings, port: u16, server_guid: i64, config: &SharedConfiguration) -> String {
+    let players = config.load().get_server(&settings.host)
+        .map(|server| server.players.read().unwrap().len())
+        .unwrap_or(0);
+    let motd = settings.motd.as_ref().unwrap_or(&settings.host);
+    format!("MCPE;{};{};{};{};{};{};{};Survival;1;{};{};",
+            motd, settings.protocol_version, settings.version, players, settings.max_players,
+            server_guid, settings.sub_motd, port, port)
+}
+
+/// Relays the datagrams of one client and lists the player once its Login packet was read
+pub struct BedrockSession {
+    config: Arc<SharedConfiguration>,
+    host: String,
+    upstream: Upstream,
+    idle_timeout: Duration,
+    name_prefix: String,
+    client: SocketAddr,
+
+    /// Datagrams to the client, sent through the listener socket
+    replies: mpsc::Sender<(SocketAddr, Bytes)>,
+
+    /// The socket connected to the upstream, bound once the upstream was resolved
+    socket: Option<Arc<UdpSocket>>,
+
+    last_activity: Instant,
+    reassembler: Reassembler,
+    inspected_packets: usize,
+    player: Option<PlayerEntry>,
+}
+
+/// A datagram received from the client of a session
+struct ClientDatagram(Bytes);
+impl Message for ClientDatagram { type Result = (); }
+
+impl BedrockSession {
+    fn new(config: Arc<SharedConfiguration>, settings: &BedrockSettings, upstream: Upstream, client: SocketAddr,
+           replies: mpsc::Sender<(SocketAddr, Bytes)>) -> BedrockSession {
+        BedrockSession {
+            config,
+            host: settings.host.clone(),
+            upstream,
+            idle_timeout: settings.idle_timeout(),
+            name_prefix: settings.name_prefix.clone(),
+            client,
+            replies,
+            socket: None,
+            last_activity: Instant::now(),
+            reassembler: Reassembler::default(),
+            inspected_packets: 0,
+            player: None,
+        }
+    }
+
+    /// Follow the frames of a datagram, to read the name of the player and to notice disconnections
+    fn inspect(&mut self, datagram: &[u8], from_client: bool, ctx: &mut Context<Self>) {
+        let frames = match raknet::read_frames(datagram) {
+            Ok(frames) => frames,
+            Err(()) => return,
+        };
+        for frame in frames {
+            if frame.split.is_none() && frame.body.first() == Some(&DISCONNECTION_NOTIFICATION) {
+                ctx.stop();
+                return;
+            }
+            if !from_client || self.player.is_some() || self.inspected_packets >= MAX_INSPECTED_PACKETS {
+                continue;
+            }
+            if let Some(packet) = self.reassembler.push(frame).filter(|packet| packet.first() == Some(&GAME_PACKET)) {
+                self.inspected_packets += 1;
+                if let Some(name) = login_display_name(&packet) {
+                    self.join(format!("{}{}", self.name_prefix, name), ctx);
+                }
+            }
+        }
+    }
+
+    /// List the player on the server of the listener, unless their name is taken by a player who is online.
+    /// Datagrams are not relayed until the registry decided.
+    fn join(&mut self, name: String, ctx: &mut Context<Self>) {
+        let config = self.config.load();
+        if let Some(reason) = config.get_ban(&name) {
+            info!("Refused the Bedrock player '{}' from {}, who is banned: {}", name, self.client, reason);
+            ctx.stop();
+            return;
+        }
+        drop(config);
+
+        let register = SessionRegistry::from_registry().send(RegisterSession {
+            name: name.clone(),
+            host: self.host.clone(),
+            kick: ctx.address().recipient(),
+            switch: None,
+            exclusive: true,
+        });
+        ctx.wait(register.into_actor(self).map(move |registered, session, ctx| {
+            if !matches!(registered, Ok(true)) {
+                info!("Refused the Bedrock player '{}' from {}, whose name is already online", name, session.client);
+                ctx.stop();
+                return;
+            }
+
+            session.player = Some(PlayerEntry::add(session.config.clone(), name.clone(), session.host.clone()));
+            Event::PlayerJoin {
+                host: session.host.clone(),
+                player: name,
+                address: session.client.to_string(),
+            }.publish();
+        }));
+    }
+}
+
+impl Actor for BedrockSession {
+    type Context = Context<Self>;
+
+    /// Connect a socket to the upstream before relaying any datagrams
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let resolver = self.config.load().resolver();
+        let upstream = self.upstream.clone();
+        let connect = async move {
+            let address = resolver.resolve(&upstream).await.map_err(|error| error.0)?;
+            let local: IpAddr = match address {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await.map_err(|error| error.to_string())?;
+            socket.connect(address).await.map_err(|error| error.to_string())?;
+            Ok::<_, String>(socket)
+        };
+        ctx.wait(connect.into_actor(self).map(|connected, session, ctx| match connected {
+            Ok(socket) => {
+                let socket = Arc::new(socket);
+                ctx.add_stream(datagrams(socket.clone()));
+                session.socket = Some(socket);
+            }
+            Err(error) => {
+                warn!("Cannot relay the Bedrock client {} to {}: {}", session.client, session.upstream, error);
+                ctx.stop();
+            }
+        }));
+
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |session, ctx| {
+            if session.last_activity.elapsed() >= session.idle_timeout {
+                ctx.stop();
+            }
+        });
+    }
+
+    /// Dropping the entry removes the player from the player list
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        if let Some(player) = self.player.take() {
+            SessionRegistry::from_registry().do_send(UnregisterSession {
+                name: player.name.clone(),
+                kick: ctx.address().recipient(),
+            });
+
+            Event::PlayerLeave {
+                host: player.host.clone(),
+                player: player.name.clone(),
+            }.publish();
+        }
+    }
+}
+
+/// The datagrams received from the upstream
+fn datagrams(socket: Arc<UdpSocket>) -> impl Stream<Item = io::Result<Bytes>> {
+    let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+    stream::poll_fn(move |cx| {
+        socket.poll_recv(cx, &mut datagram)
+            .map(|received| Some(received.map(|size| Bytes::copy_from_slice(&datagram[..size]))))
+    })
+}
+
+impl Handler<ClientDatagram> for BedrockSession {
+    type Result = ();
+    fn handle(&mut self, ClientDatagram(datagram): ClientDatagram, ctx: &mut Self::Context) {
+        self.last_activity = Instant::now();
+        if let Some(socket) = &self.socket {
+            // Like any datagram, one that cannot be sent right away may get lost
+            let _ = socket.try_send(&datagram);
+        }
+        self.inspect(&datagram, true, ctx);
+    }
+}
+
+impl StreamHandler<io::Result<Bytes>> for BedrockSession {
+    fn handle(&mut self, datagram: io::Result<Bytes>, ctx: &mut Self::Context) {
+        match datagram {
+            Ok(datagram) => {
+                self.last_activity = Instant::now();
+                let _ = self.replies.try_send((self.client, datagram.clone()));
+                self.inspect(&datagram, false, ctx);
+            }
+            Err(error) => {
+                warn!("Cannot receive from the upstream of the Bedrock client {}: {}", self.client, error);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/// Bedrock clients cannot be shown a reason without speaking the game protocol,
+/// their session stops being relayed and times out on the client
+impl Handler<Kick> for BedrockSession {
+    type Result = ();
+    fn handle(&mut self, _kick: Kick, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstreams_default_to_the_bedrock_port() {
+        assert_eq!(parse_upstream("bedrock.local").unwrap(), Upstream { host: "bedrock.local".to_owned(), port: Some(DEFAULT_PORT) });
+        assert_eq!(parse_upstream("10.0.0.3:19133").unwrap(), Upstream { host: "10.0.0.3".to_owned(), port: Some(19133) });
+        assert!(parse_upstream("bedrock.local:mcpe").is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Inspect and replay packet captures recorded by mineroute.
+//!
+//! ```text
+//! mineroute-capture list <capture>
+//! mineroute-capture json <capture>
+//! mineroute-capture replay <capture> <backend address> [speed]
+//! ```
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+use bytes::BytesMut;
+use serde::Serialize;
+use mineroute::capture::format::{CaptureHeader, CaptureRecord, Direction};
+use mineroute::net::{Client, Server, Protocol, PacketClientEnum, PacketServerEnum};
+use mineroute::net::buffer::{Buffer, BufferMut};
+use mineroute::net::handshake::HandshakePacket;
+use mineroute::net::login::LoginStartPacket;
+use mineroute::net::play::PlayPacketIds;
+use mineroute::net::pipeline::compressor::{Compressor, CompressionLevel};
+use mineroute::net::pipeline::framing::MAX_DECOMPRESSED_SIZE;
+use mineroute::net::wire_codec::{WireCodec, ClientWireCodec, ServerWireCodec};
+
+/// How long to wait for the backend to accept the login
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to keep the connection open after the last packet was replayed
+const LINGER: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "Usage: mineroute-capture list <capture>
+       mineroute-capture json <capture>
+       mineroute-capture replay <capture> <backend address> [speed]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["list", path] => read_capture(path).map(|(header, records)| list(&header, &records)),
+        ["json", path] => read_capture(path).and_then(|(header, records)| json(header, &records)),
+        ["replay", path, backend] => read_capture(path).and_then(|(header, records)| replay(&header, &records, backend, 1.0)),
+        ["replay", path, backend, speed] => match speed.parse::<f64>() {
+            Ok(speed) if speed > 0.0 => read_capture(path).and_then(|(header, records)| replay(&header, &records, backend, speed)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "the speed must be a positive number")),
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        exit(1);
+    }
+}
+
+fn read_capture(path: &str) -> io::Result<(CaptureHeader, Vec<CaptureRecord>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = CaptureHeader::read(&mut reader)?;
+
+    let mut records = vec![];
+    while let Some(record) = CaptureRecord::read(&mut reader)? {
+        records.push(record);
+    }
+    Ok((header, records))
+}
+
+/// A packet decoded with the codecs of the proxy
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Decoded {
+    Serverbound(PacketClientEnum),
+    Clientbound(PacketServerEnum),
+}
+
+fn decode(record: &CaptureRecord) -> Result<Decoded, ()> {
+    let mut buf = BytesMut::from(&record.packet[..]);
+    let id = buf.read_u8()?;
+    match record.direction {
+        Direction::Serverbound => <ClientWireCodec as WireCodec<Client>>::read_packet(&record.protocol, id, &mut buf, None).map(Decoded::Serverbound),
+        Direction::Clientbound => <ServerWireCodec as WireCodec<Server>>::read_packet(&record.protocol, id, &mut buf, None).map(Decoded::Clientbound),
+    }
+}
+
+/// A short name of the packet, if it is known
+fn packet_name(record: &CaptureRecord, ids: Option<PlayPacketIds>) -> Option<&'static str> {
+    match decode(record).ok()? {
+        Decoded::Serverbound(PacketClientEnum::Handshake(_)) => Some("handshake"),
+        Decoded::Serverbound(PacketClientEnum::StatusRequest(_)) => Some("status_request"),
+        Decoded::Serverbound(PacketClientEnum::Ping(_)) => Some("ping"),
+        Decoded::Serverbound(PacketClientEnum::LoginStart(_)) => Some("login_start"),
+        Decoded::Clientbound(PacketServerEnum::StatusResponse(_)) => Some("status_response"),
+        Decoded::Clientbound(PacketServerEnum::Pong(_)) => Some("pong"),
+        Decoded::Clientbound(PacketServerEnum::Disconnect(_)) => Some("disconnect"),
+        Decoded::Clientbound(PacketServerEnum::Compression(_)) => Some("set_compression"),
+        Decoded::Clientbound(PacketServerEnum::LoginSuccess(_)) => Some("login_success"),
+        Decoded::Serverbound(PacketClientEnum::Raw(packet)) => match ids? {
+            ids if packet.id == ids.keep_alive_serverbound => Some("keep_alive"),
+            _ => None,
+        },
+        Decoded::Clientbound(PacketServerEnum::Raw(packet)) => match ids? {
+            ids if packet.id == ids.keep_alive_clientbound => Some("keep_alive"),
+            ids if packet.id == ids.disconnect => Some("disconnect"),
+            _ => None,
+        },
+    }
+}
+
+fn protocol_name(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::Handshake => "handshake",
+        Protocol::Status => "status",
+        Protocol::Login => "login",
+        Protocol::Play => "play",
+    }
+}
+
+/// Print one line per packet, similar to the listing of a pcap viewer
+fn list(header: &CaptureHeader, records: &[CaptureRecord]) {
+    println!("# {} on {} from {}, protocol version {}, started at {} ms",
+             header.player, header.host, header.address, header.protocol_version, header.started);
+
+    let ids = PlayPacketIds::for_version(header.protocol_version);
+    for (number, record) in records.iter().enumerate() {
+        let direction = match record.direction {
+            Direction::Serverbound => "C->S",
+            Direction::Clientbound => "S->C",
+        };
+
+        println!("{:>6} {:>12.6}  {}  {:<9} 0x{:02x} {:>8}  {}",
+                 number + 1,
+                 record.time as f64 / 1_000_000.0,
+                 direction,
+                 protocol_name(&record.protocol),
+                 record.packet.first().copied().unwrap_or(0),
+                 record.packet.len(),
+                 packet_name(record, ids).unwrap_or(""));
+    }
+}
+
+#[derive(Serialize)]
+struct JsonCapture {
+    header: CaptureHeader,
+    records: Vec<JsonRecord>,
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+    /// Microseconds since the start of the capture
+    time: u64,
+    direction: Direction,
+    protocol: &'static str,
+    length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packet: Option<Decoded>,
+}
+
+/// Print the capture as json, with all packets decoded as far as the proxy understands them
+fn json(header: CaptureHeader, records: &[CaptureRecord]) -> io::Result<()> {
+    let ids = PlayPacketIds::for_version(header.protocol_version);
+    let records = records.iter()
+        .map(|record| JsonRecord {
+            time: record.time,
+            direction: record.direction,
+            protocol: protocol_name(&record.protocol),
+            length: record.packet.len(),
+            name: packet_name(record, ids),
+            packet: decode(record).ok(),
+        })
+        .collect();
+
+    let stdout = io::stdout();
+    serde_json::to_writer_pretty(stdout.lock(), &JsonCapture { header, records })?;
+    println!();
+    Ok(())
+}
+
+/// The compression state of the replayed connection, shared by its reading and writing side
+type Compression = Arc<Mutex<Option<usize>>>;
+
+/// Log in to a backend as the captured player and send the recorded play packets
+/// with their original timing, divided by `speed`.
+///
+/// Keep Alive packets of the backend are answered instead of replaying the recorded ones.
+fn replay(header: &CaptureHeader, records: &[CaptureRecord], backend: &str, speed: f64) -> io::Result<()> {
+    let address = backend.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot resolve {}", backend)))?;
+    let stream = TcpStream::connect(address)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let compression: Compression = Arc::new(Mutex::new(None));
+    let ids = PlayPacketIds::for_version(header.protocol_version);
+
+    let handshake = PacketClientEnum::Handshake(HandshakePacket {
+        protocol_version: header.protocol_version,
+        server_address: header.host.clone(),
+        server_port: address.port(),
+        next_protocol: Protocol::Login,
+    });
+    send_packet(&writer, &compression, &encode(&Protocol::Handshake, &handshake)?)?;
+    let login_start = PacketClientEnum::LoginStart(LoginStartPacket { name: header.player.clone() });
+    send_packet(&writer, &compression, &encode(&Protocol::Login, &login_start)?)?;
+
+    // Read packets of the backend in the background
+    let (logged_in_tx, logged_in_rx) = channel();
+    let received = Arc::new(Mutex::new(0usize));
+    {
+        let (writer, compression, received) = (writer.clone(), compression.clone(), received.clone());
+        let mut reader = BufReader::new(stream);
+        thread::spawn(move || {
+            let mut protocol = Protocol::Login;
+            let mut inflater = Compressor::new(0, CompressionLevel::Default);
+            while let Ok(packet) = read_packet(&mut reader, &compression, &mut inflater) {
+                let mut buf = BytesMut::from(&packet[..]);
+                let id = match buf.read_u8() {
+                    Ok(id) => id,
+                    Err(()) => break,
+                };
+
+                match <ServerWireCodec as WireCodec<Server>>::read_packet(&protocol, id, &mut buf, None) {
+                    Ok(PacketServerEnum::Compression(packet)) => *compression.lock().unwrap() = packet.size_limit,
+                    Ok(PacketServerEnum::LoginSuccess(_)) => {
+                        protocol = Protocol::Play;
+                        let _ = logged_in_tx.send(());
+                    }
+                    Ok(PacketServerEnum::Disconnect(packet)) => println!("Disconnected: {}", packet.reason),
+                    Ok(PacketServerEnum::Raw(packet)) => match ids {
+                        Some(ids) if packet.id == ids.keep_alive_clientbound => {
+                            let mut answer = vec![ids.keep_alive_serverbound];
+                            answer.extend_from_slice(&packet.data);
+                            let _ = send_packet(&writer, &compression, &answer);
+                        }
+                        Some(ids) if packet.id == ids.disconnect => {
+                            let reason = BytesMut::from(&packet.data[..]).read_string().unwrap_or_default();
+                            println!("Disconnected: {}", reason);
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+                *received.lock().unwrap() += 1;
+            }
+        });
+    }
+
+    logged_in_rx.recv_timeout(LOGIN_TIMEOUT)
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the backend did not accept the login"))?;
+    println!("Logged in to {} as {}", backend, header.player);
+
+    let play: Vec<_> = records.iter()
+        .filter(|record| record.direction == Direction::Serverbound && matches!(record.protocol, Protocol::Play))
+        .filter(|record| ids.map_or(true, |ids| record.packet.first() != Some(&ids.keep_alive_serverbound)))
+        .collect();
+
+    let started = Instant::now();
+    let first = play.first().map(|record| record.time).unwrap_or(0);
+    for record in &play {
+        let due = Duration::from_micros(((record.time - first) as f64 / speed) as u64);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+        send_packet(&writer, &compression, &record.packet)?;
+    }
+
+    thread::sleep(LINGER);
+    println!("Replayed {} packets, received {}", play.len(), received.lock().unwrap());
+    Ok(())
+}
+
+fn encode(protocol: &Protocol, packet: &PacketClientEnum) -> io::Result<Vec<u8>> {
+    let mut buf = BytesMut::new();
+    <ServerWireCodec as WireCodec<Server>>::write_packet(protocol, packet, &mut buf)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "cannot encode packet"))?;
+    Ok(buf.to_vec())
+}
+
+/// Frame and, if enabled, compress a packet and write it to the backend
+fn send_packet(writer: &Mutex<TcpStream>, compression: &Compression, packet: &[u8]) -> io::Result<()> {
+    let mut payload = BytesMut::new();
+    match *compression.lock().unwrap() {
+        Some(threshold) if packet.len() >= threshold => {
+            payload.write_var_int(packet.len() as i32);
+            payload.write_raw_bytes(&deflate::deflate_bytes_zlib(packet));
+        }
+        Some(_) => {
+            payload.write_var_int(0);
+            payload.write_raw_bytes(packet);
+        }
+        None => payload.write_raw_bytes(packet),
+    }
+
+    let mut frame = BytesMut::new();
+    frame.write_var_int(payload.len() as i32);
+    frame.write_raw_bytes(&payload);
+    writer.lock().unwrap().write_all(&frame)
+}
+
+/// Read a frame and decompress it if necessary
+fn read_packet<R: Read>(reader: &mut R, compression: &Compression, inflater: &mut Compressor) -> io::Result<Vec<u8>> {
+    let length = read_var_int(reader)?;
+    if length < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "negative frame length"));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+
+    if compression.lock().unwrap().is_none() {
+        return Ok(payload);
+    }
+
+    inflater.decode(BytesMut::from(&payload[..]), MAX_DECOMPRESSED_SIZE)
+        .map(|packet| packet.to_vec())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+}
+
+fn read_var_int<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut result = 0;
+    for i in 0..5 {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        result |= ((byte[0] & 127) as i32) << (i * 7);
+        if byte[0] & 128 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "var int is too long"))
+}
@@ -0,0 +1,189 @@
+//! The file format of packet captures.
+//!
+//! A capture starts with a header describing the session,
+//! followed by one record per packet until the end of the file.
+//! All integers are big endian.
+//!
+//! ```text
+//! header: magic "MRCAP" | version u8 | started u64 (unix millis) | protocol version i32
+//!         | player string | host string | address string
+//! record: time u64 (micros since started) | direction u8 | protocol u8 | length u32 | packet
+//! string: length u16 | utf-8 bytes
+//! ```
+//!
+//! The packet of a record is its id followed by its data, as it is after decompression.
+
+use std::io::{self, Read, Write, ErrorKind};
+use serde::Serialize;
+use crate::net::Protocol;
+
+const MAGIC: &[u8; 5] = b"MRCAP";
+const VERSION: u8 = 1;
+
+/// Describes the session that a capture was recorded from
+#[derive(Serialize, Debug, Clone)]
+pub struct CaptureHeader {
+    /// When the capture was started, in milliseconds since the unix epoch
+    pub started: u64,
+
+    /// The protocol version sent by the client in its handshake
+    pub protocol_version: i32,
+
+    pub player: String,
+    pub host: String,
+
+    /// The address of the client
+    pub address: String,
+}
+
+/// Whom a packet was sent to
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Serverbound,
+    Clientbound,
+}
+
+impl Direction {
+    pub fn reverse(self) -> Direction {
+        match self {
+            Direction::Serverbound => Direction::Clientbound,
+            Direction::Clientbound => Direction::Serverbound,
+        }
+    }
+}
+
+/// A packet that was sent or received at some point of the session
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    /// Microseconds since the start of the capture
+    pub time: u64,
+    pub direction: Direction,
+    pub protocol: Protocol,
+
+    /// The packet id followed by the packet data
+    pub packet: Vec<u8>,
+}
+
+impl CaptureHeader {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&self.started.to_be_bytes())?;
+        writer.write_all(&self.protocol_version.to_be_bytes())?;
+        write_string(writer, &self.player)?;
+        write_string(writer, &self.host)?;
+        write_string(writer, &self.address)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<CaptureHeader> {
+        let mut magic = [0; 5];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a mineroute capture"));
+        }
+
+        let version = read_u8(reader)?;
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported capture version {}", version)));
+        }
+
+        let mut started = [0; 8];
+        reader.read_exact(&mut started)?;
+        let mut protocol_version = [0; 4];
+        reader.read_exact(&mut protocol_version)?;
+
+        Ok(CaptureHeader {
+            started: u64::from_be_bytes(started),
+            protocol_version: i32::from_be_bytes(protocol_version),
+            player: read_string(reader)?,
+            host: read_string(reader)?,
+            address: read_string(reader)?,
+        })
+    }
+}
+
+impl CaptureRecord {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let direction = match self.direction {
+            Direction::Serverbound => 0,
+            Direction::Clientbound => 1,
+        };
+
+        writer.write_all(&self.time.to_be_bytes())?;
+        writer.write_all(&[direction, protocol_to_byte(&self.protocol)])?;
+        writer.write_all(&(self.packet.len() as u32).to_be_bytes())?;
+        writer.write_all(&self.packet)
+    }
+
+    /// Read the next record, or `None` at the end of the capture
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Option<CaptureRecord>> {
+        let mut time = [0; 8];
+        match reader.read_exact(&mut time) {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+
+        let direction = match read_u8(reader)? {
+            0 => Direction::Serverbound,
+            1 => Direction::Clientbound,
+            direction => return Err(invalid_data(format!("invalid direction {}", direction))),
+        };
+        let protocol = protocol_from_byte(read_u8(reader)?)?;
+
+        let mut length = [0; 4];
+        reader.read_exact(&mut length)?;
+        let mut packet = vec![0; u32::from_be_bytes(length) as usize];
+        reader.read_exact(&mut packet)?;
+
+        Ok(Some(CaptureRecord {
+            time: u64::from_be_bytes(time),
+            direction,
+            protocol,
+            packet,
+        }))
+    }
+}
+
+fn protocol_to_byte(protocol: &Protocol) -> u8 {
+    match protocol {
+        Protocol::Handshake => 0,
+        Protocol::Status => 1,
+        Protocol::Login => 2,
+        Protocol::Play => 3,
+    }
+}
+
+fn protocol_from_byte(byte: u8) -> io::Result<Protocol> {
+    match byte {
+        0 => Ok(Protocol::Handshake),
+        1 => Ok(Protocol::Status),
+        2 => Ok(Protocol::Login),
+        3 => Ok(Protocol::Play),
+        _ => Err(invalid_data(format!("invalid protocol {}", byte))),
+    }
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn write_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+    writer.write_all(&(string.len() as u16).to_be_bytes())?;
+    writer.write_all(string.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut length = [0; 2];
+    reader.read_exact(&mut length)?;
+    let mut string = vec![0; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut string)?;
+    String::from_utf8(string).map_err(|_| invalid_data("invalid string"))
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
@@ -0,0 +1,156 @@
+//! Recording of the packets of selected sessions, to debug issues reported by players.
+//!
+//! Captures are enabled for players or hostnames through the admin api
+//! and apply to sessions that log in afterwards.
+//! They can be inspected and replayed with the `mineroute-capture` binary.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use log::warn;
+use crate::net::Protocol;
+
+pub mod format;
+
+use self::format::{CaptureHeader, CaptureRecord, Direction};
+
+/// The sessions that a capture applies to
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureTarget {
+    Player(String),
+    Host(String),
+}
+
+/// The captures enabled through the admin api
+#[derive(Clone)]
+pub struct Captures {
+    /// Where capture files are written to
+    directory: PathBuf,
+
+    /// Each capture has a flag that is cleared once it gets disabled,
+    /// so that the recorders of running sessions stop as well
+    targets: HashMap<CaptureTarget, Arc<AtomicBool>>,
+}
+
+impl Captures {
+    pub fn new(directory: PathBuf) -> Captures {
+        Captures {
+            directory,
+            targets: HashMap::new(),
+        }
+    }
+
+    pub fn set_directory(&mut self, directory: PathBuf) {
+        self.directory = directory;
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item = &CaptureTarget> {
+        self.targets.keys()
+    }
+
+    /// Capture sessions of the target that log in from now on.
+    /// Returns false if the target is already captured.
+    pub fn start(&mut self, target: CaptureTarget) -> bool {
+        if self.targets.contains_key(&target) {
+            return false;
+        }
+        self.targets.insert(target, Arc::new(AtomicBool::new(true)));
+        true
+    }
+
+    /// Stop capturing the target, including sessions that are being recorded.
+    /// Returns false if the target was not captured.
+    pub fn stop(&mut self, target: &CaptureTarget) -> bool {
+        match self.targets.remove(target) {
+            Some(active) => {
+                active.store(false, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start recording a session if a capture applies to it
+    pub fn open(&self, header: CaptureHeader) -> Option<io::Result<Recorder>> {
+        let active = self.targets.get(&CaptureTarget::Player(header.player.clone()))
+            .or_else(|| self.targets.get(&CaptureTarget::Host(header.host.clone())))?;
+
+        Some(Recorder::create(&self.directory, header, active.clone()))
+    }
+}
+
+/// Records the packets of one session into a capture file.
+///
+/// Records are written by a separate thread, so that actors do not block on file io.
+pub struct Recorder {
+    sender: RefCell<Option<Sender<CaptureRecord>>>,
+    active: Arc<AtomicBool>,
+    started: Instant,
+}
+
+impl Recorder {
+    fn create(directory: &Path, header: CaptureHeader, active: Arc<AtomicBool>) -> io::Result<Recorder> {
+        fs::create_dir_all(directory)?;
+        // Player names are chosen by clients and must not escape the directory
+        let name: String = format!("{}-{}-{}.mrcap", header.started, header.player, header.host).chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+            .collect();
+        let file = File::create(directory.join(name))?;
+
+        let (sender, receiver) = channel::<CaptureRecord>();
+        thread::spawn(move || {
+            let mut writer = BufWriter::new(file);
+            let result = header.write(&mut writer)
+                .and_then(|_| receiver.iter().try_for_each(|record| record.write(&mut writer)))
+                .and_then(|_| writer.flush());
+
+            if let Err(error) = result {
+                warn!("Failed to write the capture of '{}': {}", header.player, error);
+            }
+        });
+
+        Ok(Recorder {
+            sender: RefCell::new(Some(sender)),
+            active,
+            started: Instant::now(),
+        })
+    }
+
+    /// Record a packet, consisting of its id and data
+    pub fn record(&self, direction: Direction, protocol: &Protocol, packet: &[u8]) {
+        let mut sender = self.sender.borrow_mut();
+
+        // Closing the channel lets the thread finish the file
+        if !self.active.load(Ordering::Relaxed) {
+            sender.take();
+        }
+
+        if let Some(sender) = sender.as_ref() {
+            let _ = sender.send(CaptureRecord {
+                time: self.started.elapsed().as_micros() as u64,
+                direction,
+                protocol: protocol.clone(),
+                packet: packet.to_vec(),
+            });
+        }
+    }
+
+    /// Whether the capture was not disabled yet
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Milliseconds since the unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0)
+}
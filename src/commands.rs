@@ -0,0 +1,221 @@
+//! Chat commands that players send to the proxy itself
+
+use std::collections::{BTreeMap, HashSet};
+use serde::Deserialize;
+use crate::server_state::Configuration;
+use crate::settings::ListenerSettings;
+
+/// The commands handled by the proxy
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    /// List the servers or switch to one of them
+    Server,
+
+    /// List the players of all servers
+    Glist,
+
+    /// Move another player to a server
+    Send,
+}
+
+impl Command {
+    const ALL: [Command; 3] = [Command::Server, Command::Glist, Command::Send];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Server => "server",
+            Command::Glist => "glist",
+            Command::Send => "send",
+        }
+    }
+
+    fn usage(&self) -> &'static str {
+        match self {
+            Command::Server => "[server]",
+            Command::Glist => "",
+            Command::Send => "<player> <server>",
+        }
+    }
+}
+
+/// A command entered by a player
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invocation {
+    ListServers,
+    Switch(String),
+    GlobalList,
+    Send { player: String, host: String },
+
+    /// The arguments do not match the command
+    Usage(String),
+}
+
+impl Invocation {
+    pub fn command(&self) -> Option<Command> {
+        match self {
+            Invocation::ListServers | Invocation::Switch(_) => Some(Command::Server),
+            Invocation::GlobalList => Some(Command::Glist),
+            Invocation::Send { .. } => Some(Command::Send),
+            Invocation::Usage(_) => None,
+        }
+    }
+}
+
+/// Chat commands handled by the proxy instead of the upstream server
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSettings {
+    /// Chat messages consisting of this prefix and the name of a command are not forwarded
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+
+    /// The commands that members of a group may use.
+    /// All players are members of the group `default`.
+    #[serde(default = "default_groups")]
+    pub groups: BTreeMap<String, HashSet<Command>>,
+
+    /// The groups of players by name, in addition to `default`
+    #[serde(default)]
+    pub players: BTreeMap<String, Vec<String>>,
+}
+
+impl CommandSettings {
+    /// Parse a chat message of a player.
+    /// Returns `None` for messages that are forwarded to the upstream server.
+    pub fn parse(&self, message: &str) -> Option<Invocation> {
+        let mut words = message.strip_prefix(&self.prefix)?.split_whitespace();
+        let name = words.next()?;
+        let command = *Command::ALL.iter().find(|command| command.name().eq_ignore_ascii_case(name))?;
+        let arguments: Vec<&str> = words.collect();
+
+        let invocation = match (command, arguments.as_slice()) {
+            (Command::Server, []) => Invocation::ListServers,
+            (Command::Server, [host]) => Invocation::Switch(host.to_ascii_lowercase()),
+            (Command::Glist, []) => Invocation::GlobalList,
+            (Command::Send, [player, host]) => Invocation::Send {
+                player: (*player).to_owned(),
+                host: host.to_ascii_lowercase(),
+            },
+            _ => Invocation::Usage(format!("Usage: {}{} {}", self.prefix, command.name(), command.usage()).trim_end().to_owned()),
+        };
+        Some(invocation)
+    }
+
+    /// Whether a player is a member of a group that may use the command
+    pub fn permits(&self, player: &str, command: Command) -> bool {
+        let groups = self.players.get(player).into_iter().flatten().map(String::as_str);
+        std::iter::once("default").chain(groups)
+            .filter_map(|group| self.groups.get(group))
+            .any(|commands| commands.contains(&command))
+    }
+
+    /// The groups assigned to players that are not defined
+    pub fn undefined_groups(&self) -> Vec<&str> {
+        self.players.values().flatten()
+            .filter(|group| !self.groups.contains_key(*group))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// The servers that players of a listener may switch to, marking the current one
+pub fn server_list(config: &Configuration, listener: &ListenerSettings, current: Option<&str>) -> Vec<String> {
+    let mut hosts: Vec<&String> = config.get_server_hosts().into_iter()
+        .filter(|host| listener.allows_host(host))
+        .collect();
+    hosts.sort();
+
+    let mut lines = vec!["Servers:".to_owned()];
+    lines.extend(hosts.into_iter().map(|host| match current {
+        Some(current) if current == host => format!("- {} (connected)", host),
+        _ => format!("- {}", host),
+    }));
+    lines
+}
+
+/// The players of all servers that are reachable through a listener
+pub fn global_list(config: &Configuration, listener: &ListenerSettings) -> Vec<String> {
+    let mut hosts: Vec<&String> = config.get_server_hosts().into_iter()
+        .filter(|host| listener.allows_host(host))
+        .collect();
+    hosts.sort();
+
+    let mut total = 0;
+    let mut lines = vec![];
+    for host in hosts {
+        let players = config.get_server(host).unwrap().players.read().unwrap();
+        total += players.len();
+        lines.push(match players.len() {
+            0 => format!("{} (0)", host),
+            count => format!("{} ({}): {}", host, count, players.join(", ")),
+        });
+    }
+    lines.push(format!("{} players are online", total));
+    lines
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        CommandSettings {
+            prefix: default_prefix(),
+            groups: default_groups(),
+            players: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_prefix() -> String {
+    "/".to_owned()
+}
+
+/// Players may switch between servers and list the players of all servers
+fn default_groups() -> BTreeMap<String, HashSet<Command>> {
+    let mut groups = BTreeMap::new();
+    groups.insert("default".to_owned(), vec![Command::Server, Command::Glist].into_iter().collect());
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed_after_the_prefix() {
+        let settings = CommandSettings::default();
+        assert_eq!(settings.parse("/server"), Some(Invocation::ListServers));
+        assert_eq!(settings.parse("/SERVER Lobby.Test"), Some(Invocation::Switch("lobby.test".to_owned())));
+        assert_eq!(settings.parse("/glist"), Some(Invocation::GlobalList));
+        assert_eq!(settings.parse("/send steve a.test"), Some(Invocation::Send {
+            player: "steve".to_owned(),
+            host: "a.test".to_owned(),
+        }));
+        assert_eq!(settings.parse("/send steve"), Some(Invocation::Usage("Usage: /send <player> <server>".to_owned())));
+
+        // Other commands and messages are handled by the upstream server
+        assert_eq!(settings.parse("/help"), None);
+        assert_eq!(settings.parse("server"), None);
+        assert_eq!(settings.parse("/"), None);
+    }
+
+    #[test]
+    fn permissions_are_granted_through_groups() {
+        let settings: CommandSettings = toml::from_str(r#"
+            prefix = "!"
+
+            [groups]
+            default = ["server"]
+            admin = ["glist", "send"]
+
+            [players]
+            alice = ["admin"]
+        "#).unwrap();
+
+        assert_eq!(settings.parse("!glist"), Some(Invocation::GlobalList));
+        assert!(settings.permits("alice", Command::Send));
+        assert!(settings.permits("alice", Command::Server));
+        assert!(settings.permits("bob", Command::Server));
+        assert!(!settings.permits("bob", Command::Glist));
+        assert!(settings.undefined_groups().is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use actix::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::server_state::Health;
+
+/// Categories of events that a subscriber may filter by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Players,
+    Health,
+    Config,
+    Moderation,
+}
+
+/// Events published to all subscribers of the [EventBus]
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    PlayerJoin { host: String, player: String, address: String },
+    PlayerLeave { host: String, player: String },
+
+    HealthChanged { host: String, health: Health, error: Option<String> },
+
+    ServerAdded { host: String, sockaddr: String },
+    ServerUpdated { host: String, sockaddr: String },
+    ServerRemoved { host: String },
+
+    PlayerKicked { host: String, player: String, reason: String },
+    PlayerBanned { player: String, reason: String },
+    PlayerUnbanned { player: String },
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::PlayerJoin { .. } | Event::PlayerLeave { .. } => Topic::Players,
+            Event::HealthChanged { .. } => Topic::Health,
+            Event::ServerAdded { .. } | Event::ServerUpdated { .. } | Event::ServerRemoved { .. } => Topic::Config,
+            Event::PlayerKicked { .. } | Event::PlayerBanned { .. } | Event::PlayerUnbanned { .. } => Topic::Moderation,
+        }
+    }
+
+    /// The hostname this event refers to, if it is bound to a single server
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            Event::PlayerJoin { host, .. } |
+            Event::PlayerLeave { host, .. } |
+            Event::HealthChanged { host, .. } |
+            Event::ServerAdded { host, .. } |
+            Event::ServerUpdated { host, .. } |
+            Event::ServerRemoved { host } |
+            Event::PlayerKicked { host, .. } => Some(host),
+            Event::PlayerBanned { .. } | Event::PlayerUnbanned { .. } => None,
+        }
+    }
+
+    /// Publish this event to all subscribers of the system wide [EventBus]
+    pub fn publish(self) {
+        EventBus::from_registry().do_send(Publish(self));
+    }
+}
+
+impl Message for Event { type Result = (); }
+
+/// A system wide actor broadcasting [Event]s to its subscribers
+#[derive(Default)]
+pub struct EventBus {
+    next_id: usize,
+    subscribers: HashMap<usize, Recipient<Event>>,
+}
+
+impl Actor for EventBus {
+    type Context = Context<Self>;
+}
+
+impl Supervised for EventBus {}
+impl SystemService for EventBus {}
+
+/// Register a recipient for all future events.
+/// Returns an id that can be used to unsubscribe again.
+pub struct Subscribe(pub Recipient<Event>);
+impl Message for Subscribe { type Result = usize; }
+
+pub struct Unsubscribe(pub usize);
+impl Message for Unsubscribe { type Result = (); }
+
+pub struct Publish(pub Event);
+impl Message for Publish { type Result = (); }
+
+impl Handler<Subscribe> for EventBus {
+    type Result = usize;
+    fn handle(&mut self, Subscribe(recipient): Subscribe, _ctx: &mut Self::Context) -> Self::Result {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.insert(id, recipient);
+        id
+    }
+}
+
+impl Handler<Unsubscribe> for EventBus {
+    type Result = ();
+    fn handle(&mut self, Unsubscribe(id): Unsubscribe, _ctx: &mut Self::Context) {
+        self.subscribers.remove(&id);
+    }
+}
+
+impl Handler<Publish> for EventBus {
+    type Result = ();
+    fn handle(&mut self, Publish(event): Publish, _ctx: &mut Self::Context) {
+        // Drop subscribers whose actor has already stopped
+        self.subscribers.retain(|_, subscriber| {
+            subscriber.do_send(event.clone()).is_ok()
+        });
+    }
+}
@@ -0,0 +1,186 @@
+//! Custom behavior plugged into the proxy sessions.
+//!
+//! Extensions are compiled into the binary and registered at startup
+//! through [Configuration::register_extension](crate::server_state::Configuration::register_extension).
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use crate::net::{PacketClientEnum, PacketServerEnum};
+use crate::net::handshake::HandshakePacket;
+use crate::net::status::server_status::ServerInfo;
+
+/// What is known about a session when a hook is called
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// The address of the client, as reported by the PROXY protocol if enabled
+    pub address: SocketAddr,
+
+    /// The protocol version sent in the handshake
+    pub protocol_version: Option<i32>,
+
+    /// The hostname of the server that the session is proxied to
+    pub host: Option<String>,
+
+    /// The name of the player once the login started
+    pub player: Option<String>,
+}
+
+impl SessionInfo {
+    pub fn new(address: SocketAddr) -> SessionInfo {
+        SessionInfo {
+            address,
+            protocol_version: None,
+            host: None,
+            player: None,
+        }
+    }
+}
+
+/// Whether a session may continue after a hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Continue,
+
+    /// Disconnect the client with this reason
+    Deny(String),
+}
+
+/// What to do with an intercepted packet
+#[derive(Debug, Clone)]
+pub enum PacketAction<P> {
+    Forward,
+
+    /// Forward this packet instead
+    Replace(P),
+
+    Drop,
+}
+
+/// Hooks into the sessions of the proxy.
+/// All hooks do nothing by default.
+pub trait Extension: Send + Sync {
+    /// Identifies the extension in logs
+    fn name(&self) -> &str;
+
+    /// The client sent its handshake. The packet may be changed before it is routed.
+    fn on_handshake(&self, _session: &SessionInfo, _handshake: &mut HandshakePacket) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// A player started to log in
+    fn on_login(&self, _session: &SessionInfo) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Route a logging in player to the server of another hostname
+    fn select_server(&self, _session: &SessionInfo) -> Option<String> {
+        None
+    }
+
+    /// Change the status of a server before it is sent to the client
+    fn rewrite_status(&self, _session: &SessionInfo, _status: &mut ServerInfo) {}
+
+    /// Whether play packets need to pass the packet hooks.
+    /// Otherwise they may be spliced between the sockets without being decoded.
+    fn intercepts_play(&self) -> bool {
+        false
+    }
+
+    /// Inspect a packet sent by the client, before it is handled or forwarded
+    fn on_client_packet(&self, _session: &SessionInfo, _packet: &PacketClientEnum) -> PacketAction<PacketClientEnum> {
+        PacketAction::Forward
+    }
+
+    /// Inspect a packet sent by the upstream server, before it is forwarded to the client
+    fn on_server_packet(&self, _session: &SessionInfo, _packet: &PacketServerEnum) -> PacketAction<PacketServerEnum> {
+        PacketAction::Forward
+    }
+
+    /// The session ended
+    fn on_disconnect(&self, _session: &SessionInfo) {}
+}
+
+/// The registered extensions, called in the order of their registration
+#[derive(Clone, Default)]
+pub struct Extensions {
+    extensions: Vec<Arc<dyn Extension>>,
+}
+
+impl Extensions {
+    pub fn register(&mut self, extension: Arc<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    /// The first denial of any extension
+    pub fn handshake(&self, session: &SessionInfo, handshake: &mut HandshakePacket) -> Verdict {
+        self.extensions.iter()
+            .map(|extension| extension.on_handshake(session, handshake))
+            .find(|verdict| *verdict != Verdict::Continue)
+            .unwrap_or(Verdict::Continue)
+    }
+
+    /// The first denial of any extension
+    pub fn login(&self, session: &SessionInfo) -> Verdict {
+        self.extensions.iter()
+            .map(|extension| extension.on_login(session))
+            .find(|verdict| *verdict != Verdict::Continue)
+            .unwrap_or(Verdict::Continue)
+    }
+
+    /// The hostname chosen by the first extension that selects a server
+    pub fn select_server(&self, session: &SessionInfo) -> Option<String> {
+        self.extensions.iter().find_map(|extension| extension.select_server(session))
+    }
+
+    pub fn rewrite_status(&self, session: &SessionInfo, status: &mut ServerInfo) {
+        for extension in &self.extensions {
+            extension.rewrite_status(session, status);
+        }
+    }
+
+    pub fn intercepts_play(&self) -> bool {
+        self.extensions.iter().any(|extension| extension.intercepts_play())
+    }
+
+    /// Pass a packet of the client through all extensions.
+    /// Returns `None` if it was dropped.
+    pub fn client_packet(&self, session: &SessionInfo, mut packet: PacketClientEnum) -> Option<PacketClientEnum> {
+        for extension in &self.extensions {
+            match extension.on_client_packet(session, &packet) {
+                PacketAction::Forward => {}
+                PacketAction::Replace(PacketClientEnum::Raw(mut replacement)) => {
+                    // The frame of the original packet must not be forwarded instead
+                    replacement.compressed = None;
+                    packet = PacketClientEnum::Raw(replacement);
+                }
+                PacketAction::Replace(replacement) => packet = replacement,
+                PacketAction::Drop => return None,
+            }
+        }
+        Some(packet)
+    }
+
+    /// Pass a packet of the upstream server through all extensions.
+    /// Returns `None` if it was dropped.
+    pub fn server_packet(&self, session: &SessionInfo, mut packet: PacketServerEnum) -> Option<PacketServerEnum> {
+        for extension in &self.extensions {
+            match extension.on_server_packet(session, &packet) {
+                PacketAction::Forward => {}
+                PacketAction::Replace(PacketServerEnum::Raw(mut replacement)) => {
+                    // The frame of the original packet must not be forwarded instead
+                    replacement.compressed = None;
+                    packet = PacketServerEnum::Raw(replacement);
+                }
+                PacketAction::Replace(replacement) => packet = replacement,
+                PacketAction::Drop => return None,
+            }
+        }
+        Some(packet)
+    }
+
+    pub fn disconnect(&self, session: &SessionInfo) {
+        for extension in &self.extensions {
+            extension.on_disconnect(session);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+use std::sync::Arc;
+use actix::prelude::*;
+use futures::future::join_all;
+use tokio::time::timeout;
+use crate::events::Event;
+use crate::net::manager::StatusServerManager;
+use crate::server_state::{SharedConfiguration, Health};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Periodically request the status of all upstream servers
+/// and keep track of whether they are reachable.
+pub struct HealthChecker {
+    config: Arc<SharedConfiguration>,
+}
+
+impl HealthChecker {
+    pub fn new(config: Arc<SharedConfiguration>) -> HealthChecker {
+        HealthChecker { config }
+    }
+
+    fn check_all(&mut self, ctx: &mut Context<Self>) {
+        let (resolver, upstreams) = {
+            let config = self.config.load();
+            let upstreams: Vec<_> = config.get_server_hosts().into_iter()
+                .filter_map(|host| config.get_server(host).map(|server| (host.clone(), server.upstream.clone())))
+                .collect();
+            (config.resolver(), upstreams)
+        };
+
+        let checks = upstreams.into_iter().map(|(host, upstream)| {
+            let resolver = resolver.clone();
+            async move {
+                let address = match resolver.resolve(&upstream).await {
+                    Ok(address) => address,
+                    Err(error) => return (host, Health::Unresolvable, Some(error.to_string())),
+                };
+
+                match timeout(CHECK_TIMEOUT, StatusServerManager::fetch_status(address)).await {
+                    Ok(Ok(_)) => (host, Health::Healthy, None),
+                    Ok(Err(_)) => (host, Health::Unhealthy, Some(format!("{} did not answer the status request", address))),
+                    Err(_) => (host, Health::Unhealthy, Some(format!("{} timed out", address))),
+                }
+            }
+        });
+
+        let future = join_all(checks)
+            .into_actor(self)
+            .map(|results, checker, _ctx| {
+                for (host, health, error) in results {
+                    update_health(&checker.config, &host, health, error);
+                }
+            });
+        ctx.spawn(future);
+    }
+}
+
+/// Store the health of an upstream server and notify
+/// event subscribers if it has changed.
+pub fn update_health(config: &SharedConfiguration, host: &str, health: Health, error: Option<String>) {
+    // Most checks change nothing, which does not require copying the configuration
+    let unchanged = config.load().get_server(host)
+        .map_or(true, |server| server.health == health && server.health_error == error);
+    if unchanged {
+        return;
+    }
+
+    let mut config = config.write();
+    if let Some(server) = config.get_server_mut(host) {
+        let changed = server.health != health;
+        server.health = health;
+        server.health_error = error.clone();
+
+        if changed {
+            Event::HealthChanged {
+                host: host.to_owned(),
+                health,
+                error,
+            }.publish();
+        }
+    }
+}
+
+impl Actor for HealthChecker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.check_all(ctx);
+        ctx.run_interval(CHECK_INTERVAL, |checker, ctx| checker.check_all(ctx));
+    }
+}
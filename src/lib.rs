@@ -0,0 +1,21 @@
+// Decoding errors are reported as `Err(())` throughout the protocol code
+#![allow(clippy::result_unit_err)]
+
+pub mod net;
+pub mod web;
+pub mod server_state;
+pub mod events;
+pub mod health;
+pub mod sessions;
+pub mod upstream;
+pub mod settings;
+pub mod listener;
+pub mod metrics;
+pub mod capture;
+pub mod extensions;
+pub mod commands;
+pub mod arbiters;
+pub mod query;
+pub mod bedrock;
+pub mod rcon;
+pub mod audit;
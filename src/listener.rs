@@ -0,0 +1,305 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::sync::Arc;
+use std::collections::HashMap;
+use actix::prelude::*;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use futures::future::join_all;
+use log::{error, info, warn};
+use socket2::{Socket, Domain, Type};
+use crate::net::manager::ProxyClientManager;
+use crate::arbiters::ArbiterPool;
+use crate::net::proxy_protocol;
+use crate::query;
+use crate::bedrock;
+use crate::server_state::SharedConfiguration;
+use crate::settings::{Settings, ListenerSettings};
+
+/// How long a proxy may take to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Owns all sockets accepting minecraft connections.
+///
+/// Listeners are identified by their name. When new settings get applied,
+/// listeners that were removed or changed are closed and new ones are bound.
+pub struct ListenerManager {
+    config: Arc<SharedConfiguration>,
+
+    /// Run the sessions of accepted connections
+    arbiters: Arc<ArbiterPool>,
+
+    /// The configuration file that is read again on [Reload]
+    settings_path: PathBuf,
+
+    running: HashMap<String, RunningListener>,
+}
+
+struct RunningListener {
+    settings: Arc<ListenerSettings>,
+
+    /// Close the sockets of the listener, its UDP sockets included, when sent or dropped
+    shutdown: Vec<oneshot::Sender<()>>,
+
+    /// Complete once the sockets of the listener have been closed
+    stopped: Vec<oneshot::Receiver<()>>,
+}
+
+impl ListenerManager {
+    pub fn new(config: Arc<SharedConfiguration>, arbiters: Arc<ArbiterPool>, settings_path: PathBuf) -> ListenerManager {
+        ListenerManager {
+            config,
+            arbiters,
+            settings_path,
+            running: HashMap::new(),
+        }
+    }
+
+    fn apply(&mut self, listeners: Vec<ListenerSettings>) -> ResponseActFuture<Self, Result<(), String>> {
+        // Listeners that are no longer configured or whose settings changed
+        let outdated: Vec<String> = self.running.iter()
+            .filter(|(name, running)| !listeners.iter().any(|listener| listener.name == **name && *listener == *running.settings))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let held: Vec<SocketAddr> = outdated.iter()
+            .flat_map(|name| addresses(&self.running[name].settings))
+            .collect();
+
+        // New sockets are bound before the ones they replace get closed, so that a failed bind keeps the old listener.
+        // Only addresses still held by an outdated listener have to wait until it was closed.
+        let mut errors = vec![];
+        let mut bound = vec![];
+        let mut deferred = vec![];
+        for listener in listeners.iter().filter(|listener| !self.running.contains_key(&listener.name) || outdated.contains(&listener.name)) {
+            match bind_sockets(listener) {
+                Ok(sockets) => bound.push((listener.clone(), sockets)),
+                Err(error) if error.kind() == io::ErrorKind::AddrInUse && addresses(listener).iter().any(|address| held.contains(address)) => {
+                    deferred.push(listener.clone());
+                }
+                Err(error) => errors.push(format!("Cannot bind listener '{}': {}", listener.name, error)),
+            }
+        }
+
+        let replaced: Vec<String> = outdated.into_iter()
+            .filter(|name| {
+                let failed = listeners.iter().any(|listener| listener.name == *name)
+                    && !bound.iter().any(|(listener, _)| listener.name == *name)
+                    && !deferred.iter().any(|listener| listener.name == *name);
+                !failed
+            })
+            .collect();
+        let mut previous = HashMap::new();
+        let stopped: Vec<_> = replaced.into_iter()
+            .filter_map(|name| self.running.remove(&name))
+            .flat_map(|running| {
+                for shutdown in running.shutdown {
+                    let _ = shutdown.send(());
+                }
+                info!("Closed listener '{}' on {}", running.settings.name, running.settings.bind);
+                previous.insert(running.settings.name.clone(), running.settings);
+                running.stopped
+            })
+            .collect();
+
+        for (listener, sockets) in bound {
+            self.start_listener(listener, sockets);
+        }
+
+        // Sockets must be closed before their addresses can be bound again
+        let future = join_all(stopped).into_actor(self).map(move |_, manager, _ctx| {
+            for listener in deferred {
+                match bind_sockets(&listener) {
+                    Ok(sockets) => manager.start_listener(listener, sockets),
+                    Err(error) => {
+                        errors.push(format!("Cannot bind listener '{}': {}", listener.name, error));
+
+                        // Keep serving with the settings that were replaced
+                        if let Some(settings) = previous.remove(&listener.name) {
+                            match bind_sockets(&settings) {
+                                Ok(sockets) => manager.start_listener((*settings).clone(), sockets),
+                                Err(error) => error!("Cannot restore listener '{}': {}", settings.name, error),
+                            }
+                        }
+                    }
+                }
+            }
+
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors.join(", "))
+            }
+        });
+        Box::new(future)
+    }
+
+    fn start_listener(&mut self, settings: ListenerSettings, sockets: ListenerSockets) {
+        info!("Listening for connections on {} ('{}')", settings.bind, settings.name);
+        if let Some(bind) = settings.query_bind() {
+            info!("Answering queries on {} ('{}')", bind, settings.name);
+        }
+        if let Some(bind) = settings.bedrock_bind() {
+            info!("Relaying Bedrock clients on {} ('{}')", bind, settings.name);
+        }
+
+        let settings = Arc::new(settings);
+        let mut running = RunningListener {
+            settings: settings.clone(),
+            shutdown: vec![],
+            stopped: vec![],
+        };
+
+        let (shutdown, stopped) = running.add_socket();
+        actix::spawn(accept_loop(sockets.listener, settings.clone(), self.config.clone(), self.arbiters.clone(), shutdown, stopped));
+
+        if let Some(socket) = sockets.query {
+            let (shutdown, stopped) = running.add_socket();
+            actix::spawn(query::serve(socket, settings.clone(), self.config.clone(), shutdown, stopped));
+        }
+
+        if let Some(socket) = sockets.bedrock {
+            let (shutdown, stopped) = running.add_socket();
+            actix::spawn(bedrock::serve(socket, settings.clone(), self.config.clone(), shutdown, stopped));
+        }
+
+        self.running.insert(settings.name.clone(), running);
+    }
+}
+
+/// The sockets of a listener, bound before any of them is served
+struct ListenerSockets {
+    listener: TcpListener,
+    query: Option<UdpSocket>,
+    bedrock: Option<UdpSocket>,
+}
+
+fn bind_sockets(settings: &ListenerSettings) -> io::Result<ListenerSockets> {
+    let with_address = |address: SocketAddr| move |error: io::Error| io::Error::new(error.kind(), format!("{}: {}", address, error));
+    let listener = bind(settings).map_err(with_address(settings.bind))?;
+    let query = match settings.query_bind() {
+        Some(address) => Some(query::bind(address, settings.ipv6_only).map_err(with_address(address))?),
+        None => None,
+    };
+    let bedrock = match settings.bedrock_bind() {
+        Some(address) => Some(bedrock::bind(address, settings.ipv6_only).map_err(with_address(address))?),
+        None => None,
+    };
+    Ok(ListenerSockets { listener, query, bedrock })
+}
+
+/// All addresses that the sockets of a listener are bound to
+fn addresses(settings: &ListenerSettings) -> Vec<SocketAddr> {
+    let mut addresses = vec![settings.bind];
+    addresses.extend(settings.query_bind());
+    addresses.extend(settings.bedrock_bind());
+    addresses
+}
+
+impl RunningListener {
+    /// The channels closing a socket of the listener and reporting that it was closed
+    fn add_socket(&mut self) -> (oneshot::Receiver<()>, oneshot::Sender<()>) {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        self.shutdown.push(shutdown_tx);
+        self.stopped.push(stopped_rx);
+        (shutdown_rx, stopped_tx)
+    }
+}
+
+impl Actor for ListenerManager {
+    type Context = Context<Self>;
+}
+
+/// Replace the set of running listeners
+pub struct ApplyListeners(pub Vec<ListenerSettings>);
+impl Message for ApplyListeners { type Result = Result<(), String>; }
+
+/// Read the configuration file again and apply its listeners
+pub struct Reload;
+impl Message for Reload { type Result = Result<(), String>; }
+
+impl Handler<ApplyListeners> for ListenerManager {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+    fn handle(&mut self, ApplyListeners(listeners): ApplyListeners, _ctx: &mut Self::Context) -> Self::Result {
+        self.apply(listeners)
+    }
+}
+
+impl Handler<Reload> for ListenerManager {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+    fn handle(&mut self, _reload: Reload, _ctx: &mut Self::Context) -> Self::Result {
+        match Settings::load(&self.settings_path) {
+            Ok(settings) => self.apply(settings.listeners),
+            Err(error) => Box::new(actix::fut::err(error.to_string())),
+        }
+    }
+}
+
+fn bind(settings: &ListenerSettings) -> io::Result<TcpListener> {
+    let domain = match settings.bind {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+
+    let socket = Socket::new(domain, Type::stream(), None)?;
+    socket.set_reuse_address(true)?;
+    if settings.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if settings.bind.is_ipv6() {
+        socket.set_only_v6(settings.ipv6_only)?;
+    }
+    socket.bind(&settings.bind.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+async fn accept_loop(mut listener: TcpListener, settings: Arc<ListenerSettings>, config: Arc<SharedConfiguration>,
+                     arbiters: Arc<ArbiterPool>, mut shutdown: oneshot::Receiver<()>, stopped: oneshot::Sender<()>) {
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, address)) => {
+                    let (settings, config) = (settings.clone(), config.clone());
+                    let dispatched = arbiters.dispatch(stream, move |stream| {
+                        actix::spawn(accept_connection(stream, address, settings, config));
+                    });
+                    if let Err(error) = dispatched {
+                        warn!("Cannot hand over the connection of {}: {}", address, error);
+                    }
+                }
+                Err(error) => warn!("Listener '{}' failed to accept a connection: {}", settings.name, error),
+            },
+        }
+    }
+
+    drop(listener);
+    let _ = stopped.send(());
+}
+
+async fn accept_connection(mut stream: TcpStream, mut address: SocketAddr, listener: Arc<ListenerSettings>, config: Arc<SharedConfiguration>) {
+    if stream.set_nodelay(listener.nodelay).is_err() || stream.set_keepalive(listener.keepalive()).is_err() {
+        return;
+    }
+
+    // The address of the proxy is replaced with that of the actual client
+    if listener.proxy_protocol {
+        match timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+            Ok(Ok(Some(source))) => address = source,
+            Ok(Ok(None)) => {}
+            _ => return,
+        }
+    }
+
+    ProxyClientManager::create(move |ctx| {
+        ProxyClientManager::new(config, listener, address, stream, ctx)
+    });
+}
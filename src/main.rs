@@ -1,43 +1,97 @@
-mod net;
-mod web;
-mod server_state;
-
 use std::env;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::sync::{RwLock, Arc};
-use actix::Actor;
-use tokio::net::TcpListener;
-use tokio::stream::StreamExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use actix::{Actor, Addr};
 use futures_util::future::FutureExt;
-use crate::net::manager::ProxyClientManager;
-use crate::server_state::{Configuration, ServerConfig};
+use log::{error, info};
+use mineroute::server_state::{Configuration, SharedConfiguration, ServerConfig, RconConfig};
+use mineroute::audit::AuditLog;
+use mineroute::rcon;
+use mineroute::health::HealthChecker;
+use mineroute::sessions::SessionReconciler;
+use mineroute::upstream::{DnsResolver, UpstreamResolver};
+use mineroute::settings::Settings;
+use mineroute::listener::{ListenerManager, ApplyListeners, Reload};
+use mineroute::arbiters::{ArbiterPool, available_cores};
+
+/// How long resolved upstream addresses are reused
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+const DEFAULT_SETTINGS_PATH: &str = "mineroute.toml";
 
 #[actix_rt::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
+    env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let settings_path = PathBuf::from(env::args().nth(1).unwrap_or_else(|| DEFAULT_SETTINGS_PATH.to_owned()));
+    let settings = Settings::load(&settings_path).unwrap_or_else(|error| {
+        error!("{}", error);
+        std::process::exit(1);
+    });
+
+    let resolver = {
+        let dns = DnsResolver::from_system_conf().await.unwrap();
+        Arc::new(UpstreamResolver::new(Arc::new(dns), DNS_CACHE_TTL))
+    };
 
     let config = {
-        let mut config = Configuration::new();
-        config.add_server("a.mc.local", ServerConfig::new("127.0.0.1:25566".parse().unwrap()));
-        config.add_server("b.mc.local", ServerConfig::new("127.0.0.1:25567".parse().unwrap()));
-        Arc::new(RwLock::new(config))
+        let mut config = Configuration::new(resolver);
+        config.captures_mut().set_directory(settings.capture.directory.clone());
+        config.set_commands(settings.commands.clone());
+        config.set_audit_log(AuditLog::new(settings.audit.log.clone()));
+        for (host, server) in &settings.servers {
+            let upstream = server.upstream.parse().unwrap_or_else(|_| {
+                error!("Invalid upstream '{}' of server '{}'", server.upstream, host);
+                std::process::exit(1);
+            });
+            let mut server_config = ServerConfig::new(upstream);
+            server_config.rcon = server.rcon.as_ref().map(|rcon| RconConfig {
+                address: rcon::parse_address(&rcon.address).unwrap_or_else(|_| {
+                    error!("Invalid RCON address '{}' of server '{}'", rcon.address, host);
+                    std::process::exit(1);
+                }),
+                password: rcon.password.clone(),
+                groups: rcon.groups.clone(),
+            });
+            config.add_server(&host.to_ascii_lowercase(), server_config);
+        }
+        Arc::new(SharedConfiguration::new(config))
     };
 
-    actix::spawn(web::webserver_run(config.clone()).map(|_| {}));
+    let arbiters = Arc::new(ArbiterPool::new(settings.workers.unwrap_or_else(available_cores)));
+    info!("Running sessions on {} workers", arbiters.len());
 
-    let port: u16 = args.get(1).map(|arg| arg.parse().unwrap())
-        .unwrap_or(25565);
+    let listeners = ListenerManager::new(config.clone(), arbiters, settings_path).start();
+    if let Err(error) = listeners.send(ApplyListeners(settings.listeners)).await.unwrap() {
+        error!("{}", error);
+        std::process::exit(1);
+    }
 
-    let addr = {
-        let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        SocketAddr::new(localhost, port)
-    };
+    actix::spawn(mineroute::web::webserver_run(config.clone(), listeners.clone(), settings.web.bind).map(|_| {}));
+    HealthChecker::new(config.clone()).start();
+    SessionReconciler::new(config.clone()).start();
+
+    reload_on_hangup(listeners).await;
+}
 
-    let mut listener = TcpListener::bind(&addr).await.unwrap();
-    let mut connections = listener.incoming();
-    while let Some(Ok(stream)) = connections.next().await {
-        ProxyClientManager::create(|ctx| {
-            ProxyClientManager::new(config.clone(), stream, ctx)
-        });
+/// Reload the listeners from the configuration file on SIGHUP
+#[cfg(unix)]
+async fn reload_on_hangup(listeners: Addr<ListenerManager>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+    while hangup.recv().await.is_some() {
+        match listeners.send(Reload).await {
+            Ok(Ok(())) => info!("Reloaded the configuration"),
+            Ok(Err(error)) => error!("Failed to reload the configuration: {}", error),
+            Err(_) => break,
+        }
     }
 }
+
+/// There is no SIGHUP on other platforms, the listeners are only reloaded through the admin api
+#[cfg(not(unix))]
+async fn reload_on_hangup(_listeners: Addr<ListenerManager>) {
+    futures::future::pending::<()>().await
+}
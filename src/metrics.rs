@@ -0,0 +1,233 @@
+//! Process wide metrics, exposed in the Prometheus text format
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A monotonically increasing counter
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter::new()
+    }
+}
+
+/// A value that may go up and down
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, amount: u64) {
+        self.0.fetch_sub(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge::new()
+    }
+}
+
+/// A metric that can be rendered as part of a [Family]
+trait Metric: Sync {
+    fn value(&self) -> u64;
+}
+
+impl Metric for Counter {
+    fn value(&self) -> u64 {
+        self.get()
+    }
+}
+
+impl Metric for Gauge {
+    fn value(&self) -> u64 {
+        self.get()
+    }
+}
+
+/// Sessions that were disconnected because a timeout elapsed, by the state they were stuck in
+pub mod session_timeouts {
+    use super::Counter;
+
+    pub static HANDSHAKE: Counter = Counter::new();
+    pub static STATUS: Counter = Counter::new();
+    pub static LOGIN: Counter = Counter::new();
+    pub static CLIENT_KEEP_ALIVE: Counter = Counter::new();
+    pub static SERVER_KEEP_ALIVE: Counter = Counter::new();
+    pub static UPSTREAM_STATUS: Counter = Counter::new();
+    pub static SLOW_CLIENT: Counter = Counter::new();
+}
+
+/// Sessions disconnected because they sent too many packets before their upstream was ready
+pub static QUEUE_OVERFLOWS: Counter = Counter::new();
+
+/// Sessions closed because handling one of their packets or messages panicked
+pub static SESSION_PANICS: Counter = Counter::new();
+
+/// Players removed from the player lists because their session was gone
+pub static GHOST_PLAYERS: Counter = Counter::new();
+
+/// Frames that were rejected because they exceeded a size limit
+pub mod frame_limits {
+    use super::Counter;
+    use crate::net::pipeline::framing::FrameError;
+
+    /// The announced frame size was too large
+    pub static FRAME: Counter = Counter::new();
+
+    /// The packet would have decompressed to too many bytes
+    pub static DECOMPRESSED: Counter = Counter::new();
+
+    /// Count a rejected frame if it violated a limit
+    pub fn record(error: &FrameError) {
+        match error {
+            FrameError::Malformed => {}
+            FrameError::TooLarge { .. } => FRAME.inc(),
+            FrameError::DecompressedTooLarge { .. } => DECOMPRESSED.inc(),
+        }
+    }
+}
+
+/// The write buffers of all connections of one kind
+pub struct WriteBufferMetrics {
+    /// Bytes that were encoded but not yet written to the sockets
+    pub bytes: Gauge,
+
+    /// How often reading from a peer connection was paused because a buffer was full
+    pub pauses: Counter,
+}
+
+impl WriteBufferMetrics {
+    const fn new() -> WriteBufferMetrics {
+        WriteBufferMetrics {
+            bytes: Gauge::new(),
+            pauses: Counter::new(),
+        }
+    }
+}
+
+pub mod write_buffers {
+    use super::WriteBufferMetrics;
+
+    /// Connections to clients
+    pub static CLIENT: WriteBufferMetrics = WriteBufferMetrics::new();
+
+    /// Connections to upstream servers
+    pub static UPSTREAM: WriteBufferMetrics = WriteBufferMetrics::new();
+}
+
+/// A metric family with its help text and its labeled metrics
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    metrics: &'static [(&'static str, &'static dyn Metric)],
+}
+
+static FAMILIES: &[Family] = &[
+    Family {
+        name: "mineroute_session_timeouts_total",
+        help: "Sessions disconnected because a timeout elapsed",
+        kind: "counter",
+        metrics: &[
+            ("state=\"handshake\"", &session_timeouts::HANDSHAKE),
+            ("state=\"status\"", &session_timeouts::STATUS),
+            ("state=\"login\"", &session_timeouts::LOGIN),
+            ("state=\"client_keep_alive\"", &session_timeouts::CLIENT_KEEP_ALIVE),
+            ("state=\"server_keep_alive\"", &session_timeouts::SERVER_KEEP_ALIVE),
+            ("state=\"upstream_status\"", &session_timeouts::UPSTREAM_STATUS),
+            ("state=\"slow_client\"", &session_timeouts::SLOW_CLIENT),
+        ],
+    },
+    Family {
+        name: "mineroute_frame_limit_violations_total",
+        help: "Received frames that were rejected because they exceeded a size limit",
+        kind: "counter",
+        metrics: &[
+            ("limit=\"frame\"", &frame_limits::FRAME),
+            ("limit=\"decompressed\"", &frame_limits::DECOMPRESSED),
+        ],
+    },
+    Family {
+        name: "mineroute_queue_overflows_total",
+        help: "Sessions disconnected because they sent too many packets before their upstream was ready",
+        kind: "counter",
+        metrics: &[("", &QUEUE_OVERFLOWS)],
+    },
+    Family {
+        name: "mineroute_session_panics_total",
+        help: "Sessions closed because handling one of their packets or messages panicked",
+        kind: "counter",
+        metrics: &[("", &SESSION_PANICS)],
+    },
+    Family {
+        name: "mineroute_ghost_players_removed_total",
+        help: "Players removed from the player lists because their session was gone",
+        kind: "counter",
+        metrics: &[("", &GHOST_PLAYERS)],
+    },
+    Family {
+        name: "mineroute_write_buffer_bytes",
+        help: "Bytes waiting to be written to the sockets",
+        kind: "gauge",
+        metrics: &[
+            ("connection=\"client\"", &write_buffers::CLIENT.bytes),
+            ("connection=\"upstream\"", &write_buffers::UPSTREAM.bytes),
+        ],
+    },
+    Family {
+        name: "mineroute_backpressure_pauses_total",
+        help: "Times reading was paused because the connection that packets are forwarded to fell behind",
+        kind: "counter",
+        metrics: &[
+            ("connection=\"client\"", &write_buffers::CLIENT.pauses),
+            ("connection=\"upstream\"", &write_buffers::UPSTREAM.pauses),
+        ],
+    },
+];
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut output = String::new();
+    for family in FAMILIES {
+        let _ = writeln!(output, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(output, "# TYPE {} {}", family.name, family.kind);
+        for (labels, metric) in family.metrics {
+            if labels.is_empty() {
+                let _ = writeln!(output, "{} {}", family.name, metric.value());
+            } else {
+                let _ = writeln!(output, "{}{{{}}} {}", family.name, labels, metric.value());
+            }
+        }
+    }
+    output
+}
@@ -1,11 +1,21 @@
 use std::rc::Rc;
-use std::sync::RwLock;
-use actix::AsyncContext;
+use std::cell::RefCell;
+use std::time::Duration;
+use actix::{Actor, ActorContext, AsyncContext, Context};
 use actix::io::SinkWrite;
 use tokio::net::TcpStream;
-use crate::net::{Protocol, ConnectionType};
+use crate::net::{Protocol, ConnectionType, ConnectionMetrics};
 use crate::net::pipeline::{HandlerPipeline, PipelineSink};
-use crate::net::manager::ConnectionManager;
+use crate::net::pipeline::splice::{DetachedReader, DetachedWriter};
+use crate::net::pipeline::backpressure::Backpressure;
+use crate::net::manager::{ConnectionManager, Peer};
+use crate::net::manager::relay;
+use crate::settings::{CompressionLevel, FrameLimits};
+use crate::capture::Recorder;
+use crate::capture::format::Direction;
+
+/// How long a connection that is shut down may take to flush its pending packets
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
 
 /// A connection to a remote minecraft server or client.
 ///
@@ -17,11 +27,12 @@ use crate::net::manager::ConnectionManager;
 /// packets to the server and execute protocol changes.
 pub struct Connection<CT: ConnectionType> {
     sink: SinkWrite<CT::Out, PipelineSink<CT>>,
-    pipeline: Rc<RwLock<HandlerPipeline<CT>>>,
+    pipeline: Rc<RefCell<HandlerPipeline<CT>>>,
 }
 
 impl<CT: ConnectionType> Connection<CT> {
-    pub fn new<H: ConnectionManager<CT>>(stream: TcpStream, ctx: &mut H::Context) -> Connection<CT> {
+    pub fn new<H: ConnectionManager<CT>>(stream: TcpStream, ctx: &mut H::Context) -> Connection<CT>
+        where CT: ConnectionMetrics {
         let (pipeline, sink, stream) = HandlerPipeline::new(stream);
         ctx.add_stream(stream);
         Connection {
@@ -34,15 +45,80 @@ impl<CT: ConnectionType> Connection<CT> {
         self.sink.write(packet)
     }
 
+    /// Open a relay through which the other leg of a proxied session passes packets
+    /// and control messages on to this connection, handled by the managing actor as they arrive
+    pub fn relay<H: ConnectionManager<CT>>(&self, ctx: &mut H::Context) -> Peer<CT> {
+        let (peer, receiver) = relay::channel(self.backpressure());
+        ctx.add_stream(receiver);
+        peer
+    }
+
+    /// The pipeline shared by the stream and sink of this connection
+    pub fn pipeline(&self) -> Rc<RefCell<HandlerPipeline<CT>>> {
+        self.pipeline.clone()
+    }
+
+    /// Reject received frames exceeding these limits
+    pub fn set_limits(&self, limits: FrameLimits) {
+        self.pipeline.borrow_mut().set_limits(limits);
+    }
+
+    /// Record the packets of this connection.
+    /// See [HandlerPipeline::set_recorder].
+    pub fn set_recorder(&self, recorder: Recorder, received: Direction) {
+        self.pipeline.borrow_mut().set_recorder(recorder, received);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.pipeline.borrow().is_recording()
+    }
+
+    /// The fill level of the write buffer of this connection
+    pub fn backpressure(&self) -> Rc<Backpressure> {
+        self.pipeline.borrow().backpressure()
+    }
+
+    /// Pause reading from either connection while the other one cannot keep up with the forwarded packets
+    pub fn link_backpressure<P: ConnectionType>(&self, peer: &RefCell<HandlerPipeline<P>>) {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let mut peer = peer.borrow_mut();
+        pipeline.throttle(peer.backpressure());
+        peer.throttle(pipeline.backpressure());
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.pipeline.borrow().protocol()
+    }
+
+    /// The compression threshold of this connection, if compression is enabled
+    pub fn compression(&self) -> Option<usize> {
+        self.pipeline.borrow().compression()
+    }
+
+    /// Take the socket out of this connection to splice it with another one.
+    /// See [HandlerPipeline::detach].
+    pub fn detach(&mut self) -> Option<(DetachedReader, DetachedWriter)> {
+        self.pipeline.borrow_mut().detach()
+    }
+
     pub fn set_protocol(&self, protocol: Protocol) {
-        self.pipeline.write().unwrap().set_protocol(protocol);
+        self.pipeline.borrow_mut().set_protocol(protocol);
     }
 
-    pub fn enable_compression(&mut self, size_limit: Option<usize>) {
-        self.pipeline.write().unwrap().enable_compression(size_limit)
+    pub fn enable_compression(&mut self, size_limit: Option<usize>, level: CompressionLevel) {
+        self.pipeline.borrow_mut().enable_compression(size_limit, level)
     }
 
     pub fn disconnect(&mut self) {
         self.sink.close()
     }
+
+    /// Close the connection and stop the managing actor after a grace period
+    /// that allows pending packets to get flushed.
+    /// Unlike [Connection::disconnect] this also frees the actor and socket
+    /// if the remote never closes its side of the connection.
+    pub fn shutdown<A: Actor<Context = Context<A>>>(&mut self, ctx: &mut Context<A>) {
+        self.disconnect();
+        ctx.run_later(SHUTDOWN_GRACE, |_, ctx| ctx.stop());
+    }
 }
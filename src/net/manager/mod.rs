@@ -1,20 +1,24 @@
 mod proxy_client_manager;
 mod status_server_manager;
 mod proxy_server_manager;
+pub mod relay;
 
 pub use status_server_manager::StatusServerManager;
 pub use proxy_client_manager::ProxyClientManager;
 pub use proxy_server_manager::ProxyServerManager;
+pub use relay::{Peer, Relayed};
 
-use actix::{Actor, Context, StreamHandler, Handler, Message};
+use std::panic::{self, AssertUnwindSafe};
+use actix::{Actor, Context, StreamHandler};
 use actix::io::WriteHandler;
 use crate::net::{Packet, Protocol, ConnectionType};
+use crate::metrics::SESSION_PANICS;
 
 /// Manage a connection between a server/client setup
 pub trait ConnectionManager<CT: ConnectionType> where
     Self: Actor<Context = Context<Self>>,
     Self: StreamHandler<Result<CT::In, ()>>,
-    Self: Handler<HandlerMessage<CT>>,
+    Self: StreamHandler<Relayed<CT>>,
     Self: WriteHandler<()> {}
 
 /// Handle an incoming packet retrieved from a server/client.
@@ -22,13 +26,27 @@ pub trait PacketHandler<CT: ConnectionType, P: Packet>: ConnectionManager<CT> {
     fn handle_packet(&mut self, packet: P, ctx: &mut Self::Context) -> Result<(), ()>;
 }
 
-/// Messages supported by [ConnectionManager] actors acting as proxy
-pub enum HandlerMessage<CT: ConnectionType> {
-    SendPacket(CT::Out),
+/// Control messages that [ConnectionManager] actors acting as proxy relay to each other.
+/// Packets are relayed alongside them, see [Peer].
+pub enum HandlerMessage {
     SetProtocol(Protocol),
+    /// Compression was enabled on the other leg with the provided threshold
     EnableCompression(Option<usize>),
     Disconnect(),
+    /// The client logged in to the upstream it is switching to
+    SwitchCompleted(),
+    /// The client could not be switched to another upstream and stays on its current one
+    SwitchFailed(),
 }
-impl<CT: ConnectionType> Message for HandlerMessage<CT> {
-    type Result = Result<(), ()>;
+
+/// Run the handling of a packet or message, so that a panic only ends the session that caused it.
+/// Returns `None` if the handling panicked.
+pub(crate) fn contain_panic<R>(handle: impl FnOnce() -> R) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(handle)) {
+        Ok(result) => Some(result),
+        Err(_) => {
+            SESSION_PANICS.inc();
+            None
+        }
+    }
 }
@@ -1,27 +1,83 @@
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::sync::{Mutex, RwLock, Arc};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use actix::prelude::*;
 use actix::io::WriteHandler;
 use tokio::net::TcpStream;
-use futures::FutureExt;
+use tokio::time::timeout;
+use log::{error, warn};
 use crate::net::handshake::HandshakePacket;
 use crate::net::*;
-use crate::net::login::LoginStartPacket;
+use crate::net::login::{LoginStartPacket, DisconnectPacket, CompressionPacket};
 use crate::net::status::{StatusRequestPacket, StatusResponsePacket, PingPacket, PongPacket};
-use crate::net::manager::{ProxyServerManager, PacketHandler, HandlerMessage, ConnectionManager, StatusServerManager};
-use crate::net::play::RawPacket;
-use crate::server_state::Configuration;
+use crate::net::manager::{ProxyServerManager, PacketHandler, HandlerMessage, ConnectionManager, StatusServerManager, Peer, Relayed, contain_panic};
+use crate::net::play::{RawPacket, PlayPacketIds, WorldPacketIds, ServerOverlay};
+use crate::net::pipeline::HandlerPipeline;
+use crate::net::pipeline::splice::splice;
+use crate::net::pipeline::framing::MAX_FRAME_SIZE;
+use crate::server_state::{SharedConfiguration, Health};
+use crate::events::Event;
+use crate::health::update_health;
+use crate::upstream::Upstream;
+use crate::sessions::{SessionRegistry, RegisterSession, UnregisterSession, PlayerEntry, Kick, SendPlayer, SendRefused, SwitchServer};
+use crate::settings::ListenerSettings;
+use crate::metrics::{Counter, QUEUE_OVERFLOWS, session_timeouts};
+use crate::capture::unix_millis;
+use crate::capture::format::{CaptureHeader, Direction};
+use crate::extensions::{Extensions, SessionInfo, Verdict};
+use crate::commands::{self, CommandSettings, Invocation};
+
+/// How long a client may take to send its handshake after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a status request (including the ping) may take
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the login may take, from the handshake until the play protocol is entered
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long connecting to an upstream server may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Servers send a Keep Alive about every 15 seconds and
+/// expect the client to answer it within 30 seconds.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the keep alive watchdog checks a playing session
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the write buffer of a client may stay full before they are disconnected
+const SLOW_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many packets of a client are queued until its upstream accepted the login
+const MAX_QUEUED_PACKETS: usize = 64;
 
 /// Manage a client connection to this server.
 ///
 /// This server will act as a proxy server,
 /// forwarding all packets to a defined upstream.
 pub struct ProxyClientManager {
-    config: Arc<RwLock<Configuration>>,
+    config: Arc<SharedConfiguration>,
     connection: Connection<Client>,
+
+    /// The listener that accepted this connection
+    listener: Arc<ListenerSettings>,
+
+    /// The address of the client, as reported by the PROXY protocol if enabled
+    address: SocketAddr,
+
     handshake: Option<HandshakePacket>,
-    upstream: Rc<Mutex<Option<Addr<ProxyServerManager<ProxyClientManager>>>>>,
+    state: SessionState,
+
+    /// Packets of the client received before the upstream connection was ready
+    queue: VecDeque<RawPacket>,
+
+    /// The pipeline of the upstream connection, until it gets spliced with this connection
+    upstream_pipeline: Option<Rc<RefCell<HandlerPipeline<Server>>>>,
 
     /// The name of the user if already connected
     name: Option<String>,
@@ -29,20 +85,568 @@ pub struct ProxyClientManager {
     /// The hostname used to connect to the server
     connection_host: Option<String>,
 
-    /// The hostname that this connection should get proxied to
-    upstream_host: Option<SocketAddr>,
+    /// The upstream that this connection should get proxied to
+    upstream_host: Option<Upstream>,
+
+    /// Disconnects the client if it does not leave its current protocol state in time
+    state_timeout: Option<SpawnHandle>,
+
+    /// Tracks Keep Alive packets once the client has entered the play protocol
+    watchdog: Option<Rc<RefCell<KeepAliveWatchdog>>>,
+
+    /// The extensions registered when the client connected
+    extensions: Arc<Extensions>,
+
+    /// What the extensions are told about this session
+    session: SessionInfo,
+
+    /// Chat commands handled by the proxy, if enabled
+    commands: Option<Arc<CommandSettings>>,
+
+    /// The entry of the player in the player list of their server, once logged in
+    player: Option<PlayerEntry>,
+
+    /// The player list entries, boss bars and scoreboards shown by the current upstream
+    overlay: ServerOverlay,
+
+    /// Whether the client still has to be moved to the world of the upstream it switched to
+    rejoining: bool,
+}
+
+/// The connection of a proxied player to an upstream server
+type UpstreamConnection = (Peer<Server>, Rc<RefCell<HandlerPipeline<Server>>>);
+
+/// Where a session is in its lifecycle.
+/// Packets of the client are only accepted in the states that expect them.
+enum SessionState {
+    /// Waiting for the handshake of the client
+    AwaitingHandshake,
+
+    /// Answering the status requests of the client
+    Status,
+
+    /// Waiting for the client to start its login
+    AwaitingLogin,
+
+    /// Connecting to the upstream, while packets of the client are queued
+    Connecting,
+
+    /// Waiting for the upstream to accept the login, while packets of the client are still queued
+    LoggingIn(Peer<Server>),
+
+    Play(Peer<Server>),
+
+    /// Playing on an upstream while logging in to another one
+    Switching(Peer<Server>, PendingSwitch),
+
+    /// The connection is shutting down and packets of the client are dropped
+    Closing,
+}
+
+impl SessionState {
+    /// The upstream that packets of the client are forwarded to
+    fn upstream(&self) -> Option<&Peer<Server>> {
+        match self {
+            SessionState::LoggingIn(upstream) | SessionState::Play(upstream) | SessionState::Switching(upstream, _) => Some(upstream),
+            _ => None,
+        }
+    }
+}
+
+/// An upstream that replaces the current one once the player logged in to it
+struct PendingSwitch {
+    host: String,
+    upstream: Upstream,
+
+    /// Available once the connection was established
+    connection: Option<UpstreamConnection>,
+
+    /// Abandons the switch if the login does not complete in time
+    timeout: SpawnHandle,
 }
 
 impl ProxyClientManager {
-    pub fn new(config: Arc<RwLock<Configuration>>, stream: TcpStream, ctx: &mut Context<Self>) -> ProxyClientManager {
+    pub fn new(config: Arc<SharedConfiguration>, listener: Arc<ListenerSettings>, address: SocketAddr,
+               stream: TcpStream, ctx: &mut Context<Self>) -> ProxyClientManager {
+        let connection = Connection::new::<Self>(stream, ctx);
+        connection.set_limits(listener.limits);
+        let (extensions, commands) = {
+            let config = config.load();
+            (config.extensions(), config.commands())
+        };
+
         ProxyClientManager {
             config,
-            connection: Connection::new::<Self>(stream, ctx),
+            connection,
+            listener,
+            address,
             handshake: None,
-            upstream: Rc::new(Mutex::new(None)),
+            state: SessionState::AwaitingHandshake,
+            queue: VecDeque::new(),
+            upstream_pipeline: None,
             name: None,
             connection_host: None,
             upstream_host: None,
+            state_timeout: None,
+            watchdog: None,
+            extensions,
+            session: SessionInfo::new(address),
+            commands,
+            player: None,
+            overlay: ServerOverlay::default(),
+            rejoining: false,
+        }
+    }
+
+    /// Handle a packet or message of the session, closing it if the handling panics
+    fn contained<R>(&mut self, ctx: &mut Context<Self>, handle: impl FnOnce(&mut Self, &mut Context<Self>) -> R) -> Option<R> {
+        let result = contain_panic(|| handle(self, ctx));
+        if result.is_none() {
+            error!("Closing the session of {} after a panic", self.address);
+            self.close();
+            self.connection.disconnect();
+            ctx.stop();
+        }
+        result
+    }
+
+    /// Enter the closing state, disconnecting from all upstreams
+    fn close(&mut self) {
+        match mem::replace(&mut self.state, SessionState::Closing) {
+            SessionState::LoggingIn(upstream) | SessionState::Play(upstream) => upstream.send_control(HandlerMessage::Disconnect()),
+            SessionState::Switching(upstream, switch) => {
+                upstream.send_control(HandlerMessage::Disconnect());
+                if let Some((pending, _)) = switch.connection {
+                    pending.send_control(HandlerMessage::Disconnect());
+                }
+            }
+            _ => {}
+        }
+        self.queue.clear();
+    }
+
+    /// The ids of the play packets of the protocol version of the client, if known
+    fn play_ids(&self) -> Option<PlayPacketIds> {
+        self.watchdog.as_ref().and_then(|watchdog| watchdog.borrow().ids)
+    }
+
+    /// The ids of the packets needed to switch the client to another upstream, if known
+    fn world_ids(&self) -> Option<WorldPacketIds> {
+        self.handshake.as_ref().and_then(|handshake| WorldPacketIds::for_version(handshake.protocol_version))
+    }
+
+    /// Where other players send this player to another server, if the version of the client allows it
+    fn switch_recipient(&self, ctx: &mut Context<Self>) -> Option<Recipient<SwitchServer>> {
+        self.world_ids().map(|_| ctx.address().recipient())
+    }
+
+    /// Show lines of text in the chat of the client
+    fn send_chat<S: AsRef<str>>(&mut self, lines: &[S]) {
+        if let Some(ids) = self.play_ids() {
+            for line in lines {
+                let _ = self.connection.send_packet(PacketServerEnum::Raw(ids.chat_packet(line.as_ref())));
+            }
+        }
+    }
+
+    /// The command of a chat message sent by the client, if it is handled by the proxy
+    fn parse_command(&self, packet: &RawPacket) -> Option<(Arc<CommandSettings>, Invocation)> {
+        let commands = self.commands.clone()?;
+        let message = self.play_ids()?.read_chat(packet)?;
+        let invocation = commands.parse(&message)?;
+        Some((commands, invocation))
+    }
+
+    fn run_command(&mut self, commands: &CommandSettings, invocation: Invocation, ctx: &mut Context<Self>) {
+        let name = self.name.clone().unwrap_or_default();
+        if let Some(command) = invocation.command() {
+            if !commands.permits(&name, command) {
+                self.send_chat(&["You are not allowed to use this command"]);
+                return;
+            }
+        }
+
+        match invocation {
+            Invocation::ListServers => {
+                let lines = commands::server_list(&self.config.load(), &self.listener, self.connection_host.as_deref());
+                self.send_chat(&lines);
+            }
+            Invocation::Switch(host) => self.switch_server(host, ctx),
+            Invocation::GlobalList => {
+                let lines = commands::global_list(&self.config.load(), &self.listener);
+                self.send_chat(&lines);
+            }
+            Invocation::Send { player, host } if player == name => self.switch_server(host, ctx),
+            Invocation::Send { player, host } => {
+                let known = self.listener.allows_host(&host) && self.config.load().get_server(&host).is_some();
+                if !known {
+                    self.send_chat(&[format!("Unknown server {}", host)]);
+                    return;
+                }
+
+                let sent = SessionRegistry::from_registry().send(SendPlayer { name: player.clone(), host: host.clone() })
+                    .into_actor(self)
+                    .map(move |sent, manager, _ctx| match sent {
+                        Ok(Ok(())) => manager.send_chat(&[format!("Sending {} to {}", player, host)]),
+                        Ok(Err(SendRefused::Unsupported)) => {
+                            manager.send_chat(&[format!("The version of Minecraft of {} does not support switching servers", player)])
+                        }
+                        _ => manager.send_chat(&[format!("{} is not online", player)]),
+                    });
+                ctx.spawn(sent);
+            }
+            Invocation::Usage(usage) => self.send_chat(&[usage]),
+        }
+    }
+
+    /// Log the player in to the server of another hostname.
+    /// The current upstream is kept until the login succeeded.
+    fn switch_server(&mut self, host: String, ctx: &mut Context<Self>) {
+        match self.state {
+            SessionState::Play(_) => {}
+            SessionState::Switching(..) => {
+                self.send_chat(&["You are already connecting to a server"]);
+                return;
+            }
+            _ => return,
+        }
+        if self.connection_host.as_ref() == Some(&host) {
+            self.send_chat(&[format!("You are already connected to {}", host)]);
+            return;
+        }
+        // The world of the new upstream cannot be shown to clients whose packets are unknown
+        if self.world_ids().is_none() {
+            self.send_chat(&["Switching servers is not supported by your version of Minecraft"]);
+            return;
+        }
+        let (handshake, name) = match (&self.handshake, &self.name) {
+            (Some(handshake), Some(name)) => (handshake.clone(), name.clone()),
+            _ => return,
+        };
+
+        let upstream = self.config.load().get_server(&host)
+            .filter(|_| self.listener.allows_host(&host))
+            .map(|server| server.upstream.clone());
+        let upstream = match upstream {
+            Some(upstream) => upstream,
+            None => {
+                self.send_chat(&[format!("Unknown server {}", host)]);
+                return;
+            }
+        };
+
+        let timeout = ctx.run_later(LOGIN_TIMEOUT, |manager, _ctx| manager.switch_failed());
+        let switch = PendingSwitch {
+            host: host.clone(),
+            upstream: upstream.clone(),
+            connection: None,
+            timeout,
+        };
+        self.state = match mem::replace(&mut self.state, SessionState::Closing) {
+            SessionState::Play(current) => SessionState::Switching(current, switch),
+            state => state,
+        };
+        self.send_chat(&[format!("Connecting to {}", host)]);
+
+        let future = connect_upstream(self.config.clone(), host.clone(), upstream)
+            .into_actor(self)
+            .map(move |stream, manager, ctx| {
+                let stream = match (stream, &manager.state) {
+                    (Ok(stream), SessionState::Switching(_, switch)) if switch.host == host && switch.connection.is_none() => stream,
+                    // The switch was abandoned in the meantime
+                    (Ok(_), _) => return,
+                    (Err(()), _) => return manager.switch_failed(),
+                };
+
+                let mut session = manager.session.clone();
+                session.host = Some(host.clone());
+                let handshake = HandshakePacket { server_address: host, ..handshake };
+                let connection = manager.start_upstream(stream, session, true, handshake, LoginStartPacket { name }, ctx);
+                if let SessionState::Switching(_, switch) = &mut manager.state {
+                    switch.connection = Some(connection);
+                }
+            });
+        ctx.spawn(future);
+    }
+
+    /// Start proxying to a connected upstream by logging the client in to it
+    fn start_upstream(&self, stream: TcpStream, session: SessionInfo, switching: bool,
+                      handshake: HandshakePacket, login: LoginStartPacket, ctx: &mut Context<Self>) -> UpstreamConnection {
+        let downstream = self.connection.relay::<Self>(ctx);
+        let extensions = self.extensions.clone();
+        let mut upstream = None;
+        ProxyServerManager::create(|ctx| {
+            let mut manager = ProxyServerManager::new(downstream, extensions, session, stream, ctx);
+            if switching {
+                manager = manager.switching();
+            }
+            upstream = Some((manager.relay(ctx), manager.pipeline()));
+            manager
+        });
+        let (upstream, upstream_pipeline) = upstream.expect("the upstream actor is created synchronously");
+        // Packets relayed before the connections are linked are bounded as well, e.g. while switching
+        upstream_pipeline.borrow_mut().throttle(self.connection.backpressure());
+
+        // The new relay has room for the login
+        let _ = upstream.send_packet(PacketClientEnum::Handshake(handshake));
+        upstream.send_control(HandlerMessage::SetProtocol(Protocol::Login));
+        let _ = upstream.send_packet(PacketClientEnum::LoginStart(login));
+        (upstream, upstream_pipeline)
+    }
+
+    /// Replace the upstream once the player logged in to the new one.
+    /// What the previous upstream showed is removed and the client is moved to the new world with its Join Game packet.
+    fn complete_switch(&mut self, ctx: &mut Context<Self>) -> Result<(), ()> {
+        let (previous, host, upstream_host, timeout, (upstream, upstream_pipeline)) = match mem::replace(&mut self.state, SessionState::Closing) {
+            SessionState::Switching(previous, PendingSwitch { connection: Some(connection), host, upstream, timeout }) => {
+                (previous, host, upstream, timeout, connection)
+            }
+            state => {
+                self.state = state;
+                return Ok(());
+            }
+        };
+        ctx.cancel_future(timeout);
+
+        previous.send_control(HandlerMessage::Disconnect());
+        self.state = SessionState::Play(upstream);
+        if let Some(ids) = self.world_ids() {
+            for packet in self.overlay.clear(&ids) {
+                self.connection.send_packet(PacketServerEnum::Raw(packet))?;
+            }
+            self.rejoining = true;
+        }
+        self.connection.link_backpressure(&upstream_pipeline);
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.borrow_mut().reset(Instant::now());
+        }
+
+        let previous_host = self.connection_host.replace(host.clone());
+        self.upstream_host = Some(upstream_host);
+        self.session.host = Some(host.clone());
+
+        let name = match &mut self.player {
+            Some(player) => {
+                player.move_to(host.clone());
+                player.name.clone()
+            }
+            None => return Ok(()),
+        };
+
+        SessionRegistry::from_registry().do_send(RegisterSession {
+            name: name.clone(),
+            host: host.clone(),
+            kick: ctx.address().recipient(),
+            switch: self.switch_recipient(ctx),
+            exclusive: false,
+        });
+        if let Some(host) = previous_host {
+            Event::PlayerLeave { host, player: name.clone() }.publish();
+        }
+        Event::PlayerJoin {
+            host,
+            player: name,
+            address: self.address.to_string(),
+        }.publish();
+        Ok(())
+    }
+
+    /// Abandon a switch, leaving the player on their current upstream
+    fn switch_failed(&mut self) {
+        let switch = match mem::replace(&mut self.state, SessionState::Closing) {
+            SessionState::Switching(current, switch) => {
+                self.state = SessionState::Play(current);
+                switch
+            }
+            state => {
+                self.state = state;
+                return;
+            }
+        };
+        if let Some((upstream, _)) = switch.connection {
+            upstream.send_control(HandlerMessage::Disconnect());
+        }
+        self.send_chat(&[format!("Cannot connect to {}", switch.host)]);
+    }
+
+    /// Disconnect the client, showing the reason if the current protocol allows it
+    fn disconnect_with_reason(&mut self, reason: &str, ctx: &mut Context<Self>) {
+        let packet = DisconnectPacket::from_text(reason);
+        let packet = match self.connection.protocol() {
+            Protocol::Login => Some(PacketServerEnum::Disconnect(packet)),
+            Protocol::Play => self.play_ids()
+                .map(|ids| PacketServerEnum::Raw(ids.disconnect_packet(&packet))),
+            _ => None,
+        };
+
+        if let Some(packet) = packet {
+            let _ = self.connection.send_packet(packet);
+        }
+        self.close();
+        self.connection.shutdown(ctx);
+    }
+
+    /// Disconnect the client unless the timeout is replaced or cleared before it elapses
+    fn set_state_timeout(&mut self, duration: Duration, counter: &'static Counter, reason: &'static str, ctx: &mut Context<Self>) {
+        self.clear_state_timeout(ctx);
+        self.state_timeout = Some(ctx.run_later(duration, move |manager, ctx| {
+            counter.inc();
+            manager.disconnect_with_reason(reason, ctx);
+        }));
+    }
+
+    fn clear_state_timeout(&mut self, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.state_timeout.take() {
+            ctx.cancel_future(handle);
+        }
+    }
+
+    /// Start watching the Keep Alive packets of a session that entered the play protocol
+    fn start_watchdog(&mut self, ctx: &mut Context<Self>) {
+        self.clear_state_timeout(ctx);
+
+        let protocol_version = self.handshake.as_ref().map(|handshake| handshake.protocol_version);
+        let watchdog = KeepAliveWatchdog::new(protocol_version.and_then(PlayPacketIds::for_version));
+        self.watchdog = Some(Rc::new(RefCell::new(watchdog)));
+
+        ctx.run_interval(WATCHDOG_INTERVAL, |manager, ctx| {
+            let now = Instant::now();
+            match manager.connection.backpressure().paused_for(now) {
+                Some(paused) if paused > SLOW_CLIENT_TIMEOUT => {
+                    session_timeouts::SLOW_CLIENT.inc();
+                    manager.disconnect_with_reason("Your connection is too slow", ctx);
+                    return;
+                }
+                // Keep Alive packets are stuck in the full buffer, neither side is to blame
+                Some(_) => {
+                    if let Some(watchdog) = &manager.watchdog {
+                        watchdog.borrow_mut().reset(now);
+                    }
+                    return;
+                }
+                None => {}
+            }
+
+            let stuck = manager.watchdog.as_ref().and_then(|watchdog| watchdog.borrow().check(now));
+            match stuck {
+                Some(Stuck::Client) => {
+                    session_timeouts::CLIENT_KEEP_ALIVE.inc();
+                    manager.disconnect_with_reason("Timed out", ctx);
+                }
+                Some(Stuck::Server) => {
+                    session_timeouts::SERVER_KEEP_ALIVE.inc();
+                    manager.disconnect_with_reason("The server stopped responding", ctx);
+                }
+                None => {}
+            }
+        });
+    }
+
+    /// Hand both sockets over to splice loops that copy frames without decoding them.
+    ///
+    /// This is only possible once no packets need to be processed by the actors anymore
+    /// and both legs frame and compress packets alike.
+    fn try_splice(&mut self, ctx: &mut Context<Self>) {
+        let upstream = match self.upstream_pipeline.take() {
+            Some(upstream) => upstream,
+            None => return,
+        };
+        let mut upstream = upstream.borrow_mut();
+
+        // Recorded and intercepted packets have to be decoded, as well as chat messages containing commands
+        let compression = self.connection.compression();
+        if !self.listener.splice || self.connection.is_recording() || self.extensions.intercepts_play()
+            || self.commands.is_some() || compression != upstream.compression() {
+            return;
+        }
+
+        let ((client_reader, client_writer), (server_reader, server_writer)) = match (self.connection.detach(), upstream.detach()) {
+            (Some(client), Some(server)) => (client, server),
+            _ => return,
+        };
+
+        let watchdog = self.watchdog.clone();
+        let upstream_flow = splice(client_reader, server_writer, self.listener.limits.play, move |id| {
+            if let Some(watchdog) = &watchdog {
+                watchdog.borrow_mut().client_packet(id);
+            }
+        });
+
+        let watchdog = self.watchdog.clone();
+        let downstream_flow = splice(server_reader, client_writer, MAX_FRAME_SIZE, move |id| {
+            if let Some(watchdog) = &watchdog {
+                watchdog.borrow_mut().server_packet(id);
+            }
+        });
+
+        // The session ends as soon as either side closes its connection
+        ctx.spawn(upstream_flow.into_actor(self).map(|_, _, ctx| ctx.stop()));
+        ctx.spawn(downstream_flow.into_actor(self).map(|_, _, ctx| ctx.stop()));
+    }
+}
+
+/// Detects stalled sessions in the play protocol by tracking Keep Alive packets in both directions.
+///
+/// If the packet ids of the protocol version are unknown,
+/// any packet is treated as a Keep Alive instead.
+struct KeepAliveWatchdog {
+    ids: Option<PlayPacketIds>,
+
+    /// When the server last sent a Keep Alive
+    last_server_keep_alive: Instant,
+
+    /// When the oldest Keep Alive that the client did not yet answer was forwarded to it
+    unanswered_since: Option<Instant>,
+}
+
+/// The side of a session that stopped sending Keep Alive packets
+enum Stuck {
+    Client,
+    Server,
+}
+
+impl KeepAliveWatchdog {
+    fn new(ids: Option<PlayPacketIds>) -> KeepAliveWatchdog {
+        KeepAliveWatchdog {
+            ids,
+            last_server_keep_alive: Instant::now(),
+            unanswered_since: None,
+        }
+    }
+
+    /// Track a packet sent by the server to the client.
+    /// The id may be unknown if the packet was not decoded.
+    fn server_packet(&mut self, id: Option<u8>) {
+        if self.ids.map_or(true, |ids| id == Some(ids.keep_alive_clientbound)) {
+            let now = Instant::now();
+            self.last_server_keep_alive = now;
+            self.unanswered_since.get_or_insert(now);
+        }
+    }
+
+    /// Track a packet sent by the client to the server.
+    /// The id may be unknown if the packet was not decoded.
+    fn client_packet(&mut self, id: Option<u8>) {
+        if self.ids.map_or(true, |ids| id == Some(ids.keep_alive_serverbound)) {
+            self.unanswered_since = None;
+        }
+    }
+
+    /// Restart both timeouts, e.g. while packets cannot be forwarded
+    fn reset(&mut self, now: Instant) {
+        self.last_server_keep_alive = now;
+        if self.unanswered_since.is_some() {
+            self.unanswered_since = Some(now);
+        }
+    }
+
+    fn check(&self, now: Instant) -> Option<Stuck> {
+        if now.duration_since(self.last_server_keep_alive) > KEEP_ALIVE_TIMEOUT {
+            Some(Stuck::Server)
+        } else if self.unanswered_since.is_some_and(|since| now.duration_since(since) > KEEP_ALIVE_TIMEOUT) {
+            Some(Stuck::Client)
+        } else {
+            None
         }
     }
 }
@@ -51,11 +655,61 @@ impl ConnectionManager<Client> for ProxyClientManager {}
 
 impl Actor for ProxyClientManager {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.set_state_timeout(HANDSHAKE_TIMEOUT, &session_timeouts::HANDSHAKE, "Timed out", ctx);
+    }
+
+    /// Handle a disconnection of a player from the mineroute server.
+    ///
+    /// If the player was connected to an upstream server,
+    /// they should get removed from its player list.
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.close();
+        let (extensions, session) = (&self.extensions, &self.session);
+        if contain_panic(|| extensions.disconnect(session)).is_none() {
+            error!("An extension panicked while the session of {} ended", self.address);
+        }
+
+        // Dropping the entry removes the player from the player list
+        if let Some(player) = self.player.take() {
+            SessionRegistry::from_registry().do_send(UnregisterSession {
+                name: player.name.clone(),
+                kick: ctx.address().recipient(),
+            });
+
+            Event::PlayerLeave {
+                host: player.host.clone(),
+                player: player.name.clone(),
+            }.publish();
+        }
+    }
 }
 
 impl StreamHandler<Result<PacketClientEnum, ()>> for ProxyClientManager {
     /// Handle incoming packets by delegating to the corresponding [[PacketHandler]].
     fn handle(&mut self, packet: Result<PacketClientEnum, ()>, ctx: &mut Self::Context) {
+        self.contained(ctx, |manager, ctx| manager.handle_client_packet(packet, ctx));
+    }
+
+    /// The client closed the connection
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+impl ProxyClientManager {
+    fn handle_client_packet(&mut self, packet: Result<PacketClientEnum, ()>, ctx: &mut Context<Self>) {
+        if let SessionState::Closing = self.state {
+            return;
+        }
+
+        let packet = match packet.map(|packet| self.extensions.client_packet(&self.session, packet)) {
+            Ok(Some(packet)) => Ok(packet),
+            Ok(None) => return,
+            Err(()) => Err(()),
+        };
+
         let handle_result = packet.and_then(|packet| match packet {
             PacketClientEnum::Handshake(packet) => self.handle_packet(packet, ctx),
 
@@ -68,69 +722,168 @@ impl StreamHandler<Result<PacketClientEnum, ()>> for ProxyClientManager {
         });
 
         if let Err(()) = handle_result {
+            self.close();
             self.connection.disconnect();
         }
     }
+}
 
-    /// Handle a disconnection of a player from the mineroute server.
-    ///
-    /// If the player was connected to an upstream server,
-    /// he should get removed from its player list.
-    fn finished(&mut self, _ctx: &mut Self::Context) {
-        if let Some(ref upstream) = *self.upstream.lock().unwrap() {
-            upstream.do_send(HandlerMessage::Disconnect())
+/// Handle packets and connection control messages relayed by a linked [[ProxyServerManager]] actor
+impl StreamHandler<Relayed<Client>> for ProxyClientManager {
+    fn handle(&mut self, relayed: Relayed<Client>, ctx: &mut Self::Context) {
+        self.contained(ctx, |manager, ctx| match relayed {
+            Relayed::Packet(packet) => manager.forward_packet(packet),
+            Relayed::Control(message) => manager.handle_message(message, ctx),
+        });
+    }
+
+    /// An upstream stopped relaying, e.g. after a switch.
+    /// The session only ends if it was told to disconnect beforehand.
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+impl ProxyClientManager {
+    /// Send a packet of the upstream to the client
+    fn forward_packet(&mut self, packet: PacketServerEnum) -> Result<(), ()> {
+        if let (PacketServerEnum::Raw(packet), Some(watchdog)) = (&packet, &self.watchdog) {
+            watchdog.borrow_mut().server_packet(Some(packet.id));
         }
 
-        if let Some(ref upstream_host) = self.connection_host {
-            if let Some(ref name) = self.name {
-                let mut config = self.config.write().unwrap();
-                if let Some(server) = config.get_server_mut(upstream_host) {
-                    server.remove_player(name);
+        if let (PacketServerEnum::Raw(packet), Some(ids)) = (&packet, self.world_ids()) {
+            self.overlay.track(&ids, packet);
+            if self.rejoining {
+                if let Some(join) = ids.read_join_game(packet) {
+                    self.rejoining = false;
+                    for packet in ids.rejoin_packets(packet.clone(), &join) {
+                        self.connection.send_packet(PacketServerEnum::Raw(packet))?;
+                    }
+                    return Ok(());
                 }
             }
         }
+
+        // A listener with its own threshold compresses towards clients even if the upstream does not compress.
+        // Compression has to be enabled before the login succeeds.
+        if let PacketServerEnum::LoginSuccess(_) = &packet {
+            if let (None, Some(size_limit)) = (self.connection.compression(), self.listener.client_compression(None)) {
+                self.connection.send_packet(PacketServerEnum::Compression(CompressionPacket { size_limit: Some(size_limit) }))?;
+                self.connection.enable_compression(Some(size_limit), self.listener.compression_level);
+            }
+        }
+        self.connection.send_packet(packet)
     }
-}
 
-/// Handle connection control messages that this actor may
-/// receive from a linked [[ProxyServerManager]] actor
-impl Handler<HandlerMessage<Client>> for ProxyClientManager {
-    type Result = Result<(), ()>;
-    fn handle(&mut self, message: HandlerMessage<Client>, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle_message(&mut self, message: HandlerMessage, ctx: &mut Context<Self>) -> Result<(), ()> {
         match message {
-            HandlerMessage::SendPacket(packet) => {
-                self.connection.send_packet(packet)
-            },
+            // The upstream accepted the login
+            HandlerMessage::SetProtocol(Protocol::Play) => {
+                match mem::replace(&mut self.state, SessionState::Closing) {
+                    SessionState::LoggingIn(upstream) => {
+                        // Packets sent by the client before it saw the login succeed follow the login,
+                        // in the order they were received
+                        let queued = !self.queue.is_empty();
+                        for packet in self.queue.drain(..) {
+                            upstream.send_packet(PacketClientEnum::Raw(packet))?;
+                        }
+                        self.state = SessionState::Play(upstream);
+                        self.connection.set_protocol(Protocol::Play);
+                        self.start_watchdog(ctx);
+                        // The upstream has yet to write the queued packets, which splicing would overtake
+                        if !queued {
+                            self.try_splice(ctx);
+                        }
+                    }
+                    state => self.state = state,
+                }
+                Ok(())
+            }
             HandlerMessage::SetProtocol(protocol) => {
                 self.connection.set_protocol(protocol);
                 Ok(())
             },
-            HandlerMessage::EnableCompression(size_limit) => {
-                self.connection.enable_compression(size_limit);
+            HandlerMessage::EnableCompression(upstream_size_limit) => {
+                // The compression towards the client may differ from the one of the upstream.
+                // Packets are then compressed again when being forwarded.
+                let size_limit = self.listener.client_compression(upstream_size_limit);
+                if size_limit.is_some() {
+                    self.connection.send_packet(PacketServerEnum::Compression(CompressionPacket { size_limit }))?;
+                }
+                self.connection.enable_compression(size_limit, self.listener.compression_level);
                 Ok(())
             }
             HandlerMessage::Disconnect() => {
-                self.connection.disconnect();
+                self.close();
+                self.connection.shutdown(ctx);
+                Ok(())
+            }
+            HandlerMessage::SwitchCompleted() => self.complete_switch(ctx),
+            HandlerMessage::SwitchFailed() => {
+                self.switch_failed();
                 Ok(())
             }
         }
     }
 }
 
+/// Handle a kick issued through the admin api
+impl Handler<Kick> for ProxyClientManager {
+    type Result = ();
+    fn handle(&mut self, Kick(reason): Kick, ctx: &mut Self::Context) {
+        self.contained(ctx, |manager, ctx| manager.disconnect_with_reason(&reason, ctx));
+    }
+}
+
+/// Handle a switch issued by another player through a command
+impl Handler<SwitchServer> for ProxyClientManager {
+    type Result = ();
+    fn handle(&mut self, SwitchServer(host): SwitchServer, ctx: &mut Self::Context) {
+        self.contained(ctx, |manager, ctx| manager.switch_server(host, ctx));
+    }
+}
+
 impl WriteHandler<()> for ProxyClientManager {}
 
 // Handle the initial handshake packet by determining
 // the upstream server requested by the client.
 impl PacketHandler<Client, HandshakePacket> for ProxyClientManager {
-    fn handle_packet(&mut self, packet: HandshakePacket, _ctx: &mut Self::Context) -> Result<(), ()> {
+    fn handle_packet(&mut self, mut packet: HandshakePacket, ctx: &mut Self::Context) -> Result<(), ()> {
+        if !matches!(self.state, SessionState::AwaitingHandshake) {
+            return Err(());
+        }
+
+        self.session.protocol_version = Some(packet.protocol_version);
+        if let Verdict::Deny(_) = self.extensions.handshake(&self.session, &mut packet) {
+            // There is no way to show a reason before the next protocol was entered
+            self.connection.disconnect();
+            return Err(());
+        }
+
         if let Protocol::Status | Protocol::Login = packet.next_protocol {
-            let address = packet.server_address.clone();
-            let config = self.config.write().unwrap();
+            let address = packet.server_address.to_ascii_lowercase();
+            let config = self.config.load();
 
-            if let Some(upstream) = config.get_server(&address) {
+            // Servers that are not exposed by the listener are treated as unknown
+            let upstream = config.get_server(&address)
+                .filter(|_| self.listener.allows_host(&address));
+
+            if let Some(upstream) = upstream {
                 self.connection.set_protocol(packet.next_protocol.clone());
+                self.session.host = Some(address.clone());
                 self.connection_host = Some(address);
                 self.upstream_host = Some(upstream.upstream.clone());
+                drop(config);
+
+                match packet.next_protocol {
+                    Protocol::Status => {
+                        self.state = SessionState::Status;
+                        self.set_state_timeout(STATUS_TIMEOUT, &session_timeouts::STATUS, "Timed out", ctx);
+                    }
+                    _ => {
+                        self.state = SessionState::AwaitingLogin;
+                        self.set_state_timeout(LOGIN_TIMEOUT, &session_timeouts::LOGIN, "Timed out while logging in", ctx);
+                    }
+                }
+
                 self.handshake = Some(packet);
                 return Ok(())
             }
@@ -145,13 +898,25 @@ impl PacketHandler<Client, HandshakePacket> for ProxyClientManager {
 /// asking it for its status and forwarding that response to the client
 impl PacketHandler<Client, StatusRequestPacket> for ProxyClientManager {
     fn handle_packet(&mut self, _packet: StatusRequestPacket, ctx: &mut Self::Context) -> Result<(), ()> {
-        let upstream_addr = self.upstream_host.unwrap();
+        let (host, upstream) = match (&self.state, &self.connection_host, &self.upstream_host) {
+            (SessionState::Status, Some(host), Some(upstream)) => (host.clone(), upstream.clone()),
+            _ => return Err(()),
+        };
+        let config = self.config.clone();
 
-        let server_info = StatusServerManager::fetch_status(upstream_addr)
+        let server_info = async move {
+            let address = resolve_upstream(config, host, upstream).await?;
+            StatusServerManager::fetch_status(address).await
+        }
             .into_actor(self)
             .map(|server_info, manager, ctx| {
                 match server_info {
-                    Ok(status) => manager.connection.send_packet(PacketServerEnum::StatusResponse(StatusResponsePacket { status })).unwrap(),
+                    Ok(mut status) => {
+                        manager.extensions.rewrite_status(&manager.session, &mut status);
+                        if manager.connection.send_packet(PacketServerEnum::StatusResponse(StatusResponsePacket { status })).is_err() {
+                            ctx.stop();
+                        }
+                    }
                     _ => ctx.stop(),
                 }
             });
@@ -164,6 +929,10 @@ impl PacketHandler<Client, StatusRequestPacket> for ProxyClientManager {
 /// Immediately respond to incoming ping packets with a PongPacket
 impl PacketHandler<Client, PingPacket> for ProxyClientManager {
     fn handle_packet(&mut self, packet: PingPacket, _ctx: &mut Self::Context) -> Result<(), ()> {
+        if !matches!(self.state, SessionState::Status) {
+            return Err(());
+        }
+
         let packet = PacketServerEnum::Pong(PongPacket {
             payload: packet.payload,
         });
@@ -174,49 +943,154 @@ impl PacketHandler<Client, PingPacket> for ProxyClientManager {
 
 impl PacketHandler<Client, LoginStartPacket> for ProxyClientManager {
     fn handle_packet(&mut self, packet: LoginStartPacket, ctx: &mut Self::Context) -> Result<(), ()> {
-        let handshake = self.handshake.clone().unwrap();
-        let downstream = ctx.address().clone();
-        let self_upstream = self.upstream.clone();
+        let handshake = match (&self.state, &self.handshake) {
+            (SessionState::AwaitingLogin, Some(handshake)) => handshake.clone(),
+            _ => return Err(()),
+        };
 
         let name = packet.name.clone();
-        self.name = Some(name.clone());
+        self.session.player = Some(name.clone());
 
-        let mut config = self.config.write().unwrap();
-        let server = config.get_server_mut(self.connection_host.as_ref().unwrap()).unwrap();
-        server.add_player(name);
-        drop(config); // config lock is no longer required
+        let config = self.config.load();
+        if let Some(reason) = config.get_ban(&name) {
+            let packet = DisconnectPacket::from_text(&format!("You are banned: {}", reason));
+            self.connection.send_packet(PacketServerEnum::Disconnect(packet))?;
+            self.connection.disconnect();
+            return Ok(());
+        }
 
-        let upstream = self.upstream_host.clone().unwrap();
-        let future = async move {
-            let stream = TcpStream::connect(upstream).await.map_err(|_| ())?;
+        if let Verdict::Deny(reason) = self.extensions.login(&self.session) {
+            self.connection.send_packet(PacketServerEnum::Disconnect(DisconnectPacket::from_text(&reason)))?;
+            self.connection.disconnect();
+            return Ok(());
+        }
 
-            let upstream = ProxyServerManager::create(|ctx| {
-                ProxyServerManager::new(downstream, stream, ctx)
-            });
+        // Extensions may route the player to another server than the one of the handshake
+        let selected = self.extensions.select_server(&self.session)
+            .map(|host| host.to_ascii_lowercase())
+            .filter(|host| self.listener.allows_host(host));
+        if let Some(host) = selected {
+            match config.get_server(&host) {
+                Some(server) => {
+                    self.upstream_host = Some(server.upstream.clone());
+                    self.session.host = Some(host.clone());
+                    self.connection_host = Some(host);
+                }
+                None => warn!("Cannot route '{}' to the unknown server '{}'", name, host),
+            }
+        }
+        let (host, upstream) = match (&self.connection_host, &self.upstream_host) {
+            (Some(host), Some(upstream)) if config.get_server(host).is_some() => (host.clone(), upstream.clone()),
+            _ => return Err(()),
+        };
 
-            upstream.send(HandlerMessage::SendPacket(PacketClientEnum::Handshake(handshake))).await.unwrap_or_else(|_| Err(()))?;
-            upstream.send(HandlerMessage::SetProtocol(Protocol::Login)).await.unwrap_or_else(|_| Err(()))?;
-            upstream.send(HandlerMessage::SendPacket(PacketClientEnum::LoginStart(packet))).await.unwrap_or_else(|_| Err(()))?;
+        let header = CaptureHeader {
+            started: unix_millis(),
+            protocol_version: handshake.protocol_version,
+            player: name.clone(),
+            host: host.clone(),
+            address: self.address.to_string(),
+        };
+        match config.captures().open(header) {
+            Some(Ok(recorder)) => self.connection.set_recorder(recorder, Direction::Serverbound),
+            Some(Err(error)) => warn!("Cannot capture the session of '{}': {}", name, error),
+            None => {}
+        }
 
-            Ok(upstream)
-        }.into_actor(self).map(move |upstream_result: Result<Addr<ProxyServerManager<ProxyClientManager>>, ()>, actor, _ctx| {
-            match upstream_result {
-                Ok(upstream) => *self_upstream.lock().unwrap() = Some(upstream),
-                Err(_) => actor.connection.disconnect(),
+        self.name = Some(name.clone());
+        drop(config); // config lock is no longer required
+        self.player = Some(PlayerEntry::add(self.config.clone(), name.clone(), host.clone()));
+
+        SessionRegistry::from_registry().do_send(RegisterSession {
+            name: name.clone(),
+            host: host.clone(),
+            kick: ctx.address().recipient(),
+            switch: self.switch_recipient(ctx),
+            exclusive: false,
+        });
+        Event::PlayerJoin {
+            host: host.clone(),
+            player: name,
+            address: self.address.to_string(),
+        }.publish();
+
+        // Packets of the client are queued until the upstream accepted the login
+        self.state = SessionState::Connecting;
+        let future = connect_upstream(self.config.clone(), host, upstream).into_actor(self).map(move |stream, manager, ctx| {
+            match (stream, &manager.state) {
+                (Ok(stream), SessionState::Connecting) => {
+                    let (upstream, upstream_pipeline) = manager.start_upstream(stream, manager.session.clone(), false, handshake, packet, ctx);
+                    manager.connection.link_backpressure(&upstream_pipeline);
+                    manager.upstream_pipeline = Some(upstream_pipeline);
+                    manager.state = SessionState::LoggingIn(upstream);
+                }
+                // The client left while connecting
+                (Ok(_), _) => {}
+                (Err(()), _) => {
+                    manager.close();
+                    manager.connection.disconnect();
+                }
             }
         });
-        ctx.wait(future);
+        ctx.spawn(future);
         Ok(())
     }
 }
 
+/// Connect to the upstream of a server, reporting failures through the health of the server
+async fn connect_upstream(config: Arc<SharedConfiguration>, host: String, upstream: Upstream) -> Result<TcpStream, ()> {
+    let address = resolve_upstream(config.clone(), host.clone(), upstream).await?;
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => {
+            update_health(&config, &host, Health::Healthy, None);
+            Ok(stream)
+        }
+        Ok(Err(error)) => {
+            update_health(&config, &host, Health::Unhealthy, Some(error.to_string()));
+            Err(())
+        }
+        Err(_) => {
+            update_health(&config, &host, Health::Unhealthy, Some(format!("{} timed out", address)));
+            Err(())
+        }
+    }
+}
+
+/// Resolve the upstream of a server, reporting failures through the health of the server
+async fn resolve_upstream(config: Arc<SharedConfiguration>, host: String, upstream: Upstream) -> Result<SocketAddr, ()> {
+    let resolver = config.load().resolver();
+    resolver.resolve(&upstream).await.map_err(|error| {
+        update_health(&config, &host, Health::Unresolvable, Some(error.to_string()));
+    })
+}
+
 impl PacketHandler<Client, RawPacket> for ProxyClientManager {
     fn handle_packet(&mut self, packet: RawPacket, ctx: &mut Self::Context) -> Result<(), ()> {
-        let upstream = self.upstream.lock().unwrap();
-        let upstream = upstream.as_ref().unwrap();
-        upstream.send(HandlerMessage::SendPacket(PacketClientEnum::Raw(packet)))
-            .map(|_| ()).into_actor(self).wait(ctx);
-        Ok(())
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.borrow_mut().client_packet(Some(packet.id));
+        }
+
+        // Commands of the proxy are not forwarded
+        if let Some((commands, invocation)) = self.parse_command(&packet) {
+            self.run_command(&commands, invocation, ctx);
+            return Ok(());
+        }
+
+        match &self.state {
+            SessionState::Connecting | SessionState::LoggingIn(_) if self.queue.len() >= MAX_QUEUED_PACKETS => {
+                QUEUE_OVERFLOWS.inc();
+                Err(())
+            }
+            SessionState::Connecting | SessionState::LoggingIn(_) => {
+                self.queue.push_back(packet);
+                Ok(())
+            }
+            state => {
+                // Packets are only forwarded once the login started
+                let upstream = state.upstream().ok_or(())?;
+                upstream.send_packet(PacketClientEnum::Raw(packet))
+            }
+        }
     }
 }
 
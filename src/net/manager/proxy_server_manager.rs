@@ -1,52 +1,161 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
 use actix::prelude::*;
 use actix::io::WriteHandler;
 use tokio::net::TcpStream;
-use futures::FutureExt;
+use log::error;
 use crate::net::{Connection, PacketServerEnum, Protocol, Server, Client};
-use crate::net::manager::{HandlerMessage, PacketHandler, ConnectionManager};
+use crate::net::manager::{HandlerMessage, PacketHandler, ConnectionManager, Peer, Relayed, contain_panic};
 use crate::net::login::{CompressionPacket, LoginSuccessPacket};
+use crate::net::pipeline::HandlerPipeline;
+use crate::settings::CompressionLevel;
+use crate::extensions::{Extensions, SessionInfo};
 
 /// Manage a connection to a remote server in which we act as client.
 /// The received packets are proxied to some other client.
-pub struct ProxyServerManager<C: ConnectionManager<Client>> {
+pub struct ProxyServerManager {
     /// proxy packets received from the server to this client
-    downstream: Addr<C>,
+    downstream: Peer<Client>,
 
     /// The connection to the remote upstream server
     connection: Connection<Server>,
+
+    /// Inspect packets before they are proxied
+    extensions: Arc<Extensions>,
+
+    /// The session of the client as it was when connecting to the upstream
+    session: SessionInfo,
+
+    /// The client already plays on another upstream, so the login is not forwarded to it
+    switching: bool,
+
+    /// The client no longer expects packets of this connection
+    closing: bool,
 }
 
-impl<C: ConnectionManager<Client>> ProxyServerManager<C> {
-    pub fn new(downstream: Addr<C>, stream: TcpStream, ctx: &mut Context<Self>) -> ProxyServerManager<C> {
+impl ProxyServerManager {
+    pub fn new(downstream: Peer<Client>, extensions: Arc<Extensions>, session: SessionInfo,
+               stream: TcpStream, ctx: &mut Context<Self>) -> ProxyServerManager {
         ProxyServerManager {
             downstream,
             connection: Connection::new::<Self>(stream, ctx),
+            extensions,
+            session,
+            switching: false,
+            closing: false,
         }
     }
+
+    /// Log in without forwarding the login to the client, which is already playing on another upstream.
+    /// The client is told once the login succeeded or failed.
+    pub fn switching(mut self) -> ProxyServerManager {
+        self.switching = true;
+        self
+    }
+
+    /// Handle a packet received while logging in for a client that is switching to this upstream
+    fn handle_switching(&mut self, packet: PacketServerEnum, ctx: &mut Context<Self>) -> Result<(), ()> {
+        match packet {
+            PacketServerEnum::Compression(packet) => {
+                self.connection.enable_compression(packet.size_limit, CompressionLevel::Default);
+            }
+            PacketServerEnum::LoginSuccess(_) => {
+                self.switching = false;
+                self.connection.set_protocol(Protocol::Play);
+                self.downstream.send_control(HandlerMessage::SwitchCompleted());
+            }
+            PacketServerEnum::Disconnect(_) => {
+                self.closing = true;
+                self.downstream.send_control(HandlerMessage::SwitchFailed());
+                self.connection.shutdown(ctx);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handle a packet or message of the upstream, closing the connection if the handling panics.
+    /// The client is told as if the upstream had closed the connection.
+    fn contained<R>(&mut self, ctx: &mut Context<Self>, handle: impl FnOnce(&mut Self, &mut Context<Self>) -> R) -> Option<R> {
+        let result = contain_panic(|| handle(self, ctx));
+        if result.is_none() {
+            error!("Closing the upstream connection of {} after a panic", self.session.address);
+            if !self.closing {
+                self.closing = true;
+                self.downstream.send_control(match self.switching {
+                    true => HandlerMessage::SwitchFailed(),
+                    false => HandlerMessage::Disconnect(),
+                });
+            }
+            self.connection.disconnect();
+            ctx.stop();
+        }
+        result
+    }
+
+    /// The pipeline of the connection to the server
+    pub fn pipeline(&self) -> Rc<RefCell<HandlerPipeline<Server>>> {
+        self.connection.pipeline()
+    }
+
+    /// Open the relay through which the client passes packets on to the server
+    pub fn relay(&self, ctx: &mut Context<Self>) -> Peer<Server> {
+        self.connection.relay::<Self>(ctx)
+    }
 }
 
-impl<C: ConnectionManager<Client>> ConnectionManager<Server> for ProxyServerManager<C> {}
+impl ConnectionManager<Server> for ProxyServerManager {}
 
-impl<C: ConnectionManager<Client>> Actor for ProxyServerManager<C> {
+impl Actor for ProxyServerManager {
     type Context = Context<Self>;
 }
 
-impl<C: ConnectionManager<Client>> StreamHandler<Result<PacketServerEnum, ()>> for ProxyServerManager<C> {
+impl StreamHandler<Result<PacketServerEnum, ()>> for ProxyServerManager {
     /// Handle incoming packets by delegating to the corresponding [[PacketHandler]].
     fn handle(&mut self, packet: Result<PacketServerEnum, ()>, ctx: &mut Self::Context) {
+        self.contained(ctx, |manager, ctx| manager.handle_server_packet(packet, ctx));
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        if self.switching && !self.closing {
+            self.downstream.send_control(HandlerMessage::SwitchFailed());
+        } else if !self.closing {
+            self.downstream.send_control(HandlerMessage::Disconnect());
+        }
+        ctx.stop();
+    }
+}
+
+impl ProxyServerManager {
+    fn handle_server_packet(&mut self, packet: Result<PacketServerEnum, ()>, ctx: &mut Context<Self>) {
         let handle_result = packet.and_then(|packet| {
-            self.downstream.send(HandlerMessage::SendPacket(packet.clone()))
-                .map(|_| ()).into_actor(self).wait(ctx);
+            if self.closing {
+                return Ok(());
+            }
+            if self.switching {
+                return self.handle_switching(packet, ctx);
+            }
 
-            match packet {
-                PacketServerEnum::StatusResponse(_) => Ok(()),
-                PacketServerEnum::Pong(_) => Ok(()),
+            // The client leg negotiates its compression on its own
+            if let PacketServerEnum::Compression(packet) = packet {
+                return self.handle_packet(packet, ctx);
+            }
 
-                PacketServerEnum::Disconnect(_) => Ok(()),
-                PacketServerEnum::Compression(packet) => self.handle_packet(packet, ctx),
-                PacketServerEnum::LoginSuccess(packet) => self.handle_packet(packet, ctx),
+            // Dropped packets are still handled, e.g. to follow the protocol state.
+            // Only the rare packets handled here are cloned, play packets are passed on as they are.
+            let login = match &packet {
+                PacketServerEnum::LoginSuccess(packet) => Some(packet.clone()),
+                _ => None,
+            };
+
+            if let Some(forwarded) = self.extensions.server_packet(&self.session, packet) {
+                self.downstream.send_packet(forwarded)?;
+            }
 
-                PacketServerEnum::Raw(_) => Ok(()),
+            match login {
+                Some(packet) => self.handle_packet(packet, ctx),
+                None => Ok(()),
             }
         });
 
@@ -54,54 +163,58 @@ impl<C: ConnectionManager<Client>> StreamHandler<Result<PacketServerEnum, ()>> f
             self.connection.disconnect();
         }
     }
+}
 
-    fn finished(&mut self, _ctx: &mut Self::Context) {
-        self.downstream.do_send(HandlerMessage::Disconnect());
+/// Handle packets and connection control messages relayed by the linked [[ProxyClientManager]] actor
+impl StreamHandler<Relayed<Server>> for ProxyServerManager {
+    fn handle(&mut self, relayed: Relayed<Server>, ctx: &mut Self::Context) {
+        self.contained(ctx, |manager, ctx| match relayed {
+            Relayed::Packet(packet) => manager.connection.send_packet(packet),
+            Relayed::Control(message) => manager.handle_message(message, ctx),
+        });
     }
+
+    /// The client no longer relays anything once its session ended, after telling this actor to disconnect
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }
 
-/// Handle connection control messages that this actor may
-/// receive from a linked [[ProxyClientManager]] actor
-impl<C: ConnectionManager<Client>> Handler<HandlerMessage<Server>> for ProxyServerManager<C> {
-    type Result = Result<(), ()>;
-    fn handle(&mut self, message: HandlerMessage<Server>, _ctx: &mut Self::Context) -> Self::Result {
+impl ProxyServerManager {
+    fn handle_message(&mut self, message: HandlerMessage, ctx: &mut Context<Self>) -> Result<(), ()> {
         match message {
-            HandlerMessage::SendPacket(packet) => {
-                self.connection.send_packet(packet)
-            },
             HandlerMessage::SetProtocol(protocol) => {
                 self.connection.set_protocol(protocol);
                 Ok(())
             },
             HandlerMessage::EnableCompression(size_limit) => {
-                self.connection.enable_compression(size_limit);
+                self.connection.enable_compression(size_limit, CompressionLevel::Default);
                 Ok(())
             },
             HandlerMessage::Disconnect() => {
-                self.connection.disconnect();
+                // A stalled server may never close its side of the connection
+                self.closing = true;
+                self.connection.shutdown(ctx);
                 Ok(())
             }
+            HandlerMessage::SwitchCompleted() | HandlerMessage::SwitchFailed() => Ok(()),
         }
     }
 }
 
-impl<C: ConnectionManager<Client>> WriteHandler<()> for ProxyServerManager<C> {}
+impl WriteHandler<()> for ProxyServerManager {}
 
-impl<C: ConnectionManager<Client>> PacketHandler<Server, CompressionPacket> for ProxyServerManager<C> {
-    fn handle_packet(&mut self, packet: CompressionPacket, ctx: &mut Self::Context) -> Result<(), ()> {
-        self.connection.enable_compression(packet.size_limit);
-        self.downstream.send(HandlerMessage::EnableCompression(packet.size_limit))
-            .map(|_| ()).into_actor(self).wait(ctx);
+impl PacketHandler<Server, CompressionPacket> for ProxyServerManager {
+    fn handle_packet(&mut self, packet: CompressionPacket, _ctx: &mut Self::Context) -> Result<(), ()> {
+        self.connection.enable_compression(packet.size_limit, CompressionLevel::Default);
+        self.downstream.send_control(HandlerMessage::EnableCompression(packet.size_limit));
 
         Ok(())
     }
 }
 
-impl<C: ConnectionManager<Client>> PacketHandler<Server, LoginSuccessPacket> for ProxyServerManager<C> {
-    fn handle_packet(&mut self, _packet: LoginSuccessPacket, ctx: &mut Self::Context) -> Result<(), ()> {
+impl PacketHandler<Server, LoginSuccessPacket> for ProxyServerManager {
+    fn handle_packet(&mut self, _packet: LoginSuccessPacket, _ctx: &mut Self::Context) -> Result<(), ()> {
         self.connection.set_protocol(Protocol::Play);
-        self.downstream.send(HandlerMessage::SetProtocol(Protocol::Play))
-            .map(|_| ()).into_actor(self).wait(ctx);
+        self.downstream.send_control(HandlerMessage::SetProtocol(Protocol::Play));
 
         Ok(())
     }
@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::task::{Context, Poll};
+use futures::Stream;
+use futures::stream::FusedStream;
+use crate::net::ConnectionType;
+use crate::net::manager::HandlerMessage;
+use crate::net::pipeline::backpressure::{Backpressure, RELAY_CAPACITY};
+
+/// What one leg of a proxied session passes on to the other leg
+pub enum Relayed<CT: ConnectionType> {
+    Packet(CT::Out),
+    Control(HandlerMessage),
+}
+
+/// The other leg of a proxied session, which packets and control messages are passed on to.
+///
+/// Both go through the same channel, so that the peer handles them in the order they were sent.
+/// The peer drains the channel in its own context, without answering every packet.
+/// Packets waiting in the channel count towards the backpressure of the peer,
+/// so a linked connection stops reading once [RELAY_CAPACITY] of them were not yet handled.
+/// The channel is bounded by the same capacity.
+pub struct Peer<CT: ConnectionType> {
+    sender: RefCell<Sender<Relayed<CT>>>,
+    backpressure: Rc<Backpressure>,
+}
+
+impl<CT: ConnectionType> Peer<CT> {
+    /// Pass a packet on to be sent by the peer.
+    /// It is dropped if the peer already stopped.
+    ///
+    /// Fails if the channel is full, which means that the connection the packet was read from
+    /// did not stop reading although the peer fell behind.
+    pub fn send_packet(&self, packet: CT::Out) -> Result<(), ()> {
+        match self.sender.borrow_mut().try_send(Relayed::Packet(packet)) {
+            Ok(()) => {
+                self.backpressure.relay();
+                Ok(())
+            }
+            Err(error) if error.is_full() => Err(()),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Pass a control message on, to be handled after all packets relayed before it.
+    ///
+    /// Control messages are never refused because the channel is full:
+    /// every sender may exceed the capacity by one message, so each one is sent by a sender of its own.
+    pub fn send_control(&self, message: HandlerMessage) {
+        let mut sender = self.sender.borrow().clone();
+        if sender.try_send(Relayed::Control(message)).is_ok() {
+            self.backpressure.relay();
+        }
+    }
+}
+
+/// The stream of everything relayed to a connection, handled by its actor
+pub struct RelayReceiver<CT: ConnectionType> {
+    receiver: Receiver<Relayed<CT>>,
+    backpressure: Rc<Backpressure>,
+}
+
+/// Open a relay to a connection with the given backpressure
+pub fn channel<CT: ConnectionType>(backpressure: Rc<Backpressure>) -> (Peer<CT>, RelayReceiver<CT>) {
+    let (sender, receiver) = mpsc::channel(RELAY_CAPACITY);
+    let peer = Peer {
+        sender: RefCell::new(sender),
+        backpressure: backpressure.clone(),
+    };
+    (peer, RelayReceiver { receiver, backpressure })
+}
+
+impl<CT: ConnectionType> Stream for RelayReceiver<CT> {
+    type Item = Relayed<CT>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let relayed = Pin::new(&mut self.receiver).poll_next(cx);
+        if let Poll::Ready(Some(_)) = relayed {
+            self.backpressure.handled();
+        }
+        relayed
+    }
+}
+
+impl<CT: ConnectionType> Drop for RelayReceiver<CT> {
+    /// Packets that are never handled no longer hold back the peer
+    fn drop(&mut self) {
+        if self.receiver.is_terminated() {
+            return;
+        }
+        self.receiver.close();
+        while let Ok(Some(_)) = self.receiver.try_next() {
+            self.backpressure.handled();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker;
+    use crate::net::{Client, PacketServerEnum, Protocol};
+    use crate::net::play::RawPacket;
+    use crate::metrics::write_buffers;
+
+    fn packet(id: u8) -> PacketServerEnum {
+        PacketServerEnum::Raw(RawPacket { id, data: Default::default(), compressed: None })
+    }
+
+    fn next(receiver: &mut RelayReceiver<Client>) -> Option<Relayed<Client>> {
+        let waker = noop_waker();
+        match Pin::new(receiver).poll_next(&mut Context::from_waker(&waker)) {
+            Poll::Ready(relayed) => relayed,
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn control_messages_keep_their_order() {
+        let (peer, mut receiver) = channel::<Client>(Rc::new(Backpressure::new(&write_buffers::CLIENT)));
+        peer.send_packet(packet(1)).unwrap();
+        peer.send_control(HandlerMessage::SetProtocol(Protocol::Play));
+        peer.send_packet(packet(2)).unwrap();
+
+        assert!(matches!(next(&mut receiver), Some(Relayed::Packet(PacketServerEnum::Raw(RawPacket { id: 1, .. })))));
+        assert!(matches!(next(&mut receiver), Some(Relayed::Control(HandlerMessage::SetProtocol(Protocol::Play)))));
+        assert!(matches!(next(&mut receiver), Some(Relayed::Packet(PacketServerEnum::Raw(RawPacket { id: 2, .. })))));
+        assert!(next(&mut receiver).is_none());
+    }
+
+    #[test]
+    fn unhandled_packets_pause_the_peer() {
+        let backpressure = Rc::new(Backpressure::new(&write_buffers::CLIENT));
+        let (peer, mut receiver) = channel::<Client>(backpressure.clone());
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        for id in 0..RELAY_CAPACITY {
+            assert!(backpressure.poll_ready(&mut cx).is_ready());
+            peer.send_packet(packet(id as u8)).unwrap();
+        }
+        assert!(backpressure.poll_ready(&mut cx).is_pending());
+
+        for _ in 0..RELAY_CAPACITY / 2 {
+            next(&mut receiver).unwrap();
+        }
+        assert!(backpressure.poll_ready(&mut cx).is_ready());
+
+        // Packets dropped with the receiver are not waited for
+        for id in 0..RELAY_CAPACITY / 2 {
+            peer.send_packet(packet(id as u8)).unwrap();
+        }
+        drop(receiver);
+        assert!(backpressure.poll_ready(&mut cx).is_ready());
+        assert!(peer.send_packet(packet(0)).is_ok());
+        assert!(backpressure.poll_ready(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn full_relays_refuse_packets_but_not_control_messages() {
+        let (peer, mut receiver) = channel::<Client>(Rc::new(Backpressure::new(&write_buffers::CLIENT)));
+        let mut sent = 0;
+        while peer.send_packet(packet(1)).is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, RELAY_CAPACITY + 1);
+        peer.send_control(HandlerMessage::Disconnect());
+
+        for _ in 0..sent {
+            assert!(matches!(next(&mut receiver), Some(Relayed::Packet(_))));
+        }
+        assert!(matches!(next(&mut receiver), Some(Relayed::Control(HandlerMessage::Disconnect()))));
+        assert!(next(&mut receiver).is_none());
+        assert!(peer.send_packet(packet(2)).is_ok());
+    }
+}
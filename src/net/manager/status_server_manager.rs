@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 use actix::prelude::*;
 use actix::io::WriteHandler;
 use tokio::net::TcpStream;
@@ -7,7 +8,12 @@ use crate::net::{Connection, PacketServerEnum, PacketClientEnum, Protocol, Serve
 use crate::net::status::{StatusResponsePacket, StatusRequestPacket, server_status};
 use crate::net::status::server_status::ServerInfo;
 use crate::net::handshake::HandshakePacket;
-use crate::net::manager::{HandlerMessage, PacketHandler, ConnectionManager};
+use crate::net::manager::{HandlerMessage, PacketHandler, ConnectionManager, Relayed};
+use crate::metrics::session_timeouts;
+use crate::settings::CompressionLevel;
+
+/// How long the server may take to answer the status request
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Manage a connection to a remote server where we act as a client.
 ///
@@ -39,7 +45,15 @@ impl ConnectionManager<Server> for StatusServerManager {}
 impl Actor for StatusServerManager {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_later(STATUS_TIMEOUT, |manager, ctx| {
+            session_timeouts::UPSTREAM_STATUS.inc();
+            if let Some(sender) = manager.channel.take() {
+                let _ = sender.send(Err(()));
+            }
+            manager.connection.shutdown(ctx);
+        });
+
         let handshake = PacketClientEnum::Handshake(HandshakePacket {
             protocol_version: 57,
             server_address: "127.0.0.1".to_string(),
@@ -73,26 +87,23 @@ impl StreamHandler<Result<PacketServerEnum, ()>> for StatusServerManager {
     }
 }
 
-/// Handle connection control messages send to this actor
-impl Handler<HandlerMessage<Server>> for StatusServerManager {
-    type Result = Result<(), ()>;
-    fn handle(&mut self, message: HandlerMessage<Server>, _ctx: &mut Self::Context) -> Self::Result {
-        match message {
-            HandlerMessage::SendPacket(packet) => self.connection.send_packet(packet),
-            HandlerMessage::SetProtocol(protocol) => {
-                self.connection.set_protocol(protocol);
-                Ok(())
-            },
-            HandlerMessage::EnableCompression(size_limit) => {
-                self.connection.enable_compression(size_limit);
-                Ok(())
-            },
-            HandlerMessage::Disconnect() => {
-                self.connection.disconnect();
-                Ok(())
+/// Handle packets and connection control messages relayed to this actor
+impl StreamHandler<Relayed<Server>> for StatusServerManager {
+    fn handle(&mut self, relayed: Relayed<Server>, _ctx: &mut Self::Context) {
+        match relayed {
+            Relayed::Packet(packet) => {
+                let _ = self.connection.send_packet(packet);
+            }
+            Relayed::Control(HandlerMessage::SetProtocol(protocol)) => self.connection.set_protocol(protocol),
+            Relayed::Control(HandlerMessage::EnableCompression(size_limit)) => {
+                self.connection.enable_compression(size_limit, CompressionLevel::Default);
             }
+            Relayed::Control(HandlerMessage::Disconnect()) => self.connection.disconnect(),
+            Relayed::Control(HandlerMessage::SwitchCompleted()) | Relayed::Control(HandlerMessage::SwitchFailed()) => {}
         }
     }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }
 
 impl WriteHandler<()> for StatusServerManager {}
@@ -100,12 +111,12 @@ impl WriteHandler<()> for StatusServerManager {}
 /// Forward the received server status through the oneshot channel and
 /// close the connection to the server.
 impl PacketHandler<Server, StatusResponsePacket> for StatusServerManager {
-    fn handle_packet(&mut self, packet: StatusResponsePacket, _ctx: &mut Self::Context) -> Result<(), ()> {
+    fn handle_packet(&mut self, packet: StatusResponsePacket, ctx: &mut Self::Context) -> Result<(), ()> {
         if let Some(sender) = self.channel.take() {
             sender.send(Ok(packet.status)).map_err(|_| ())?;
         }
 
-        self.connection.disconnect();
+        self.connection.shutdown(ctx);
         Ok(())
     }
 }
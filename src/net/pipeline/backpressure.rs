@@ -0,0 +1,107 @@
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+use futures::task::{Context, Poll, Waker};
+use crate::metrics::WriteBufferMetrics;
+
+/// Reading from the peer connection is paused once this many bytes wait to be written
+pub const HIGH_WATERMARK: usize = 2 * 1024 * 1024;
+
+/// Reading from the peer connection resumes once the buffer drained below this size
+pub const LOW_WATERMARK: usize = 512 * 1024;
+
+/// Reading from the peer connection is also paused once this many of its relayed packets
+/// wait to be handled, and resumes once half of them were handled
+pub const RELAY_CAPACITY: usize = 1024;
+
+/// Tracks how many bytes a connection has yet to write to its socket,
+/// and how many packets relayed to it were not yet handled by its actor.
+///
+/// The connection whose packets are forwarded into this one stops reading
+/// while the buffer is above its high watermark, until it drained below the low watermark.
+pub struct Backpressure {
+    metrics: &'static WriteBufferMetrics,
+    buffered: Cell<usize>,
+
+    /// Packets relayed to this connection that were not yet handled
+    relayed: Cell<usize>,
+
+    /// When the buffer or the relayed packets exceeded their limit, while reading is paused
+    paused_since: Cell<Option<Instant>>,
+
+    /// The stream of the peer connection waiting for reading to resume
+    waker: RefCell<Option<Waker>>,
+}
+
+impl Backpressure {
+    pub fn new(metrics: &'static WriteBufferMetrics) -> Backpressure {
+        Backpressure {
+            metrics,
+            buffered: Cell::new(0),
+            relayed: Cell::new(0),
+            paused_since: Cell::new(None),
+            waker: RefCell::new(None),
+        }
+    }
+
+    /// Record the current size of the write buffer
+    pub fn update(&self, buffered: usize) {
+        let previous = self.buffered.replace(buffered);
+        if buffered > previous {
+            self.metrics.bytes.add((buffered - previous) as u64);
+        } else {
+            self.metrics.bytes.sub((previous - buffered) as u64);
+        }
+        self.update_pause();
+    }
+
+    /// Record a packet relayed to this connection
+    pub fn relay(&self) {
+        self.relayed.set(self.relayed.get() + 1);
+        self.update_pause();
+    }
+
+    /// Record that a relayed packet was handled
+    pub fn handled(&self) {
+        self.relayed.set(self.relayed.get().saturating_sub(1));
+        self.update_pause();
+    }
+
+    fn update_pause(&self) {
+        let (buffered, relayed) = (self.buffered.get(), self.relayed.get());
+        match self.paused_since.get() {
+            None if buffered >= HIGH_WATERMARK || relayed >= RELAY_CAPACITY => {
+                self.paused_since.set(Some(Instant::now()));
+                self.metrics.pauses.inc();
+            }
+            Some(_) if buffered <= LOW_WATERMARK && relayed <= RELAY_CAPACITY / 2 => {
+                self.paused_since.set(None);
+                if let Some(waker) = self.waker.borrow_mut().take() {
+                    waker.wake();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the peer connection may read more packets.
+    /// Otherwise its task is woken once the buffer drained.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.paused_since.get().is_some() {
+            *self.waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    /// How long reading from the peer connection has been paused
+    pub fn paused_for(&self, now: Instant) -> Option<Duration> {
+        self.paused_since.get().map(|since| now.duration_since(since))
+    }
+}
+
+impl Drop for Backpressure {
+    fn drop(&mut self) {
+        self.update(0);
+    }
+}
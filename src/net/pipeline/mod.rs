@@ -1,48 +1,250 @@
 use std::rc::Rc;
-use std::sync::RwLock;
-use tokio::io::split;
+use std::cell::RefCell;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::channel::oneshot;
+use tokio::io::{split, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use crate::net::{Protocol, ConnectionType};
+use crate::net::{Protocol, ConnectionType, ConnectionMetrics};
 use crate::net::pipeline::packet_codec::PacketCodec;
 use crate::net::pipeline::compressor::Compressor;
+use crate::net::pipeline::framing::{FrameBuffer, FrameCodec, FrameError};
+use crate::net::pipeline::write_queue::WriteQueue;
+use crate::net::pipeline::splice::{DetachedReader, DetachedWriter};
+use crate::net::pipeline::backpressure::Backpressure;
+use crate::net::play::CompressedFrame;
+use crate::net::wire_codec::WireCodec;
+use crate::settings::{CompressionLevel, FrameLimits};
+use crate::capture::Recorder;
+use crate::capture::format::Direction;
 
 mod stream;
 mod sink;
-mod framing;
-mod compressor;
 mod packet_codec;
+mod write_queue;
+pub mod splice;
+pub mod backpressure;
 
 pub use self::sink::PipelineSink;
+pub use mineroute_protocol::{framing, compressor};
 pub use self::stream::PipelineStream;
 
-/// This struct encapsulates
+const MIN_BUFFER_SIZE: usize = 256;
+
+/// This struct encapsulates the state of a connection that is shared
+/// between its [PipelineStream] and [PipelineSink].
+///
+/// Both legs of a session are pinned to the arbiter of its client connection,
+/// so the pipeline never leaves that thread and is shared through a `RefCell`.
 pub struct HandlerPipeline<C: ConnectionType> {
-    protocol: Rc<RwLock<Protocol>>,
-    compressor: Option<Compressor>,
+    protocol: Rc<RefCell<Protocol>>,
+    /// Shared with the splice loop reading from the socket once it got detached
+    compressor: Option<Rc<RefCell<Compressor>>>,
     codec: PacketCodec<C>,
+
+    /// The maximum sizes of received frames
+    limits: FrameLimits,
+
+    /// The halves of the socket, until they get detached for splicing
+    reader: Option<ReadHalf<TcpStream>>,
+    writer: Option<WriteHalf<TcpStream>>,
+
+    /// Received bytes that do not yet form a complete frame
+    read_buf: BytesMut,
+
+    /// Outgoing packets are encoded into this buffer
+    encode_buf: BytesMut,
+
+    /// Encoded frames that were not yet written to the socket
+    write_queue: WriteQueue,
+
+    /// The fill level of `write_queue`, shared with the peer connection
+    backpressure: Rc<Backpressure>,
+
+    /// The write buffer of the peer connection that packets of this one are forwarded to.
+    /// Reading is paused while it is full.
+    throttle: Option<Rc<Backpressure>>,
+
+    /// Frames sent after the socket got detached are written by the splice loop
+    splice: Option<UnboundedSender<Bytes>>,
+
+    /// Completes once the splice loop stopped writing to the detached socket
+    splice_closed: Option<oneshot::Receiver<()>>,
+
+    /// Records decoded packets, together with the direction of received ones
+    recorder: Option<(Recorder, Direction)>,
 }
 
 impl<C: ConnectionType> HandlerPipeline<C> {
-    pub fn new(stream: TcpStream) -> (Rc<RwLock<HandlerPipeline<C>>>, PipelineSink<C>, PipelineStream<C>) {
+    pub fn new(stream: TcpStream) -> (Rc<RefCell<HandlerPipeline<C>>>, PipelineSink<C>, PipelineStream<C>)
+        where C: ConnectionMetrics {
         let (r, w) = split(stream);
-        let protocol = Rc::new(RwLock::new(Protocol::Handshake));
-        let pipeline = Rc::new(RwLock::new(HandlerPipeline {
+        let protocol = Rc::new(RefCell::new(Protocol::Handshake));
+        let pipeline = Rc::new(RefCell::new(HandlerPipeline {
             protocol: protocol.clone(),
             compressor: None,
             codec: PacketCodec::new(protocol.clone()),
+            limits: FrameLimits::protocol_maximum(),
+            reader: Some(r),
+            writer: Some(w),
+            read_buf: BytesMut::with_capacity(MIN_BUFFER_SIZE),
+            encode_buf: BytesMut::new(),
+            write_queue: WriteQueue::default(),
+            backpressure: Rc::new(Backpressure::new(C::write_buffer_metrics())),
+            throttle: None,
+            splice: None,
+            splice_closed: None,
+            recorder: None,
         }));
 
-        let stream = PipelineStream::new(r, pipeline.clone());
-        let sink = PipelineSink::new(w, pipeline.clone());
+        let stream = PipelineStream::new(pipeline.clone());
+        let sink = PipelineSink::new(pipeline.clone());
 
         (pipeline, sink, stream)
     }
 
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.borrow().clone()
+    }
+
     pub fn set_protocol(&mut self, proto: Protocol) {
-        *self.protocol.write().unwrap() = proto;
+        *self.protocol.borrow_mut() = proto;
+    }
+
+    pub fn enable_compression(&mut self, size_limit: Option<usize>, level: CompressionLevel) {
+        self.compressor = size_limit.map(|size_limit| Rc::new(RefCell::new(Compressor::new(size_limit, level))));
+    }
+
+    /// The compression threshold of this connection, if compression is enabled
+    pub fn compression(&self) -> Option<usize> {
+        self.compressor.as_ref().map(|compressor| compressor.borrow().size_limit)
+    }
+
+    /// Reject received frames exceeding these limits
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+
+    /// Record all packets that are decoded or encoded from now on.
+    /// Packets are recorded after decompression.
+    pub fn set_recorder(&mut self, recorder: Recorder, received: Direction) {
+        self.recorder = Some((recorder, received));
+    }
+
+    /// Whether packets of this connection are being recorded
+    pub fn is_recording(&self) -> bool {
+        self.recorder.as_ref().is_some_and(|(recorder, _)| recorder.is_active())
+    }
+
+    /// The fill level of the write buffer of this connection
+    pub fn backpressure(&self) -> Rc<Backpressure> {
+        self.backpressure.clone()
+    }
+
+    /// Pause reading while the write buffer of the connection that packets are forwarded to is full
+    pub fn throttle(&mut self, peer: Rc<Backpressure>) {
+        self.throttle = Some(peer);
+    }
+
+    /// Take the socket out of this pipeline, so that its frames can be spliced to another socket.
+    ///
+    /// Bytes that were already received or not yet written are handed over as well.
+    /// Packets sent afterwards are still encoded by this pipeline and passed on to the splice loop.
+    pub fn detach(&mut self) -> Option<(DetachedReader, DetachedWriter)> {
+        let (reader, writer) = match (self.reader.take(), self.writer.take()) {
+            (Some(reader), Some(writer)) => (reader, writer),
+            (reader, writer) => {
+                self.reader = reader;
+                self.writer = writer;
+                return None;
+            }
+        };
+
+        let (splice, inject) = unbounded();
+        let (closed, splice_closed) = oneshot::channel();
+        self.splice = Some(splice);
+        self.splice_closed = Some(splice_closed);
+
+        let reader = DetachedReader {
+            reader,
+            buffer: self.read_buf.split(),
+            compressor: self.compressor.clone(),
+        };
+        let writer = DetachedWriter {
+            writer,
+            pending: self.write_queue.take(),
+            inject,
+            closed,
+        };
+
+        // The splice loops only buffer a bounded amount of bytes
+        self.backpressure.update(0);
+        self.throttle = None;
+        Some((reader, writer))
+    }
+
+    /// Try to decode a packet from the received bytes.
+    /// Returns `None` if no complete frame was received yet.
+    fn try_decode(&mut self) -> Result<Option<C::In>, FrameError> {
+        let protocol = self.protocol();
+        if let Some(mut frame) = FrameCodec::try_decode(&mut self.read_buf, self.limits.frame_limit(&protocol))? {
+            let mut compressed = None;
+            if let Some(compressor) = &self.compressor {
+                let mut compressor = compressor.borrow_mut();
+                // Keep the compressed frame, so that it may be forwarded without compressing it again
+                if Compressor::is_compressed(&frame) {
+                    let data = frame.freeze();
+                    frame = compressor.decompress(&data, self.limits.decompressed_limit(&protocol))?;
+                    compressed = Some(CompressedFrame {
+                        size_limit: compressor.size_limit,
+                        data,
+                    });
+                } else {
+                    frame = compressor.decode(frame, self.limits.decompressed_limit(&protocol))?;
+                }
+            }
+
+            if let Some((recorder, received)) = &self.recorder {
+                recorder.record(*received, &protocol, &frame);
+            }
+
+            Ok(Some(self.codec.decode(&mut frame, compressed)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Record the size of the write buffer after it changed
+    fn update_backpressure(&self) {
+        self.backpressure.update(self.write_queue.remaining());
     }
 
-    pub fn enable_compression(&mut self, size_limit: Option<usize>) {
-        self.compressor = size_limit.map(|size_limit| Compressor { size_limit });
+    /// Encode a packet into a frame ready to be written to the wire.
+    /// The frame is split off a buffer of this pipeline, whose space is reused once the frame was written.
+    fn encode(&mut self, packet: &C::Out) -> Result<BytesMut, ()> {
+        // Forward frames that were compressed with the same threshold as received
+        let forwarded = match (&self.compressor, C::WC::compressed_frame(packet)) {
+            (Some(compressor), Some(compressed)) if compressed.size_limit == compressor.borrow().size_limit => Some(&compressed.data),
+            _ => None,
+        };
+
+        let protocol = self.protocol();
+        let mut frame = FrameBuffer::new(&mut self.encode_buf);
+        if forwarded.is_none() || self.recorder.is_some() {
+            self.codec.encode(packet, frame.payload_mut())?;
+            if let Some((recorder, received)) = &self.recorder {
+                recorder.record(received.reverse(), &protocol, frame.payload());
+            }
+        }
+
+        match (forwarded, &self.compressor) {
+            (Some(data), _) => {
+                frame.clear();
+                frame.payload_mut().extend_from_slice(data);
+                frame.finish()
+            }
+            (None, Some(compressor)) => compressor.borrow_mut().encode(frame)?.finish(),
+            (None, None) => frame.finish(),
+        }
     }
 }
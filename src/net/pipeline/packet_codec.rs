@@ -1,31 +1,34 @@
 use std::rc::Rc;
-use std::sync::RwLock;
+use std::cell::RefCell;
 use std::marker::PhantomData;
-use bytes::{Buf, BytesMut};
+use bytes::BytesMut;
 use crate::net::*;
+use crate::net::buffer::Buffer;
+use crate::net::wire_codec::WireCodec;
+use crate::net::play::CompressedFrame;
 
 /// Encodes/Decodes packets to bytes
 pub struct PacketCodec<C: ConnectionType> {
-    protocol: Rc<RwLock<Protocol>>,
+    protocol: Rc<RefCell<Protocol>>,
     phantom: PhantomData<C>,
 }
 
 impl<C: ConnectionType> PacketCodec<C> {
-    pub fn new(protocol: Rc<RwLock<Protocol>>) -> PacketCodec<C> {
+    pub fn new(protocol: Rc<RefCell<Protocol>>) -> PacketCodec<C> {
         PacketCodec {
             protocol,
             phantom: PhantomData,
         }
     }
 
-    pub fn decode(&self, src: &mut BytesMut) -> Result<C::In, ()> {
-        let packet_id = src.get_u8();
-        let protocol = self.protocol.read().unwrap();
-        C::WC::read_packet(&protocol, packet_id, src)
+    pub fn decode(&self, src: &mut BytesMut, compressed: Option<CompressedFrame>) -> Result<C::In, ()> {
+        let packet_id = src.read_u8()?;
+        let protocol = self.protocol.borrow();
+        C::WC::read_packet(&protocol, packet_id, src, compressed)
     }
 
     pub fn encode(&self, packet: &C::Out, dst: &mut BytesMut) -> Result<(), ()> {
-        let protocol = self.protocol.write().unwrap();
+        let protocol = self.protocol.borrow();
         C::WC::write_packet(&protocol, packet, dst)
     }
 }
@@ -1,54 +1,42 @@
 use std::rc::Rc;
-use std::sync::{RwLock};
+use std::cell::RefCell;
 use std::pin::Pin;
-use bytes::{BytesMut, Buf, BufMut};
-use futures::{Sink, ready};
+use bytes::Buf;
+use futures::{Future, Sink, ready};
 use futures::task::{Context, Poll};
-use tokio::io::{AsyncWrite, WriteHalf};
-use tokio::net::TcpStream;
+use tokio::io::AsyncWrite;
 use crate::net::pipeline::HandlerPipeline;
 use crate::net::ConnectionType;
-use crate::net::pipeline::framing::FrameCodec;
 
 /// A Sink that writes incoming packets to the wire,
 /// applying all processors defined in the pipeline.
 pub struct PipelineSink<C: ConnectionType> {
-    w: Pin<Box<WriteHalf<TcpStream>>>,
-    pipeline: Rc<RwLock<HandlerPipeline<C>>>,
-    buffer: Option<BytesMut>,
+    pipeline: Rc<RefCell<HandlerPipeline<C>>>,
 }
 
 impl<C: ConnectionType> PipelineSink<C> {
-    pub fn new(w: WriteHalf<TcpStream>, pipeline: Rc<RwLock<HandlerPipeline<C>>>) -> PipelineSink<C> {
+    pub fn new(pipeline: Rc<RefCell<HandlerPipeline<C>>>) -> PipelineSink<C> {
         PipelineSink {
-            w: Box::pin(w),
             pipeline,
-            buffer: None,
         }
     }
 
-    fn encode(&mut self, packet: C::Out) -> Result<BytesMut, ()> {
-        let mut buffer = BytesMut::new();
-
-        let pipeline = self.pipeline.write().unwrap();
-        pipeline.codec.encode(&packet, &mut buffer)?;
-
-        if let Some(compressor) = &pipeline.compressor {
-            buffer = compressor.encode(buffer)?;
-        }
-
-        buffer = FrameCodec::encode(buffer)?;
+    fn flush_pending_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let pipeline = &mut *pipeline;
 
-        Ok(buffer)
-    }
+        while pipeline.write_queue.has_remaining() {
+            let writer = match pipeline.writer.as_mut() {
+                Some(writer) => writer,
+                None => break,
+            };
 
-    fn flush_pending_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
-        if let Some(buffer) = &mut self.buffer {
-            while buffer.has_remaining() {
-                let written_bytes = ready!(self.w.as_mut().poll_write(cx, buffer)).map_err(|_| ())?;
-                buffer.advance(written_bytes);
+            // Writes all queued frames at once, as far as the socket accepts them
+            let written_bytes = ready!(Pin::new(writer).poll_write_buf(cx, &mut pipeline.write_queue)).map_err(|_| ())?;
+            if written_bytes == 0 {
+                return Poll::Ready(Err(()));
             }
-            self.buffer = None;
+            pipeline.update_backpressure();
         }
         Poll::Ready(Ok(()))
     }
@@ -61,27 +49,46 @@ impl<C: ConnectionType> Sink<C::Out> for PipelineSink<C> {
         self.flush_pending_buffer(cx)
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: C::Out) -> Result<(), Self::Error> {
-        let payload = self.encode(item)?;
-
-        // Temporary workaround, until actix [SinkWrite] calls `poll_ready` properly.
-        if let Some(buffer) = &mut self.buffer {
-            buffer.reserve(payload.len());
-            buffer.put_slice(&payload);
-            Ok(())
-        } else {
-            self.buffer = Some(payload);
-            Ok(())
+    fn start_send(self: Pin<&mut Self>, item: C::Out) -> Result<(), Self::Error> {
+        let mut pipeline = self.pipeline.borrow_mut();
+        let frame = pipeline.encode(&item)?.freeze();
+
+        if let Some(splice) = &pipeline.splice {
+            return splice.unbounded_send(frame).map_err(|_| ());
         }
+
+        // actix [SinkWrite] does not call `poll_ready`, so frames are always buffered.
+        // The queue is bounded by pausing the peer connection once it gets full.
+        pipeline.write_queue.push(frame);
+        pipeline.update_backpressure();
+        Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         ready!(self.flush_pending_buffer(cx))?;
-        self.w.as_mut().poll_flush(cx).map_err(|_| ())
+
+        let mut pipeline = self.pipeline.borrow_mut();
+        match pipeline.writer.as_mut() {
+            Some(writer) => Pin::new(writer).poll_flush(cx).map_err(|_| ()),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         ready!(self.flush_pending_buffer(cx))?;
-        self.w.as_mut().poll_shutdown(cx).map_err(|_| ())
+
+        let mut pipeline = self.pipeline.borrow_mut();
+
+        // Let the splice loop close the socket once it wrote all injected frames
+        pipeline.splice = None;
+        if let Some(splice_closed) = pipeline.splice_closed.as_mut() {
+            let _ = ready!(Pin::new(splice_closed).poll(cx));
+            return Poll::Ready(Ok(()));
+        }
+
+        match pipeline.writer.as_mut() {
+            Some(writer) => Pin::new(writer).poll_shutdown(cx).map_err(|_| ()),
+            None => Poll::Ready(Ok(())),
+        }
     }
 }
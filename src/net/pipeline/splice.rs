@@ -0,0 +1,121 @@
+//! Forwarding of frames between two sockets, without decoding them.
+//!
+//! Once both legs of a proxied connection use the same framing and compression,
+//! there is no need to pass every packet through the actors managing them.
+
+use std::io;
+use std::cell::RefCell;
+use std::rc::Rc;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::StreamExt;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::oneshot;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use crate::net::pipeline::compressor::Compressor;
+use crate::net::pipeline::framing::{FrameCodec, FrameError};
+use crate::net::pipeline::write_queue::WriteQueue;
+use crate::metrics::frame_limits;
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Compressed frames larger than this cannot contain a packet that the splice loop observes
+const MAX_OBSERVED_COMPRESSED_SIZE: usize = 64;
+
+/// The read half of a socket taken out of a [HandlerPipeline](super::HandlerPipeline)
+pub struct DetachedReader {
+    pub(super) reader: ReadHalf<TcpStream>,
+
+    /// Bytes that were received but not yet decoded
+    pub(super) buffer: BytesMut,
+
+    /// The compressor of the connection, if it compresses
+    pub(super) compressor: Option<Rc<RefCell<Compressor>>>,
+}
+
+/// The write half of a socket taken out of a [HandlerPipeline](super::HandlerPipeline)
+pub struct DetachedWriter {
+    pub(super) writer: WriteHalf<TcpStream>,
+
+    /// Frames that were encoded but not yet written
+    pub(super) pending: WriteQueue,
+
+    /// Frames encoded by the pipeline after it was detached.
+    /// The socket is closed once this channel closes.
+    pub(super) inject: UnboundedReceiver<Bytes>,
+
+    /// Dropped once the splice loop stopped writing to the socket
+    pub(super) closed: oneshot::Sender<()>,
+}
+
+/// Copy whole frames from a reader to a writer until either side closes.
+///
+/// Frames sent through the pipeline of the writer are written in between the copied ones.
+/// `observe` is called with the id of every copied packet that can be read without
+/// decompressing a large frame, so that e.g. Keep Alive packets can still be tracked.
+/// Frames larger than `max_frame` end the splice, their decompressed size is not checked.
+pub async fn splice<F: FnMut(Option<u8>)>(from: DetachedReader, to: DetachedWriter, max_frame: usize,
+                                          mut observe: F) -> io::Result<()> {
+    let DetachedReader { mut reader, mut buffer, compressor } = from;
+    let DetachedWriter { mut writer, mut pending, mut inject, closed: _closed } = to;
+
+    while pending.has_remaining() {
+        if writer.write_buf(&mut pending).await? == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+    }
+
+    loop {
+        // Forward all frames that were completely received
+        let mut complete = 0;
+        while let Some((prefix_size, frame_size)) = FrameCodec::peek_frame(&buffer[complete..]).map_err(|_| invalid_frame())? {
+            if frame_size > max_frame {
+                let error = FrameError::TooLarge { size: frame_size, limit: max_frame };
+                frame_limits::record(&error);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string()));
+            }
+
+            let start = complete + prefix_size;
+            if buffer.len() < start + frame_size {
+                break;
+            }
+
+            observe(peek_packet_id(&buffer[start..start + frame_size], compressor.as_deref()));
+            complete = start + frame_size;
+        }
+
+        if complete > 0 {
+            let frames = buffer.split_to(complete);
+            writer.write_all(&frames).await?;
+        }
+
+        buffer.reserve(READ_BUFFER_SIZE);
+        tokio::select! {
+            read = reader.read_buf(&mut buffer) => {
+                if read? == 0 {
+                    break;
+                }
+            }
+            frame = inject.next() => match frame {
+                Some(frame) => writer.write_all(&frame).await?,
+                None => break,
+            },
+        }
+    }
+
+    writer.shutdown().await
+}
+
+/// Read the packet id of a frame
+fn peek_packet_id(frame: &[u8], compressor: Option<&RefCell<Compressor>>) -> Option<u8> {
+    match compressor {
+        None => frame.first().copied(),
+        // Packets below the threshold are sent uncompressed after a zero size
+        Some(_) if frame.first() != Some(&0) && frame.len() > MAX_OBSERVED_COMPRESSED_SIZE => None,
+        Some(compressor) => compressor.borrow_mut().peek_packet_id(frame),
+    }
+}
+
+fn invalid_frame() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid frame length")
+}
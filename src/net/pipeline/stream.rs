@@ -1,73 +1,31 @@
 use std::rc::Rc;
-use std::sync::RwLock;
+use std::cell::RefCell;
 use std::pin::Pin;
-use bytes::BytesMut;
 use core::task::Poll;
 use futures::task::Context;
-use futures::{Stream};
-use tokio::io::{ReadHalf, AsyncRead};
-use tokio::net::TcpStream;
+use futures::Stream;
+use tokio::io::AsyncRead;
 use crate::net::ConnectionType;
-use crate::net::pipeline::HandlerPipeline;
-use crate::net::pipeline::framing::FrameCodec;
-
-const MIN_BUFFER_SIZE: usize = 256;
+use crate::metrics::frame_limits;
+use crate::net::pipeline::{HandlerPipeline, MIN_BUFFER_SIZE};
 
 /// A Stream of decoded incoming packets.
 ///
 /// It utilizes the processors configured
 /// in the pipeline to decode the stream.
 pub struct PipelineStream<C: ConnectionType> {
-    r: Pin<Box<ReadHalf<TcpStream>>>,
-    pipeline: Rc<RwLock<HandlerPipeline<C>>>,
+    pipeline: Rc<RefCell<HandlerPipeline<C>>>,
 
-    /// A buffer that temporary stores not yet completely
-    /// received packet frames until they are complete.
-    ///
-    /// To workaround issues of the ownership system,
-    /// the buffer gets moved out of this structs and
-    /// is then returned.
-    /// Constructs such as RefCell or Mutex cannot solve
-    /// this issue as they keep a non-mutable reference
-    /// to self which prevents mutable uses of self.
-    read_buf: Option<BytesMut>,
+    /// Set once a frame could not be decoded.
+    /// The remaining bytes cannot be framed anymore, so the stream ends.
+    failed: bool,
 }
 
 impl<C: ConnectionType> PipelineStream<C> {
-    pub fn new(r: ReadHalf<TcpStream>, pipeline: Rc<RwLock<HandlerPipeline<C>>>) -> PipelineStream<C> {
+    pub fn new(pipeline: Rc<RefCell<HandlerPipeline<C>>>) -> PipelineStream<C> {
         PipelineStream {
-            r: Box::pin(r),
-            read_buf: Some(BytesMut::with_capacity(MIN_BUFFER_SIZE)),
             pipeline,
-        }
-    }
-
-    fn borrow_buf(&mut self) -> &mut BytesMut {
-        self.read_buf.as_mut().unwrap()
-    }
-
-    /// Take the ownership of the read_buf.
-    ///
-    /// Note that it must always get returned afterward.
-    fn take_buf(&mut self) -> BytesMut {
-        self.read_buf.take().unwrap_or_else(||
-            BytesMut::with_capacity(MIN_BUFFER_SIZE))
-    }
-
-    fn return_buf(&mut self, buffer: BytesMut) {
-        self.read_buf = Some(buffer);
-    }
-
-    fn try_read(&mut self, pipeline: &HandlerPipeline<C>) -> Poll<Option<Result<C::In, ()>>> {
-        let mut buffer = self.borrow_buf();
-        if let Some(mut frame) = FrameCodec::try_decode(&mut buffer)? {
-            if let Some(compressor) = &pipeline.compressor {
-                frame = compressor.decode(frame)?;
-            }
-
-            Poll::Ready(Some(Ok(pipeline.codec.decode(&mut frame)?)))
-        } else {
-            Poll::Pending
+            failed: false,
         }
     }
 }
@@ -75,26 +33,48 @@ impl<C: ConnectionType> PipelineStream<C> {
 impl<C: ConnectionType> Stream for PipelineStream<C> {
     type Item = Result<C::In, ()>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let pipeline = self.pipeline.clone();
-        let pipeline = pipeline.write().unwrap();
-
-        // Try to read remaining frames on the buffer
-        if let Poll::Ready(Some(read_data)) = self.try_read(&pipeline) {
-            return Poll::Ready(Some(read_data))
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.failed {
+            return Poll::Ready(None);
         }
 
-        // Else try to load more data into the buffer
-        let mut buffer = self.take_buf();
-        buffer.reserve(MIN_BUFFER_SIZE);
-        let poll_result = self.r.as_mut().poll_read_buf(cx, &mut buffer);
-        self.return_buf(buffer);
+        let mut pipeline = this.pipeline.borrow_mut();
+        let pipeline = &mut *pipeline;
+
+        loop {
+            // Stop handling packets while the connection they are forwarded to cannot keep up
+            if let Some(throttle) = &pipeline.throttle {
+                if throttle.poll_ready(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
 
-        match poll_result {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(_)) => Poll::Ready(Some(Err(()))),
-            Poll::Ready(Ok(0)) => Poll::Ready(None), // End of stream reached
-            Poll::Ready(Ok(_)) => self.try_read(&pipeline),
+            // Try to read remaining frames on the buffer
+            match pipeline.try_decode() {
+                Ok(Some(packet)) => return Poll::Ready(Some(Ok(packet))),
+                Ok(None) => {}
+                Err(error) => {
+                    frame_limits::record(&error);
+                    this.failed = true;
+                    return Poll::Ready(Some(Err(())));
+                }
+            }
+
+            // Once detached, the splice loop reads from the socket
+            let reader = match pipeline.reader.as_mut() {
+                Some(reader) => reader,
+                None => return Poll::Pending,
+            };
+
+            // Else try to load more data into the buffer
+            pipeline.read_buf.reserve(MIN_BUFFER_SIZE);
+            match Pin::new(reader).poll_read_buf(cx, &mut pipeline.read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(_)) => return Poll::Ready(Some(Err(()))),
+                Poll::Ready(Ok(0)) => return Poll::Ready(None), // End of stream reached
+                Poll::Ready(Ok(_)) => {}
+            }
         }
     }
 }
@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use bytes::{Buf, Bytes};
+
+/// Encoded frames that were not yet written to the socket.
+///
+/// Frames are queued as they are instead of being copied into one buffer,
+/// and written with a single vectored write.
+#[derive(Default)]
+pub struct WriteQueue {
+    frames: VecDeque<Bytes>,
+
+    /// The number of bytes in all frames
+    len: usize,
+}
+
+impl WriteQueue {
+    pub fn push(&mut self, frame: Bytes) {
+        if !frame.is_empty() {
+            self.len += frame.len();
+            self.frames.push_back(frame);
+        }
+    }
+
+    /// Take all queued frames, leaving the queue empty
+    pub fn take(&mut self) -> WriteQueue {
+        std::mem::take(self)
+    }
+}
+
+impl Buf for WriteQueue {
+    fn remaining(&self) -> usize {
+        self.len
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.frames.front().map_or(&[], |frame| frame.bytes())
+    }
+
+    fn advance(&mut self, mut count: usize) {
+        assert!(count <= self.len, "cannot advance past the queued frames");
+        self.len -= count;
+        while count > 0 {
+            let frame = self.frames.front_mut().unwrap();
+            if count < frame.len() {
+                frame.advance(count);
+                return;
+            }
+            count -= frame.len();
+            self.frames.pop_front();
+        }
+    }
+
+    fn bytes_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut count = 0;
+        for (slice, frame) in dst.iter_mut().zip(&self.frames) {
+            *slice = IoSlice::new(frame);
+            count += 1;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(frames: &[&'static [u8]]) -> WriteQueue {
+        let mut queue = WriteQueue::default();
+        for frame in frames {
+            queue.push(Bytes::from_static(frame));
+        }
+        queue
+    }
+
+    #[test]
+    fn advancing_splits_frames() {
+        let mut queue = queue(&[b"abc", b"", b"de", b"fgh"]);
+        assert_eq!(queue.remaining(), 8);
+
+        queue.advance(4);
+        assert_eq!(queue.bytes(), b"e");
+        queue.advance(2);
+        assert_eq!(queue.bytes(), b"gh");
+        assert_eq!(queue.to_bytes(), Bytes::from_static(b"gh"));
+    }
+
+    #[test]
+    fn frames_are_written_vectored() {
+        let queue = queue(&[b"abc", b"de", b"fgh"]);
+        let mut slices = [IoSlice::new(&[]); 2];
+        assert_eq!(queue.bytes_vectored(&mut slices), 2);
+        assert_eq!(&*slices[0], b"abc");
+        assert_eq!(&*slices[1], b"de");
+    }
+}
@@ -1,5 +0,0 @@
-//! All packets of the [Protocol::Handshake] Protocol
-
-mod raw;
-
-pub use raw::RawPacket;
@@ -0,0 +1,172 @@
+//! Parsing of the PROXY protocol header (version 1 and 2) that load balancers
+//! like HAProxy prepend to a connection to pass on the original client address.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a version 1 header, including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read a PROXY protocol header from the start of a stream.
+///
+/// Exactly the bytes of the header are consumed, so the stream is positioned
+/// at the first byte sent by the client afterwards.
+/// Returns the original source address of the connection or
+/// `None` if the proxy did not provide one (e.g. for health checks).
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>, ()> {
+    // Both versions are longer than the v2 signature
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await.map_err(|_| ())?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(())
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, start: &[u8]) -> Result<Option<SocketAddr>, ()> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(());
+        }
+        line.push(stream.read_u8().await.map_err(|_| ())?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| ())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _destination, source_port, _destination_port] |
+        ["PROXY", "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| ())?;
+            let port = source_port.parse::<u16>().map_err(|_| ())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(()),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>, ()> {
+    let version_command = stream.read_u8().await.map_err(|_| ())?;
+    let family = stream.read_u8().await.map_err(|_| ())?;
+    let length = stream.read_u16().await.map_err(|_| ())? as usize;
+
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await.map_err(|_| ())?;
+
+    if version_command >> 4 != 2 {
+        return Err(());
+    }
+
+    // The LOCAL command is used by the proxy itself, e.g. for health checks
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if payload.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&payload[0..4]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)))
+        }
+        // AF_INET6
+        2 if payload.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&payload[0..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // AF_UNSPEC or unix sockets do not carry a usable address
+        0 | 3 => Ok(None),
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    /// Read a header and return the bytes following it
+    fn read(data: &[u8]) -> (Result<Option<SocketAddr>, ()>, &[u8]) {
+        let mut stream = data;
+        let source = block_on(read_header(&mut stream));
+        (source, stream)
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    #[test]
+    fn v1_headers_are_read() {
+        let (source, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25565\r\nhandshake");
+        assert_eq!(source, Ok(Some("192.0.2.1:56324".parse().unwrap())));
+        assert_eq!(rest, b"handshake");
+
+        let (source, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 25565\r\n");
+        assert_eq!(source, Ok(Some("[2001:db8::1]:4000".parse().unwrap())));
+
+        let (source, rest) = read(b"PROXY UNKNOWN\r\nhandshake");
+        assert_eq!(source, Ok(None));
+        assert_eq!(rest, b"handshake");
+    }
+
+    #[test]
+    fn invalid_v1_headers_are_rejected() {
+        assert_eq!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 port 25565\r\n").0, Err(()));
+        assert_eq!(read(b"PROXY TCP4 192.0.2.1\r\n").0, Err(()));
+        assert_eq!(read(format!("PROXY {}\r\n", "x".repeat(V1_MAX_LENGTH)).as_bytes()).0, Err(()));
+        assert_eq!(read(b"GET / HTTP/1.1\r\n").0, Err(()));
+    }
+
+    #[test]
+    fn v2_headers_are_read() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        payload.extend_from_slice(&56324u16.to_be_bytes());
+        payload.extend_from_slice(&25565u16.to_be_bytes());
+        let mut data = v2(1, 0x11, &payload);
+        data.extend_from_slice(b"handshake");
+        let (source, rest) = read(&data);
+        assert_eq!(source, Ok(Some("192.0.2.1:56324".parse().unwrap())));
+        assert_eq!(rest, b"handshake");
+
+        let mut payload = Ipv6Addr::LOCALHOST.octets().to_vec();
+        payload.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        payload.extend_from_slice(&4000u16.to_be_bytes());
+        payload.extend_from_slice(&25565u16.to_be_bytes());
+        assert_eq!(read(&v2(1, 0x21, &payload)).0, Ok(Some("[::1]:4000".parse().unwrap())));
+    }
+
+    #[test]
+    fn v2_local_and_unspecified_headers_have_no_source() {
+        let data = [v2(0, 0x11, &[0; 12]), b"handshake".to_vec()].concat();
+        let (source, rest) = read(&data);
+        assert_eq!(source, Ok(None));
+        assert_eq!(rest, b"handshake");
+        assert_eq!(read(&v2(1, 0x00, &[])).0, Ok(None));
+    }
+
+    #[test]
+    fn invalid_v2_headers_are_rejected() {
+        // Truncated addresses, unknown families and other versions
+        assert_eq!(read(&v2(1, 0x11, &[192, 0, 2, 1])).0, Err(()));
+        assert_eq!(read(&v2(1, 0x41, &[0; 12])).0, Err(()));
+        let mut data = v2(1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert_eq!(read(&data).0, Err(()));
+    }
+}
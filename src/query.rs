@@ -0,0 +1,279 @@
+//! Answers the UDP query protocol on behalf of one server of a listener,
+//! either from the players known to the proxy or by forwarding to the query port of the upstream.
+
+use std::io;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::BytesMut;
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use log::{debug, warn};
+use socket2::{Socket, Domain, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use crate::net::query::{QueryRequest, QueryResponse, ServerStat};
+use crate::server_state::SharedConfiguration;
+use crate::settings::{ListenerSettings, QuerySettings};
+
+/// How long a challenge token stays valid, tokens of the previous period are accepted as well
+const TOKEN_PERIOD: Duration = Duration::from_secs(30);
+
+/// How long the upstream may take to answer each request of a forwarded query
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Requests arriving while this many are being forwarded get dropped
+const MAX_FORWARDS: usize = 64;
+
+/// Queries are small, larger datagrams are truncated and rejected
+const MAX_DATAGRAM_SIZE: usize = 4096;
+
+/// Bind the query socket of a listener
+pub fn bind(address: SocketAddr, ipv6_only: bool) -> io::Result<UdpSocket> {
+    let domain = match address {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+
+    let socket = Socket::new(domain, Type::dgram(), None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.bind(&address.into())?;
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+/// Issues challenge tokens without remembering the clients they were issued to.
+/// A token is derived from the address of the client and the current period.
+struct Challenges {
+    keys: RandomState,
+    started: Instant,
+}
+
+impl Challenges {
+    fn new() -> Challenges {
+        Challenges {
+            keys: RandomState::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn period(&self) -> u64 {
+        self.started.elapsed().as_secs() / TOKEN_PERIOD.as_secs()
+    }
+
+    fn token_for(&self, address: &SocketAddr, period: u64) -> i32 {
+        (self.keys.hash_one((address, period)) & 0x7FFF_FFFF) as i32
+    }
+
+    fn issue(&self, address: &SocketAddr) -> i32 {
+        self.token_for(address, self.period())
+    }
+
+    fn verify(&self, address: &SocketAddr, token: i32) -> bool {
+        let period = self.period();
+        token == self.token_for(address, period) || (period > 0 && token == self.token_for(address, period - 1))
+    }
+}
+
+/// Answer query requests until the listener gets closed
+pub async fn serve(socket: UdpSocket, listener: Arc<ListenerSettings>, config: Arc<SharedConfiguration>,
+                   mut shutdown: oneshot::Receiver<()>, stopped: oneshot::Sender<()>) {
+    let settings = match &listener.query {
+        Some(settings) => settings.clone(),
+        None => return,
+    };
+    let host_address = socket.local_addr().unwrap_or(listener.bind);
+    let (mut receiver, mut sender) = socket.split();
+    let challenges = Challenges::new();
+    let mut forwards = FuturesUnordered::new();
+    let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+    let mut buffer = BytesMut::new();
+
+    loop {
+        let (address, response) = tokio::select! {
+            _ = &mut shutdown => break,
+            Some(forwarded) = forwards.next(), if !forwards.is_empty() => match forwarded {
+                Some(forwarded) => forwarded,
+                None => continue,
+            },
+            received = receiver.recv_from(&mut datagram) => match received {
+                Ok((size, address)) => {
+                    let request = match QueryRequest::read(&datagram[..size]) {
+                        Ok(request) => request,
+                        Err(()) => continue,
+                    };
+                    match request {
+                        QueryRequest::Handshake { session_id } => {
+                            (address, QueryResponse::Handshake { session_id, token: challenges.issue(&address) })
+                        }
+                        _ if !challenges.verify(&address, token(&request)) => continue,
+                        _ => match settings.upstream_port {
+                            Some(port) => {
+                                if forwards.len() < MAX_FORWARDS {
+                                    forwards.push(forward(request, address, port, settings.clone(), listener.bind, config.clone()));
+                                }
+                                continue;
+                            }
+                            None => match local_response(&request, &settings, listener.bind, &config) {
+                                Some(response) => (address, response),
+                                None => continue,
+                            },
+                        },
+                    }
+                }
+                Err(error) => {
+                    warn!("Query socket of listener '{}' on {} failed to receive: {}", listener.name, host_address, error);
+                    continue;
+                }
+            },
+        };
+
+        buffer.clear();
+        response.write(&mut buffer);
+        if let Err(error) = sender.send_to(&buffer, &address).await {
+            debug!("Cannot answer the query of {}: {}", address, error);
+        }
+    }
+
+    drop((receiver, sender));
+    let _ = stopped.send(());
+}
+
+fn token(request: &QueryRequest) -> i32 {
+    match *request {
+        QueryRequest::Handshake { .. } => 0,
+        QueryRequest::BasicStat { token, .. } | QueryRequest::FullStat { token, .. } => token,
+    }
+}
+
+/// Answer a stat request with the players known to the proxy
+fn local_response(request: &QueryRequest, settings: &QuerySettings, bind: SocketAddr, config: &SharedConfiguration) -> Option<QueryResponse> {
+    let config = config.load();
+    let server = config.get_server(&settings.host)?;
+    let players = server.players.read().unwrap().clone();
+
+    let stat = ServerStat {
+        motd: settings.motd.clone().unwrap_or_else(|| settings.host.clone()),
+        game_type: "SMP".to_owned(),
+        game_id: "MINECRAFT".to_owned(),
+        version: settings.version.clone(),
+        plugins: String::new(),
+        map: "world".to_owned(),
+        num_players: players.len(),
+        max_players: settings.max_players,
+        host_port: bind.port(),
+        host_ip: bind.ip().to_string(),
+        players,
+    };
+    Some(stat_response(request, stat))
+}
+
+fn stat_response(request: &QueryRequest, stat: ServerStat) -> QueryResponse {
+    let session_id = request.session_id();
+    match request {
+        QueryRequest::FullStat { .. } => QueryResponse::FullStat { session_id, stat },
+        _ => QueryResponse::BasicStat { session_id, stat },
+    }
+}
+
+/// Forward a stat request to the query port of the upstream, reporting the address of the listener instead.
+/// Failures are logged and leave the client without an answer.
+async fn forward(request: QueryRequest, address: SocketAddr, port: u16, settings: QuerySettings, bind: SocketAddr,
+                 config: Arc<SharedConfiguration>) -> Option<(SocketAddr, QueryResponse)> {
+    match query_upstream(&request, port, &settings, &config).await {
+        Ok(mut stat) => {
+            stat.host_port = bind.port();
+            stat.host_ip = bind.ip().to_string();
+            Some((address, stat_response(&request, stat)))
+        }
+        Err(error) => {
+            warn!("Cannot forward the query of {} to '{}': {}", address, settings.host, error);
+            None
+        }
+    }
+}
+
+async fn query_upstream(request: &QueryRequest, port: u16, settings: &QuerySettings, config: &SharedConfiguration) -> Result<ServerStat, String> {
+    let (upstream, resolver) = {
+        let config = config.load();
+        let server = config.get_server(&settings.host).ok_or_else(|| "the server is not registered".to_owned())?;
+        (server.upstream.clone(), config.resolver())
+    };
+    let upstream = resolver.resolve(&upstream).await.map_err(|error| error.0)?;
+    let upstream = SocketAddr::new(upstream.ip(), port);
+
+    let local: IpAddr = match upstream {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut socket = UdpSocket::bind(SocketAddr::new(local, 0)).await.map_err(|error| error.to_string())?;
+    socket.connect(upstream).await.map_err(|error| error.to_string())?;
+
+    // The upstream issues its own challenge token to the proxy
+    let session_id = request.session_id();
+    let token = match exchange(&mut socket, QueryRequest::Handshake { session_id }).await? {
+        QueryResponse::Handshake { token, .. } => token,
+        _ => return Err("unexpected response to the handshake".to_owned()),
+    };
+
+    let upstream_request = match request {
+        QueryRequest::FullStat { .. } => QueryRequest::FullStat { session_id, token },
+        _ => QueryRequest::BasicStat { session_id, token },
+    };
+    match exchange(&mut socket, upstream_request).await? {
+        QueryResponse::BasicStat { stat, .. } | QueryResponse::FullStat { stat, .. } => Ok(stat),
+        QueryResponse::Handshake { .. } => Err("unexpected response to the stat request".to_owned()),
+    }
+}
+
+/// Send a request to the upstream and wait for its response
+async fn exchange(socket: &mut UdpSocket, request: QueryRequest) -> Result<QueryResponse, String> {
+    let mut buffer = BytesMut::new();
+    request.write(&mut buffer);
+    socket.send(&buffer).await.map_err(|error| error.to_string())?;
+
+    let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+    let size = timeout(UPSTREAM_TIMEOUT, socket.recv(&mut datagram)).await
+        .map_err(|_| "the upstream did not answer in time".to_owned())?
+        .map_err(|error| error.to_string())?;
+    let response = QueryResponse::read(&request, &datagram[..size]).map_err(|_| "the upstream sent a malformed response".to_owned())?;
+    if response.session_id() != request.session_id() {
+        return Err("the upstream answered with another session id".to_owned());
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_bound_to_the_address() {
+        let challenges = Challenges::new();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+
+        let token = challenges.issue(&client);
+        assert!(token >= 0);
+        assert!(challenges.verify(&client, token));
+        assert!(!challenges.verify(&other, token));
+    }
+
+    #[test]
+    fn tokens_of_the_previous_period_are_accepted() {
+        let mut challenges = Challenges::new();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let token = challenges.issue(&client);
+
+        challenges.started -= TOKEN_PERIOD;
+        assert!(challenges.verify(&client, token));
+        challenges.started -= TOKEN_PERIOD;
+        assert!(!challenges.verify(&client, token));
+    }
+}
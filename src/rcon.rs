@@ -0,0 +1,135 @@
+//! Executes commands on upstream servers through their RCON port.
+//!
+//! Each command opens its own connection, so that no idle connections
+//! to the upstreams are kept and a failed command cannot affect others.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+use crate::net::rcon::{RconCodec, RconError, RconPacket, COMMAND, LOGIN, MAX_COMMAND_LENGTH, RESPONSE_VALUE};
+use crate::upstream::{ResolveError, Upstream};
+
+/// The port of the vanilla server, used if the configured address has none
+pub const DEFAULT_PORT: u16 = 25575;
+
+/// How long connecting, logging in and executing a command may take altogether
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOGIN_ID: i32 = 1;
+const COMMAND_ID: i32 = 2;
+
+/// Sent after the command, its response follows the last packet of the command response
+const END_MARKER_ID: i32 = 3;
+
+/// Parse the RCON address of a server.
+/// Unlike upstreams, no SRV record is looked up for addresses without a port.
+pub fn parse_address(address: &str) -> Result<Upstream, ResolveError> {
+    let mut upstream: Upstream = address.parse()?;
+    upstream.port.get_or_insert(DEFAULT_PORT);
+    Ok(upstream)
+}
+
+/// The reason why a command could not be executed
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command exceeds [MAX_COMMAND_LENGTH] or contains null characters
+    InvalidCommand,
+    Connect(io::Error),
+    Rcon(RconError),
+    AuthenticationFailed,
+    Timeout,
+    Closed,
+}
+
+impl From<RconError> for CommandError {
+    fn from(error: RconError) -> Self {
+        CommandError::Rcon(error)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::InvalidCommand => write!(f, "commands are limited to {} characters without null characters", MAX_COMMAND_LENGTH),
+            CommandError::Connect(error) => write!(f, "cannot connect: {}", error),
+            CommandError::Rcon(error) => error.fmt(f),
+            CommandError::AuthenticationFailed => f.write_str("the password was not accepted"),
+            CommandError::Timeout => f.write_str("the server did not respond in time"),
+            CommandError::Closed => f.write_str("the server closed the connection"),
+        }
+    }
+}
+
+/// Log in to a server and execute a command, returning the response of the server
+pub async fn execute(address: SocketAddr, password: &str, command: &str) -> Result<String, CommandError> {
+    if command.len() > MAX_COMMAND_LENGTH || command.contains('\0') {
+        return Err(CommandError::InvalidCommand);
+    }
+    timeout(COMMAND_TIMEOUT, execute_on(address, password, command)).await
+        .unwrap_or(Err(CommandError::Timeout))
+}
+
+async fn execute_on(address: SocketAddr, password: &str, command: &str) -> Result<String, CommandError> {
+    let stream = TcpStream::connect(address).await.map_err(CommandError::Connect)?;
+    let mut connection = Framed::new(stream, RconCodec);
+
+    connection.send(packet(LOGIN_ID, LOGIN, password)).await?;
+    loop {
+        let response = receive(&mut connection).await?;
+        match (response.kind, response.request_id) {
+            // Some servers send an empty response value in front of the login response
+            (RESPONSE_VALUE, _) => continue,
+            (COMMAND, LOGIN_ID) => break,
+            _ => return Err(CommandError::AuthenticationFailed),
+        }
+    }
+
+    // The server handles packets in order, so the response to an invalid
+    // request marks the end of a response that was split into several packets
+    connection.send(packet(COMMAND_ID, COMMAND, command)).await?;
+    connection.send(packet(END_MARKER_ID, RESPONSE_VALUE, "")).await?;
+
+    // Characters may be split across packets, so the output is only decoded once complete
+    let mut output = Vec::new();
+    loop {
+        let response = receive(&mut connection).await?;
+        match response.request_id {
+            COMMAND_ID => output.extend_from_slice(&response.payload),
+            END_MARKER_ID => return Ok(String::from_utf8_lossy(&output).into_owned()),
+            _ => continue,
+        }
+    }
+}
+
+fn packet(request_id: i32, kind: i32, payload: &str) -> RconPacket {
+    RconPacket {
+        request_id,
+        kind,
+        payload: Bytes::copy_from_slice(payload.as_bytes()),
+    }
+}
+
+async fn receive(connection: &mut Framed<TcpStream, RconCodec>) -> Result<RconPacket, CommandError> {
+    match connection.next().await {
+        Some(packet) => Ok(packet?),
+        None => Err(CommandError::Closed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_default_to_the_rcon_port() {
+        assert_eq!(parse_address("mc.local").unwrap(), Upstream { host: "mc.local".to_owned(), port: Some(DEFAULT_PORT) });
+        assert_eq!(parse_address("10.0.0.2:1234").unwrap(), Upstream { host: "10.0.0.2".to_owned(), port: Some(1234) });
+        assert!(parse_address("mc.local:rcon").is_err());
+    }
+}
@@ -1,19 +1,57 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::RwLock;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::{Deref, DerefMut};
+use std::sync::{RwLock, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use arc_swap::{ArcSwap, Guard};
+use log::info;
+use serde::{Serialize, Deserialize};
+use crate::upstream::{Upstream, UpstreamResolver};
+use crate::capture::Captures;
+use crate::audit::AuditLog;
+use crate::extensions::{Extension, Extensions};
+use crate::commands::CommandSettings;
 
+#[derive(Clone)]
 pub struct Configuration {
     /// Map Hostnames to the corresponding servers
-    servers: HashMap<String, ServerConfig>
+    servers: HashMap<String, ServerConfig>,
+
+    /// Resolves the upstreams of all servers
+    resolver: Arc<UpstreamResolver>,
+
+    /// Map names of banned players to the reason of their ban
+    bans: HashMap<String, String>,
+
+    /// Players and hostnames whose sessions are recorded
+    captures: Captures,
+
+    /// Hooks that are called by every session
+    extensions: Arc<Extensions>,
+
+    /// Chat commands handled by the proxy, if enabled
+    commands: Option<Arc<CommandSettings>>,
+
+    /// Records the commands executed through RCON
+    audit: AuditLog,
 }
 
 impl Configuration {
-    pub fn new() -> Configuration {
+    pub fn new(resolver: Arc<UpstreamResolver>) -> Configuration {
         Configuration{
-            servers: HashMap::new()
+            servers: HashMap::new(),
+            resolver,
+            bans: HashMap::new(),
+            captures: Captures::new("captures".into()),
+            extensions: Arc::new(Extensions::default()),
+            commands: None,
+            audit: AuditLog::new("audit.log".into()),
         }
     }
 
+    pub fn resolver(&self) -> Arc<UpstreamResolver> {
+        self.resolver.clone()
+    }
+
     pub fn get_server_hosts(&self) -> Vec<&String> {
         self.servers.keys().collect()
     }
@@ -33,29 +71,190 @@ impl Configuration {
     pub fn get_server_mut(&mut self, host: &str) -> Option<&mut ServerConfig> {
         self.servers.get_mut(host)
     }
+
+    /// The servers whose RCON settings include the group, ordered by hostname
+    pub fn get_rcon_group(&self, group: &str) -> Vec<(&String, &RconConfig)> {
+        let mut servers: Vec<_> = self.servers.iter()
+            .filter_map(|(host, server)| server.rcon.as_ref().map(|rcon| (host, rcon)))
+            .filter(|(_, rcon)| rcon.groups.contains(group))
+            .collect();
+        servers.sort_by_key(|(host, _)| *host);
+        servers
+    }
+
+    pub fn get_bans(&self) -> &HashMap<String, String> {
+        &self.bans
+    }
+
+    pub fn get_ban(&self, player: &str) -> Option<&String> {
+        self.bans.get(player)
+    }
+
+    pub fn add_ban(&mut self, player: &str, reason: String) {
+        self.bans.insert(player.to_owned(), reason);
+    }
+
+    pub fn remove_ban(&mut self, player: &str) -> Option<String> {
+        self.bans.remove(player)
+    }
+
+    pub fn captures(&self) -> &Captures {
+        &self.captures
+    }
+
+    pub fn captures_mut(&mut self) -> &mut Captures {
+        &mut self.captures
+    }
+
+    pub fn extensions(&self) -> Arc<Extensions> {
+        self.extensions.clone()
+    }
+
+    pub fn commands(&self) -> Option<Arc<CommandSettings>> {
+        self.commands.clone()
+    }
+
+    /// Enable chat commands for all sessions that start afterwards
+    pub fn set_commands(&mut self, commands: Option<CommandSettings>) {
+        self.commands = commands.map(Arc::new);
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub fn set_audit_log(&mut self, audit: AuditLog) {
+        self.audit = audit;
+    }
+
+    /// Register an extension for all sessions that start afterwards
+    pub fn register_extension(&mut self, extension: Arc<dyn Extension>) {
+        info!("Registered extension '{}'", extension.name());
+        Arc::make_mut(&mut self.extensions).register(extension);
+    }
 }
 
+/// The configuration shared by all arbiters.
+///
+/// Sessions read the current snapshot without locking. Writers change a copy
+/// of the configuration, which replaces the snapshot once they are done.
+pub struct SharedConfiguration {
+    current: ArcSwap<Configuration>,
+
+    /// Only one writer may copy the configuration at a time, so that no change gets lost
+    writer: Mutex<()>,
+}
+
+impl SharedConfiguration {
+    pub fn new(config: Configuration) -> SharedConfiguration {
+        SharedConfiguration {
+            current: ArcSwap::from_pointee(config),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current snapshot of the configuration
+    pub fn load(&self) -> Guard<Arc<Configuration>> {
+        self.current.load()
+    }
+
+    /// Change the configuration. The changes are published once the writer is dropped.
+    pub fn write(&self) -> ConfigurationWriter<'_> {
+        // A writer that panicked did not publish its copy, so the configuration is still consistent
+        let lock = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        ConfigurationWriter {
+            config: Some(Configuration::clone(&self.current.load())),
+            shared: self,
+            _lock: lock,
+        }
+    }
+}
+
+/// A copy of the configuration that replaces the current snapshot when dropped
+pub struct ConfigurationWriter<'a> {
+    config: Option<Configuration>,
+    shared: &'a SharedConfiguration,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl Deref for ConfigurationWriter<'_> {
+    type Target = Configuration;
+    fn deref(&self) -> &Configuration {
+        self.config.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ConfigurationWriter<'_> {
+    fn deref_mut(&mut self) -> &mut Configuration {
+        self.config.as_mut().unwrap()
+    }
+}
+
+impl Drop for ConfigurationWriter<'_> {
+    fn drop(&mut self) {
+        // Changes of a panicking writer may be incomplete
+        if let (Some(config), false) = (self.config.take(), thread::panicking()) {
+            self.shared.current.store(Arc::new(config));
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ServerConfig {
-    pub upstream: SocketAddr,
-    pub players: RwLock<Vec<String>>,
+    pub upstream: Upstream,
+
+    /// The players are shared by all snapshots of the configuration
+    pub players: Arc<RwLock<Vec<String>>>,
+    pub health: Health,
+
+    /// Describes why the last connection attempt to the upstream failed
+    pub health_error: Option<String>,
+
+    /// Commands may be executed on the server through the admin api if set
+    pub rcon: Option<RconConfig>,
+}
+
+/// How the proxy reaches the RCON port of a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RconConfig {
+    pub address: Upstream,
+    pub password: String,
+
+    /// Commands broadcast to any of these groups are executed on the server
+    pub groups: BTreeSet<String>,
 }
 
 impl ServerConfig {
-    pub fn new(upstream: SocketAddr) -> ServerConfig {
+    pub fn new(upstream: Upstream) -> ServerConfig {
         ServerConfig {
             upstream,
-            players: RwLock::new(Vec::new()),
+            players: Arc::new(RwLock::new(Vec::new())),
+            health: Health::Unknown,
+            health_error: None,
+            rcon: None,
         }
     }
 
-    pub fn add_player(&mut self, player: String) {
+    pub fn add_player(&self, player: String) {
         self.players.write().unwrap().push(player);
     }
 
-    pub fn remove_player(&mut self, player: &str) {
+    pub fn remove_player(&self, player: &str) {
         let mut players = self.players.write().unwrap();
         if let Some(index) = players.iter().position(|name| name == player) {
             players.remove(index);
         }
     }
 }
+
+/// Whether an upstream server answered the last status request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Unknown,
+    Healthy,
+    Unhealthy,
+
+    /// The hostname of the upstream could not be resolved
+    Unresolvable,
+}
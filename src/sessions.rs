@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+use actix::prelude::*;
+use log::warn;
+use crate::server_state::{Configuration, SharedConfiguration};
+use crate::events::Event;
+use crate::metrics::GHOST_PLAYERS;
+
+/// How often the player lists are reconciled with the live sessions
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Shown to a player whose session was replaced by a new login with the same name
+const REPLACED_SESSION_REASON: &str = "You logged in from another location";
+
+/// A system wide actor keeping track of the sessions of all logged in players.
+///
+/// It allows other parts of mineroute, like the admin api,
+/// to reach the actor managing the connection of a certain player.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: HashMap<String, Session>,
+}
+
+struct Session {
+    host: String,
+
+    /// Identifies the actor of the session
+    kick: Recipient<Kick>,
+
+    /// Sessions of Bedrock players and of Java versions without known world packets cannot be switched to another server
+    switch: Option<Recipient<SwitchServer>>,
+}
+
+impl Actor for SessionRegistry {
+    type Context = Context<Self>;
+}
+
+impl Supervised for SessionRegistry {}
+impl SystemService for SessionRegistry {}
+
+/// Register the session of a player by name.
+/// Resolves to whether it was registered.
+///
+/// A live session that already uses the name is kicked, unless the registration is exclusive.
+pub struct RegisterSession {
+    pub name: String,
+    pub host: String,
+    pub kick: Recipient<Kick>,
+    pub switch: Option<Recipient<SwitchServer>>,
+
+    /// Refuse the name if another live session uses it, instead of replacing that session
+    pub exclusive: bool,
+}
+impl Message for RegisterSession { type Result = bool; }
+
+pub struct UnregisterSession {
+    pub name: String,
+    pub kick: Recipient<Kick>,
+}
+impl Message for UnregisterSession { type Result = (); }
+
+/// The hostnames of all players whose session is still alive, by name.
+/// Sessions whose actor is gone are forgotten.
+pub struct LiveSessions;
+impl Message for LiveSessions { type Result = HashMap<String, String>; }
+
+/// Disconnect a player by name.
+/// Resolves to the hostname the player was connected to.
+pub struct KickPlayer {
+    pub name: String,
+    pub reason: String,
+}
+impl Message for KickPlayer { type Result = Option<String>; }
+
+/// Disconnect the player of a session, showing the provided reason if the edition allows it
+pub struct Kick(pub String);
+impl Message for Kick { type Result = (); }
+
+/// Move a player by name to the server of another hostname.
+/// Resolves to why the player cannot be moved, if they cannot.
+pub struct SendPlayer {
+    pub name: String,
+    pub host: String,
+}
+impl Message for SendPlayer { type Result = Result<(), SendRefused>; }
+
+/// Why a player cannot be moved to another server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendRefused {
+    Offline,
+
+    /// The edition or version of Minecraft of the player does not support switching servers
+    Unsupported,
+}
+
+/// Switch the player of a session to the server of another hostname
+pub struct SwitchServer(pub String);
+impl Message for SwitchServer { type Result = (); }
+
+impl Handler<RegisterSession> for SessionRegistry {
+    type Result = bool;
+    fn handle(&mut self, message: RegisterSession, _ctx: &mut Self::Context) -> Self::Result {
+        let taken = self.sessions.get(&message.name)
+            .filter(|session| session.kick.connected() && session.kick != message.kick);
+        if message.exclusive && taken.is_some() {
+            return false;
+        }
+        let kick = message.kick.clone();
+        let replaced = self.sessions.insert(message.name, Session {
+            host: message.host,
+            kick: message.kick,
+            switch: message.switch,
+        });
+
+        // Like vanilla servers, only the latest login of a player is kept
+        if let Some(replaced) = replaced.filter(|session| session.kick != kick) {
+            let _ = replaced.kick.do_send(Kick(REPLACED_SESSION_REASON.to_owned()));
+        }
+        true
+    }
+}
+
+impl Handler<UnregisterSession> for SessionRegistry {
+    type Result = ();
+    fn handle(&mut self, message: UnregisterSession, _ctx: &mut Self::Context) {
+        // The player may have already reconnected with a new session
+        if let Some(session) = self.sessions.get(&message.name) {
+            if session.kick == message.kick {
+                self.sessions.remove(&message.name);
+            }
+        }
+    }
+}
+
+impl Handler<SendPlayer> for SessionRegistry {
+    type Result = Result<(), SendRefused>;
+    fn handle(&mut self, message: SendPlayer, _ctx: &mut Self::Context) -> Self::Result {
+        let session = self.sessions.get(&message.name).ok_or(SendRefused::Offline)?;
+        let switch = session.switch.as_ref().ok_or(SendRefused::Unsupported)?;
+        switch.do_send(SwitchServer(message.host)).map_err(|_| SendRefused::Offline)
+    }
+}
+
+impl Handler<KickPlayer> for SessionRegistry {
+    type Result = Option<String>;
+    fn handle(&mut self, message: KickPlayer, _ctx: &mut Self::Context) -> Self::Result {
+        let session = self.sessions.remove(&message.name)?;
+        let _ = session.kick.do_send(Kick(message.reason));
+        Some(session.host)
+    }
+}
+
+impl Handler<LiveSessions> for SessionRegistry {
+    type Result = MessageResult<LiveSessions>;
+    fn handle(&mut self, _message: LiveSessions, _ctx: &mut Self::Context) -> Self::Result {
+        self.sessions.retain(|_, session| session.kick.connected());
+        MessageResult(self.sessions.iter()
+            .map(|(name, session)| (name.clone(), session.host.clone()))
+            .collect())
+    }
+}
+
+/// A player listed on a server.
+/// The player is removed from the list once this is dropped,
+/// which also happens if the actor of the session is dropped while panicking.
+pub struct PlayerEntry {
+    config: Arc<SharedConfiguration>,
+    pub name: String,
+    pub host: String,
+}
+
+impl PlayerEntry {
+    pub fn add(config: Arc<SharedConfiguration>, name: String, host: String) -> PlayerEntry {
+        if let Some(server) = config.load().get_server(&host) {
+            server.add_player(name.clone());
+        }
+        PlayerEntry { config, name, host }
+    }
+
+    /// List the player on another server instead
+    pub fn move_to(&mut self, host: String) {
+        let config = self.config.load();
+        if let Some(server) = config.get_server(&self.host) {
+            server.remove_player(&self.name);
+        }
+        if let Some(server) = config.get_server(&host) {
+            server.add_player(self.name.clone());
+        }
+        self.host = host;
+    }
+}
+
+impl Drop for PlayerEntry {
+    fn drop(&mut self) {
+        // The panic ending the session may have poisoned the player list
+        if let Some(server) = self.config.load().get_server(&self.host) {
+            let mut players = server.players.write().unwrap_or_else(PoisonError::into_inner);
+            if let Some(index) = players.iter().position(|name| *name == self.name) {
+                players.remove(index);
+            }
+        }
+    }
+}
+
+/// Periodically remove players from the player lists of the servers
+/// whose session is gone without having cleaned up after itself.
+pub struct SessionReconciler {
+    config: Arc<SharedConfiguration>,
+    interval: Duration,
+
+    /// Players without a live session in the previous pass, by hostname and name
+    suspects: HashSet<(String, String)>,
+}
+
+impl SessionReconciler {
+    pub fn new(config: Arc<SharedConfiguration>) -> SessionReconciler {
+        SessionReconciler::with_interval(config, RECONCILE_INTERVAL)
+    }
+
+    pub fn with_interval(config: Arc<SharedConfiguration>, interval: Duration) -> SessionReconciler {
+        SessionReconciler {
+            config,
+            interval,
+            suspects: HashSet::new(),
+        }
+    }
+
+    fn reconcile(&mut self, ctx: &mut Context<Self>) {
+        let future = SessionRegistry::from_registry().send(LiveSessions)
+            .into_actor(self)
+            .map(|live, reconciler, _ctx| {
+                if let Ok(live) = live {
+                    reconciler.remove_ghosts(&live);
+                }
+            });
+        ctx.wait(future);
+    }
+
+    /// Remove the players that were missing a live session in two passes in a row.
+    /// A player that just logged in may not be registered yet when a pass sees them first.
+    fn remove_ghosts(&mut self, live: &HashMap<String, String>) {
+        let config = self.config.load();
+        let ghosts = ghost_players(&config, live);
+        for (host, name) in ghosts.intersection(&self.suspects) {
+            if let Some(server) = config.get_server(host) {
+                warn!("Removing '{}' from the player list of '{}' since their session is gone", name, host);
+                server.remove_player(name);
+                GHOST_PLAYERS.inc();
+                Event::PlayerLeave {
+                    host: host.clone(),
+                    player: name.clone(),
+                }.publish();
+            }
+        }
+        self.suspects = ghosts;
+    }
+}
+
+/// The players listed on a server without a live session on it, by hostname and name
+fn ghost_players(config: &Configuration, live: &HashMap<String, String>) -> HashSet<(String, String)> {
+    let mut ghosts = HashSet::new();
+    for host in config.get_server_hosts() {
+        if let Some(server) = config.get_server(host) {
+            for name in server.players.read().unwrap().iter() {
+                if live.get(name) != Some(host) {
+                    ghosts.insert((host.clone(), name.clone()));
+                }
+            }
+        }
+    }
+    ghosts
+}
+
+impl Actor for SessionReconciler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |reconciler, ctx| reconciler.reconcile(ctx));
+    }
+}
+
@@ -0,0 +1,485 @@
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::net::SocketAddr;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use serde::{Deserialize, Deserializer};
+use crate::net::Protocol;
+use crate::net::pipeline::framing::{MAX_FRAME_SIZE, MAX_DECOMPRESSED_SIZE};
+use crate::commands::CommandSettings;
+use crate::bedrock;
+
+pub use mineroute_protocol::compressor::CompressionLevel;
+
+/// The contents of the mineroute configuration file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default)]
+    pub web: WebSettings,
+
+    #[serde(default)]
+    pub capture: CaptureSettings,
+
+    #[serde(default)]
+    pub audit: AuditSettings,
+
+    #[serde(default = "default_listeners")]
+    pub listeners: Vec<ListenerSettings>,
+
+    /// Chat commands handled by the proxy, disabled if omitted.
+    /// Sessions are not spliced while commands are enabled.
+    #[serde(default)]
+    pub commands: Option<CommandSettings>,
+
+    /// How many arbiters run the sessions, one per core if omitted.
+    /// Changes take effect after a restart.
+    #[serde(default)]
+    pub workers: Option<usize>,
+
+    /// Servers that get registered on startup.
+    /// Afterwards servers are managed through the admin api.
+    #[serde(default)]
+    pub servers: BTreeMap<String, ServerSettings>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebSettings {
+    /// Address of the admin frontend and api
+    pub bind: SocketAddr,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureSettings {
+    /// Where packet captures enabled through the admin api are written to
+    pub directory: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuditSettings {
+    /// The file that every command executed through RCON is appended to
+    pub log: PathBuf,
+}
+
+/// A socket accepting minecraft connections
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings {
+    /// Identifies the listener when the configuration gets reloaded
+    pub name: String,
+
+    /// The address to listen on. Use `[::]` together with `ipv6_only = false`
+    /// to accept both IPv4 and IPv6 connections.
+    pub bind: SocketAddr,
+
+    #[serde(default)]
+    pub ipv6_only: bool,
+
+    /// Expect a PROXY protocol header (v1 or v2) in front of each connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    /// The hostnames that clients may connect to through this listener.
+    /// All servers are reachable if omitted.
+    #[serde(default, deserialize_with = "lowercase_hostnames")]
+    pub hostnames: Option<HashSet<String>>,
+
+    #[serde(default = "default_true")]
+    pub nodelay: bool,
+
+    /// Enable TCP keepalive probes with this interval in seconds
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+
+    #[serde(default)]
+    pub reuse_port: bool,
+
+    /// Compress packets to clients exceeding this size in bytes.
+    /// A negative value disables compression, e.g. for LAN users.
+    /// The threshold of the upstream server is used if omitted.
+    #[serde(default)]
+    pub compression_threshold: Option<i32>,
+
+    #[serde(default)]
+    pub compression_level: CompressionLevel,
+
+    /// Copy play packets between the sockets without decoding them,
+    /// whenever both legs use the same compression
+    #[serde(default = "default_true")]
+    pub splice: bool,
+
+    /// Upper bounds on the size of frames received from clients
+    #[serde(default)]
+    pub limits: FrameLimits,
+
+    /// Answer the UDP query protocol for one of the servers, disabled if omitted
+    #[serde(default)]
+    pub query: Option<QuerySettings>,
+
+    /// Relay Bedrock Edition clients to a Bedrock server, disabled if omitted
+    #[serde(default)]
+    pub bedrock: Option<BedrockSettings>,
+}
+
+/// A UDP socket answering the query protocol (`enable-query`) of a listener.
+///
+/// Query requests carry no hostname, so every socket reports a single server.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct QuerySettings {
+    /// The address to listen on, the address of the listener if omitted
+    #[serde(default)]
+    pub bind: Option<SocketAddr>,
+
+    /// The hostname of the reported server
+    #[serde(deserialize_with = "lowercase_hostname")]
+    pub host: String,
+
+    /// Forward requests to this query port of the upstream server.
+    /// If omitted, the proxy answers with its own list of players.
+    #[serde(default)]
+    pub upstream_port: Option<u16>,
+
+    /// The description reported by the proxy, the hostname if omitted
+    #[serde(default)]
+    pub motd: Option<String>,
+
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+
+    /// The version reported by the proxy
+    #[serde(default)]
+    pub version: String,
+}
+
+/// A UDP socket accepting Bedrock Edition clients over RakNet.
+///
+/// Their sessions are relayed to a single Bedrock server without being decoded,
+/// the name of each player is read from its Login packet for the player list of a server.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BedrockSettings {
+    /// The address to listen on, the address of the listener with port 19132 if omitted
+    #[serde(default)]
+    pub bind: Option<SocketAddr>,
+
+    /// The hostname of the server whose player list Bedrock players are added to
+    #[serde(deserialize_with = "lowercase_hostname")]
+    pub host: String,
+
+    /// The address of the Bedrock server, port 19132 if it has none
+    pub upstream: String,
+
+    /// The description shown in the server list, the hostname if omitted
+    #[serde(default)]
+    pub motd: Option<String>,
+
+    /// The second line of the description, shown on the world selection screen
+    #[serde(default)]
+    pub sub_motd: String,
+
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+
+    /// The version and protocol version reported in the server list
+    #[serde(default = "default_bedrock_version")]
+    pub version: String,
+
+    #[serde(default = "default_bedrock_protocol_version")]
+    pub protocol_version: u32,
+
+    /// Sessions are closed once neither side sent a datagram for this many seconds
+    #[serde(default = "default_bedrock_idle_timeout")]
+    pub idle_timeout: u64,
+
+    /// Prepended to the names of Bedrock players, which are not verified,
+    /// so that they are told apart from Java players of the same name
+    #[serde(default = "default_bedrock_name_prefix")]
+    pub name_prefix: String,
+}
+
+impl BedrockSettings {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+}
+
+/// The maximum size in bytes of frames received in each protocol state
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields, default)]
+pub struct FrameLimits {
+    pub handshake: usize,
+    pub status: usize,
+    pub login: usize,
+    pub play: usize,
+
+    /// The maximum size of play packets after decompression.
+    /// Packets of other states may not decompress beyond the frame limit of the state.
+    pub decompressed: usize,
+}
+
+impl FrameLimits {
+    /// No limits beyond the ones of the protocol itself
+    pub const fn protocol_maximum() -> FrameLimits {
+        FrameLimits {
+            handshake: MAX_FRAME_SIZE,
+            status: MAX_FRAME_SIZE,
+            login: MAX_FRAME_SIZE,
+            play: MAX_FRAME_SIZE,
+            decompressed: MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    /// The maximum size of a frame received in a protocol state
+    pub fn frame_limit(&self, protocol: &Protocol) -> usize {
+        match protocol {
+            Protocol::Handshake => self.handshake,
+            Protocol::Status => self.status,
+            Protocol::Login => self.login,
+            Protocol::Play => self.play,
+        }
+    }
+
+    /// The maximum size of a packet received in a protocol state after decompression
+    pub fn decompressed_limit(&self, protocol: &Protocol) -> usize {
+        match protocol {
+            Protocol::Play => self.decompressed,
+            protocol => self.frame_limit(protocol),
+        }
+    }
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            handshake: 1024,
+            status: 256,
+            login: 4096,
+            play: MAX_FRAME_SIZE,
+            decompressed: MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+impl ListenerSettings {
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive.map(Duration::from_secs)
+    }
+
+    /// The compression threshold towards clients, given the one chosen by the upstream server
+    pub fn client_compression(&self, upstream_size_limit: Option<usize>) -> Option<usize> {
+        match self.compression_threshold {
+            None => upstream_size_limit,
+            Some(threshold) if threshold < 0 => None,
+            Some(threshold) => Some(threshold as usize),
+        }
+    }
+
+    /// Whether clients may reach a server of the provided hostname through this listener
+    pub fn allows_host(&self, host: &str) -> bool {
+        self.hostnames.as_ref().map_or(true, |hostnames| hostnames.contains(host))
+    }
+
+    /// The address of the query socket, if the query protocol is enabled
+    pub fn query_bind(&self) -> Option<SocketAddr> {
+        self.query.as_ref().map(|query| query.bind.unwrap_or(self.bind))
+    }
+
+    /// The address of the Bedrock socket, if Bedrock clients are accepted
+    pub fn bedrock_bind(&self) -> Option<SocketAddr> {
+        self.bedrock.as_ref().map(|bedrock| bedrock.bind.unwrap_or_else(|| SocketAddr::new(self.bind.ip(), bedrock::DEFAULT_PORT)))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    pub upstream: String,
+
+    #[serde(default)]
+    pub rcon: Option<RconSettings>,
+}
+
+/// The RCON port of a server, which the admin api executes commands on
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RconSettings {
+    /// The port defaults to 25575 if omitted
+    pub address: String,
+    pub password: String,
+
+    /// Commands broadcast to any of these groups are executed on the server
+    #[serde(default)]
+    pub groups: BTreeSet<String>,
+}
+
+/// The reason why the configuration could not be loaded
+#[derive(Debug, Clone)]
+pub struct SettingsError(pub String);
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Settings {
+    /// Load the configuration file.
+    /// A missing file results in the default configuration.
+    pub fn load(path: &Path) -> Result<Settings, SettingsError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(SettingsError(format!("Cannot read {}: {}", path.display(), error))),
+        };
+
+        let settings: Settings = toml::from_str(&content)
+            .map_err(|error| SettingsError(format!("Invalid configuration {}: {}", path.display(), error)))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), SettingsError> {
+        let mut names = HashSet::new();
+        for listener in &self.listeners {
+            if !names.insert(&listener.name) {
+                return Err(SettingsError(format!("The listener name '{}' is used more than once", listener.name)));
+            }
+            if let Some(query) = listener.query.as_ref().filter(|query| !listener.allows_host(&query.host)) {
+                return Err(SettingsError(format!("The listener '{}' cannot report '{}' over query, as it does not serve that hostname",
+                                                 listener.name, query.host)));
+            }
+            if let Some(bedrock) = &listener.bedrock {
+                if !listener.allows_host(&bedrock.host) {
+                    return Err(SettingsError(format!("The listener '{}' cannot list Bedrock players on '{}', as it does not serve that hostname",
+                                                     listener.name, bedrock.host)));
+                }
+                if let Err(error) = bedrock::parse_upstream(&bedrock.upstream) {
+                    return Err(SettingsError(format!("The Bedrock upstream of listener '{}' is invalid: {}", listener.name, error.0)));
+                }
+            }
+        }
+
+        if self.workers == Some(0) {
+            return Err(SettingsError("At least one worker is required".to_owned()));
+        }
+
+        if let Some(group) = self.commands.iter().flat_map(|commands| commands.undefined_groups()).next() {
+            return Err(SettingsError(format!("The command group '{}' is not defined", group)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for WebSettings {
+    fn default() -> Self {
+        WebSettings {
+            bind: "127.0.0.1:8080".parse().unwrap(),
+        }
+    }
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        AuditSettings {
+            log: PathBuf::from("audit.log"),
+        }
+    }
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        CaptureSettings {
+            directory: PathBuf::from("captures"),
+        }
+    }
+}
+
+fn default_listeners() -> Vec<ListenerSettings> {
+    vec![ListenerSettings {
+        name: "default".to_owned(),
+        bind: "0.0.0.0:25565".parse().unwrap(),
+        ipv6_only: false,
+        proxy_protocol: false,
+        hostnames: None,
+        nodelay: true,
+        keepalive: None,
+        reuse_port: false,
+        compression_threshold: None,
+        compression_level: CompressionLevel::Default,
+        splice: true,
+        limits: FrameLimits::default(),
+        query: None,
+        bedrock: None,
+    }]
+}
+
+/// Hostnames are compared with the lowercased hostname of the handshake
+fn lowercase_hostnames<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<HashSet<String>>, D::Error> {
+    let hostnames: Option<HashSet<String>> = Option::deserialize(deserializer)?;
+    Ok(hostnames.map(|hostnames| hostnames.iter().map(|host| host.to_ascii_lowercase()).collect()))
+}
+
+fn lowercase_hostname<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(String::deserialize(deserializer)?.to_ascii_lowercase())
+}
+
+fn default_max_players() -> usize {
+    20
+}
+
+fn default_bedrock_version() -> String {
+    "1.21.0".to_owned()
+}
+
+fn default_bedrock_protocol_version() -> u32 {
+    685
+}
+
+fn default_bedrock_name_prefix() -> String {
+    ".".to_owned()
+}
+
+fn default_bedrock_idle_timeout() -> u64 {
+    30
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames_are_lowercased() {
+        let settings: Settings = toml::from_str(r#"
+            [[listeners]]
+            name = "java"
+            bind = "0.0.0.0:25565"
+            hostnames = ["Play.MC.local"]
+        "#).unwrap();
+        assert!(settings.listeners[0].allows_host("play.mc.local"));
+    }
+
+    #[test]
+    fn reported_hostnames_are_lowercased() {
+        let settings: Settings = toml::from_str(r#"
+            [[listeners]]
+            name = "java"
+            bind = "0.0.0.0:25565"
+            hostnames = ["Play.MC.local"]
+            query = { host = "Play.MC.local" }
+            bedrock = { host = "PLAY.mc.local", upstream = "127.0.0.1" }
+        "#).unwrap();
+        settings.validate().unwrap();
+        assert_eq!(settings.listeners[0].query.as_ref().unwrap().host, "play.mc.local");
+        assert_eq!(settings.listeners[0].bedrock.as_ref().unwrap().host, "play.mc.local");
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use futures::future::{BoxFuture, FutureExt};
+use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+
+/// Port used by minecraft servers if neither the config nor a SRV record define one
+pub const DEFAULT_PORT: u16 = 25565;
+
+/// Prefix of the SRV records that the vanilla client looks up
+const SRV_PREFIX: &str = "_minecraft._tcp.";
+
+/// The address of an upstream server as configured by the user.
+///
+/// The host may either be an ip address or a domain name that
+/// gets resolved each time a connection to the upstream is established.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    pub host: String,
+
+    /// If no port is provided, the port is determined
+    /// by a SRV record just like the vanilla client does.
+    pub port: Option<u16>,
+}
+
+impl FromStr for Upstream {
+    type Err = ResolveError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = string.parse::<SocketAddr>() {
+            return Ok(Upstream {
+                host: address.ip().to_string(),
+                port: Some(address.port()),
+            });
+        }
+
+        if let Ok(ip) = string.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(Upstream { host: ip.to_string(), port: None });
+        }
+
+        let (host, port) = match string.rfind(':') {
+            Some(index) => {
+                let port = string[index + 1..].parse::<u16>()
+                    .map_err(|_| ResolveError(format!("'{}' has an invalid port", string)))?;
+                (&string[..index], Some(port))
+            }
+            None => (string, None),
+        };
+
+        if host.is_empty() || host.contains(':') {
+            return Err(ResolveError(format!("'{}' is not a valid upstream address", string)));
+        }
+
+        Ok(Upstream { host: host.to_owned(), port })
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) if self.host.contains(':') => write!(f, "[{}]:{}", self.host, port),
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// The reason why an upstream could not be resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveError(pub String);
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A target defined by a SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub target: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+/// The DNS queries required to resolve upstreams.
+///
+/// This abstraction allows to replace DNS lookups in tests.
+pub trait Resolver: Send + Sync {
+    /// Look up the SRV records of a name.
+    /// A name without records resolves to an empty list.
+    fn lookup_srv(&self, name: &str) -> BoxFuture<'static, Result<Vec<SrvTarget>, ResolveError>>;
+
+    /// Look up the A and AAAA records of a host
+    fn lookup_ip(&self, host: &str) -> BoxFuture<'static, Result<Vec<IpAddr>, ResolveError>>;
+}
+
+/// A [Resolver] querying real DNS servers
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    /// Create a resolver using the name servers of the operating system.
+    /// Falls back to public name servers if the system configuration cannot be read.
+    pub async fn from_system_conf() -> Result<DnsResolver, ResolveError> {
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf().await {
+            Ok(resolver) => resolver,
+            Err(_) => TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default()).await
+                .map_err(|error| ResolveError(error.to_string()))?,
+        };
+        Ok(DnsResolver { resolver })
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn lookup_srv(&self, name: &str) -> BoxFuture<'static, Result<Vec<SrvTarget>, ResolveError>> {
+        let resolver = self.resolver.clone();
+        let name = name.to_owned();
+        async move {
+            match resolver.srv_lookup(name.as_str()).await {
+                Ok(lookup) => Ok(lookup.iter()
+                    .map(|srv| SrvTarget {
+                        target: srv.target().to_utf8(),
+                        port: srv.port(),
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                    })
+                    .collect()),
+                Err(error) => match error.kind() {
+                    ResolveErrorKind::NoRecordsFound { .. } => Ok(vec![]),
+                    _ => Err(ResolveError(error.to_string())),
+                },
+            }
+        }.boxed()
+    }
+
+    fn lookup_ip(&self, host: &str) -> BoxFuture<'static, Result<Vec<IpAddr>, ResolveError>> {
+        let resolver = self.resolver.clone();
+        let host = host.to_owned();
+        async move {
+            let lookup = resolver.lookup_ip(host.as_str()).await
+                .map_err(|error| ResolveError(error.to_string()))?;
+            Ok(lookup.iter().collect())
+        }.boxed()
+    }
+}
+
+/// Resolves [Upstream]s to socket addresses, caching the results for a fixed time.
+pub struct UpstreamResolver {
+    resolver: Arc<dyn Resolver>,
+    ttl: Duration,
+    cache: Mutex<HashMap<Upstream, CacheEntry>>,
+}
+
+struct CacheEntry {
+    address: SocketAddr,
+    expires: Instant,
+}
+
+impl UpstreamResolver {
+    pub fn new(resolver: Arc<dyn Resolver>, ttl: Duration) -> UpstreamResolver {
+        UpstreamResolver {
+            resolver,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn resolve(&self, upstream: &Upstream) -> Result<SocketAddr, ResolveError> {
+        if let Ok(ip) = upstream.host.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, upstream.port.unwrap_or(DEFAULT_PORT)));
+        }
+
+        if let Some(address) = self.cached(upstream) {
+            return Ok(address);
+        }
+
+        let address = self.lookup(upstream).await?;
+        self.cache.lock().unwrap().insert(upstream.clone(), CacheEntry {
+            address,
+            expires: Instant::now() + self.ttl,
+        });
+        Ok(address)
+    }
+
+    fn cached(&self, upstream: &Upstream) -> Option<SocketAddr> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(upstream) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.address),
+            Some(_) => {
+                cache.remove(upstream);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn lookup(&self, upstream: &Upstream) -> Result<SocketAddr, ResolveError> {
+        let (host, port) = match upstream.port {
+            Some(port) => (upstream.host.clone(), port),
+            None => match self.lookup_srv(&upstream.host).await {
+                Some(srv) => (srv.target, srv.port),
+                None => (upstream.host.clone(), DEFAULT_PORT),
+            },
+        };
+
+        let ips = self.resolver.lookup_ip(&host).await?;
+        ips.into_iter().next()
+            .map(|ip| SocketAddr::new(ip, port))
+            .ok_or_else(|| ResolveError(format!("'{}' has no addresses", host)))
+    }
+
+    /// Find the preferred minecraft SRV record of a host.
+    ///
+    /// Like the vanilla client, a failed lookup is not an error
+    /// and the host itself gets used instead.
+    async fn lookup_srv(&self, host: &str) -> Option<SrvTarget> {
+        let mut targets = self.resolver.lookup_srv(&format!("{}{}", SRV_PREFIX, host)).await.ok()?;
+        targets.sort_by_key(|srv| (srv.priority, std::cmp::Reverse(srv.weight)));
+        targets.into_iter().next().map(|mut srv| {
+            if srv.target.ends_with('.') {
+                srv.target.pop();
+            }
+            srv
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::executor::block_on;
+    use futures::future::ready;
+
+    /// A resolver answering from static tables and counting its queries
+    #[derive(Default)]
+    struct StaticResolver {
+        srv: HashMap<String, Vec<SrvTarget>>,
+        ips: HashMap<String, Vec<IpAddr>>,
+        queries: AtomicUsize,
+    }
+
+    impl StaticResolver {
+        fn with_ip(mut self, host: &str, ip: &str) -> Self {
+            self.ips.entry(host.to_owned()).or_default().push(ip.parse().unwrap());
+            self
+        }
+
+        fn with_srv(mut self, name: &str, target: &str, port: u16, priority: u16) -> Self {
+            self.srv.entry(name.to_owned()).or_default().push(SrvTarget {
+                target: target.to_owned(),
+                port,
+                priority,
+                weight: 0,
+            });
+            self
+        }
+    }
+
+    impl Resolver for StaticResolver {
+        fn lookup_srv(&self, name: &str) -> BoxFuture<'static, Result<Vec<SrvTarget>, ResolveError>> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            ready(Ok(self.srv.get(name).cloned().unwrap_or_default())).boxed()
+        }
+
+        fn lookup_ip(&self, host: &str) -> BoxFuture<'static, Result<Vec<IpAddr>, ResolveError>> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            let result = self.ips.get(host).cloned()
+                .ok_or_else(|| ResolveError(format!("{} not found", host)));
+            ready(result).boxed()
+        }
+    }
+
+    fn resolve(resolver: &Arc<StaticResolver>, upstream: &str) -> Result<SocketAddr, ResolveError> {
+        let upstream_resolver = UpstreamResolver::new(resolver.clone(), Duration::from_secs(60));
+        block_on(upstream_resolver.resolve(&upstream.parse().unwrap()))
+    }
+
+    #[test]
+    fn parse_upstreams() {
+        assert_eq!("mc.example.com".parse(), Ok(Upstream { host: "mc.example.com".to_owned(), port: None }));
+        assert_eq!("mc.example.com:25570".parse(), Ok(Upstream { host: "mc.example.com".to_owned(), port: Some(25570) }));
+        assert_eq!("[::1]:25570".parse(), Ok(Upstream { host: "::1".to_owned(), port: Some(25570) }));
+        assert_eq!("::1".parse(), Ok(Upstream { host: "::1".to_owned(), port: None }));
+        assert!("mc.example.com:port".parse::<Upstream>().is_err());
+        assert!(":25565".parse::<Upstream>().is_err());
+        assert_eq!("[::1]:25570".parse::<Upstream>().unwrap().to_string(), "[::1]:25570");
+    }
+
+    #[test]
+    fn ip_literals_are_not_looked_up() {
+        let resolver = Arc::new(StaticResolver::default());
+        assert_eq!(resolve(&resolver, "10.0.0.1"), Ok("10.0.0.1:25565".parse().unwrap()));
+        assert_eq!(resolve(&resolver, "10.0.0.1:25570"), Ok("10.0.0.1:25570".parse().unwrap()));
+        assert_eq!(resolver.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn srv_record_is_preferred_without_port() {
+        let resolver = Arc::new(StaticResolver::default()
+            .with_srv("_minecraft._tcp.mc.example.com", "backup.example.com.", 25580, 20)
+            .with_srv("_minecraft._tcp.mc.example.com", "node.example.com.", 25570, 10)
+            .with_ip("node.example.com", "10.0.0.2")
+            .with_ip("mc.example.com", "10.0.0.1"));
+
+        assert_eq!(resolve(&resolver, "mc.example.com"), Ok("10.0.0.2:25570".parse().unwrap()));
+    }
+
+    #[test]
+    fn explicit_port_skips_srv_lookup() {
+        let resolver = Arc::new(StaticResolver::default()
+            .with_srv("_minecraft._tcp.mc.example.com", "node.example.com", 25570, 10)
+            .with_ip("mc.example.com", "10.0.0.1"));
+
+        assert_eq!(resolve(&resolver, "mc.example.com:25566"), Ok("10.0.0.1:25566".parse().unwrap()));
+        assert_eq!(resolver.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn missing_srv_record_falls_back_to_default_port() {
+        let resolver = Arc::new(StaticResolver::default()
+            .with_ip("mc.example.com", "10.0.0.1"));
+
+        assert_eq!(resolve(&resolver, "mc.example.com"), Ok("10.0.0.1:25565".parse().unwrap()));
+    }
+
+    #[test]
+    fn unknown_hosts_fail() {
+        let resolver = Arc::new(StaticResolver::default());
+        assert!(resolve(&resolver, "unknown.example.com:25565").is_err());
+    }
+
+    #[test]
+    fn results_are_cached() {
+        let resolver = Arc::new(StaticResolver::default()
+            .with_ip("mc.example.com", "10.0.0.1"));
+        let upstream_resolver = UpstreamResolver::new(resolver.clone(), Duration::from_secs(60));
+        let upstream = "mc.example.com:25565".parse().unwrap();
+
+        block_on(upstream_resolver.resolve(&upstream)).unwrap();
+        block_on(upstream_resolver.resolve(&upstream)).unwrap();
+        assert_eq!(resolver.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn expired_results_are_looked_up_again() {
+        let resolver = Arc::new(StaticResolver::default()
+            .with_ip("mc.example.com", "10.0.0.1"));
+        let upstream_resolver = UpstreamResolver::new(resolver.clone(), Duration::from_secs(0));
+        let upstream = "mc.example.com:25565".parse().unwrap();
+
+        block_on(upstream_resolver.resolve(&upstream)).unwrap();
+        block_on(upstream_resolver.resolve(&upstream)).unwrap();
+        assert_eq!(resolver.queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use actix::prelude::*;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::{Serialize, Deserialize};
+use crate::events::{Event, EventBus, Subscribe, Unsubscribe, Topic};
+
+/// Restrict the events delivered to a session.
+/// A missing set of topics or hosts matches all events.
+#[derive(Deserialize, Debug, Default)]
+struct EventFilter {
+    topics: Option<HashSet<Topic>>,
+    hosts: Option<HashSet<String>>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        let topic_matches = self.topics.as_ref()
+            .map_or(true, |topics| topics.contains(&event.topic()));
+
+        let host_matches = match (&self.hosts, event.host()) {
+            (Some(hosts), Some(host)) => hosts.contains(host),
+            _ => true,
+        };
+
+        topic_matches && host_matches
+    }
+}
+
+/// The initial filter may be supplied as comma separated query parameters,
+/// e.g. `/api/events?topics=players,health&hosts=a.mc.local`
+#[derive(Deserialize, Debug)]
+struct EventQuery {
+    topics: Option<String>,
+    hosts: Option<String>,
+}
+
+impl EventQuery {
+    fn into_filter(self) -> Result<EventFilter, serde_json::Error> {
+        let topics = match self.topics {
+            Some(topics) => Some(split_list(&topics)
+                .map(|topic| serde_json::from_value(serde_json::Value::String(topic)))
+                .collect::<Result<_, _>>()?),
+            None => None,
+        };
+
+        Ok(EventFilter {
+            topics,
+            hosts: self.hosts.map(|hosts| split_list(&hosts).collect()),
+        })
+    }
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.split(',').filter(|item| !item.is_empty()).map(str::to_owned)
+}
+
+/// Commands that a frontend may send over the websocket
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Command {
+    /// Replace the filter of this session
+    Subscribe(EventFilter),
+}
+
+#[derive(Serialize)]
+struct CommandError {
+    error: String,
+}
+
+/// A websocket session of an admin frontend, that receives [Event]s as json
+struct EventSession {
+    filter: EventFilter,
+    subscription: Option<usize>,
+}
+
+impl Actor for EventSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        EventBus::from_registry()
+            .send(Subscribe(ctx.address().recipient()))
+            .into_actor(self)
+            .map(|result, session, ctx| match result {
+                Ok(id) => session.subscription = Some(id),
+                Err(_) => ctx.stop(),
+            })
+            .wait(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(id) = self.subscription.take() {
+            EventBus::from_registry().do_send(Unsubscribe(id));
+        }
+    }
+}
+
+impl Handler<Event> for EventSession {
+    type Result = ();
+    fn handle(&mut self, event: Event, ctx: &mut Self::Context) {
+        if self.filter.matches(&event) {
+            if let Ok(json) = serde_json::to_string(&event) {
+                ctx.text(json);
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for EventSession {
+    fn handle(&mut self, message: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match message {
+            Ok(ws::Message::Ping(payload)) => ctx.pong(&payload),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<Command>(&text) {
+                Ok(Command::Subscribe(filter)) => self.filter = filter,
+                Err(error) => {
+                    let error = CommandError { error: error.to_string() };
+                    if let Ok(json) = serde_json::to_string(&error) {
+                        ctx.text(json);
+                    }
+                }
+            },
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
+
+#[get("/api/events")]
+pub async fn events(req: HttpRequest, stream: web::Payload, query: web::Query<EventQuery>) -> Result<HttpResponse, Error> {
+    let filter = query.into_inner().into_filter()
+        .map_err(|error| actix_web::error::ErrorBadRequest(error.to_string()))?;
+
+    ws::start(EventSession { filter, subscription: None }, &req, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use bytes::Bytes;
+    use futures::{Sink, SinkExt, Stream, StreamExt};
+    use crate::server_state::Health;
+
+    /// The test server runs in a system of its own, with its own event bus
+    async fn publish(event: web::Path<String>) -> HttpResponse {
+        let event = match event.as_str() {
+            "player_join" => Event::PlayerJoin { host: "a.test".to_owned(), player: "steve".to_owned(), address: "127.0.0.1:5000".to_owned() },
+            _ => Event::HealthChanged { host: "a.test".to_owned(), health: Health::Unhealthy, error: None },
+        };
+        event.publish();
+        HttpResponse::Ok().finish()
+    }
+
+    fn start_server() -> test::TestServer {
+        test::start(|| App::new().service(events).route("/publish/{event}", web::post().to(publish)))
+    }
+
+    async fn publish_on(server: &test::TestServer, event: &str) {
+        assert!(server.post(format!("/publish/{}", event)).send().await.unwrap().status().is_success());
+    }
+
+    /// The json of the next text frame
+    async fn next_json<S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin>(socket: &mut S) -> serde_json::Value {
+        loop {
+            match socket.next().await.unwrap().unwrap() {
+                ws::Frame::Text(text) => return serde_json::from_slice(&text).unwrap(),
+                ws::Frame::Pong(_) => continue,
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+    }
+
+    async fn send<S: Sink<ws::Message> + Unpin>(socket: &mut S, message: ws::Message) {
+        if socket.send(message).await.is_err() {
+            panic!("cannot send a message");
+        }
+    }
+
+    /// Messages are handled once the session subscribed to the event bus, so the pong confirms the subscription
+    async fn wait_subscribed<S>(socket: &mut S)
+        where S: Sink<ws::Message> + Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin {
+        send(socket, ws::Message::Ping(Bytes::new())).await;
+        match socket.next().await.unwrap().unwrap() {
+            ws::Frame::Pong(_) => {}
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn events_are_filtered_by_the_query() {
+        System::new("test").block_on(async {
+            let mut server = start_server();
+            let mut socket = server.ws_at("/api/events?topics=players&hosts=a.test").await.unwrap();
+            wait_subscribed(&mut socket).await;
+
+            publish_on(&server, "health_changed").await;
+            publish_on(&server, "player_join").await;
+            let event = next_json(&mut socket).await;
+            assert_eq!(event["type"], "player_join");
+            assert_eq!(event["player"], "steve");
+        });
+    }
+
+    #[test]
+    fn subscribing_replaces_the_filter() {
+        System::new("test").block_on(async {
+            let mut server = start_server();
+            let mut socket = server.ws_at("/api/events?topics=players").await.unwrap();
+            wait_subscribed(&mut socket).await;
+
+            send(&mut socket, ws::Message::Text(r#"{"action":"subscribe","topics":["health"]}"#.to_owned())).await;
+            wait_subscribed(&mut socket).await;
+            publish_on(&server, "player_join").await;
+            publish_on(&server, "health_changed").await;
+            assert_eq!(next_json(&mut socket).await["type"], "health_changed");
+
+            send(&mut socket, ws::Message::Text(r#"{"action":"unknown"}"#.to_owned())).await;
+            assert!(next_json(&mut socket).await["error"].is_string());
+        });
+    }
+
+    #[test]
+    fn unknown_topics_are_rejected() {
+        System::new("test").block_on(async {
+            let server = start_server();
+            let response = server.get("/api/events?topics=weather").send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        });
+    }
+}
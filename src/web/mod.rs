@@ -1,4 +1,7 @@
 mod routes;
 mod worker;
+mod events;
+mod error;
+mod validation;
 
 pub use routes::webserver_run;
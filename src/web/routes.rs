@@ -1,19 +1,64 @@
 use std::io;
-use std::sync::{RwLock, Arc};
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
-use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use futures::future::join_all;
+use serde::{Serialize, Deserialize, Deserializer};
+use actix::{Addr, SystemService};
 use actix_web::*;
 use actix_web::body::Body;
 use actix_web::web::HttpResponse;
+use actix_web::http::StatusCode;
+use actix_web::error::BlockingError;
 use actix_files::Files;
-use crate::server_state::{Configuration, ServerConfig};
+use crate::events::Event;
+use crate::sessions::{SessionRegistry, KickPlayer};
+use crate::server_state::{SharedConfiguration, ServerConfig, RconConfig, Health};
+use crate::audit::AuditRecord;
+use crate::rcon;
+use crate::net::rcon::MAX_COMMAND_LENGTH;
+use crate::web::events::events;
+use crate::web::error::ApiError;
+use crate::web::validation::{validate_hostname, validate_upstream, validate_rcon_address};
+use crate::listener::{ListenerManager, Reload};
+use crate::metrics;
+use crate::capture::CaptureTarget;
 
-type Conf = Arc<RwLock<Configuration>>;
+type Conf = Arc<SharedConfiguration>;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Server {
     domain: String,
     sockaddr: String,
+
+    /// The password is never sent back, so neither are the RCON settings
+    #[serde(default, skip_serializing)]
+    rcon: Option<RconBody>,
+}
+
+/// A partial update of the settings of a server
+#[derive(Deserialize, Debug)]
+struct ServerUpdate {
+    sockaddr: Option<String>,
+
+    /// Disables RCON if `null`
+    #[serde(default, deserialize_with = "present")]
+    rcon: Option<Option<RconBody>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RconBody {
+    address: String,
+    password: String,
+    #[serde(default)]
+    groups: BTreeSet<String>,
+}
+
+/// The RCON settings of a server without its password
+#[derive(Serialize, Deserialize, Debug)]
+struct RconStatus {
+    address: String,
+    groups: BTreeSet<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,45 +66,188 @@ struct ServerStatus {
     domain: String,
     sockaddr: String,
     players: Vec<String>,
+    health: Health,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rcon: Option<RconStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Ban {
+    player: String,
+    reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct KickRequest {
+    reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommandRequest {
+    command: String,
+}
+
+/// The outcome of a command executed on a server
+#[derive(Serialize, Debug)]
+struct CommandResponse {
+    host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+
+    /// Why the command could not be written to the audit log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audit_error: Option<String>,
 }
 
-pub async fn webserver_run(config: Conf) -> io::Result<()> {
+/// Tell a field that was set to `null` apart from an omitted one
+fn present<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+pub async fn webserver_run(config: Conf, listeners: Addr<ListenerManager>, bind: SocketAddr) -> io::Result<()> {
     let server = HttpServer::new(move || {
         App::new().data(config.clone())
+            .data(listeners.clone())
+            .app_data(json_config())
             .service(get_servers)
             .service(get_server)
             .service(post_server)
+            .service(put_server)
+            .service(patch_server)
             .service(delete_server)
+            .service(post_server_rcon)
+            .service(post_group_rcon)
+            .service(kick_player)
+            .service(get_bans)
+            .service(put_ban)
+            .service(delete_ban)
+            .service(events)
+            .service(get_captures)
+            .service(put_player_capture)
+            .service(delete_player_capture)
+            .service(put_host_capture)
+            .service(delete_host_capture)
+            .service(reload)
+            .service(get_metrics)
             .service(Files::new("/", "static/").index_file("index.html"))
     });
 
-    server.bind("127.0.0.1:8080")?.run().await
+    server.bind(bind)?.run().await
+}
+
+/// Malformed bodies are answered with an [ApiError] like any other invalid request
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|error, _req| {
+        ApiError::bad_request("invalid_body", error.to_string()).into()
+    })
 }
 
 #[post("/api/servers")]
-async fn post_server(config: web::Data<Conf>, body: web::Json<Server>) -> impl Responder {
-    match body.sockaddr.parse::<SocketAddr>() {
-        Ok(server_address) => {
-            let server = ServerConfig::new(server_address.clone());
-            config.write().unwrap().add_server(&body.domain, server);
-            HttpResponse::Created().json(Server {
-                domain: body.domain.clone(),
-                sockaddr: body.sockaddr.clone(),
-            })
-        }
-        Err(_error) => HttpResponse::BadRequest().body("Bad Socket Address"),
+async fn post_server(config: web::Data<Conf>, body: web::Json<Server>) -> Result<HttpResponse, ApiError> {
+    let domain = validate_hostname(&body.domain)?;
+    let resolver = config.load().resolver();
+    let upstream = validate_upstream(&body.sockaddr, &resolver).await?;
+    let rcon = match body.into_inner().rcon {
+        Some(rcon) => Some(validate_rcon(rcon, &config).await?),
+        None => None,
+    };
+
+    let mut config = config.write();
+    if config.get_server(&domain).is_some() {
+        return Err(ApiError::conflict(format!("A server for '{}' already exists", domain)));
+    }
+    let mut server = ServerConfig::new(upstream.clone());
+    server.rcon = rcon;
+    config.add_server(&domain, server);
+
+    Event::ServerAdded {
+        host: domain.clone(),
+        sockaddr: upstream.to_string(),
+    }.publish();
+    Ok(HttpResponse::Created().json(Server {
+        domain,
+        sockaddr: upstream.to_string(),
+        rcon: None,
+    }))
+}
+
+/// Replace the settings of a server.
+/// The domain of a server cannot be changed, since it identifies the server.
+#[put("/api/servers/{key}")]
+async fn put_server(host: web::Path<String>, config: web::Data<Conf>, body: web::Json<Server>) -> Result<HttpResponse, ApiError> {
+    let host = host.to_ascii_lowercase();
+    if validate_hostname(&body.domain)? != host {
+        return Err(ApiError::bad_request("domain_mismatch", "The domain of a server cannot be changed"));
+    }
+
+    let body = body.into_inner();
+    let update = ServerUpdate {
+        sockaddr: Some(body.sockaddr),
+        rcon: Some(body.rcon),
+    };
+    update_server(&host, &config, update).await
+}
+
+/// Change some settings of a server, leaving all omitted settings untouched
+#[patch("/api/servers/{key}")]
+async fn patch_server(host: web::Path<String>, config: web::Data<Conf>, body: web::Json<ServerUpdate>) -> Result<HttpResponse, ApiError> {
+    update_server(&host.to_ascii_lowercase(), &config, body.into_inner()).await
+}
+
+/// Apply the update in place, so that the players
+/// connected to the server are not disturbed.
+async fn update_server(host: &str, config: &Conf, update: ServerUpdate) -> Result<HttpResponse, ApiError> {
+    let resolver = config.load().resolver();
+    let upstream = match update.sockaddr {
+        Some(ref sockaddr) => Some(validate_upstream(sockaddr, &resolver).await?),
+        None => None,
+    };
+    let rcon = match update.rcon {
+        Some(Some(rcon)) => Some(Some(validate_rcon(rcon, config).await?)),
+        Some(None) => Some(None),
+        None => None,
+    };
+
+    let mut config = config.write();
+    let server = config.get_server_mut(host)
+        .ok_or_else(|| server_not_found(host))?;
+
+    let mut changed = false;
+    if let Some(upstream) = upstream.filter(|upstream| *upstream != server.upstream) {
+        // The health of the previous upstream says nothing about the new one
+        server.upstream = upstream;
+        server.health = Health::Unknown;
+        server.health_error = None;
+        changed = true;
     }
+    if let Some(rcon) = rcon.filter(|rcon| *rcon != server.rcon) {
+        server.rcon = rcon;
+        changed = true;
+    }
+
+    if changed {
+        Event::ServerUpdated {
+            host: host.to_owned(),
+            sockaddr: server.upstream.to_string(),
+        }.publish();
+    }
+    Ok(respond_with_server(host, server))
 }
 
 #[get("/api/servers")]
 async fn get_servers(config: web::Data<Conf>) -> impl Responder {
-    let config = config.read().unwrap();
+    let config = config.load();
     let server_list: Vec<_> = config.get_server_hosts().iter()
         .map(|server_socket| {
             let server_data = config.get_server(server_socket).unwrap();
             Server {
                 domain: (*server_socket).clone(),
                 sockaddr: server_data.upstream.to_string(),
+                rcon: None,
             }
         })
         .collect();
@@ -68,23 +256,27 @@ async fn get_servers(config: web::Data<Conf>) -> impl Responder {
 }
 
 #[delete("/api/servers/{key}")]
-async fn delete_server(host: web::Path<String>, config: web::Data<Conf>) -> impl Responder {
-    let server = config.write().unwrap().remove_server(&host);
-    if let Some(server) = server {
-        respond_with_server(&host, &server)
-    } else {
-        HttpResponse::NotFound().finish()
-    }
+async fn delete_server(host: web::Path<String>, config: web::Data<Conf>) -> Result<HttpResponse, ApiError> {
+    let host = host.to_ascii_lowercase();
+    let server = config.write().remove_server(&host)
+        .ok_or_else(|| server_not_found(&host))?;
+
+    Event::ServerRemoved { host: host.clone() }.publish();
+    Ok(respond_with_server(&host, &server))
 }
 
 #[get("/api/servers/{key}")]
-async fn get_server(host: web::Path<String>, config: web::Data<Conf>) -> impl Responder {
-    let config = config.read().unwrap();
-    if let Some(server) = config.get_server(&host) {
-        respond_with_server(&host, server)
-    } else {
-        HttpResponse::NotFound().finish()
-    }
+async fn get_server(host: web::Path<String>, config: web::Data<Conf>) -> Result<HttpResponse, ApiError> {
+    let host = host.to_ascii_lowercase();
+    let config = config.load();
+    let server = config.get_server(&host)
+        .ok_or_else(|| server_not_found(&host))?;
+
+    Ok(respond_with_server(&host, server))
+}
+
+fn server_not_found(host: &str) -> ApiError {
+    ApiError::not_found(format!("There is no server for '{}'", host))
 }
 
 fn respond_with_server(domain: &str, server: &ServerConfig) -> HttpResponse<Body> {
@@ -92,5 +284,420 @@ fn respond_with_server(domain: &str, server: &ServerConfig) -> HttpResponse<Body
         domain: domain.parse().unwrap(),
         sockaddr: server.upstream.to_string(),
         players: server.players.read().unwrap().clone(),
+        health: server.health,
+        health_error: server.health_error.clone(),
+        rcon: server.rcon.as_ref().map(|rcon| RconStatus {
+            address: rcon.address.to_string(),
+            groups: rcon.groups.clone(),
+        }),
+    })
+}
+
+async fn validate_rcon(rcon: RconBody, config: &Conf) -> Result<RconConfig, ApiError> {
+    let resolver = config.load().resolver();
+    let address = validate_rcon_address(&rcon.address, &resolver).await?;
+    Ok(RconConfig {
+        address,
+        password: rcon.password,
+        groups: rcon.groups,
+    })
+}
+
+/// Execute a command on a server through its RCON port
+#[post("/api/servers/{key}/rcon")]
+async fn post_server_rcon(host: web::Path<String>, request: HttpRequest, config: web::Data<Conf>,
+                          body: web::Json<CommandRequest>) -> Result<HttpResponse, ApiError> {
+    let host = host.to_ascii_lowercase();
+    let rcon = config.load().get_server(&host)
+        .ok_or_else(|| server_not_found(&host))?
+        .rcon.clone()
+        .ok_or_else(|| ApiError::new(StatusCode::CONFLICT, "rcon_disabled", format!("RCON is not configured for '{}'", host)))?;
+    let command = validate_command(&body.command)?;
+
+    let response = execute_command(&config, &request, host, rcon, command).await;
+    match response.error {
+        None => Ok(HttpResponse::Ok().json(response)),
+        Some(error) => {
+            let message = match response.audit_error {
+                Some(audit_error) => format!("{}. {}", error, audit_error),
+                None => error,
+            };
+            Err(ApiError::new(StatusCode::BAD_GATEWAY, "rcon_failed", message))
+        }
+    }
+}
+
+/// Execute a command on all servers of a group at once.
+/// Responds with the outcome on each server, even if some of them failed.
+#[post("/api/groups/{group}/rcon")]
+async fn post_group_rcon(group: web::Path<String>, request: HttpRequest, config: web::Data<Conf>,
+                         body: web::Json<CommandRequest>) -> Result<HttpResponse, ApiError> {
+    let servers: Vec<_> = config.load().get_rcon_group(&group).into_iter()
+        .map(|(host, rcon)| (host.clone(), rcon.clone()))
+        .collect();
+    if servers.is_empty() {
+        return Err(ApiError::not_found(format!("No server with RCON belongs to the group '{}'", group)));
+    }
+    let command = validate_command(&body.command)?;
+
+    let responses = join_all(servers.into_iter()
+        .map(|(host, rcon)| execute_command(&config, &request, host, rcon, command))).await;
+    Ok(HttpResponse::Ok().json(responses))
+}
+
+fn validate_command(command: &str) -> Result<&str, ApiError> {
+    let command = command.trim();
+    if command.is_empty() || command.len() > MAX_COMMAND_LENGTH || command.contains('\0') {
+        let message = format!("Commands must have between 1 and {} characters without null characters", MAX_COMMAND_LENGTH);
+        return Err(ApiError::bad_request("invalid_command", message));
+    }
+    Ok(command)
+}
+
+/// Execute a command and add it to the audit log
+async fn execute_command(config: &Conf, request: &HttpRequest, host: String, rcon: RconConfig, command: &str) -> CommandResponse {
+    let resolver = config.load().resolver();
+    let result = match resolver.resolve(&rcon.address).await {
+        Ok(address) => rcon::execute(address, &rcon.password, command).await.map_err(|error| error.to_string()),
+        Err(error) => Err(format!("Cannot resolve '{}': {}", rcon.address, error)),
+    };
+
+    let audit = config.load().audit().clone();
+    let (audited_host, audited_command) = (host.clone(), command.to_owned());
+    let requested_by = request.peer_addr().map(|address| address.to_string());
+    let error = result.as_ref().err().cloned();
+    // Writing to the file blocks, so it is done on the thread pool of actix
+    let audited = web::block(move || audit.record(&AuditRecord {
+        host: &audited_host,
+        command: &audited_command,
+        requested_by,
+        error,
+    }).map_err(|error| format!("Cannot write to the audit log {}: {}", audit.path().display(), error))).await;
+    let audit_error = match audited {
+        Ok(()) => None,
+        Err(BlockingError::Error(error)) => Some(error),
+        Err(BlockingError::Canceled) => Some("Writing to the audit log was canceled".to_owned()),
+    };
+
+    let (response, error) = match result {
+        Ok(response) => (Some(response), None),
+        Err(error) => (None, Some(error)),
+    };
+    CommandResponse { host, response, error, audit_error }
+}
+
+#[post("/api/players/{name}/kick")]
+async fn kick_player(name: web::Path<String>, body: web::Json<KickRequest>) -> Result<HttpResponse, ApiError> {
+    let kick = KickPlayer {
+        name: name.clone(),
+        reason: body.reason.clone(),
+    };
+
+    match SessionRegistry::from_registry().send(kick).await {
+        Ok(Some(host)) => {
+            Event::PlayerKicked {
+                host,
+                player: name.into_inner(),
+                reason: body.into_inner().reason,
+            }.publish();
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(None) => Err(ApiError::not_found(format!("'{}' is not online", name))),
+        Err(_) => Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "The session registry is not available")),
+    }
+}
+
+#[get("/api/bans")]
+async fn get_bans(config: web::Data<Conf>) -> impl Responder {
+    let config = config.load();
+    let bans: Vec<_> = config.get_bans().iter()
+        .map(|(player, reason)| Ban {
+            player: player.clone(),
+            reason: reason.clone(),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(&bans)
+}
+
+/// Ban a player and kick them if they are currently online
+#[put("/api/bans/{name}")]
+async fn put_ban(name: web::Path<String>, config: web::Data<Conf>, body: web::Json<KickRequest>) -> impl Responder {
+    let reason = body.into_inner().reason;
+    config.write().add_ban(&name, reason.clone());
+    Event::PlayerBanned {
+        player: name.clone(),
+        reason: reason.clone(),
+    }.publish();
+
+    SessionRegistry::from_registry().do_send(KickPlayer {
+        name: name.clone(),
+        reason: reason.clone(),
+    });
+
+    HttpResponse::Ok().json(Ban {
+        player: name.into_inner(),
+        reason,
     })
 }
+
+#[delete("/api/bans/{name}")]
+async fn delete_ban(name: web::Path<String>, config: web::Data<Conf>) -> Result<HttpResponse, ApiError> {
+    let reason = config.write().remove_ban(&name)
+        .ok_or_else(|| ApiError::not_found(format!("'{}' is not banned", name)))?;
+
+    Event::PlayerUnbanned { player: name.clone() }.publish();
+    Ok(HttpResponse::Ok().json(Ban {
+        player: name.into_inner(),
+        reason,
+    }))
+}
+
+#[get("/api/captures")]
+async fn get_captures(config: web::Data<Conf>) -> impl Responder {
+    let config = config.load();
+    let captures: Vec<_> = config.captures().targets().collect();
+    HttpResponse::Ok().json(&captures)
+}
+
+/// Record the sessions of a player, starting with their next login
+#[put("/api/captures/players/{name}")]
+async fn put_player_capture(name: web::Path<String>, config: web::Data<Conf>) -> impl Responder {
+    start_capture(&config, CaptureTarget::Player(name.into_inner()))
+}
+
+#[delete("/api/captures/players/{name}")]
+async fn delete_player_capture(name: web::Path<String>, config: web::Data<Conf>) -> Result<HttpResponse, ApiError> {
+    stop_capture(&config, CaptureTarget::Player(name.into_inner()))
+}
+
+/// Record the sessions of all players connecting to a hostname, starting with the next login
+#[put("/api/captures/hosts/{host}")]
+async fn put_host_capture(host: web::Path<String>, config: web::Data<Conf>) -> Result<HttpResponse, ApiError> {
+    let host = validate_hostname(&host)?;
+    Ok(start_capture(&config, CaptureTarget::Host(host)))
+}
+
+#[delete("/api/captures/hosts/{host}")]
+async fn delete_host_capture(host: web::Path<String>, config: web::Data<Conf>) -> Result<HttpResponse, ApiError> {
+    stop_capture(&config, CaptureTarget::Host(host.to_ascii_lowercase()))
+}
+
+fn start_capture(config: &Conf, target: CaptureTarget) -> HttpResponse {
+    if config.write().captures_mut().start(target.clone()) {
+        HttpResponse::Created().json(target)
+    } else {
+        HttpResponse::Ok().json(target)
+    }
+}
+
+fn stop_capture(config: &Conf, target: CaptureTarget) -> Result<HttpResponse, ApiError> {
+    if config.write().captures_mut().stop(&target) {
+        Ok(HttpResponse::Ok().json(target))
+    } else {
+        Err(ApiError::not_found("No capture exists for this target"))
+    }
+}
+
+/// Read the configuration file again and apply its listeners
+#[post("/api/reload")]
+async fn reload(listeners: web::Data<Addr<ListenerManager>>) -> Result<HttpResponse, ApiError> {
+    match listeners.send(Reload).await {
+        Ok(Ok(())) => Ok(HttpResponse::NoContent().finish()),
+        Ok(Err(error)) => Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "reload_failed", error)),
+        Err(_) => Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", "The listener manager is not available")),
+    }
+}
+
+#[get("/metrics")]
+async fn get_metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use actix::prelude::*;
+    use actix_web::test::{self, TestRequest};
+    use futures::future::{ready, BoxFuture, FutureExt};
+    use serde_json::{json, Value};
+    use crate::events::{EventBus, Subscribe, Unsubscribe};
+    use crate::audit::AuditLog;
+    use crate::server_state::Configuration;
+    use crate::upstream::{ResolveError, Resolver, SrvTarget, UpstreamResolver};
+
+    /// Upstreams are IP addresses, so no DNS queries are made
+    struct NoDns;
+
+    impl Resolver for NoDns {
+        fn lookup_srv(&self, _name: &str) -> BoxFuture<'static, Result<Vec<SrvTarget>, ResolveError>> {
+            ready(Ok(vec![])).boxed()
+        }
+
+        fn lookup_ip(&self, host: &str) -> BoxFuture<'static, Result<Vec<IpAddr>, ResolveError>> {
+            ready(Err(ResolveError(format!("{} not found", host)))).boxed()
+        }
+    }
+
+    /// Collects the events published while a test runs
+    #[derive(Default)]
+    struct Recorder(Vec<Event>);
+
+    impl Actor for Recorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Event> for Recorder {
+        type Result = ();
+        fn handle(&mut self, event: Event, _ctx: &mut Self::Context) {
+            self.0.push(event);
+        }
+    }
+
+    struct Recorded;
+    impl Message for Recorded { type Result = Vec<Event>; }
+
+    impl Handler<Recorded> for Recorder {
+        type Result = MessageResult<Recorded>;
+        fn handle(&mut self, _recorded: Recorded, _ctx: &mut Self::Context) -> Self::Result {
+            MessageResult(self.0.drain(..).collect())
+        }
+    }
+
+    /// The events published so far, which the bus has handed on once it handled a later message
+    async fn recorded(recorder: &Addr<Recorder>, subscription: usize) -> Vec<Event> {
+        EventBus::from_registry().send(Unsubscribe(subscription)).await.unwrap();
+        recorder.send(Recorded).await.unwrap()
+    }
+
+    fn config() -> Conf {
+        let resolver = Arc::new(UpstreamResolver::new(Arc::new(NoDns), Duration::from_secs(60)));
+        let mut config = Configuration::new(resolver);
+        let mut server = ServerConfig::new("127.0.0.1:25570".parse().unwrap());
+        server.health = Health::Healthy;
+        config.add_server("a.test", server);
+        config.add_server("b.test", ServerConfig::new("127.0.0.1:25571".parse().unwrap()));
+        Arc::new(SharedConfiguration::new(config))
+    }
+
+    /// Send a request to the server routes, returning the status and the json body of the response
+    async fn call(config: &Conf, request: TestRequest, body: Value) -> (StatusCode, Value) {
+        let mut app = test::init_service(App::new()
+            .data(config.clone())
+            .app_data(json_config())
+            .service(post_server)
+            .service(put_server)
+            .service(patch_server)
+            .service(post_server_rcon)
+            .service(post_group_rcon)).await;
+        let response = test::call_service(&mut app, request.set_json(&body).to_request()).await;
+        let status = response.status();
+        (status, serde_json::from_slice(&test::read_body(response).await).unwrap())
+    }
+
+    fn assert_error(response: (StatusCode, Value), status: StatusCode, error: &str) {
+        assert_eq!(response.0, status, "{}", response.1);
+        assert_eq!(response.1["error"], error);
+        assert!(response.1["message"].is_string());
+    }
+
+    #[test]
+    fn invalid_updates_are_rejected() {
+        System::new("test").block_on(async {
+            let config = config();
+            let put = || TestRequest::put().uri("/api/servers/a.test");
+            assert_error(call(&config, put(), json!({"domain": "a_test", "sockaddr": "127.0.0.1:25572"})).await,
+                         StatusCode::BAD_REQUEST, "invalid_hostname");
+            assert_error(call(&config, put(), json!({"domain": "b.test", "sockaddr": "127.0.0.1:25572"})).await,
+                         StatusCode::BAD_REQUEST, "domain_mismatch");
+            assert_error(call(&config, put(), json!({"domain": "a.test", "sockaddr": "127.0.0.1:port"})).await,
+                         StatusCode::BAD_REQUEST, "invalid_upstream");
+            assert_error(call(&config, put(), json!({"domain": "a.test"})).await,
+                         StatusCode::BAD_REQUEST, "invalid_body");
+
+            let patch = TestRequest::patch().uri("/api/servers/a.test");
+            assert_error(call(&config, patch, json!({"rcon": {"address": "127.0.0.1:port", "password": "secret"}})).await,
+                         StatusCode::BAD_REQUEST, "invalid_rcon_address");
+            assert_eq!(config.load().get_server("a.test").unwrap().upstream.to_string(), "127.0.0.1:25570");
+        });
+    }
+
+    #[test]
+    fn unknown_and_existing_servers_are_reported() {
+        System::new("test").block_on(async {
+            let config = config();
+            let put = TestRequest::put().uri("/api/servers/c.test");
+            assert_error(call(&config, put, json!({"domain": "c.test", "sockaddr": "127.0.0.1:25572"})).await,
+                         StatusCode::NOT_FOUND, "not_found");
+            let patch = TestRequest::patch().uri("/api/servers/C.test");
+            let response = call(&config, patch, json!({"sockaddr": "127.0.0.1:25572"})).await;
+            assert_eq!(response.1["message"], "There is no server for 'c.test'");
+            assert_error(response, StatusCode::NOT_FOUND, "not_found");
+
+            let post = TestRequest::post().uri("/api/servers");
+            assert_error(call(&config, post, json!({"domain": "A.test", "sockaddr": "127.0.0.1:25572"})).await,
+                         StatusCode::CONFLICT, "conflict");
+            assert!(config.load().get_server("c.test").is_none());
+        });
+    }
+
+    #[test]
+    fn only_changes_are_published() {
+        System::new("test").block_on(async {
+            let config = config();
+            let recorder = Recorder::default().start();
+            let subscription = EventBus::from_registry().send(Subscribe(recorder.clone().recipient())).await.unwrap();
+            let patch = || TestRequest::patch().uri("/api/servers/a.test");
+
+            let (status, server) = call(&config, patch(), json!({"sockaddr": "127.0.0.1:25570"})).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(server["health"], "healthy");
+            let (_, server) = call(&config, patch(), json!({"rcon": {"address": "127.0.0.1:25575", "password": "secret"}})).await;
+            assert_eq!((&server["health"], &server["rcon"]["address"]), (&json!("healthy"), &json!("127.0.0.1:25575")));
+            let put = TestRequest::put().uri("/api/servers/a.test");
+            let (_, server) = call(&config, put, json!({"domain": "a.test", "sockaddr": "127.0.0.1:25572"})).await;
+            assert_eq!((&server["sockaddr"], &server["health"]), (&json!("127.0.0.1:25572"), &json!("unknown")));
+            assert!(server.get("rcon").is_none());
+
+            let published: Vec<_> = recorded(&recorder, subscription).await.into_iter()
+                .map(|event| match event {
+                    Event::ServerUpdated { sockaddr, .. } => sockaddr,
+                    event => panic!("unexpected event {:?}", event),
+                })
+                .collect();
+            assert_eq!(published, vec!["127.0.0.1:25570", "127.0.0.1:25572"]);
+        });
+    }
+
+    #[test]
+    fn audit_failures_are_reported() {
+        System::new("test").block_on(async {
+            let config = config();
+            let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let mut writer = config.write();
+            // A directory cannot be appended to
+            writer.set_audit_log(AuditLog::new(std::env::temp_dir()));
+            writer.get_server_mut("a.test").unwrap().rcon = Some(RconConfig {
+                address: closed.to_string().parse().unwrap(),
+                password: "secret".to_owned(),
+                groups: vec!["lobby".to_owned()].into_iter().collect(),
+            });
+            drop(writer);
+
+            let post = TestRequest::post().uri("/api/groups/lobby/rcon");
+            let (status, responses) = call(&config, post, json!({"command": "list"})).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(responses[0]["error"].is_string());
+            assert!(responses[0]["audit_error"].as_str().unwrap().starts_with("Cannot write to the audit log"), "{}", responses);
+
+            let post = TestRequest::post().uri("/api/servers/a.test/rcon");
+            let (status, response) = call(&config, post, json!({"command": "list"})).await;
+            assert_eq!(status, StatusCode::BAD_GATEWAY);
+            assert!(response["message"].as_str().unwrap().contains("Cannot write to the audit log"), "{}", response);
+        });
+    }
+}
@@ -0,0 +1,68 @@
+use crate::upstream::{Upstream, UpstreamResolver};
+use crate::rcon;
+use crate::web::error::ApiError;
+
+const MAX_HOSTNAME_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Check that a hostname is syntactically valid and normalize it to lowercase
+pub fn validate_hostname(hostname: &str) -> Result<String, ApiError> {
+    let invalid = |reason: &str| ApiError::bad_request("invalid_hostname", format!("'{}' is not a valid hostname: {}", hostname, reason));
+
+    if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LENGTH {
+        return Err(invalid("length must be between 1 and 253 characters"));
+    }
+
+    for label in hostname.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return Err(invalid("labels must be between 1 and 63 characters"));
+        }
+
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(invalid("only letters, digits and '-' are allowed"));
+        }
+
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid("labels must not start or end with '-'"));
+        }
+    }
+
+    Ok(hostname.to_ascii_lowercase())
+}
+
+/// Parse an upstream and check that it can be resolved
+pub async fn validate_upstream(upstream: &str, resolver: &UpstreamResolver) -> Result<Upstream, ApiError> {
+    let upstream = upstream.parse::<Upstream>()
+        .map_err(|error| ApiError::bad_request("invalid_upstream", error.to_string()))?;
+
+    resolver.resolve(&upstream).await
+        .map_err(|error| ApiError::bad_request("unresolvable_upstream", format!("Cannot resolve '{}': {}", upstream, error)))?;
+
+    Ok(upstream)
+}
+
+/// Parse the RCON address of a server and check that it can be resolved
+pub async fn validate_rcon_address(address: &str, resolver: &UpstreamResolver) -> Result<Upstream, ApiError> {
+    let address = rcon::parse_address(address)
+        .map_err(|error| ApiError::bad_request("invalid_rcon_address", error.to_string()))?;
+
+    resolver.resolve(&address).await
+        .map_err(|error| ApiError::bad_request("unresolvable_rcon_address", format!("Cannot resolve '{}': {}", address, error)))?;
+
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames_are_checked_and_lowercased() {
+        assert_eq!(validate_hostname("Play.MC-1.local").unwrap(), "play.mc-1.local");
+        let too_long = format!("{}.test", "a".repeat(64));
+        for hostname in &["", "a..test", "a_b.test", "-a.test", "a-.test", too_long.as_str()] {
+            let error = validate_hostname(hostname).unwrap_err();
+            assert!(error.to_string().starts_with("invalid_hostname: "), "{}", error);
+        }
+    }
+}
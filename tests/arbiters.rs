@@ -0,0 +1,86 @@
+//! Sessions running on a pool of arbiters
+
+mod support;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use mineroute::extensions::{Extension, PacketAction, SessionInfo};
+use mineroute::net::{PacketClientEnum, PacketServerEnum};
+use support::*;
+
+/// Records the threads that the packets of each player were handled on
+#[derive(Default)]
+struct Threads {
+    client: Mutex<Vec<(String, ThreadId)>>,
+    server: Mutex<Vec<(String, ThreadId)>>,
+}
+
+impl Threads {
+    fn record(threads: &Mutex<Vec<(String, ThreadId)>>, session: &SessionInfo) {
+        let player = session.player.clone().unwrap_or_default();
+        threads.lock().unwrap().push((player, thread::current().id()));
+    }
+
+    fn of(threads: &Mutex<Vec<(String, ThreadId)>>, player: &str) -> HashSet<ThreadId> {
+        threads.lock().unwrap().iter()
+            .filter(|(name, _)| name == player)
+            .map(|(_, thread)| *thread)
+            .collect()
+    }
+}
+
+impl Extension for Threads {
+    fn name(&self) -> &str {
+        "threads"
+    }
+
+    fn intercepts_play(&self) -> bool {
+        true
+    }
+
+    fn on_client_packet(&self, session: &SessionInfo, _packet: &PacketClientEnum) -> PacketAction<PacketClientEnum> {
+        Threads::record(&self.client, session);
+        PacketAction::Forward
+    }
+
+    fn on_server_packet(&self, session: &SessionInfo, _packet: &PacketServerEnum) -> PacketAction<PacketServerEnum> {
+        Threads::record(&self.server, session);
+        PacketAction::Forward
+    }
+}
+
+#[test]
+fn both_legs_of_a_session_share_an_arbiter() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start_pooled(listener(), &[("a.test", backend.address)], 2).await;
+        let threads = Arc::new(Threads::default());
+        proxy.config.write().register_extension(threads.clone());
+
+        let mut clients = vec![];
+        for name in &["steve", "alex"] {
+            let mut client = FakeClient::connect(proxy.address).await;
+            client.login("a.test", name).await.unwrap();
+            backend.next_event().await;
+            backend.next_event().await;
+
+            client.send_play(0x0b, &[1]).await;
+            assert!(matches!(backend.next_event().await, Some(BackendEvent::Play(_))));
+            clients.push(client);
+        }
+
+        let mut session_threads = vec![];
+        for name in &["steve", "alex"] {
+            let client = Threads::of(&threads.client, name);
+            assert_eq!(client.len(), 1);
+            assert_eq!(client, Threads::of(&threads.server, name));
+            assert!(!client.contains(&thread::current().id()));
+            session_threads.extend(client);
+        }
+
+        // Connections are handed to the arbiters in turn
+        assert_ne!(session_threads[0], session_threads[1]);
+        assert_eq!(*proxy.config.load().get_server("a.test").unwrap().players.read().unwrap(), vec!["steve".to_owned(), "alex".to_owned()]);
+    });
+}
@@ -0,0 +1,231 @@
+//! Bedrock Edition clients relayed over UDP to a fake Bedrock server
+
+mod support;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use actix::SystemService;
+use bytes::{BufMut, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::time::{delay_for, timeout};
+use mineroute::net::bedrock::GAME_PACKET;
+use mineroute::net::raknet::{UnconnectedPing, UnconnectedPong, MAGIC, OPEN_CONNECTION_REQUEST_1};
+use mineroute::sessions::{KickPlayer, SessionRegistry};
+use mineroute::settings::{BedrockSettings, ListenerSettings};
+use support::*;
+
+/// How long a test waits for a datagram before giving up
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An address on which no UDP socket is bound
+async fn free_udp_address() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.local_addr().unwrap()
+}
+
+async fn bedrock_listener(upstream: SocketAddr, idle_timeout: u64) -> ListenerSettings {
+    ListenerSettings {
+        bedrock: Some(BedrockSettings {
+            bind: Some(free_udp_address().await),
+            host: "a.test".to_owned(),
+            upstream: upstream.to_string(),
+            motd: Some("A relayed server".to_owned()),
+            sub_motd: "mineroute".to_owned(),
+            max_players: 40,
+            version: "1.21.0".to_owned(),
+            protocol_version: 685,
+            idle_timeout,
+            name_prefix: ".".to_owned(),
+        }),
+        ..listener()
+    }
+}
+
+/// A Bedrock server echoing every datagram back to its sender
+async fn echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let (mut receiver, mut sender) = socket.split();
+    actix::spawn(async move {
+        let mut datagram = vec![0; 2048];
+        while let Ok((size, client)) = receiver.recv_from(&mut datagram).await {
+            let _ = sender.send_to(&datagram[..size], &client).await;
+        }
+    });
+    address
+}
+
+async fn client(proxy: SocketAddr) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(proxy).await.unwrap();
+    socket
+}
+
+async fn receive(socket: &mut UdpSocket) -> Option<Vec<u8>> {
+    let mut datagram = vec![0; 2048];
+    let size = timeout(RECEIVE_TIMEOUT, socket.recv(&mut datagram)).await.ok()?.unwrap();
+    datagram.truncate(size);
+    Some(datagram)
+}
+
+fn connection_request() -> Vec<u8> {
+    let mut datagram = vec![OPEN_CONNECTION_REQUEST_1];
+    datagram.extend_from_slice(&MAGIC);
+    datagram.push(11); // RakNet protocol version
+    datagram.resize(1400, 0); // Padding probing the maximum transfer unit
+    datagram
+}
+
+fn encode_base64_url(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// A connected datagram with one frame carrying an uncompressed batch with the Login packet of a player
+fn login_datagram(name: &str) -> Vec<u8> {
+    let claims = format!(r#"{{"extraData":{{"displayName":"{}","XUID":"2"}}}}"#, name);
+    let chain = format!(r#"{{"chain":["e30.{}.c2ln"]}}"#, encode_base64_url(claims.as_bytes()));
+
+    let mut request = BytesMut::new();
+    request.put_i32_le(chain.len() as i32);
+    request.put_slice(chain.as_bytes());
+    request.put_i32_le(0);
+
+    let mut packet = vec![0x01]; // Login
+    packet.put_i32(685);
+    packet.push(0x80 | (request.len() & 0x7f) as u8);
+    packet.push((request.len() >> 7) as u8);
+    packet.extend_from_slice(&request);
+
+    let mut body = vec![GAME_PACKET, 0xff, 0x80 | (packet.len() & 0x7f) as u8, (packet.len() >> 7) as u8];
+    body.extend_from_slice(&packet);
+
+    let mut datagram = vec![0x84, 0, 0, 0];
+    datagram.push(2 << 5); // Reliable
+    datagram.put_u16((body.len() * 8) as u16);
+    datagram.extend_from_slice(&[0, 0, 0]); // Message index
+    datagram.extend_from_slice(&body);
+    datagram
+}
+
+/// Wait until the players listed on a.test are the expected ones
+async fn expect_players(proxy: &Proxy, expected: &[&str]) {
+    for _ in 0..100 {
+        if *proxy.config.load().get_server("a.test").unwrap().players.read().unwrap() == expected {
+            return;
+        }
+        delay_for(Duration::from_millis(50)).await;
+    }
+    panic!("the players of a.test are {:?}", proxy.config.load().get_server("a.test").unwrap().players.read().unwrap());
+}
+
+#[test]
+fn pings_are_answered_with_the_configured_description() {
+    run(async move {
+        let listener = bedrock_listener(closed_address().await, 30).await;
+        let bind = listener.bedrock.as_ref().unwrap().bind.unwrap();
+        let proxy = Proxy::start_pooled(listener, &[("a.test", closed_address().await)], 1).await;
+        proxy.config.load().get_server("a.test").unwrap().add_player("steve".to_owned());
+
+        let mut client = client(bind).await;
+        let mut ping = BytesMut::new();
+        UnconnectedPing { time: 1234, client_guid: 5 }.write(&mut ping);
+        client.send(&ping).await.unwrap();
+
+        let pong = UnconnectedPong::read(&receive(&mut client).await.unwrap()).unwrap();
+        assert_eq!(pong.time, 1234);
+        let fields: Vec<&str> = pong.motd.split(';').collect();
+        assert_eq!(&fields[..6], &["MCPE", "A relayed server", "685", "1.21.0", "1", "40"]);
+        assert_eq!(fields[6], pong.server_guid.to_string());
+        assert_eq!(fields[7], "mineroute");
+        assert_eq!(fields[10], bind.port().to_string());
+    });
+}
+
+#[test]
+fn datagrams_are_relayed_both_ways() {
+    run(async move {
+        let listener = bedrock_listener(echo_server().await, 30).await;
+        let bind = listener.bedrock.as_ref().unwrap().bind.unwrap();
+        let _proxy = Proxy::start_pooled(listener, &[("a.test", closed_address().await)], 1).await;
+
+        let mut client = client(bind).await;
+        client.send(&connection_request()).await.unwrap();
+        assert_eq!(receive(&mut client).await.unwrap(), connection_request());
+
+        client.send(&[0x84, 1, 2, 3]).await.unwrap();
+        assert_eq!(receive(&mut client).await.unwrap(), vec![0x84, 1, 2, 3]);
+    });
+}
+
+#[test]
+fn datagrams_without_a_session_are_dropped() {
+    run(async move {
+        let listener = bedrock_listener(echo_server().await, 30).await;
+        let bind = listener.bedrock.as_ref().unwrap().bind.unwrap();
+        let _proxy = Proxy::start_pooled(listener, &[("a.test", closed_address().await)], 1).await;
+
+        let mut client = client(bind).await;
+        client.send(&[0x84, 1, 2, 3]).await.unwrap();
+        let mut datagram = vec![0; 2048];
+        assert!(timeout(Duration::from_millis(300), client.recv(&mut datagram)).await.is_err());
+    });
+}
+
+#[test]
+fn players_are_listed_until_their_session_expires() {
+    run(async move {
+        let listener = bedrock_listener(echo_server().await, 1).await;
+        let bind = listener.bedrock.as_ref().unwrap().bind.unwrap();
+        let proxy = Proxy::start_pooled(listener, &[("a.test", closed_address().await)], 1).await;
+
+        let mut client = client(bind).await;
+        client.send(&connection_request()).await.unwrap();
+        receive(&mut client).await.unwrap();
+        client.send(&login_datagram("Steve Bedrock")).await.unwrap();
+        assert_eq!(receive(&mut client).await.unwrap(), login_datagram("Steve Bedrock"));
+        expect_players(&proxy, &[".Steve Bedrock"]).await;
+
+        // Neither the client nor the upstream send anything anymore
+        expect_players(&proxy, &[]).await;
+    });
+}
+
+#[test]
+fn names_of_online_players_are_refused() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let mut listener = bedrock_listener(echo_server().await, 30).await;
+        let bind = listener.bedrock.as_ref().unwrap().bind.unwrap();
+        listener.bedrock.as_mut().unwrap().name_prefix = String::new();
+        let proxy = Proxy::start_pooled(listener, &[("a.test", backend.address)], 1).await;
+
+        let mut java = FakeClient::connect(proxy.address).await;
+        java.login("a.test", "steve").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+        expect_players(&proxy, &["steve"]).await;
+
+        // Without a prefix the Bedrock player claims the name of the Java player
+        let mut client = client(bind).await;
+        client.send(&connection_request()).await.unwrap();
+        receive(&mut client).await.unwrap();
+        client.send(&login_datagram("steve")).await.unwrap();
+        let mut datagram = vec![0; 2048];
+        assert!(timeout(Duration::from_millis(300), client.recv(&mut datagram)).await.is_err());
+        expect_players(&proxy, &["steve"]).await;
+
+        // Kicks still reach the Java player
+        let kick = KickPlayer { name: "steve".to_owned(), reason: "Kicked".to_owned() };
+        assert_eq!(SessionRegistry::from_registry().send(kick).await.unwrap().as_deref(), Some("a.test"));
+        expect_players(&proxy, &[]).await;
+        assert!(java.next_packet().await.is_some());
+    });
+}
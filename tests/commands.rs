@@ -0,0 +1,245 @@
+//! Chat commands handled by the proxy
+
+mod support;
+
+use bytes::BytesMut;
+use mineroute::commands::CommandSettings;
+use mineroute::net::PacketServerEnum;
+use mineroute::net::buffer::{Buffer, BufferMut};
+use mineroute::net::play::{PlayPacketIds, RawPacket, WorldPacketIds};
+use support::*;
+
+/// Only `alice` may send other players to a server
+fn commands() -> CommandSettings {
+    toml::from_str(r#"
+        [groups]
+        default = ["server", "glist"]
+        admin = ["send"]
+
+        [players]
+        alice = ["admin"]
+    "#).unwrap()
+}
+
+#[test]
+fn players_can_switch_servers() {
+    run(async move {
+        let mut a = FakeBackend::start(BackendScript::new("a")).await;
+        let mut b = FakeBackend::start(BackendScript::new("b")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", b.address)]).await;
+        proxy.config.write().set_commands(Some(commands()));
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        a.next_event().await;
+        a.next_event().await;
+
+        client.chat("/server").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("Servers:"));
+        assert_eq!(client.next_chat().await.as_deref(), Some("- a.test (connected)"));
+        assert_eq!(client.next_chat().await.as_deref(), Some("- b.test"));
+
+        client.chat("/server b.test").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("Connecting to b.test"));
+        match b.next_event().await {
+            Some(BackendEvent::Handshake(handshake)) => assert_eq!(handshake.server_address, "b.test"),
+            event => panic!("expected a handshake, got {:?}", event),
+        }
+        match b.next_event().await {
+            Some(BackendEvent::LoginStart(name)) => assert_eq!(name, "steve"),
+            event => panic!("expected a login, got {:?}", event),
+        }
+
+        // Play packets are proxied to the new server, other chat messages as well
+        client.chat("/help").await;
+        match b.next_event().await {
+            Some(BackendEvent::Play(packet)) => assert_eq!(&packet.data[1..], b"/help"),
+            event => panic!("expected the chat message, got {:?}", event),
+        }
+
+        client.chat("/glist").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("a.test (0)"));
+        assert_eq!(client.next_chat().await.as_deref(), Some("b.test (1): steve"));
+        assert_eq!(client.next_chat().await.as_deref(), Some("1 players are online"));
+    });
+}
+
+fn world_ids() -> WorldPacketIds {
+    WorldPacketIds::for_version(PROTOCOL_VERSION).unwrap()
+}
+
+fn join_game(entity_id: i32, dimension: i32) -> RawPacket {
+    let mut data = BytesMut::new();
+    data.write_raw_bytes(&entity_id.to_be_bytes());
+    data.write_u8(0);
+    data.write_raw_bytes(&dimension.to_be_bytes());
+    data.write_u8(1);
+    data.write_u8(20);
+    data.write_string("default");
+    data.write_u8(0);
+    RawPacket { id: world_ids().join_game, data: data.freeze(), compressed: None }
+}
+
+fn scoreboard_objective(name: &str) -> RawPacket {
+    let mut data = BytesMut::new();
+    data.write_string(name);
+    data.write_u8(0);
+    data.write_string(name);
+    data.write_string("integer");
+    RawPacket { id: world_ids().scoreboard_objective, data: data.freeze(), compressed: None }
+}
+
+/// The next play packet that is not a chat message
+async fn next_world_packet(client: &mut FakeClient) -> RawPacket {
+    let chat = PlayPacketIds::for_version(PROTOCOL_VERSION).unwrap().chat_clientbound;
+    loop {
+        match client.next_packet().await {
+            Some(PacketServerEnum::Raw(packet)) if packet.id == chat => continue,
+            Some(PacketServerEnum::Raw(packet)) => return packet,
+            packet => panic!("expected a play packet, got {:?}", packet),
+        }
+    }
+}
+
+#[test]
+fn switching_respawns_the_client() {
+    run(async move {
+        let ids = world_ids();
+        let mut a = FakeBackend::start(BackendScript::new("a")
+            .with_join_packets(vec![join_game(1, 0), scoreboard_objective("kills")])).await;
+        let mut b = FakeBackend::start(BackendScript::new("b").with_join_packets(vec![join_game(2, 0)])).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", b.address)]).await;
+        proxy.config.write().set_commands(Some(commands()));
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        assert_eq!(next_world_packet(&mut client).await.id, ids.join_game);
+        assert_eq!(next_world_packet(&mut client).await.id, ids.scoreboard_objective);
+        a.next_event().await;
+        a.next_event().await;
+
+        client.chat("/server b.test").await;
+        assert!(matches!(b.next_event().await, Some(BackendEvent::Handshake(_))));
+        assert!(matches!(b.next_event().await, Some(BackendEvent::LoginStart(_))));
+
+        // The objective of a.test is removed before the client moves to the world of b.test
+        let mut removed = BytesMut::from(&next_world_packet(&mut client).await.data[..]);
+        assert_eq!((removed.read_string().as_deref(), removed.read_u8()), (Ok("kills"), Ok(1)));
+
+        let join = next_world_packet(&mut client).await;
+        assert_eq!(ids.read_join_game(&join).map(|join| join.entity_id), Some(2));
+        for dimension in [-1i32, 0] {
+            let respawn = next_world_packet(&mut client).await;
+            assert_eq!(respawn.id, ids.respawn);
+            assert_eq!(&respawn.data[..4], &dimension.to_be_bytes());
+        }
+    });
+}
+
+#[test]
+fn commands_require_permissions() {
+    run(async move {
+        let mut a = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address)]).await;
+        proxy.config.write().set_commands(Some(commands()));
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        a.next_event().await;
+        a.next_event().await;
+
+        client.chat("/send alice a.test").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("You are not allowed to use this command"));
+        client.chat("/server c.test").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("Unknown server c.test"));
+        client.chat("/server a.test").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("You are already connected to a.test"));
+
+        // None of the commands reached the server
+        client.send_play(0x0b, &[1]).await;
+        match a.next_event().await {
+            Some(BackendEvent::Play(packet)) => assert_eq!(packet.id, 0x0b),
+            event => panic!("expected a play packet, got {:?}", event),
+        }
+    });
+}
+
+#[test]
+fn players_can_be_sent_to_other_servers() {
+    run(async move {
+        let mut a = FakeBackend::start(BackendScript::new("a")).await;
+        let mut b = FakeBackend::start(BackendScript::new("b")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", b.address)]).await;
+        proxy.config.write().set_commands(Some(commands()));
+
+        let mut steve = FakeClient::connect(proxy.address).await;
+        steve.login("a.test", "steve").await.unwrap();
+        let mut alice = FakeClient::connect(proxy.address).await;
+        alice.login("a.test", "alice").await.unwrap();
+        for _ in 0..4 {
+            a.next_event().await;
+        }
+
+        alice.chat("/send steve b.test").await;
+        assert_eq!(alice.next_chat().await.as_deref(), Some("Sending steve to b.test"));
+        assert_eq!(steve.next_chat().await.as_deref(), Some("Connecting to b.test"));
+        assert!(matches!(b.next_event().await, Some(BackendEvent::Handshake(_))));
+        assert!(matches!(b.next_event().await, Some(BackendEvent::LoginStart(_))));
+
+        alice.chat("/send bob b.test").await;
+        assert_eq!(alice.next_chat().await.as_deref(), Some("bob is not online"));
+
+        // Wait for the switch to complete before checking the player lists
+        steve.send_play(0x0b, &[1]).await;
+        assert!(matches!(b.next_event().await, Some(BackendEvent::Play(_))));
+        let config = proxy.config.load();
+        assert_eq!(*config.get_server("b.test").unwrap().players.read().unwrap(), vec!["steve".to_owned()]);
+        assert_eq!(*config.get_server("a.test").unwrap().players.read().unwrap(), vec!["alice".to_owned()]);
+    });
+}
+
+#[test]
+fn players_whose_version_cannot_switch_are_not_sent() {
+    run(async move {
+        let mut a = FakeBackend::start(BackendScript::new("a")).await;
+        let b = FakeBackend::start(BackendScript::new("b")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", b.address)]).await;
+        proxy.config.write().set_commands(Some(commands()));
+
+        // Minecraft 1.8
+        let mut steve = FakeClient::connect(proxy.address).await;
+        steve.login_version("a.test", "steve", 47).await.unwrap();
+        let mut alice = FakeClient::connect(proxy.address).await;
+        alice.login("a.test", "alice").await.unwrap();
+        for _ in 0..4 {
+            a.next_event().await;
+        }
+
+        alice.chat("/send steve b.test").await;
+        assert_eq!(alice.next_chat().await.as_deref(), Some("The version of Minecraft of steve does not support switching servers"));
+        assert_eq!(*proxy.config.load().get_server("a.test").unwrap().players.read().unwrap(), vec!["steve".to_owned(), "alice".to_owned()]);
+    });
+}
+
+#[test]
+fn failed_switches_keep_the_current_server() {
+    run(async move {
+        let mut a = FakeBackend::start(BackendScript::new("a")).await;
+        let closed = closed_address().await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", closed)]).await;
+        proxy.config.write().set_commands(Some(commands()));
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        a.next_event().await;
+        a.next_event().await;
+
+        client.chat("/server b.test").await;
+        assert_eq!(client.next_chat().await.as_deref(), Some("Connecting to b.test"));
+        assert_eq!(client.next_chat().await.as_deref(), Some("Cannot connect to b.test"));
+
+        client.send_play(0x0b, &[1]).await;
+        assert!(matches!(a.next_event().await, Some(BackendEvent::Play(_))));
+        assert!(matches!(client.next_packet().await, Some(PacketServerEnum::Raw(_))));
+    });
+}
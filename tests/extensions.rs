@@ -0,0 +1,156 @@
+//! Extensions hooking into proxied sessions
+
+mod support;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::delay_for;
+use bytes::Bytes;
+use mineroute::extensions::{Extension, PacketAction, SessionInfo, Verdict};
+use mineroute::net::{PacketClientEnum, PacketServerEnum};
+use mineroute::net::status::server_status::ServerInfo;
+use support::*;
+
+/// Routes players to the hostname after the first dot of their name and denies `herobrine`
+struct Lobby;
+
+impl Extension for Lobby {
+    fn name(&self) -> &str {
+        "lobby"
+    }
+
+    fn on_login(&self, session: &SessionInfo) -> Verdict {
+        match session.player.as_deref() {
+            Some("herobrine") => Verdict::Deny("Not today".to_owned()),
+            _ => Verdict::Continue,
+        }
+    }
+
+    fn select_server(&self, session: &SessionInfo) -> Option<String> {
+        let player = session.player.as_ref()?;
+        player.find('.').map(|index| player[index + 1..].to_owned())
+    }
+
+    fn rewrite_status(&self, _session: &SessionInfo, status: &mut ServerInfo) {
+        status.description = serde_json::json!("Rewritten");
+    }
+}
+
+/// Drops client packets with the id 0x0c and replaces the payload of server packets
+#[derive(Default)]
+struct Filter {
+    disconnected: Mutex<Vec<String>>,
+}
+
+impl Extension for Filter {
+    fn name(&self) -> &str {
+        "filter"
+    }
+
+    fn intercepts_play(&self) -> bool {
+        true
+    }
+
+    fn on_client_packet(&self, _session: &SessionInfo, packet: &PacketClientEnum) -> PacketAction<PacketClientEnum> {
+        match packet {
+            PacketClientEnum::Raw(packet) if packet.id == 0x0c => PacketAction::Drop,
+            _ => PacketAction::Forward,
+        }
+    }
+
+    fn on_server_packet(&self, _session: &SessionInfo, packet: &PacketServerEnum) -> PacketAction<PacketServerEnum> {
+        match packet {
+            PacketServerEnum::Raw(packet) => {
+                let mut replacement = packet.clone();
+                replacement.data = Bytes::from_static(&[9]);
+                PacketAction::Replace(PacketServerEnum::Raw(replacement))
+            }
+            _ => PacketAction::Forward,
+        }
+    }
+
+    fn on_disconnect(&self, session: &SessionInfo) {
+        let player = session.player.clone().unwrap_or_default();
+        self.disconnected.lock().unwrap().push(player);
+    }
+}
+
+#[test]
+fn status_can_be_rewritten() {
+    run(async move {
+        let backend = FakeBackend::start(BackendScript::new("A fake backend")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+        proxy.config.write().register_extension(Arc::new(Lobby));
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        let status = client.status("a.test").await.unwrap();
+        assert_eq!(status.description, serde_json::json!("Rewritten"));
+    });
+}
+
+#[test]
+fn logins_can_be_denied_and_routed() {
+    run(async move {
+        let a = FakeBackend::start(BackendScript::new("a")).await;
+        let mut b = FakeBackend::start(BackendScript::new("b")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", b.address)]).await;
+        proxy.config.write().register_extension(Arc::new(Lobby));
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        match client.login("a.test", "herobrine").await {
+            Err(Some(PacketServerEnum::Disconnect(_))) => {}
+            result => panic!("expected a disconnect, got {:?}", result),
+        }
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve.b.test").await.unwrap();
+        assert!(matches!(b.next_event().await, Some(BackendEvent::Handshake(_))));
+        match b.next_event().await {
+            Some(BackendEvent::LoginStart(name)) => assert_eq!(name, "steve.b.test"),
+            event => panic!("expected a login, got {:?}", event),
+        }
+
+        let config = proxy.config.load();
+        assert_eq!(*config.get_server("b.test").unwrap().players.read().unwrap(), vec!["steve.b.test".to_owned()]);
+        assert!(config.get_server("a.test").unwrap().players.read().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn play_packets_can_be_dropped_and_replaced() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+        let filter = Arc::new(Filter::default());
+        proxy.config.write().register_extension(filter.clone());
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+
+        // Both legs compress alike, so the sessions would be spliced without the extension
+        client.send_play(0x0c, &[1]).await;
+        client.send_play(0x0b, &[1, 2, 3]).await;
+        match backend.next_event().await {
+            Some(BackendEvent::Play(packet)) => assert_eq!(packet.id, 0x0b),
+            event => panic!("expected a play packet, got {:?}", event),
+        }
+        match client.next_packet().await {
+            Some(PacketServerEnum::Raw(packet)) => {
+                assert_eq!(packet.id, 0x0b);
+                assert_eq!(&packet.data[..], &[9]);
+            }
+            packet => panic!("expected the replaced play packet, got {:?}", packet),
+        }
+
+        client.disconnect().await;
+        for _ in 0..100 {
+            if !filter.disconnected.lock().unwrap().is_empty() {
+                break;
+            }
+            delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*filter.disconnected.lock().unwrap(), vec!["steve".to_owned()]);
+    });
+}
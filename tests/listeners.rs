@@ -0,0 +1,68 @@
+//! Listeners replaced by reloading the configuration file
+
+mod support;
+
+use std::fs;
+use std::path::PathBuf;
+use tokio::net::UdpSocket;
+use support::*;
+
+/// A configuration file with the provided listeners, removed once dropped
+struct SettingsFile(PathBuf);
+
+impl SettingsFile {
+    fn new(name: &str) -> SettingsFile {
+        let directory = std::env::temp_dir().join(format!("mineroute-listeners-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        SettingsFile(directory.join(format!("{}.toml", name)))
+    }
+
+    fn write(&self, content: &str) {
+        fs::write(&self.0, content).unwrap();
+    }
+}
+
+impl Drop for SettingsFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn reloaded_listeners_keep_their_address() {
+    run(async move {
+        let backend = FakeBackend::start(BackendScript::new("A fake backend")).await;
+        let settings = SettingsFile::new("reload");
+        let proxy = Proxy::start_reloadable(listener(), &[("a.test", backend.address), ("b.test", backend.address)], 1, settings.0.clone()).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.status("b.test").await.is_some());
+
+        settings.write(&format!("[[listeners]]\nname = \"test\"\nbind = \"{}\"\nhostnames = [\"A.Test\"]\n", proxy.address));
+        proxy.reload().await.unwrap();
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.status("a.test").await.is_some());
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.status("b.test").await.is_none());
+    });
+}
+
+#[test]
+fn failed_binds_keep_the_previous_listener() {
+    run(async move {
+        let backend = FakeBackend::start(BackendScript::new("A fake backend")).await;
+        let settings = SettingsFile::new("failed");
+        let proxy = Proxy::start_reloadable(listener(), &[("a.test", backend.address)], 1, settings.0.clone()).await;
+
+        // The query socket of the new settings cannot be bound
+        let taken = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        settings.write(&format!("[[listeners]]\nname = \"test\"\nbind = \"{}\"\nhostnames = [\"b.test\"]\n\n\
+                                 [listeners.query]\nbind = \"{}\"\nhost = \"b.test\"\n",
+                                proxy.address, taken.local_addr().unwrap()));
+        assert!(proxy.reload().await.is_err());
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.status("a.test").await.is_some());
+    });
+}
@@ -0,0 +1,320 @@
+//! End-to-end tests of clients being proxied to backends
+
+mod support;
+
+use std::collections::HashSet;
+use bytes::Bytes;
+use tokio::net::TcpListener;
+use mineroute::metrics::QUEUE_OVERFLOWS;
+use mineroute::net::{PacketClientEnum, PacketServerEnum, Protocol};
+use mineroute::net::login::LoginStartPacket;
+use mineroute::net::play::RawPacket;
+use mineroute::net::status::PingPacket;
+use mineroute::server_state::Health;
+use support::*;
+
+/// A play packet that exceeds the compression thresholds used in these tests
+fn large_payload() -> Vec<u8> {
+    (0..4096u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// Send play packets through the proxy and expect them to be echoed by the backend
+async fn assert_play_round_trip(client: &mut FakeClient, backend: &mut FakeBackend) {
+    for payload in &[vec![1, 2, 3], large_payload()] {
+        client.send_play(0x0b, payload).await;
+
+        match backend.next_event().await {
+            Some(BackendEvent::Play(packet)) => {
+                assert_eq!(packet.id, 0x0b);
+                assert_eq!(&packet.data[..], &payload[..]);
+            }
+            event => panic!("expected a play packet, got {:?}", event),
+        }
+
+        match client.next_packet().await {
+            Some(PacketServerEnum::Raw(packet)) => {
+                assert_eq!(packet.id, 0x0b);
+                assert_eq!(&packet.data[..], &payload[..]);
+            }
+            packet => panic!("expected the echoed play packet, got {:?}", packet),
+        }
+    }
+}
+
+#[test]
+fn clients_are_routed_by_hostname() {
+    run(async move {
+        let a = FakeBackend::start(BackendScript::new("a")).await;
+        let mut b = FakeBackend::start(BackendScript::new("b")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", a.address), ("b.test", b.address)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        let success = client.login("B.test", "steve").await.unwrap();
+        assert_eq!(success.name, "steve");
+
+        match b.next_event().await {
+            Some(BackendEvent::Handshake(handshake)) => {
+                assert_eq!(handshake.protocol_version, PROTOCOL_VERSION);
+                assert_eq!(handshake.next_protocol, Protocol::Login);
+            }
+            event => panic!("expected a handshake, got {:?}", event),
+        }
+        match b.next_event().await {
+            Some(BackendEvent::LoginStart(name)) => assert_eq!(name, "steve"),
+            event => panic!("expected a login, got {:?}", event),
+        }
+        assert_play_round_trip(&mut client, &mut b).await;
+
+        let config = proxy.config.load();
+        assert_eq!(*config.get_server("b.test").unwrap().players.read().unwrap(), vec!["steve".to_owned()]);
+        assert!(config.get_server("a.test").unwrap().players.read().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn unknown_hosts_are_disconnected() {
+    run(async move {
+        let backend = FakeBackend::start(BackendScript::new("a")).await;
+        let mut exposed = listener();
+        exposed.hostnames = Some(vec!["a.test".to_owned()].into_iter().collect::<HashSet<_>>());
+        let proxy = Proxy::start(exposed, &[("a.test", backend.address), ("b.test", backend.address)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.login("c.test", "steve").await.unwrap_err().is_none());
+
+        // Servers that are not exposed by the listener are treated as unknown
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.login("b.test", "steve").await.unwrap_err().is_none());
+    });
+}
+
+#[test]
+fn status_is_passed_through() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("A fake backend")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        let status = client.status("a.test").await.unwrap();
+        assert_eq!(status.description, serde_json::json!("A fake backend"));
+        assert_eq!(status.version.protocol, PROTOCOL_VERSION);
+
+        match backend.next_event().await {
+            Some(BackendEvent::Handshake(handshake)) => assert_eq!(handshake.next_protocol, Protocol::Status),
+            event => panic!("expected a handshake, got {:?}", event),
+        }
+        assert!(matches!(backend.next_event().await, Some(BackendEvent::StatusRequest)));
+
+        client.send(PacketClientEnum::Ping(PingPacket { payload: 42 })).await;
+        match client.next_packet().await {
+            Some(PacketServerEnum::Pong(pong)) => assert_eq!(pong.payload, 42),
+            packet => panic!("expected a pong, got {:?}", packet),
+        }
+    });
+}
+
+#[test]
+fn upstream_compression_is_used_by_default() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a").with_compression(256)).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        assert_eq!(client.compression().await, Some(256));
+
+        backend.next_event().await;
+        backend.next_event().await;
+        assert_play_round_trip(&mut client, &mut backend).await;
+    });
+}
+
+#[test]
+fn packets_following_the_login_keep_their_order() {
+    let packets: Vec<RawPacket> = (0..200u32).map(|i| RawPacket {
+        id: (i % 64) as u8,
+        data: Bytes::from(large_payload()[..(i as usize * 37) % 4096].to_vec()),
+        compressed: None,
+    }).collect();
+
+    for &threshold in &[None, Some(-1), Some(64)] {
+        let packets = packets.clone();
+        run(async move {
+            let backend = FakeBackend::start(BackendScript::new("a").with_compression(256).with_join_packets(packets.clone())).await;
+            let mut compressing = listener();
+            compressing.compression_threshold = threshold;
+            let proxy = Proxy::start(compressing, &[("a.test", backend.address)]).await;
+
+            // The protocol and compression of the client change between the relayed packets
+            let mut client = FakeClient::connect(proxy.address).await;
+            client.login("a.test", "steve").await.unwrap();
+            for expected in &packets {
+                match client.next_packet().await {
+                    Some(PacketServerEnum::Raw(packet)) => {
+                        assert_eq!(packet.id, expected.id);
+                        assert_eq!(packet.data, expected.data);
+                    }
+                    packet => panic!("expected a play packet, got {:?}", packet),
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn client_compression_can_differ_from_upstream() {
+    for &(threshold, client_compression) in &[(-1, None), (64, Some(64))] {
+        run(async move {
+            let mut backend = FakeBackend::start(BackendScript::new("a").with_compression(256)).await;
+            let mut compressing = listener();
+            compressing.compression_threshold = Some(threshold);
+            let proxy = Proxy::start(compressing, &[("a.test", backend.address)]).await;
+
+            let mut client = FakeClient::connect(proxy.address).await;
+            client.login("a.test", "steve").await.unwrap();
+            assert_eq!(client.compression().await, client_compression);
+
+            backend.next_event().await;
+            backend.next_event().await;
+            assert_play_round_trip(&mut client, &mut backend).await;
+        });
+    }
+}
+
+#[test]
+fn compression_can_be_enabled_towards_clients_only() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let mut compressing = listener();
+        compressing.compression_threshold = Some(128);
+        let proxy = Proxy::start(compressing, &[("a.test", backend.address)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        assert_eq!(client.compression().await, Some(128));
+
+        backend.next_event().await;
+        backend.next_event().await;
+        assert_play_round_trip(&mut client, &mut backend).await;
+    });
+}
+
+#[test]
+fn unreachable_upstream_disconnects_the_client() {
+    run(async move {
+        let upstream = closed_address().await;
+        let proxy = Proxy::start(listener(), &[("a.test", upstream)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.login("a.test", "steve").await.unwrap_err().is_none());
+
+        let config = proxy.config.load();
+        let server = config.get_server("a.test").unwrap();
+        assert_eq!(server.health, Health::Unhealthy);
+        assert!(server.health_error.is_some());
+    });
+}
+
+#[test]
+fn unreachable_upstream_has_no_status() {
+    run(async move {
+        let upstream = closed_address().await;
+        let proxy = Proxy::start(listener(), &[("a.test", upstream)]).await;
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        assert!(client.status("a.test").await.is_none());
+    });
+}
+
+#[test]
+fn unexpected_packets_disconnect_the_client() {
+    run(async move {
+        let backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+
+        // A second login arrives while the proxy is still connecting to the upstream
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.handshake("a.test", Protocol::Login).await;
+        let login = PacketClientEnum::LoginStart(LoginStartPacket { name: "steve".to_owned() });
+        client.send(login.clone()).await;
+        client.send(login).await;
+        assert!(client.next_packet().await.is_none());
+    });
+}
+
+#[test]
+fn packets_sent_before_the_login_succeeded_follow_it_in_order() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+
+        // The packets arrive while the proxy is still connecting to the upstream
+        let client = FakeClient::connect(proxy.address).await;
+        client.handshake("a.test", Protocol::Login).await;
+        client.send(PacketClientEnum::LoginStart(LoginStartPacket { name: "steve".to_owned() })).await;
+        for id in 0x0b..0x0e {
+            client.send_play(id, &[id]).await;
+        }
+
+        assert!(matches!(backend.next_event().await, Some(BackendEvent::Handshake(_))));
+        assert!(matches!(backend.next_event().await, Some(BackendEvent::LoginStart(_))));
+        for id in 0x0b..0x0e {
+            match backend.next_event().await {
+                Some(BackendEvent::Play(packet)) => assert_eq!((packet.id, &packet.data[..]), (id, &[id][..])),
+                event => panic!("expected the queued play packet {:x}, got {:?}", id, event),
+            }
+        }
+    });
+}
+
+#[test]
+fn too_many_packets_before_the_login_succeeded_disconnect_the_client() {
+    run(async move {
+        // The upstream accepts the connection but never answers the login
+        let mut silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = silent.local_addr().unwrap();
+        actix::spawn(async move {
+            let _connection = silent.accept().await;
+            futures::future::pending::<()>().await;
+        });
+        let proxy = Proxy::start(listener(), &[("a.test", address)]).await;
+        let overflows = QUEUE_OVERFLOWS.get();
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.handshake("a.test", Protocol::Login).await;
+        client.send(PacketClientEnum::LoginStart(LoginStartPacket { name: "steve".to_owned() })).await;
+        for _ in 0..=64 {
+            client.send_play(0x0b, &[1]).await;
+        }
+        assert!(client.next_packet().await.is_none());
+        assert_eq!(QUEUE_OVERFLOWS.get(), overflows + 1);
+    });
+}
+
+#[test]
+fn a_second_login_with_the_same_name_replaces_the_first_session() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+
+        let mut first = FakeClient::connect(proxy.address).await;
+        first.login("a.test", "steve").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+
+        let mut second = FakeClient::connect(proxy.address).await;
+        second.login("a.test", "steve").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+
+        match first.next_packet().await {
+            Some(PacketServerEnum::Raw(packet)) => {
+                assert!(String::from_utf8_lossy(&packet.data).contains("You logged in from another location"));
+            }
+            packet => panic!("expected a disconnect, got {:?}", packet),
+        }
+        assert!(first.next_packet().await.is_none());
+        assert_play_round_trip(&mut second, &mut backend).await;
+        assert_eq!(*proxy.config.load().get_server("a.test").unwrap().players.read().unwrap(), vec!["steve".to_owned()]);
+    });
+}
@@ -0,0 +1,179 @@
+//! The UDP query protocol answered by the query socket of a listener
+
+mod support;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use bytes::BytesMut;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use mineroute::net::query::{QueryRequest, QueryResponse, ServerStat};
+use mineroute::settings::QuerySettings;
+use support::*;
+
+/// How long a test waits for a response that is not expected
+const SILENCE: Duration = Duration::from_millis(300);
+
+fn query_settings(upstream_port: Option<u16>) -> QuerySettings {
+    QuerySettings {
+        bind: None,
+        host: "a.test".to_owned(),
+        upstream_port,
+        motd: Some("A proxied server".to_owned()),
+        max_players: 50,
+        version: "1.12.2".to_owned(),
+    }
+}
+
+/// A query client talking to the query socket of the proxy
+struct QueryClient {
+    socket: UdpSocket,
+}
+
+impl QueryClient {
+    async fn connect(address: SocketAddr) -> QueryClient {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(address).await.unwrap();
+        QueryClient { socket }
+    }
+
+    async fn request(&mut self, request: QueryRequest, wait: Duration) -> Option<QueryResponse> {
+        let mut buffer = BytesMut::new();
+        request.write(&mut buffer);
+        self.socket.send(&buffer).await.unwrap();
+
+        let mut datagram = vec![0; 4096];
+        let size = timeout(wait, self.socket.recv(&mut datagram)).await.ok()?.unwrap();
+        Some(QueryResponse::read(&request, &datagram[..size]).unwrap())
+    }
+
+    async fn token(&mut self) -> i32 {
+        match self.request(QueryRequest::Handshake { session_id: 7 }, Duration::from_secs(5)).await {
+            Some(QueryResponse::Handshake { session_id: 7, token }) => token,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    async fn stat(&mut self, request: QueryRequest) -> ServerStat {
+        match self.request(request, Duration::from_secs(5)).await {
+            Some(QueryResponse::BasicStat { session_id: 7, stat }) | Some(QueryResponse::FullStat { session_id: 7, stat }) => stat,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
+
+/// Answers the query protocol like a vanilla server with `enable-query`
+async fn fake_query_backend(stat: ServerStat) -> SocketAddr {
+    let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    actix::spawn(async move {
+        let mut datagram = vec![0; 4096];
+        while let Ok((size, client)) = socket.recv_from(&mut datagram).await {
+            let response = match QueryRequest::read(&datagram[..size]) {
+                Ok(QueryRequest::Handshake { session_id }) => QueryResponse::Handshake { session_id, token: 4242 },
+                Ok(QueryRequest::BasicStat { session_id, token: 4242 }) => QueryResponse::BasicStat { session_id, stat: stat.clone() },
+                Ok(QueryRequest::FullStat { session_id, token: 4242 }) => QueryResponse::FullStat { session_id, stat: stat.clone() },
+                _ => continue,
+            };
+            let mut buffer = BytesMut::new();
+            response.write(&mut buffer);
+            socket.send_to(&buffer, &client).await.unwrap();
+        }
+    });
+    address
+}
+
+#[test]
+fn proxy_reports_its_own_players() {
+    run(async move {
+        let mut settings = listener();
+        settings.query = Some(query_settings(None));
+        let proxy = Proxy::start_pooled(settings, &[("a.test", closed_address().await)], 1).await;
+        {
+            let config = proxy.config.load();
+            let server = config.get_server("a.test").unwrap();
+            server.add_player("steve".to_owned());
+            server.add_player("alex".to_owned());
+        }
+
+        let mut client = QueryClient::connect(proxy.address).await;
+        let token = client.token().await;
+
+        let stat = client.stat(QueryRequest::FullStat { session_id: 7, token }).await;
+        assert_eq!(stat.motd, "A proxied server");
+        assert_eq!(stat.version, "1.12.2");
+        assert_eq!(stat.num_players, 2);
+        assert_eq!(stat.max_players, 50);
+        assert_eq!(stat.host_port, proxy.address.port());
+        assert_eq!(stat.players, vec!["steve".to_owned(), "alex".to_owned()]);
+
+        let stat = client.stat(QueryRequest::BasicStat { session_id: 7, token }).await;
+        assert_eq!(stat.num_players, 2);
+        assert!(stat.players.is_empty());
+    });
+}
+
+#[test]
+fn stat_requires_the_challenge_token() {
+    run(async move {
+        let mut settings = listener();
+        settings.query = Some(query_settings(None));
+        let proxy = Proxy::start_pooled(settings, &[("a.test", closed_address().await)], 1).await;
+
+        let mut client = QueryClient::connect(proxy.address).await;
+        let token = client.token().await;
+        let response = client.request(QueryRequest::FullStat { session_id: 7, token: token ^ 1 }, SILENCE).await;
+        assert_eq!(response, None);
+
+        // Another client cannot use the token
+        let mut other = QueryClient::connect(proxy.address).await;
+        assert_eq!(other.request(QueryRequest::BasicStat { session_id: 7, token }, SILENCE).await, None);
+    });
+}
+
+#[test]
+fn queries_are_forwarded_to_the_upstream() {
+    run(async move {
+        let upstream_stat = ServerStat {
+            motd: "The backend".to_owned(),
+            game_type: "SMP".to_owned(),
+            game_id: "MINECRAFT".to_owned(),
+            version: "1.12.2".to_owned(),
+            plugins: "Paper: WorldEdit".to_owned(),
+            map: "lobby".to_owned(),
+            num_players: 1,
+            max_players: 100,
+            host_port: 25566,
+            host_ip: "10.0.0.2".to_owned(),
+            players: vec!["notch".to_owned()],
+        };
+        let backend = fake_query_backend(upstream_stat.clone()).await;
+
+        let mut settings = listener();
+        settings.query = Some(query_settings(Some(backend.port())));
+        let proxy = Proxy::start_pooled(settings, &[("a.test", closed_address().await)], 1).await;
+
+        let mut client = QueryClient::connect(proxy.address).await;
+        let token = client.token().await;
+        let stat = client.stat(QueryRequest::FullStat { session_id: 7, token }).await;
+        assert_eq!(stat, ServerStat {
+            host_port: proxy.address.port(),
+            host_ip: "127.0.0.1".to_owned(),
+            ..upstream_stat
+        });
+    });
+}
+
+#[test]
+fn failing_upstreams_leave_queries_unanswered() {
+    run(async move {
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let mut settings = listener();
+        settings.query = Some(query_settings(Some(closed.port())));
+        let proxy = Proxy::start_pooled(settings, &[("a.test", closed_address().await)], 1).await;
+
+        let mut client = QueryClient::connect(proxy.address).await;
+        let token = client.token().await;
+        assert_eq!(client.request(QueryRequest::BasicStat { session_id: 7, token }, SILENCE).await, None);
+    });
+}
@@ -0,0 +1,133 @@
+//! Commands executed on a fake server through its RCON port
+
+mod support;
+
+use std::fs;
+use std::net::SocketAddr;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+use mineroute::audit::{AuditLog, AuditRecord};
+use mineroute::net::rcon::{RconCodec, RconPacket, COMMAND, LOGIN, MAX_RESPONSE_PAYLOAD, RESPONSE_VALUE};
+use mineroute::rcon::{execute, CommandError};
+use support::*;
+
+const PASSWORD: &str = "secret";
+
+/// Answers like the vanilla server. Its commands are `echo <text>`
+/// and `repeat <count> [suffix]`, which responds with that many characters followed by the suffix.
+async fn fake_rcon_server() -> SocketAddr {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    actix::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            actix::spawn(async move {
+                let mut connection = Framed::new(stream, RconCodec);
+                let mut authenticated = false;
+                while let Some(Ok(packet)) = connection.next().await {
+                    let payload = String::from_utf8(packet.payload.to_vec()).unwrap();
+                    let responses = match packet.kind {
+                        LOGIN if payload == PASSWORD => {
+                            authenticated = true;
+                            vec![RconPacket { request_id: packet.request_id, kind: COMMAND, payload: Bytes::new() }]
+                        }
+                        LOGIN => vec![RconPacket { request_id: -1, kind: COMMAND, payload: Bytes::new() }],
+                        _ if !authenticated => return,
+                        COMMAND => {
+                            let output = match payload.strip_prefix("repeat ") {
+                                Some(arguments) => {
+                                    let (count, suffix) = arguments.split_once(' ').unwrap_or((arguments, ""));
+                                    "x".repeat(count.parse().unwrap()) + suffix
+                                }
+                                None => payload.trim_start_matches("echo ").to_owned(),
+                            };
+                            // Like the vanilla server, characters are split at the packet boundaries
+                            output.as_bytes().chunks(MAX_RESPONSE_PAYLOAD)
+                                .map(|chunk| RconPacket {
+                                    request_id: packet.request_id,
+                                    kind: RESPONSE_VALUE,
+                                    payload: Bytes::copy_from_slice(chunk),
+                                })
+                                .collect()
+                        }
+                        kind => vec![RconPacket {
+                            request_id: packet.request_id,
+                            kind: RESPONSE_VALUE,
+                            payload: Bytes::from(format!("Unknown request {:x}", kind)),
+                        }],
+                    };
+                    for response in responses {
+                        connection.send(response).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+#[test]
+fn commands_return_the_response_of_the_server() {
+    run(async move {
+        let server = fake_rcon_server().await;
+        assert_eq!(execute(server, PASSWORD, "echo There are 0 of a max 20 players online").await.unwrap(),
+                   "There are 0 of a max 20 players online");
+        assert_eq!(execute(server, PASSWORD, "echo ").await.unwrap(), "");
+    });
+}
+
+#[test]
+fn responses_split_into_several_packets_are_joined() {
+    run(async move {
+        let server = fake_rcon_server().await;
+        for count in [MAX_RESPONSE_PAYLOAD, MAX_RESPONSE_PAYLOAD + 1, MAX_RESPONSE_PAYLOAD * 3 + 100] {
+            let response = execute(server, PASSWORD, &format!("repeat {}", count)).await.unwrap();
+            assert_eq!(response, "x".repeat(count));
+        }
+
+        // The two bytes of the last character end up in different packets
+        let response = execute(server, PASSWORD, &format!("repeat {} §", MAX_RESPONSE_PAYLOAD - 1)).await.unwrap();
+        assert_eq!(response, "x".repeat(MAX_RESPONSE_PAYLOAD - 1) + "§");
+    });
+}
+
+#[test]
+fn wrong_passwords_are_reported() {
+    run(async move {
+        let server = fake_rcon_server().await;
+        assert!(matches!(execute(server, "guess", "echo hi").await, Err(CommandError::AuthenticationFailed)));
+    });
+}
+
+#[test]
+fn invalid_commands_are_not_sent() {
+    run(async move {
+        let closed = closed_address().await;
+        assert!(matches!(execute(closed, PASSWORD, &"x".repeat(2000)).await, Err(CommandError::InvalidCommand)));
+        assert!(matches!(execute(closed, PASSWORD, "say \0").await, Err(CommandError::InvalidCommand)));
+        assert!(matches!(execute(closed, PASSWORD, "list").await, Err(CommandError::Connect(_))));
+    });
+}
+
+#[test]
+fn audit_records_are_appended_as_json_lines() {
+    let path = std::env::temp_dir().join(format!("mineroute-audit-{}", std::process::id())).join("audit.log");
+    let _ = fs::remove_file(&path);
+    let audit = AuditLog::new(path.clone());
+
+    audit.record(&AuditRecord { host: "a.test", command: "list", requested_by: Some("127.0.0.1:4000".to_owned()), error: None }).unwrap();
+    audit.record(&AuditRecord { host: "b.test", command: "stop", requested_by: None, error: Some("timeout".to_owned()) }).unwrap();
+
+    let content = fs::read_to_string(&path).unwrap();
+    let records: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["host"], "a.test");
+    assert_eq!(records[0]["command"], "list");
+    assert_eq!(records[0]["requested_by"], "127.0.0.1:4000");
+    assert!(records[0].get("error").is_none());
+    assert!(records[0]["time"].as_u64().unwrap() > 0);
+    assert_eq!(records[1]["error"], "timeout");
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,102 @@
+//! Sessions failing without taking down the proxy or leaving stale state behind
+
+mod support;
+
+use std::sync::Arc;
+use std::time::Duration;
+use actix::Actor;
+use tokio::time::delay_for;
+use mineroute::extensions::{Extension, PacketAction, SessionInfo};
+use mineroute::metrics::{SESSION_PANICS, GHOST_PLAYERS};
+use mineroute::net::PacketClientEnum;
+use mineroute::sessions::SessionReconciler;
+use support::*;
+
+/// Panics on client packets with the id 0x0c
+struct Faulty;
+
+impl Extension for Faulty {
+    fn name(&self) -> &str {
+        "faulty"
+    }
+
+    fn intercepts_play(&self) -> bool {
+        true
+    }
+
+    fn on_client_packet(&self, _session: &SessionInfo, packet: &PacketClientEnum) -> PacketAction<PacketClientEnum> {
+        match packet {
+            PacketClientEnum::Raw(packet) if packet.id == 0x0c => panic!("faulty extension"),
+            _ => PacketAction::Forward,
+        }
+    }
+}
+
+/// The players listed on a server of the proxy
+fn players(proxy: &Proxy, host: &str) -> Vec<String> {
+    proxy.config.load().get_server(host).unwrap().players.read().unwrap().clone()
+}
+
+/// Wait until the player list of a server matches
+async fn await_players(proxy: &Proxy, host: &str, expected: &[&str]) {
+    for _ in 0..100 {
+        if players(proxy, host) == expected {
+            return;
+        }
+        delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(players(proxy, host), expected);
+}
+
+#[test]
+fn a_panic_only_ends_its_own_session() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+        proxy.config.write().register_extension(Arc::new(Faulty));
+        let panics = SESSION_PANICS.get();
+
+        let mut steve = FakeClient::connect(proxy.address).await;
+        steve.login("a.test", "steve").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+        let mut alex = FakeClient::connect(proxy.address).await;
+        alex.login("a.test", "alex").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+
+        steve.send_play(0x0c, &[1]).await;
+        assert!(steve.next_packet().await.is_none());
+        await_players(&proxy, "a.test", &["alex"]).await;
+        assert!(SESSION_PANICS.get() > panics);
+
+        // The other session and the proxy keep working
+        alex.send_play(0x0b, &[1, 2, 3]).await;
+        match backend.next_event().await {
+            Some(BackendEvent::Play(packet)) => assert_eq!(packet.id, 0x0b),
+            event => panic!("expected a play packet, got {:?}", event),
+        }
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        await_players(&proxy, "a.test", &["alex", "steve"]).await;
+    });
+}
+
+#[test]
+fn players_without_a_session_are_removed() {
+    run(async move {
+        let mut backend = FakeBackend::start(BackendScript::new("a")).await;
+        let proxy = Proxy::start(listener(), &[("a.test", backend.address)]).await;
+        let ghosts = GHOST_PLAYERS.get();
+
+        let mut client = FakeClient::connect(proxy.address).await;
+        client.login("a.test", "steve").await.unwrap();
+        backend.next_event().await;
+        backend.next_event().await;
+        proxy.config.load().get_server("a.test").unwrap().add_player("ghost".to_owned());
+
+        SessionReconciler::with_interval(proxy.config.clone(), Duration::from_millis(50)).start();
+        await_players(&proxy, "a.test", &["steve"]).await;
+        assert!(GHOST_PLAYERS.get() > ghosts);
+    });
+}
@@ -0,0 +1,487 @@
+//! In-process fake Minecraft clients and backends that talk to a proxy
+//! listening on an ephemeral port.
+
+#![allow(dead_code)]
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use actix::prelude::*;
+use actix::io::WriteHandler;
+use bytes::{Bytes, BytesMut};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::{ready, BoxFuture, FutureExt};
+use futures::StreamExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use uuid::Uuid;
+use mineroute::net::{Client, Connection, PacketClientEnum, PacketServerEnum, Protocol, Server};
+use mineroute::net::manager::{ConnectionManager, HandlerMessage, Peer, ProxyClientManager, Relayed};
+use mineroute::net::handshake::HandshakePacket;
+use mineroute::net::login::{CompressionPacket, LoginStartPacket, LoginSuccessPacket};
+use mineroute::net::buffer::{Buffer, BufferMut};
+use mineroute::net::play::{PlayPacketIds, RawPacket};
+use mineroute::net::status::{PingPacket, PongPacket, StatusRequestPacket, StatusResponsePacket};
+use mineroute::net::status::server_status::{Players, ServerInfo, Version};
+use mineroute::server_state::{Configuration, SharedConfiguration, ServerConfig};
+use mineroute::arbiters::ArbiterPool;
+use mineroute::listener::{ListenerManager, ApplyListeners, Reload};
+use mineroute::settings::{CompressionLevel, FrameLimits, ListenerSettings};
+use mineroute::upstream::{ResolveError, Resolver, SrvTarget, UpstreamResolver};
+
+pub const PROTOCOL_VERSION: i32 = 340;
+
+/// How long a test waits for a packet before giving up
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run a test within an actix system
+pub fn run<F: std::future::Future<Output = ()> + 'static>(test: F) {
+    System::new("test").block_on(test);
+}
+
+/// Upstreams are IP addresses, so no DNS queries are made
+struct NoDns;
+
+impl Resolver for NoDns {
+    fn lookup_srv(&self, _name: &str) -> BoxFuture<'static, Result<Vec<SrvTarget>, ResolveError>> {
+        ready(Ok(vec![])).boxed()
+    }
+
+    fn lookup_ip(&self, host: &str) -> BoxFuture<'static, Result<Vec<IpAddr>, ResolveError>> {
+        ready(Err(ResolveError(format!("{} not found", host)))).boxed()
+    }
+}
+
+/// A listener with the default settings, bound to an ephemeral port
+pub fn listener() -> ListenerSettings {
+    ListenerSettings {
+        name: "test".to_owned(),
+        bind: "127.0.0.1:0".parse().unwrap(),
+        ipv6_only: false,
+        proxy_protocol: false,
+        hostnames: None,
+        nodelay: true,
+        keepalive: None,
+        reuse_port: false,
+        compression_threshold: None,
+        compression_level: CompressionLevel::Default,
+        splice: true,
+        limits: FrameLimits::default(),
+        query: None,
+        bedrock: None,
+    }
+}
+
+/// A proxy accepting clients on an ephemeral port
+pub struct Proxy {
+    pub address: SocketAddr,
+    pub config: Arc<SharedConfiguration>,
+
+    /// Keeps the listeners open if they are managed by a [ListenerManager]
+    listeners: Option<Addr<ListenerManager>>,
+}
+
+impl Proxy {
+    /// Start accepting clients that are routed to the provided servers
+    pub async fn start(listener: ListenerSettings, servers: &[(&str, SocketAddr)]) -> Proxy {
+        let config = Proxy::config(servers);
+        let mut socket = TcpListener::bind(listener.bind).await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = Arc::new(listener);
+
+        let accept_config = config.clone();
+        actix::spawn(async move {
+            while let Ok((stream, address)) = socket.accept().await {
+                let config = accept_config.clone();
+                let listener = listener.clone();
+                ProxyClientManager::create(move |ctx| ProxyClientManager::new(config, listener, address, stream, ctx));
+            }
+        });
+
+        Proxy { address, config, listeners: None }
+    }
+
+    /// Accept clients through a [ListenerManager], which runs the sessions on a pool of arbiters
+    pub async fn start_pooled(listener: ListenerSettings, servers: &[(&str, SocketAddr)], workers: usize) -> Proxy {
+        Proxy::start_reloadable(listener, servers, workers, PathBuf::from("mineroute.toml")).await
+    }
+
+    /// Accept clients through a [ListenerManager] that reloads its listeners from a configuration file
+    pub async fn start_reloadable(mut listener: ListenerSettings, servers: &[(&str, SocketAddr)], workers: usize,
+                                  settings_path: PathBuf) -> Proxy {
+        let config = Proxy::config(servers);
+        listener.bind = closed_address().await;
+        let address = listener.bind;
+
+        let arbiters = Arc::new(ArbiterPool::new(workers));
+        let listeners = ListenerManager::new(config.clone(), arbiters, settings_path).start();
+        listeners.send(ApplyListeners(vec![listener])).await.unwrap().unwrap();
+
+        Proxy { address, config, listeners: Some(listeners) }
+    }
+
+    /// Apply the listeners of the configuration file again
+    pub async fn reload(&self) -> Result<(), String> {
+        self.listeners.as_ref().expect("the listeners are not managed").send(Reload).await.unwrap()
+    }
+
+    fn config(servers: &[(&str, SocketAddr)]) -> Arc<SharedConfiguration> {
+        let resolver = Arc::new(UpstreamResolver::new(Arc::new(NoDns), Duration::from_secs(60)));
+        let mut config = Configuration::new(resolver);
+        for (host, upstream) in servers {
+            config.add_server(host, ServerConfig::new(upstream.to_string().parse().unwrap()));
+        }
+        Arc::new(SharedConfiguration::new(config))
+    }
+}
+
+/// An address on which no server is listening
+pub async fn closed_address() -> SocketAddr {
+    let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    socket.local_addr().unwrap()
+}
+
+/// How a [FakeBackend] answers its clients
+#[derive(Clone)]
+pub struct BackendScript {
+    /// The description reported in status responses
+    pub description: String,
+
+    /// Enable compression with this threshold before the login succeeds
+    pub compression: Option<usize>,
+
+    /// Play packets sent right after the login succeeded, before the client sent anything
+    pub join_packets: Vec<RawPacket>,
+}
+
+impl BackendScript {
+    pub fn new(description: &str) -> BackendScript {
+        BackendScript {
+            description: description.to_owned(),
+            compression: None,
+            join_packets: Vec::new(),
+        }
+    }
+
+    pub fn with_compression(mut self, threshold: usize) -> BackendScript {
+        self.compression = Some(threshold);
+        self
+    }
+
+    pub fn with_join_packets(mut self, packets: Vec<RawPacket>) -> BackendScript {
+        self.join_packets = packets;
+        self
+    }
+
+    fn server_info(&self) -> ServerInfo {
+        ServerInfo {
+            version: Version { name: "1.12.2".to_owned(), protocol: PROTOCOL_VERSION },
+            players: Players { max: 20, online: 0, sample: None },
+            description: serde_json::Value::String(self.description.clone()),
+            favicon: None,
+        }
+    }
+}
+
+/// What a [FakeBackend] received from the proxy
+#[derive(Debug)]
+pub enum BackendEvent {
+    Handshake(HandshakePacket),
+    StatusRequest,
+    LoginStart(String),
+    Play(RawPacket),
+}
+
+/// A minecraft server answering status requests and logins.
+/// Play packets are echoed back to the client.
+pub struct FakeBackend {
+    pub address: SocketAddr,
+    events: UnboundedReceiver<BackendEvent>,
+}
+
+impl FakeBackend {
+    pub async fn start(script: BackendScript) -> FakeBackend {
+        let mut socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let (events, receiver) = unbounded();
+
+        actix::spawn(async move {
+            while let Ok((stream, _)) = socket.accept().await {
+                let script = script.clone();
+                let events = events.clone();
+                BackendSession::create(move |ctx| BackendSession {
+                    connection: Connection::new::<BackendSession>(stream, ctx),
+                    script,
+                    events,
+                });
+            }
+        });
+
+        FakeBackend { address, events: receiver }
+    }
+
+    /// The next packet received by any session of this backend
+    pub async fn next_event(&mut self) -> Option<BackendEvent> {
+        timeout(RECEIVE_TIMEOUT, self.events.next()).await.ok().flatten()
+    }
+}
+
+/// The connection of the proxy to a [FakeBackend]
+struct BackendSession {
+    connection: Connection<Client>,
+    script: BackendScript,
+    events: UnboundedSender<BackendEvent>,
+}
+
+impl BackendSession {
+    fn handle_packet(&mut self, packet: PacketClientEnum) -> Result<(), ()> {
+        match packet {
+            PacketClientEnum::Handshake(packet) => {
+                self.connection.set_protocol(packet.next_protocol.clone());
+                let _ = self.events.unbounded_send(BackendEvent::Handshake(packet));
+            }
+            PacketClientEnum::StatusRequest(_) => {
+                let _ = self.events.unbounded_send(BackendEvent::StatusRequest);
+                let status = self.script.server_info();
+                self.connection.send_packet(PacketServerEnum::StatusResponse(StatusResponsePacket { status }))?;
+            }
+            PacketClientEnum::Ping(PingPacket { payload }) => {
+                self.connection.send_packet(PacketServerEnum::Pong(PongPacket { payload }))?;
+            }
+            PacketClientEnum::LoginStart(packet) => {
+                if let Some(size_limit) = self.script.compression {
+                    let compression = CompressionPacket { size_limit: Some(size_limit) };
+                    self.connection.send_packet(PacketServerEnum::Compression(compression))?;
+                    self.connection.enable_compression(Some(size_limit), CompressionLevel::Default);
+                }
+
+                let success = LoginSuccessPacket { uuid: Uuid::nil(), name: packet.name.clone() };
+                self.connection.send_packet(PacketServerEnum::LoginSuccess(success))?;
+                self.connection.set_protocol(Protocol::Play);
+                for packet in &self.script.join_packets {
+                    self.connection.send_packet(PacketServerEnum::Raw(packet.clone()))?;
+                }
+                let _ = self.events.unbounded_send(BackendEvent::LoginStart(packet.name));
+            }
+            PacketClientEnum::Raw(packet) => {
+                let echo = RawPacket { id: packet.id, data: packet.data.clone(), compressed: None };
+                self.connection.send_packet(PacketServerEnum::Raw(echo))?;
+                let _ = self.events.unbounded_send(BackendEvent::Play(packet));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Actor for BackendSession {
+    type Context = Context<Self>;
+}
+
+impl ConnectionManager<Client> for BackendSession {}
+
+impl StreamHandler<Result<PacketClientEnum, ()>> for BackendSession {
+    fn handle(&mut self, packet: Result<PacketClientEnum, ()>, _ctx: &mut Self::Context) {
+        if packet.and_then(|packet| self.handle_packet(packet)).is_err() {
+            self.connection.disconnect();
+        }
+    }
+}
+
+impl StreamHandler<Relayed<Client>> for BackendSession {
+    fn handle(&mut self, relayed: Relayed<Client>, ctx: &mut Self::Context) {
+        handle_relayed(&mut self.connection, relayed, ctx);
+    }
+}
+
+impl WriteHandler<()> for BackendSession {}
+
+/// A minecraft client connected to the proxy
+pub struct FakeClient {
+    session: Addr<ClientSession>,
+    peer: Peer<Server>,
+    packets: UnboundedReceiver<PacketServerEnum>,
+}
+
+impl FakeClient {
+    pub async fn connect(proxy: SocketAddr) -> FakeClient {
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let (sender, packets) = unbounded();
+        let mut peer = None;
+        let session = ClientSession::create(|ctx| {
+            let connection = Connection::new::<ClientSession>(stream, ctx);
+            peer = Some(connection.relay::<ClientSession>(ctx));
+            ClientSession { connection, packets: sender }
+        });
+        FakeClient { session, peer: peer.unwrap(), packets }
+    }
+
+    pub async fn send(&self, packet: PacketClientEnum) {
+        self.peer.send_packet(packet).unwrap();
+    }
+
+    pub async fn handshake(&self, host: &str, next_protocol: Protocol) {
+        self.handshake_version(host, next_protocol, PROTOCOL_VERSION).await;
+    }
+
+    pub async fn handshake_version(&self, host: &str, next_protocol: Protocol, protocol_version: i32) {
+        self.send(PacketClientEnum::Handshake(HandshakePacket {
+            protocol_version,
+            server_address: host.to_owned(),
+            server_port: 25565,
+            next_protocol: next_protocol.clone(),
+        })).await;
+        self.peer.send_control(HandlerMessage::SetProtocol(next_protocol));
+    }
+
+    /// Request the status of a server.
+    /// Returns `None` if the proxy did not answer.
+    pub async fn status(&mut self, host: &str) -> Option<ServerInfo> {
+        self.handshake(host, Protocol::Status).await;
+        self.send(PacketClientEnum::StatusRequest(StatusRequestPacket)).await;
+        match self.next_packet().await? {
+            PacketServerEnum::StatusResponse(response) => Some(response.status),
+            _ => None,
+        }
+    }
+
+    /// Log in to a server and switch to the play protocol.
+    /// Returns the packet that ended the login, if it was not a successful one.
+    pub async fn login(&mut self, host: &str, name: &str) -> Result<LoginSuccessPacket, Option<PacketServerEnum>> {
+        self.login_version(host, name, PROTOCOL_VERSION).await
+    }
+
+    /// Log in like a client of another version of Minecraft
+    pub async fn login_version(&mut self, host: &str, name: &str, protocol_version: i32) -> Result<LoginSuccessPacket, Option<PacketServerEnum>> {
+        self.handshake_version(host, Protocol::Login, protocol_version).await;
+        self.send(PacketClientEnum::LoginStart(LoginStartPacket { name: name.to_owned() })).await;
+
+        loop {
+            match self.next_packet().await {
+                Some(PacketServerEnum::Compression(_)) => continue,
+                Some(PacketServerEnum::LoginSuccess(success)) => return Ok(success),
+                packet => return Err(packet),
+            }
+        }
+    }
+
+    pub async fn send_play(&self, id: u8, data: &[u8]) {
+        self.send(PacketClientEnum::Raw(RawPacket {
+            id,
+            data: Bytes::copy_from_slice(data),
+            compressed: None,
+        })).await;
+    }
+
+    /// Send a chat message, e.g. a command
+    pub async fn chat(&self, message: &str) {
+        let ids = PlayPacketIds::for_version(PROTOCOL_VERSION).unwrap();
+        let mut data = BytesMut::new();
+        data.write_string(message);
+        self.send_play(ids.chat_serverbound, &data).await;
+    }
+
+    /// The text of the next chat message, skipping other packets.
+    /// Returns `None` once the connection was closed or no message arrived in time.
+    pub async fn next_chat(&mut self) -> Option<String> {
+        let ids = PlayPacketIds::for_version(PROTOCOL_VERSION).unwrap();
+        loop {
+            match self.next_packet().await? {
+                PacketServerEnum::Raw(packet) if packet.id == ids.chat_clientbound => {
+                    let message: serde_json::Value = serde_json::from_str(&BytesMut::from(&packet.data[..]).read_string().ok()?).ok()?;
+                    return message["text"].as_str().map(str::to_owned);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// The next packet received from the proxy.
+    /// Returns `None` once the connection was closed or no packet arrived in time.
+    pub async fn next_packet(&mut self) -> Option<PacketServerEnum> {
+        timeout(RECEIVE_TIMEOUT, self.packets.next()).await.ok().flatten()
+    }
+
+    /// Close the connection to the proxy
+    pub async fn disconnect(&self) {
+        self.peer.send_control(HandlerMessage::Disconnect());
+    }
+
+    /// The compression threshold that the proxy enabled for this client
+    pub async fn compression(&self) -> Option<usize> {
+        self.session.send(GetCompression).await.ok().flatten()
+    }
+}
+
+/// The connection of a [FakeClient] to the proxy
+struct ClientSession {
+    connection: Connection<Server>,
+    packets: UnboundedSender<PacketServerEnum>,
+}
+
+impl Actor for ClientSession {
+    type Context = Context<Self>;
+}
+
+impl ConnectionManager<Server> for ClientSession {}
+
+impl StreamHandler<Result<PacketServerEnum, ()>> for ClientSession {
+    fn handle(&mut self, packet: Result<PacketServerEnum, ()>, ctx: &mut Self::Context) {
+        match packet {
+            Ok(packet) => {
+                match &packet {
+                    PacketServerEnum::Compression(compression) =>
+                        self.connection.enable_compression(compression.size_limit, CompressionLevel::Default),
+                    PacketServerEnum::LoginSuccess(_) => self.connection.set_protocol(Protocol::Play),
+                    _ => {}
+                }
+                let _ = self.packets.unbounded_send(packet);
+            }
+            Err(()) => ctx.stop(),
+        }
+    }
+
+    /// The proxy closed the connection
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+impl StreamHandler<Relayed<Server>> for ClientSession {
+    fn handle(&mut self, relayed: Relayed<Server>, ctx: &mut Self::Context) {
+        handle_relayed(&mut self.connection, relayed, ctx);
+    }
+
+    /// The [FakeClient] was dropped
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+impl WriteHandler<()> for ClientSession {}
+
+struct GetCompression;
+
+impl Message for GetCompression {
+    type Result = Option<usize>;
+}
+
+impl Handler<GetCompression> for ClientSession {
+    type Result = Option<usize>;
+    fn handle(&mut self, _: GetCompression, _ctx: &mut Self::Context) -> Self::Result {
+        self.connection.compression()
+    }
+}
+
+fn handle_relayed<C, A>(connection: &mut Connection<C>, relayed: Relayed<C>, ctx: &mut Context<A>)
+    where C: mineroute::net::ConnectionType, A: Actor<Context = Context<A>> {
+    match relayed {
+        Relayed::Packet(packet) => {
+            let _ = connection.send_packet(packet);
+        }
+        Relayed::Control(HandlerMessage::SetProtocol(protocol)) => connection.set_protocol(protocol),
+        Relayed::Control(HandlerMessage::EnableCompression(size_limit)) => {
+            connection.enable_compression(size_limit, CompressionLevel::Default);
+        }
+        Relayed::Control(HandlerMessage::Disconnect()) => connection.shutdown(ctx),
+        Relayed::Control(HandlerMessage::SwitchCompleted()) | Relayed::Control(HandlerMessage::SwitchFailed()) => {}
+    }
+}